@@ -1,4 +1,4 @@
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 use std::sync::mpsc::channel;
 
 use database::Database;
@@ -9,14 +9,50 @@ use crate::command;
 
 const UNEXPECTED_END: &str =
     "Unexpected end of file reading the append only file. You can: 1) Make a backup of your AOF \
-     file, then use ./redis-check-aof --fix <filename>. 2) Alternatively you can set the \
+     file, then use ./rsedis-check-aof --fix <filename>. 2) Alternatively you can set the \
      'aof-load-truncated' configuration option to yes and restart the server.";
 
+/// Loads the snapshot at the head of the file written by a rewrite with
+/// `aof-use-rdb-preamble`, if any, returning its length.
+fn load_preamble<R: BufRead + Seek>(db: &mut Database, reader: &mut R) -> usize {
+    let has_preamble = match reader.fill_buf() {
+        Ok(buf) => buf.starts_with(b"REDIS"),
+        Err(err) => panic!("Error reading aof: {:?}", err),
+    };
+    if !has_preamble {
+        return 0;
+    }
+    logger::log!(db.config.logger, Notice, "Reading RDB preamble from AOF file...");
+    if let Err(err) = db.rdb_load(reader) {
+        logger::log_and_exit!(
+            db.config.logger,
+            Warning,
+            1,
+            "Error reading the RDB preamble of the AOF file: {}",
+            err
+        );
+    }
+    logger::log!(db.config.logger, Notice, "Reading the remaining AOF tail...");
+    match reader.seek(SeekFrom::Current(0)) {
+        Ok(pos) => pos as usize,
+        Err(err) => panic!("Error reading aof: {:?}", err),
+    }
+}
+
 pub fn load(db: &mut Database) {
     let mut aof = db.aof.take().unwrap();
     db.loading = true;
     let mut client = command::Client::new(channel().0, 0);
+    // the file is applied like the master's stream, even on a read only
+    // replica or before the cluster slots are known
+    client.is_master = true;
     let mut parser = Parser::new();
+    let mut reader = BufReader::new(&mut aof);
+    // the parser reuses its buffer, so its position is not an offset in the file
+    let mut offset = load_preamble(db, &mut reader);
+    let mut truncate = None;
+    // where the open transaction starts, to drop it when EXEC is missing
+    let mut multi_start = None;
     loop {
         if parser.is_incomplete() {
             parser.allocate();
@@ -24,7 +60,7 @@ pub fn load(db: &mut Database) {
                 let pos = parser.written;
                 let buffer = parser.get_mut();
 
-                match aof.read(&mut buffer[pos..]) {
+                match reader.read(&mut buffer[pos..]) {
                     Ok(r) => r,
                     Err(err) => panic!("Error reading aof: {:?}", err),
                 }
@@ -36,12 +72,13 @@ pub fn load(db: &mut Database) {
                     if !db.config.aof_load_truncated {
                         logger::log_and_exit!(db.config.logger, Warning, 1, "{}", UNEXPECTED_END);
                     }
-                    aof.truncate(parser.position);
+                    truncate = Some(multi_start.unwrap_or(offset));
                 }
                 break;
             }
         }
 
+        let start = parser.position;
         let parsed_command = match parser.next() {
             Ok(p) => p,
             Err(err) => {
@@ -64,11 +101,92 @@ pub fn load(db: &mut Database) {
             }
         };
 
+        let in_multi = client.multi;
         command::command(parsed_command, db, &mut client).unwrap();
+        if !client.multi {
+            multi_start = None;
+        } else if !in_multi {
+            multi_start = Some(offset);
+        }
+        offset += parser.position - start;
+    }
+    drop(reader);
+    // the file ends inside a transaction: its commands were never applied,
+    // and the ones appended after it would be queued behind it
+    if let (None, Some(pos)) = (truncate, multi_start) {
+        if !db.config.aof_load_truncated {
+            logger::log_and_exit!(db.config.logger, Warning, 1, "{}", UNEXPECTED_END);
+        }
+        truncate = Some(pos);
     }
-    if client.multi && !db.config.aof_load_truncated {
-        logger::log_and_exit!(db.config.logger, Warning, 1, "{}", UNEXPECTED_END);
+    if let Some(pos) = truncate {
+        aof.truncate(pos);
     }
     db.aof = Some(aof);
     db.loading = false;
+    db.dirty = 0;
+}
+
+#[cfg(test)]
+mod test_aof {
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file, write};
+
+    use config::Config;
+    use database::aof::write_command;
+    use database::Database;
+    use logger::{Level, Logger};
+    use persistence::aof::Aof;
+
+    use super::load;
+
+    /// Loads `data` as the append only file `name`, and returns the
+    /// database and the contents of the file afterwards.
+    fn load_file(name: &str, data: &[u8]) -> (Database, Vec<u8>) {
+        let mut path = temp_dir();
+        path.push(name);
+        write(&path, data).unwrap();
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        db.config.aof_load_truncated = true;
+        db.aof = Some(Aof::new(&path).unwrap());
+        load(&mut db);
+        db.aof = None;
+        let contents = read(&path).unwrap();
+        let _ = remove_file(&path);
+        (db, contents)
+    }
+
+    #[test]
+    fn load_truncates_open_multi() {
+        let mut head = vec![];
+        write_command(&mut head, &[b"SELECT", b"0"]).unwrap();
+        write_command(&mut head, &[b"SET", b"a", b"1"]).unwrap();
+        let mut multi = vec![];
+        write_command(&mut multi, &[b"MULTI"]).unwrap();
+        write_command(&mut multi, &[b"SET", b"b", b"2"]).unwrap();
+        let mut last = vec![];
+        write_command(&mut last, &[b"SET", b"c", b"3"]).unwrap();
+
+        // the last command inside the transaction is truncated
+        let data = [&head[..], &multi[..], &last[..last.len() - 3]].concat();
+        let (db, contents) = load_file("rsedis-command-test-aof-multi-truncated.aof", &data);
+        assert_eq!(contents, head);
+        assert!(db.get(0, b"a").is_some());
+        assert!(db.get(0, b"b").is_none());
+
+        // the file ends cleanly before EXEC
+        let data = [&head[..], &multi[..], &last[..]].concat();
+        let (db, contents) = load_file("rsedis-command-test-aof-multi-open.aof", &data);
+        assert_eq!(contents, head);
+        assert!(db.get(0, b"b").is_none());
+        assert!(db.get(0, b"c").is_none());
+
+        // a complete transaction is kept
+        let mut exec = vec![];
+        write_command(&mut exec, &[b"EXEC"]).unwrap();
+        let data = [&head[..], &multi[..], &exec[..], &last[..last.len() - 3]].concat();
+        let (db, contents) = load_file("rsedis-command-test-aof-multi-exec.aof", &data);
+        assert_eq!(contents, [&head[..], &multi[..], &exec[..]].concat());
+        assert!(db.get(0, b"b").is_some());
+    }
 }
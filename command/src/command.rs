@@ -1,24 +1,36 @@
 use std::{
+    cmp::Ordering,
     collections::{Bound, HashMap, HashSet},
-    io::Write,
+    io::{self, BufRead, BufReader, Write},
     mem::replace,
+    net::{TcpStream, ToSocketAddrs},
+    process,
     sync::mpsc::channel,
     sync::mpsc::Sender,
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
     usize,
 };
 
-use bitflags::bitflags;
-
 use compat::{getos, getpid};
+use database::aof::write_command;
+use database::busy::Busy;
+use database::cluster::{key_hash_slot, Cluster, ClusterNode, CLUSTER_SLOTS};
+use database::module::{Command, CommandFlags, CommandProperties, Module};
+use database::replication::{MasterLinkState, Replica, ReplicaConf};
+use database::sentinel::{FailoverState, Instance, Master};
 use database::{zset, Database, PubsubEvent, Value};
 use database::zset::ValueSortedSet;
 use database::list::ValueList;
 use database::hash::ValueHash;
-use parser::{Argument, OwnedParsedCommand, ParsedCommand};
+use database::stream::{NewId, StreamEntry, StreamId, Trim, NODE_MAX_ENTRIES};
+use logger::{Level, Logger};
+use parser::{parse, Argument, OwnedParsedCommand, ParsedCommand};
 use response::{Response, ResponseError};
-use util::{mstime, ustime};
+use util::{glob_match, mstime, ustime};
+
+use crate::scripting;
 
 extern crate rand;
 
@@ -315,28 +327,286 @@ fn dump(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respon
 fn restore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
     validate_arguments_gte!(parser, 4);
     let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let _ttl = try_validate!(parser.get_i64(2), "Invalid TTL");
-    let _serialized_value = try_validate!(parser.get_vec(3), "Invalid serialized value");
-    
-    // Check if key exists (unless REPLACE is specified)
+    let ttl = try_validate!(
+        parser.get_i64(2),
+        "ERR value is not an integer or out of range"
+    );
+    let serialized_value = try_validate!(parser.get_vec(3), "Invalid serialized value");
+
     let mut replace = false;
-    if parser.argv.len() > 4 {
-        let option = try_validate!(parser.get_str(4), "Invalid option");
-        if option.to_ascii_lowercase() == "replace" {
-            replace = true;
-        } else {
-            return Response::Error("ERR syntax error".to_owned());
+    let mut absttl = false;
+    let mut idletime = None;
+    let mut freq = None;
+    let mut i = 4;
+    while i < parser.argv.len() {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error");
+        let has_value = i + 1 < parser.argv.len();
+        match &*option.to_ascii_lowercase() {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            "idletime" if has_value && freq.is_none() => {
+                i += 1;
+                let seconds = try_validate!(
+                    parser.get_i64(i),
+                    "ERR value is not an integer or out of range"
+                );
+                validate!(seconds >= 0, "ERR Invalid IDLETIME value, must be >= 0");
+                idletime = Some(try_validate!(
+                    seconds.checked_mul(1000).ok_or(()),
+                    "ERR Invalid IDLETIME value, out of range"
+                ));
+            }
+            "freq" if has_value && idletime.is_none() => {
+                i += 1;
+                let f = try_validate!(
+                    parser.get_i64(i),
+                    "ERR value is not an integer or out of range"
+                );
+                validate!(
+                    f >= 0 && f <= 255,
+                    "ERR Invalid FREQ value, must be >= 0 and <= 255"
+                );
+                freq = Some(f as u8);
+            }
+            _ => return Response::Error("ERR syntax error".to_owned()),
         }
+        i += 1;
     }
-    
+    validate!(ttl >= 0, "ERR Invalid TTL value, must be >= 0");
+    let expiration = match ttl {
+        0 => None,
+        ttl if absttl => Some(ttl),
+        ttl => Some(try_validate!(
+            ttl.checked_add(mstime()).ok_or(()),
+            "ERR invalid expire time in 'restore' command"
+        )),
+    };
+
     if !replace && db.get(dbindex, &key).is_some() {
-        return Response::Error("ERR Target key name is busy.".to_owned());
+        return Response::Error("BUSYKEY Target key name already exists.".to_owned());
+    }
+
+    let value = match Value::restore_with(&serialized_value, &db.modules) {
+        Ok(value) => value,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+
+    db.remove(dbindex, &key);
+    // an absolute TTL in the past means the key is already expired
+    if expiration.map_or(false, |ms| ms <= mstime()) {
+        db.key_updated(dbindex, &key);
+        return Response::Status("OK".to_owned());
+    }
+
+    *db.get_or_create(dbindex, &key) = value;
+    if let Some(ms) = expiration {
+        db.set_msexpiration(dbindex, key.clone(), ms);
+    }
+    if let Some(ms) = idletime {
+        db.set_idletime(dbindex, &key, ms);
+    }
+    if let Some(f) = freq {
+        db.set_freq(dbindex, &key, f);
+    }
+    db.key_updated(dbindex, &key);
+    db.notify_keyspace_event(dbindex, "restore", &key, Some('g'));
+    Response::Status("OK".to_owned())
+}
+
+fn migrate_error(e: io::Error, action: &str) -> String {
+    let reason = match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => "timeout",
+        _ => "error",
+    };
+    format!("IOERR {} {} target instance", reason, action)
+}
+
+/// The time left until `deadline`, or a timeout error once it passed.
+fn migrate_remaining(deadline: Instant) -> io::Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "the timeout expired"));
+    }
+    Ok(deadline - now)
+}
+
+/// Reads a single line reply from the target of a MIGRATE, waiting until
+/// `deadline` at most.
+fn migrate_reply(reader: &mut BufReader<&TcpStream>, deadline: Instant) -> io::Result<String> {
+    reader.get_ref().set_read_timeout(Some(migrate_remaining(deadline)?))?;
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the target closed the connection",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn migrate(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 6);
+    let host = try_validate!(parser.get_str(1), "ERR syntax error");
+    let port = try_validate!(
+        parser.get_i64(2),
+        "ERR value is not an integer or out of range"
+    );
+    validate!(port > 0 && port <= 65535, "ERR Invalid port");
+    let key = try_validate!(parser.get_vec(3), "Invalid key");
+    let destination = try_validate!(
+        parser.get_i64(4),
+        "ERR value is not an integer or out of range"
+    );
+    validate!(destination >= 0, "ERR invalid DB index");
+    let timeout = try_validate!(
+        parser.get_i64(5),
+        "ERR value is not an integer or out of range"
+    );
+    let timeout = Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 });
+
+    let mut copy = false;
+    let mut replace = false;
+    let mut password = None;
+    let mut keys = vec![];
+    let mut i = 6;
+    while i < parser.argv.len() {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error");
+        match &*option.to_ascii_lowercase() {
+            "copy" => copy = true,
+            "replace" => replace = true,
+            "auth" if i + 1 < parser.argv.len() => {
+                i += 1;
+                password = Some(try_validate!(parser.get_vec(i), "ERR syntax error"));
+            }
+            "keys" => {
+                validate!(
+                    key.is_empty(),
+                    "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                );
+                for j in (i + 1)..parser.argv.len() {
+                    keys.push(try_validate!(parser.get_vec(j), "Invalid key"));
+                }
+                break;
+            }
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+        i += 1;
+    }
+    if keys.is_empty() {
+        keys.push(key);
+    }
+
+    // the keys that do not exist or already expired are skipped
+    let now = mstime();
+    let mut entries = vec![];
+    for key in keys {
+        let mut data = vec![];
+        match db.get(dbindex, &key) {
+            Some(value) => {
+                if let Err(err) = value.dump(&mut data) {
+                    return Response::Error(err.to_string());
+                }
+            }
+            None => continue,
+        }
+        let ttl = match db.get_msexpiration(dbindex, &key) {
+            Some(ms) if *ms <= now => continue,
+            Some(ms) => *ms - now,
+            None => 0,
+        };
+        entries.push((key, ttl, data));
+    }
+    if entries.is_empty() {
+        return Response::Status("NOKEY".to_owned());
+    }
+
+    // in cluster mode the target is importing the slot, and would redirect
+    // a plain RESTORE back here
+    let restore: &[u8] = if db.cluster.is_some() {
+        b"RESTORE-ASKING"
+    } else {
+        b"RESTORE"
+    };
+    let mut request = vec![];
+    if let Some(password) = &password {
+        write_command(&mut request, &[b"AUTH", password]).unwrap();
+    }
+    write_command(&mut request, &[b"SELECT", format!("{}", destination).as_bytes()]).unwrap();
+    for (key, ttl, data) in entries.iter() {
+        let ttl = format!("{}", ttl);
+        let mut args: Vec<&[u8]> = vec![restore, key, ttl.as_bytes(), data];
+        if replace {
+            args.push(b"REPLACE");
+        }
+        write_command(&mut request, &args).unwrap();
+    }
+
+    let addr = match (host, port as u16).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        _ => return Response::Error("IOERR error or timeout connecting to the client".to_owned()),
+    };
+    // the timeout bounds the whole exchange, as the database is locked
+    // meanwhile
+    let deadline = Instant::now() + timeout;
+    let stream = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(stream) => stream,
+        Err(_) => return Response::Error("IOERR error or timeout connecting to the client".to_owned()),
+    };
+    let r = migrate_remaining(deadline)
+        .and_then(|remaining| stream.set_write_timeout(Some(remaining)))
+        .and_then(|_| (&stream).write_all(&request));
+    if let Err(e) = r {
+        return Response::Error(migrate_error(e, "writing to"));
+    }
+
+    let mut reader = BufReader::new(&stream);
+    let mut error = None;
+    let preamble = if password.is_some() { 2 } else { 1 };
+    for _ in 0..preamble {
+        match migrate_reply(&mut reader, deadline) {
+            Ok(ref reply) if reply.starts_with('-') => {
+                error = Some(reply[1..].to_owned());
+            }
+            Ok(_) => {}
+            Err(e) => return Response::Error(migrate_error(e, "reading from")),
+        }
+    }
+    let mut deleted = vec![];
+    if error.is_none() {
+        for (key, _, _) in entries.iter() {
+            match migrate_reply(&mut reader, deadline) {
+                Ok(ref reply) if reply.starts_with('-') => {
+                    error = Some(reply[1..].to_owned());
+                }
+                Ok(_) => {
+                    if !copy && db.remove(dbindex, key).is_some() {
+                        db.key_updated(dbindex, key);
+                        db.notify_keyspace_event(dbindex, "del", key, Some('g'));
+                        deleted.push(key.clone());
+                    }
+                }
+                Err(e) => {
+                    error = Some(migrate_error(e, "reading from"));
+                    break;
+                }
+            }
+        }
+    }
+
+    // the replicas and the append only file only see the keys go away
+    if !deleted.is_empty() {
+        let mut args: Vec<&[u8]> = vec![b"DEL"];
+        args.extend(deleted.iter().map(|key| &key[..]));
+        let mut data = vec![];
+        write_command(&mut data, &args).unwrap();
+        propagate_raw(db, dbindex, &data);
+        db.dirty += deleted.len() as u64;
+    }
+    match error {
+        Some(ref error) if error.starts_with("IOERR") => Response::Error(error.clone()),
+        Some(error) => Response::Error(format!("ERR Target instance replied with error: {}", error)),
+        None => Response::Status("OK".to_owned()),
     }
-    
-    // TODO: Implement full RDB deserialization
-    // For now, return error indicating it's not fully implemented
-    // This is a complex operation that requires full RDB format parsing
-    Response::Error("ERR RESTORE is not fully implemented yet".to_owned())
 }
 
 fn sort(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
@@ -651,6 +921,8 @@ fn dbtype(parser: &mut ParsedCommand, db: &Database, dbindex: usize) -> Response
         Some(Value::Set(_)) => Response::Data("set".to_owned().into_bytes()),
         Some(Value::SortedSet(_)) => Response::Data("zset".to_owned().into_bytes()),
         Some(Value::Hash(_)) => Response::Data("hash".to_owned().into_bytes()),
+        Some(Value::Stream(_)) => Response::Data("stream".to_owned().into_bytes()),
+        Some(Value::Module(m)) => Response::Data(m.type_name().as_bytes().to_vec()),
         None => Response::Data("none".to_owned().into_bytes()),
     }
 }
@@ -2439,18 +2711,25 @@ fn zinterstore(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) ->
 }
 
 fn hset(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_exact!(parser, 4);
+    validate_arguments_gte!(parser, 4);
+    validate!(
+        (parser.argv.len() - 1) % 2 == 1,
+        "ERR wrong number of arguments for 'hset' command"
+    );
     let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let field = try_validate!(parser.get_vec(2), "Invalid field");
-    let value = try_validate!(parser.get_vec(3), "Invalid value");
     let el = db.get_or_create(dbindex, &key);
-    match el.hset(field, value) {
-        Ok(created) => {
-            db.key_updated(dbindex, &key);
-            Response::Integer(if created { 1 } else { 0 })
+    let mut created = 0;
+    for i in (2..parser.argv.len()).step_by(2) {
+        let field = try_validate!(parser.get_vec(i), "Invalid field");
+        let value = try_validate!(parser.get_vec(i + 1), "Invalid value");
+        match el.hset(field, value) {
+            Ok(true) => created += 1,
+            Ok(false) => (),
+            Err(err) => return Response::Error(err.to_string()),
         }
-        Err(err) => Response::Error(err.to_string()),
     }
+    db.key_updated(dbindex, &key);
+    Response::Integer(created)
 }
 
 fn hsetnx(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
@@ -2677,111 +2956,510 @@ fn hincrbyfloat(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -
     }
 }
 
-fn scan(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_gte!(parser, 2);
-    let cursor = try_validate!(parser.get_i64(1), "ERR invalid cursor");
-    validate!(cursor >= 0, "ERR invalid cursor");
-    let mut pattern = None;
-    let mut count = 10;
-    
-    let mut i = 2;
-    while i < parser.argv.len() {
-        let arg = try_validate!(parser.get_str(i), "syntax error");
-        match &*arg.to_ascii_lowercase() {
-            "match" => {
-                pattern = Some(try_validate!(parser.get_vec(i + 1), "syntax error"));
-                i += 2;
-            }
-            "count" => {
-                let c = try_validate!(parser.get_i64(i + 1), "syntax error");
-                validate!(c >= 0, "syntax error");
-                count = c as usize;
-                if count == 0 {
-                    count = 10;
-                }
-                i += 2;
-            }
-            _ => return Response::Error("syntax error".to_owned()),
+/// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]` starting at `i`.
+/// Returns the trim, whether it is approximate, the most entries it may
+/// remove and the index of the next argument.
+fn xtrim_args(parser: &ParsedCommand, mut i: usize) -> Result<(Trim, bool, usize, usize), Response> {
+    let error = |e: &str| Err(Response::Error(e.to_owned()));
+    let strategy = match parser.get_str(i) {
+        Ok(strategy) => strategy.to_ascii_lowercase(),
+        Err(_) => return error("ERR syntax error"),
+    };
+    i += 1;
+    let mut approx = false;
+    match parser.get_slice(i) {
+        Ok(b"~") => {
+            approx = true;
+            i += 1;
+        }
+        Ok(b"=") => i += 1,
+        _ => (),
+    }
+    let trim = match &*strategy {
+        "maxlen" => match parser.get_i64(i) {
+            Ok(maxlen) if maxlen >= 0 => Trim::MaxLen(maxlen as usize),
+            Ok(_) => return error("ERR The MAXLEN argument must be >= 0."),
+            Err(_) => return error("ERR value is not an integer or out of range"),
+        },
+        "minid" => match parser.get_slice(i).map(|arg| StreamId::parse(arg, 0)) {
+            Ok(Ok(minid)) => Trim::MinId(minid),
+            Ok(Err(err)) => return Err(Response::Error(err.to_string())),
+            Err(_) => return error("ERR syntax error"),
+        },
+        _ => return error("ERR syntax error"),
+    };
+    i += 1;
+    // approximate trims only remove whole nodes, and at most a hundred of
+    // them unless told otherwise
+    let mut limit = if approx { 100 * NODE_MAX_ENTRIES } else { 0 };
+    if parser.get_str(i).map_or(false, |s| s.eq_ignore_ascii_case("limit")) {
+        limit = match parser.get_i64(i + 1) {
+            Ok(limit) if limit >= 0 => limit as usize,
+            Ok(_) => return error("ERR The LIMIT argument must be >= 0."),
+            Err(_) => return error("ERR value is not an integer or out of range"),
+        };
+        if !approx {
+            return error("ERR syntax error, LIMIT cannot be used without the special ~ option");
         }
+        i += 2;
     }
-    
-    let (next_cursor, keys) = db.scan(dbindex, cursor as usize, pattern.as_deref(), count);
-    let result = vec![
-        Response::Data(next_cursor.to_string().into_bytes()),
-        Response::Array(keys.into_iter().map(Response::Data).collect()),
-    ];
-    Response::Array(result)
+    Ok((trim, approx, limit, i))
 }
 
-fn sscan(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_gte!(parser, 3);
+/// Replies with stream entries as `[id, [field, value, ...]]` pairs.
+fn stream_entries(entries: Vec<&StreamEntry>) -> Response {
+    Response::Array(
+        entries
+            .into_iter()
+            .map(|entry| {
+                Response::Array(vec![
+                    Response::Data(entry.id.to_string().into_bytes()),
+                    Response::Array(entry.fields.iter().map(|f| Response::Data(f.clone())).collect()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn xadd(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 5);
     let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let cursor = try_validate!(parser.get_i64(2), "ERR invalid cursor");
-    validate!(cursor >= 0, "ERR invalid cursor");
-    let mut pattern = None;
-    let mut count = 10;
-    
-    let mut i = 3;
-    while i < parser.argv.len() {
-        let arg = try_validate!(parser.get_str(i), "syntax error");
-        match &*arg.to_ascii_lowercase() {
-            "match" => {
-                pattern = Some(try_validate!(parser.get_vec(i + 1), "syntax error"));
-                i += 2;
+    let mut nomkstream = false;
+    let mut trim = None;
+    let mut i = 2;
+    loop {
+        let option = match parser.get_str(i) {
+            Ok(option) => option.to_ascii_lowercase(),
+            Err(_) => break,
+        };
+        match &*option {
+            "nomkstream" => {
+                nomkstream = true;
+                i += 1;
             }
-            "count" => {
-                let c = try_validate!(parser.get_i64(i + 1), "syntax error");
-                validate!(c >= 0, "syntax error");
-                count = c as usize;
-                if count == 0 {
-                    count = 10;
+            "maxlen" | "minid" => match xtrim_args(parser, i) {
+                Ok((t, approx, limit, next)) => {
+                    trim = Some((t, approx, limit));
+                    i = next;
                 }
-                i += 2;
+                Err(r) => return r,
+            },
+            _ => break,
+        }
+    }
+    let id = match parser.get_slice(i).map(NewId::parse) {
+        Ok(Ok(id)) => id,
+        Ok(Err(err)) => return Response::Error(err.to_string()),
+        Err(_) => return Response::Error("ERR syntax error".to_owned()),
+    };
+    let len = parser.argv.len();
+    validate!(
+        len > i + 1 && (len - i - 1) % 2 == 0,
+        "ERR wrong number of arguments for 'xadd' command"
+    );
+    let fields = try_validate!(
+        (i + 1..len).map(|j| parser.get_vec(j)).collect::<Result<Vec<_>, _>>(),
+        "Invalid value"
+    );
+
+    let id = match db.get_mut(dbindex, &key) {
+        Some(el) => el.xadd(id, fields),
+        None if nomkstream => return Response::Nil,
+        // the stream is only created along with its first entry
+        None => {
+            let mut el = Value::Nil;
+            el.xadd(id, fields).map(|id| {
+                *db.get_or_create(dbindex, &key) = el;
+                id
+            })
+        }
+    };
+    let id = match id {
+        Ok(id) => id,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+    db.notify_keyspace_event(dbindex, "xadd", &key, Some('t'));
+    if let Some((t, approx, limit)) = trim {
+        if let Some(el) = db.get_mut(dbindex, &key) {
+            if el.xtrim(t, approx, limit).unwrap_or(0) > 0 {
+                db.notify_keyspace_event(dbindex, "xtrim", &key, Some('t'));
             }
-            _ => return Response::Error("syntax error".to_owned()),
         }
     }
-    
+    db.key_updated(dbindex, &key);
+    Response::Data(id.to_string().into_bytes())
+}
+
+fn xlen(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_exact!(parser, 2);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
     let el = match db.get(dbindex, &key) {
         Some(e) => e,
-        None => return Response::Array(vec![
-            Response::Data(b"0".to_vec()),
-            Response::Array(Vec::new()),
-        ]),
+        None => return Response::Integer(0),
     };
-    
-    match el.sscan(cursor as usize, pattern.as_deref(), count) {
-        Ok((next_cursor, members)) => Response::Array(vec![
-            Response::Data(next_cursor.to_string().into_bytes()),
-            Response::Array(members.into_iter().map(Response::Data).collect()),
-        ]),
+    match el.xlen() {
+        Ok(count) => Response::Integer(count as i64),
         Err(err) => Response::Error(err.to_string()),
     }
 }
 
-fn zscan(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
-    validate_arguments_gte!(parser, 3);
+/// Parses an end of an XRANGE interval, which excludes the ID itself when
+/// prefixed with `(`.
+fn xrange_bound(arg: &[u8], start: bool) -> Result<StreamId, Response> {
+    let invalid = || {
+        let which = if start { "start" } else { "end" };
+        Response::Error(format!("ERR invalid {} ID for the interval", which))
+    };
+    let missing_seq = if start { 0 } else { u64::max_value() };
+    let (exclusive, arg) = match arg.first() {
+        Some(b'(') => (true, &arg[1..]),
+        _ => (false, arg),
+    };
+    if exclusive && (arg == b"-" || arg == b"+") {
+        return Err(invalid());
+    }
+    let id = match StreamId::parse(arg, missing_seq) {
+        Ok(id) => id,
+        Err(err) => return Err(Response::Error(err.to_string())),
+    };
+    match (exclusive, start) {
+        (false, _) => Ok(id),
+        (true, true) => id.next().ok_or_else(invalid),
+        (true, false) => id.prev().ok_or_else(invalid),
+    }
+}
+
+fn generic_xrange(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize, rev: bool) -> Response {
+    validate_arguments_gte!(parser, 4);
     let key = try_validate!(parser.get_vec(1), "Invalid key");
-    let cursor = try_validate!(parser.get_i64(2), "ERR invalid cursor");
-    validate!(cursor >= 0, "ERR invalid cursor");
-    let mut pattern = None;
-    let mut count = 10;
-    
-    let mut i = 3;
-    while i < parser.argv.len() {
-        let arg = try_validate!(parser.get_str(i), "syntax error");
-        match &*arg.to_ascii_lowercase() {
-            "match" => {
-                pattern = Some(try_validate!(parser.get_vec(i + 1), "syntax error"));
-                i += 2;
+    let (start, end) = if rev { (3, 2) } else { (2, 3) };
+    let start = match xrange_bound(parser.get_slice(start).unwrap(), true) {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+    let end = match xrange_bound(parser.get_slice(end).unwrap(), false) {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+    let mut count = None;
+    match parser.argv.len() {
+        4 => (),
+        6 => {
+            let option = try_validate!(parser.get_str(4), "ERR syntax error");
+            validate!(option.eq_ignore_ascii_case("count"), "ERR syntax error");
+            let c = try_validate!(parser.get_i64(5), "ERR value is not an integer or out of range");
+            if c <= 0 {
+                return Response::Array(vec![]);
             }
-            "count" => {
-                let c = try_validate!(parser.get_i64(i + 1), "syntax error");
-                validate!(c >= 0, "syntax error");
-                count = c as usize;
-                if count == 0 {
-                    count = 10;
-                }
+            count = Some(c as usize);
+        }
+        _ => return Response::Error("ERR syntax error".to_owned()),
+    }
+
+    match db.get(dbindex, &key) {
+        Some(el) => match el.xrange(start, end, count, rev) {
+            Ok(entries) => stream_entries(entries),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        None => Response::Array(vec![]),
+    }
+}
+
+fn xrange(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    generic_xrange(parser, db, dbindex, false)
+}
+
+fn xrevrange(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    generic_xrange(parser, db, dbindex, true)
+}
+
+fn xdel(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let mut ids = Vec::with_capacity(parser.argv.len() - 2);
+    for i in 2..parser.argv.len() {
+        match parser.get_slice(i).map(NewId::parse) {
+            Ok(Ok(NewId::Id(id))) => ids.push(id),
+            Ok(Err(err)) => return Response::Error(err.to_string()),
+            _ => {
+                return Response::Error(
+                    "ERR Invalid stream ID specified as stream command argument".to_owned(),
+                )
+            }
+        }
+    }
+    let deleted = match db.get_mut(dbindex, &key) {
+        Some(el) => match el.xdel(&ids) {
+            Ok(deleted) => deleted,
+            Err(err) => return Response::Error(err.to_string()),
+        },
+        None => 0,
+    };
+    if deleted > 0 {
+        db.key_updated(dbindex, &key);
+        db.notify_keyspace_event(dbindex, "xdel", &key, Some('t'));
+    }
+    Response::Integer(deleted as i64)
+}
+
+fn xtrim(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 4);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let (trim, approx, limit) = match xtrim_args(parser, 2) {
+        Ok((trim, approx, limit, next)) if next == parser.argv.len() => (trim, approx, limit),
+        Ok(_) => return Response::Error("ERR syntax error".to_owned()),
+        Err(r) => return r,
+    };
+    let removed = match db.get_mut(dbindex, &key) {
+        Some(el) => match el.xtrim(trim, approx, limit) {
+            Ok(removed) => removed,
+            Err(err) => return Response::Error(err.to_string()),
+        },
+        None => 0,
+    };
+    if removed > 0 {
+        db.key_updated(dbindex, &key);
+        db.notify_keyspace_event(dbindex, "xtrim", &key, Some('t'));
+    }
+    Response::Integer(removed as i64)
+}
+
+fn xread(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+) -> Result<Response, ResponseError> {
+    opt_validate!(
+        parser.argv.len() >= 4,
+        "ERR wrong number of arguments for 'xread' command"
+    );
+    let time = mstime();
+    let mut count = None;
+    let mut block = None;
+    let mut i = 1;
+    loop {
+        let option = try_opt_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+        match &*option {
+            "count" => {
+                let c = try_opt_validate!(
+                    parser.get_i64(i + 1),
+                    "ERR value is not an integer or out of range"
+                );
+                count = if c > 0 { Some(c as usize) } else { None };
+                i += 2;
+            }
+            "block" => {
+                let ms = try_opt_validate!(
+                    parser.get_i64(i + 1),
+                    "ERR timeout is not an integer or out of range"
+                );
+                opt_validate!(ms >= 0, "ERR timeout is negative");
+                block = Some(ms);
+                i += 2;
+            }
+            "streams" => {
+                i += 1;
+                break;
+            }
+            _ => return Ok(Response::Error("ERR syntax error".to_owned())),
+        }
+    }
+    let rest = parser.argv.len() - i;
+    opt_validate!(
+        rest > 0 && rest % 2 == 0,
+        "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+    );
+
+    // `$` is resolved now, so that waking up later only reads newer entries
+    let mut streams = Vec::with_capacity(rest / 2);
+    for j in i..i + rest / 2 {
+        let key = try_opt_validate!(parser.get_vec(j), "Invalid key");
+        let arg = try_opt_validate!(parser.get_slice(j + rest / 2), "Invalid ID");
+        let last = if arg == b"$" {
+            match db.get(dbindex, &key).map(|el| el.xlast_id()) {
+                Some(Ok(id)) => id,
+                Some(Err(err)) => return Ok(Response::Error(err.to_string())),
+                None => StreamId::MIN,
+            }
+        } else {
+            match StreamId::parse(arg, 0) {
+                Ok(id) => id,
+                Err(err) => return Ok(Response::Error(err.to_string())),
+            }
+        };
+        streams.push((key, last));
+    }
+
+    let mut result = vec![];
+    for (key, last) in streams.iter() {
+        let (el, start) = match (db.get(dbindex, key), last.next()) {
+            (Some(el), Some(start)) => (el, start),
+            _ => continue,
+        };
+        match el.xrange(start, StreamId::MAX, count, false) {
+            Ok(ref entries) if entries.is_empty() => (),
+            Ok(entries) => result.push(Response::Array(vec![
+                Response::Data(key.clone()),
+                stream_entries(entries),
+            ])),
+            Err(err) => return Ok(Response::Error(err.to_string())),
+        }
+    }
+    if !result.is_empty() {
+        return Ok(Response::Array(result));
+    }
+    let timeout = match block {
+        Some(timeout) => timeout,
+        None => return Ok(Response::Nil),
+    };
+
+    let (txkey, rxkey) = channel();
+    let (txcommand, rxcommand) = channel();
+    if timeout > 0 {
+        let tx = txcommand.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(timeout as u64));
+            let _ = tx.send(None);
+        });
+    }
+    for (key, _) in streams.iter() {
+        db.key_subscribe(dbindex, key, txkey.clone());
+    }
+    thread::spawn(move || {
+        let _ = rxkey.recv();
+        let newtimeout = if timeout == 0 {
+            0
+        } else {
+            (timeout - mstime() + time).max(1)
+        };
+        let mut args = vec![b"XREAD".to_vec()];
+        if let Some(count) = count {
+            args.push(b"COUNT".to_vec());
+            args.push(format!("{}", count).into_bytes());
+        }
+        args.push(b"BLOCK".to_vec());
+        args.push(format!("{}", newtimeout).into_bytes());
+        args.push(b"STREAMS".to_vec());
+        let ids = streams.iter().map(|(_, last)| last.to_string().into_bytes()).collect::<Vec<_>>();
+        args.extend(streams.into_iter().map(|(key, _)| key));
+        args.extend(ids);
+
+        let args = args.iter().map(|arg| &arg[..]).collect::<Vec<_>>();
+        let mut data = vec![];
+        write_command(&mut data, &args).unwrap();
+        if let Ok((parser, _)) = parse(&data) {
+            let _ = txcommand.send(Some(parser.into_owned()));
+        }
+    });
+
+    Err(ResponseError::Wait(rxcommand))
+}
+
+fn scan(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 2);
+    let cursor = try_validate!(parser.get_i64(1), "ERR invalid cursor");
+    validate!(cursor >= 0, "ERR invalid cursor");
+    let mut pattern = None;
+    let mut count = 10;
+    
+    let mut i = 2;
+    while i < parser.argv.len() {
+        let arg = try_validate!(parser.get_str(i), "syntax error");
+        match &*arg.to_ascii_lowercase() {
+            "match" => {
+                pattern = Some(try_validate!(parser.get_vec(i + 1), "syntax error"));
+                i += 2;
+            }
+            "count" => {
+                let c = try_validate!(parser.get_i64(i + 1), "syntax error");
+                validate!(c >= 0, "syntax error");
+                count = c as usize;
+                if count == 0 {
+                    count = 10;
+                }
+                i += 2;
+            }
+            _ => return Response::Error("syntax error".to_owned()),
+        }
+    }
+    
+    let (next_cursor, keys) = db.scan(dbindex, cursor as usize, pattern.as_deref(), count);
+    let result = vec![
+        Response::Data(next_cursor.to_string().into_bytes()),
+        Response::Array(keys.into_iter().map(Response::Data).collect()),
+    ];
+    Response::Array(result)
+}
+
+fn sscan(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let cursor = try_validate!(parser.get_i64(2), "ERR invalid cursor");
+    validate!(cursor >= 0, "ERR invalid cursor");
+    let mut pattern = None;
+    let mut count = 10;
+    
+    let mut i = 3;
+    while i < parser.argv.len() {
+        let arg = try_validate!(parser.get_str(i), "syntax error");
+        match &*arg.to_ascii_lowercase() {
+            "match" => {
+                pattern = Some(try_validate!(parser.get_vec(i + 1), "syntax error"));
+                i += 2;
+            }
+            "count" => {
+                let c = try_validate!(parser.get_i64(i + 1), "syntax error");
+                validate!(c >= 0, "syntax error");
+                count = c as usize;
+                if count == 0 {
+                    count = 10;
+                }
+                i += 2;
+            }
+            _ => return Response::Error("syntax error".to_owned()),
+        }
+    }
+    
+    let el = match db.get(dbindex, &key) {
+        Some(e) => e,
+        None => return Response::Array(vec![
+            Response::Data(b"0".to_vec()),
+            Response::Array(Vec::new()),
+        ]),
+    };
+    
+    match el.sscan(cursor as usize, pattern.as_deref(), count) {
+        Ok((next_cursor, members)) => Response::Array(vec![
+            Response::Data(next_cursor.to_string().into_bytes()),
+            Response::Array(members.into_iter().map(Response::Data).collect()),
+        ]),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn zscan(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let key = try_validate!(parser.get_vec(1), "Invalid key");
+    let cursor = try_validate!(parser.get_i64(2), "ERR invalid cursor");
+    validate!(cursor >= 0, "ERR invalid cursor");
+    let mut pattern = None;
+    let mut count = 10;
+    
+    let mut i = 3;
+    while i < parser.argv.len() {
+        let arg = try_validate!(parser.get_str(i), "syntax error");
+        match &*arg.to_ascii_lowercase() {
+            "match" => {
+                pattern = Some(try_validate!(parser.get_vec(i + 1), "syntax error"));
+                i += 2;
+            }
+            "count" => {
+                let c = try_validate!(parser.get_i64(i + 1), "syntax error");
+                validate!(c >= 0, "syntax error");
+                count = c as usize;
+                if count == 0 {
+                    count = 10;
+                }
                 i += 2;
             }
             _ => return Response::Error("syntax error".to_owned()),
@@ -3030,31 +3708,124 @@ fn time(_parser: &mut ParsedCommand, _db: &mut Database) -> Response {
     ])
 }
 
-fn role(_parser: &mut ParsedCommand, _db: &mut Database) -> Response {
-    // TODO: Implement full replication support
-    // For now, always return master role
+fn role(_parser: &mut ParsedCommand, db: &mut Database) -> Response {
+    if let Some(ref sentinel) = db.sentinel {
+        return Response::Array(vec![
+            Response::Data(b"sentinel".to_vec()),
+            Response::Array(
+                sentinel
+                    .masters
+                    .keys()
+                    .map(|name| Response::Data(name.clone().into_bytes()))
+                    .collect(),
+            ),
+        ]);
+    }
+    if let Some(master) = &db.master {
+        return Response::Array(vec![
+            Response::Data(b"slave".to_vec()),
+            Response::Data(master.host.clone().into_bytes()),
+            Response::Integer(master.port as i64),
+            Response::Data(master.state.as_str().as_bytes().to_vec()),
+            Response::Integer(db.master_repl_offset as i64),
+        ]);
+    }
+    let replicas = db
+        .replicas
+        .iter()
+        .map(|r| {
+            Response::Array(vec![
+                Response::Data(r.ip.clone().into_bytes()),
+                Response::Data(format!("{}", r.conf.listening_port).into_bytes()),
+                Response::Data(format!("{}", r.ack_offset).into_bytes()),
+            ])
+        })
+        .collect();
     Response::Array(vec![
         Response::Data(b"master".to_vec()),
-        Response::Integer(0), // replication offset
-        Response::Array(Vec::new()), // connected slaves
+        Response::Integer(db.master_repl_offset as i64),
+        Response::Array(replicas),
     ])
 }
 
-fn slaveof(parser: &mut ParsedCommand, _db: &mut Database) -> Response {
+fn replconf(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    client: &mut Client,
+) -> Result<Response, ResponseError> {
+    opt_validate!(parser.argv.len() % 2 == 1, "ERR syntax error");
+    for i in (1..parser.argv.len()).step_by(2) {
+        let option = try_opt_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+        let value = try_opt_validate!(parser.get_str(i + 1), "ERR syntax error");
+        match &*option {
+            "listening-port" => {
+                client.replconf.listening_port =
+                    try_opt_validate!(value.parse(), "ERR value is not an integer or out of range");
+            }
+            "ip-address" => client.replconf.ip_address = Some(value.to_owned()),
+            "capa" => client.replconf.capa.push(value.to_ascii_lowercase()),
+            "ack" => {
+                // replicas do not expect a reply to their acknowledges
+                if let Ok(offset) = value.parse() {
+                    db.repl_ack(client.id, offset);
+                }
+                return Err(ResponseError::NoReply);
+            }
+            _ => {
+                return Ok(Response::Error(format!(
+                    "ERR Unrecognized REPLCONF option: {}",
+                    option
+                )))
+            }
+        }
+    }
+    Ok(Response::Status("OK".to_owned()))
+}
+
+/// Handles both SYNC and PSYNC. The replies are sent by the replication
+/// code as the synchronization goes on.
+fn sync(parser: &mut ParsedCommand, db: &mut Database, client: &mut Client) -> Result<Response, ResponseError> {
+    if let Some(master) = &db.master {
+        opt_validate!(
+            master.state == MasterLinkState::Connected,
+            "NOMASTERLINK Can't SYNC while not connected with my master"
+        );
+    }
+    let psync = if parser.argv.len() == 3 {
+        let replid = try_opt_validate!(parser.get_str(1), "ERR invalid replication id");
+        let offset = try_opt_validate!(parser.get_i64(2), "ERR value is not an integer or out of range");
+        Some((replid, offset))
+    } else {
+        None
+    };
+    let ip = match client.replconf.ip_address {
+        Some(ref ip) => ip.clone(),
+        None => client.addr.rsplitn(2, ':').last().unwrap_or("").to_owned(),
+    };
+    let replica = Replica::new(client.id, client.rawsender.clone(), ip, client.replconf.clone());
+    db.repl_attach(replica, psync);
+    Err(ResponseError::NoReply)
+}
+
+fn slaveof(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     validate_arguments_exact!(parser, 3);
     let host = try_validate!(parser.get_str(1), "Invalid host");
     let port = try_validate!(parser.get_str(2), "Invalid port");
-    
-    // TODO: Implement full replication support
-    // For now, just return OK
-    if host == "NO" && port == "ONE" {
-        // SLAVEOF NO ONE - stop replication
-        Response::Status("OK".to_owned())
-    } else {
-        // SLAVEOF host port - start replication
-        // TODO: Connect to master and start replication
-        Response::Status("OK".to_owned())
+
+    // the cluster decides which nodes are replicas
+    validate!(db.cluster.is_none(), "ERR REPLICAOF not allowed in cluster mode.");
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        db.repl_unset_master();
+        return Response::Status("OK".to_owned());
+    }
+    let port = try_validate!(port.parse::<u16>(), "ERR Invalid master port");
+    if let Some(master) = &db.master {
+        if master.host == host && master.port == port {
+            return Response::Status("OK Already connected to specified master".to_owned());
+        }
     }
+    db.repl_set_master(host.to_owned(), port);
+    Response::Status("OK".to_owned())
 }
 
 fn object(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Response {
@@ -3077,6 +3848,8 @@ fn object(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
                             ValueHash::ZipList(_) => "ziplist",
                             ValueHash::HashMap(_) => "hashtable",
                         },
+                        Value::Stream(_) => "stream",
+                        Value::Module(_) => "raw",
                         Value::Nil => return Response::Nil,
                     };
                     Response::Data(encoding.to_string().into_bytes())
@@ -3085,10 +3858,8 @@ fn object(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
             }
         }
         "idletime" => {
-            // TODO: Track last access time for LRU
-            // For now, return 0
             match db.get(dbindex, &key) {
-                Some(_) => Response::Integer(0),
+                Some(_) => Response::Integer(db.get_idletime(dbindex, &key).unwrap_or(0) / 1000),
                 None => Response::Nil,
             }
         }
@@ -3101,10 +3872,8 @@ fn object(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Resp
             }
         }
         "freq" => {
-            // TODO: Track access frequency for LFU
-            // For now, return 0
             match db.get(dbindex, &key) {
-                Some(_) => Response::Integer(0),
+                Some(_) => Response::Integer(db.get_freq(dbindex, &key).unwrap_or(0) as i64),
                 None => Response::Nil,
             }
         }
@@ -3324,7 +4093,45 @@ fn bitop(parser: &mut ParsedCommand, db: &mut Database, dbindex: usize) -> Respo
     }
 }
 
-fn client_cmd(parser: &mut ParsedCommand, client: &mut Client) -> Response {
+/// CLIENT KILL closes the connections from an address, or the ones matching
+/// the ID, ADDR and SKIPME filters.
+fn client_kill(parser: &mut ParsedCommand, busy: &Busy, client: &Client) -> Response {
+    let argc = parser.argv.len();
+    if argc == 3 {
+        let addr = try_validate!(parser.get_str(2), "ERR syntax error");
+        return match busy.kill_connections(|_, a| a == addr) {
+            0 => Response::Error("ERR No such client".to_owned()),
+            _ => Response::Status("OK".to_owned()),
+        };
+    }
+    validate!(argc > 3 && argc % 2 == 0, "ERR syntax error");
+    let mut id = None;
+    let mut addr = None;
+    let mut skipme = true;
+    for i in (2..argc).step_by(2) {
+        let option = try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+        match &*option {
+            "id" => id = Some(try_validate!(parser.get_i64(i + 1), "ERR client-id should be greater than 0")),
+            "addr" => addr = Some(try_validate!(parser.get_str(i + 1), "ERR syntax error")),
+            "skipme" => {
+                skipme = match &*try_validate!(parser.get_str(i + 1), "ERR syntax error").to_ascii_lowercase() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Response::Error("ERR syntax error".to_owned()),
+                }
+            }
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+    }
+    let killed = busy.kill_connections(|i, a| {
+        id.map_or(true, |id| id == i as i64)
+            && addr.map_or(true, |addr| addr == a)
+            && !(skipme && i == client.id)
+    });
+    Response::Integer(killed as i64)
+}
+
+fn client_cmd(parser: &mut ParsedCommand, busy: &Busy, client: &mut Client) -> Response {
     validate_arguments_gte!(parser, 2);
     let subcommand = try_validate!(parser.get_str(1), "Invalid subcommand");
     
@@ -3354,11 +4161,7 @@ fn client_cmd(parser: &mut ParsedCommand, client: &mut Client) -> Response {
             // For now, just return OK
             Response::Status("OK".to_owned())
         }
-        "kill" => {
-            // TODO: Implement client kill
-            // For now, return error
-            Response::Error("ERR CLIENT KILL is not implemented".to_owned())
-        }
+        "kill" => client_kill(parser, busy, client),
         "pause" => {
             // TODO: Implement client pause
             // For now, return error
@@ -3455,6 +4258,14 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
     } else {
         try_validate!(parser.get_str(1), "Invalid section").to_ascii_lowercase()
     });
+    // a sentinel only has the server and sentinel sections
+    let sentinel_section = db.sentinel.is_some()
+        && (section == "default" || section == "all" || section == "sentinel");
+    let section = match db.sentinel {
+        Some(_) if section == "default" || section == "all" || section == "server" => "server",
+        Some(_) => "",
+        None => section,
+    };
 
     let mut out = vec![];
     if section == "default" || section == "all" || section == "server" {
@@ -3507,31 +4318,48 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
                 "\
                 # Persistence\r\n\
                 loading:{}\r\n\
-                rdb_changes_since_last_save:0\r\n\
-                rdb_bgsave_in_progress:0\r\n\
+                rdb_changes_since_last_save:{}\r\n\
+                rdb_bgsave_in_progress:{}\r\n\
                 rdb_last_save_time:{}\r\n\
-                rdb_last_bgsave_status:ok\r\n\
-                rdb_last_bgsave_time_sec:-1\r\n\
-                rdb_current_bgsave_time_sec:-1\r\n\
+                rdb_last_bgsave_status:{}\r\n\
+                rdb_last_bgsave_time_sec:{}\r\n\
+                rdb_current_bgsave_time_sec:{}\r\n\
                 aof_enabled:{}\r\n\
-                aof_rewrite_in_progress:0\r\n\
-                aof_rewrite_scheduled:0\r\n\
-                aof_last_rewrite_time_sec:-1\r\n\
-                aof_current_rewrite_time_sec:-1\r\n\
-                aof_last_bgrewrite_status:ok\r\n\
+                aof_rewrite_in_progress:{}\r\n\
+                aof_rewrite_scheduled:{}\r\n\
+                aof_last_rewrite_time_sec:{}\r\n\
+                aof_current_rewrite_time_sec:{}\r\n\
+                aof_last_bgrewrite_status:{}\r\n\
+                aof_last_write_status:{}\r\n\
                 changes_since_last_save:0\r\n\
-                aof_current_size:0\r\n\
-                aof_base_size:0\r\n\
+                aof_current_size:{}\r\n\
+                aof_base_size:{}\r\n\
                 aof_pending_rewrite:0\r\n\
                 aof_buffer_length:0\r\n\
-                aof_rewrite_buffer_length:0\r\n\
-                aof_pending_bio_fsync:0\r\n\
-                aof_delayed_fsync:0\r\n\
+                aof_rewrite_buffer_length:{}\r\n\
+                aof_pending_bio_fsync:{}\r\n\
+                aof_delayed_fsync:{}\r\n\
                 \r\n\
                 ",
                 if db.loading { 1 } else { 0 },
+                db.dirty,
+                if db.rdb_child_pid.is_some() { 1 } else { 0 },
                 db.last_save_time,
+                if db.rdb_last_bgsave_ok { "ok" } else { "err" },
+                db.rdb_last_bgsave_time_sec,
+                db.rdb_current_bgsave_time_sec(),
                 if db.aof.is_some() { 1 } else { 0 },
+                if db.aof_child_pid.is_some() { 1 } else { 0 },
+                if db.aof_rewrite_scheduled { 1 } else { 0 },
+                db.aof_last_rewrite_time_sec,
+                db.aof_current_rewrite_time_sec(),
+                if db.aof_last_bgrewrite_ok { "ok" } else { "err" },
+                if db.aof_last_write_ok { "ok" } else { "err" },
+                db.aof.as_ref().map_or(0, |aof| aof.current_size()),
+                db.aof.as_ref().map_or(0, |aof| aof.base_size()),
+                db.aof.as_ref().map_or(0, |aof| aof.rewrite_buffer_len()),
+                db.aof.as_ref().map_or(0, |aof| if aof.fsync_in_progress() { 1 } else { 0 }),
+                db.aof.as_ref().map_or(0, |aof| aof.delayed_fsync()),
             ),
             "ERR unexpected"
         );
@@ -3594,9 +4422,10 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
             write!(
                 out,
                 "# Cluster\r\n\
-                cluster_enabled:0\r\n\
+                cluster_enabled:{}\r\n\
                 \r\n\
-                "
+                ",
+                db.cluster.is_some() as u8
             ),
             "ERR unexpected"
         );
@@ -3676,15 +4505,118 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
     }
 
     if section == "default" || section == "all" || section == "replication" {
-        try_validate!(
-            write!(
-                out,
-                "\
-                # Replication\r\n\
-                role:master\r\n\
-                connected_slaves:0\r\n\
+        try_validate!(write!(out, "# Replication\r\n"), "ERR unexpected");
+        let now = mstime();
+        match &db.master {
+            None => try_validate!(write!(out, "role:master\r\n"), "ERR unexpected"),
+            Some(master) => {
+                let sync_in_progress = master.state == MasterLinkState::Sync;
+                try_validate!(
+                    write!(
+                        out,
+                        "\
+                        role:slave\r\n\
+                        master_host:{}\r\n\
+                        master_port:{}\r\n\
+                        master_link_status:{}\r\n\
+                        master_last_io_seconds_ago:{}\r\n\
+                        master_sync_in_progress:{}\r\n\
+                        slave_repl_offset:{}\r\n\
+                        ",
+                        master.host,
+                        master.port,
+                        if master.state == MasterLinkState::Connected { "up" } else { "down" },
+                        if master.state == MasterLinkState::Connected {
+                            (now - master.last_io) / 1000
+                        } else {
+                            -1
+                        },
+                        sync_in_progress as u8,
+                        db.master_repl_offset,
+                    ),
+                    "ERR unexpected"
+                );
+                if sync_in_progress {
+                    try_validate!(
+                        write!(
+                            out,
+                            "\
+                            master_sync_left_bytes:{}\r\n\
+                            master_sync_last_io_seconds_ago:{}\r\n\
+                            ",
+                            master.sync_size.saturating_sub(master.sync_read),
+                            (now - master.last_io) / 1000,
+                        ),
+                        "ERR unexpected"
+                    );
+                }
+                if master.state != MasterLinkState::Connected {
+                    try_validate!(
+                        write!(
+                            out,
+                            "master_link_down_since_seconds:{}\r\n",
+                            (now - master.down_since) / 1000
+                        ),
+                        "ERR unexpected"
+                    );
+                }
+                try_validate!(
+                    write!(out, "slave_priority:{}\r\n", db.config.slave_priority),
+                    "ERR unexpected"
+                );
+                try_validate!(
+                    write!(out, "slave_read_only:{}\r\n", db.config.slave_read_only as u8),
+                    "ERR unexpected"
+                );
+            }
+        }
+        try_validate!(
+            write!(out, "connected_slaves:{}\r\n", db.replicas.len()),
+            "ERR unexpected"
+        );
+        if db.config.min_slaves_to_write > 0 && db.config.min_slaves_max_lag > 0 {
+            try_validate!(
+                write!(out, "min_slaves_good_slaves:{}\r\n", db.repl_good_replicas()),
+                "ERR unexpected"
+            );
+        }
+        for (i, replica) in db.replicas.iter().enumerate() {
+            try_validate!(
+                write!(
+                    out,
+                    "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                    i,
+                    replica.ip,
+                    replica.conf.listening_port,
+                    replica.state.as_str(),
+                    replica.ack_offset,
+                    (now - replica.ack_time) / 1000,
+                ),
+                "ERR unexpected"
+            );
+        }
+        let (backlog_active, backlog_first_byte, backlog_histlen) = match &db.repl_backlog {
+            Some(backlog) => (1, backlog.first_offset() + 1, backlog.histlen()),
+            None => (0, 0, 0),
+        };
+        try_validate!(
+            write!(
+                out,
+                "\
+                master_replid:{}\r\n\
+                master_repl_offset:{}\r\n\
+                repl_backlog_active:{}\r\n\
+                repl_backlog_size:{}\r\n\
+                repl_backlog_first_byte_offset:{}\r\n\
+                repl_backlog_histlen:{}\r\n\
                 \r\n\
-                "
+                ",
+                db.replid,
+                db.master_repl_offset,
+                backlog_active,
+                db.config.repl_backlog_size,
+                backlog_first_byte,
+                backlog_histlen,
             ),
             "ERR unexpected"
         );
@@ -3712,38 +4644,229 @@ fn info(parser: &mut ParsedCommand, db: &Database) -> Response {
         // TODO: Track command statistics
     }
 
+    if sentinel_section {
+        let sentinel = db.sentinel.as_ref().unwrap();
+        try_validate!(
+            write!(
+                out,
+                "\
+                # Sentinel\r\n\
+                sentinel_masters:{}\r\n\
+                sentinel_tilt:0\r\n\
+                sentinel_running_scripts:0\r\n\
+                sentinel_scripts_queue_length:0\r\n\
+                ",
+                sentinel.masters.len()
+            ),
+            "ERR unexpected"
+        );
+        for (i, master) in sentinel.masters.values().enumerate() {
+            let status = if master.odown_since != 0 {
+                "odown"
+            } else if master.instance.sdown_since != 0 {
+                "sdown"
+            } else {
+                "ok"
+            };
+            let (ip, port) = master.current_address();
+            try_validate!(
+                write!(
+                    out,
+                    "master{}:name={},status={},address={}:{},slaves={},sentinels={}\r\n",
+                    i,
+                    master.name,
+                    status,
+                    ip,
+                    port,
+                    master.replicas.len(),
+                    master.sentinels.len() + 1
+                ),
+                "ERR unexpected"
+            );
+        }
+        try_validate!(write!(out, "\r\n"), "ERR unexpected");
+    }
+
     Response::Data(out)
 }
 
 fn save(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     validate_arguments_exact!(parser, 1);
-    // TODO: Implement actual RDB save to disk
-    // For now, just update the last save time
-    use util::mstime;
-    db.last_save_time = mstime() / 1000;
-    Response::Status("OK".to_owned())
+    if db.rdb_child_pid.is_some() {
+        return Response::Error("ERR Background save already in progress".to_owned());
+    }
+    let filename = db.config.dbfilename.clone();
+    match db.rdb_save(&*filename) {
+        Ok(()) => {
+            db.last_save_time = mstime() / 1000;
+            db.dirty = 0;
+            logger::log!(db.config.logger, Notice, "DB saved on disk");
+            Response::Status("OK".to_owned())
+        }
+        Err(e) => {
+            logger::log!(db.config.logger, Warning, "Error saving DB on disk: {}", e);
+            Response::Error(format!("ERR Error saving DB on disk: {}", e))
+        }
+    }
 }
 
-fn bgsave(parser: &mut ParsedCommand, _db: &mut Database) -> Response {
+fn bgsave(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     validate_arguments_exact!(parser, 1);
-    // TODO: Implement background RDB save with threading
-    // For now, return appropriate response
-    Response::Status("Background saving started by pid 0".to_owned())
+    if db.rdb_child_pid.is_some() {
+        return Response::Error("ERR Background save already in progress".to_owned());
+    }
+    if db.aof_child_pid.is_some() {
+        return Response::Error("ERR Can't BGSAVE while AOF log rewriting is in progress".to_owned());
+    }
+    match db.rdb_bgsave() {
+        Ok(_) => Response::Status("Background saving started".to_owned()),
+        Err(e) => {
+            logger::log!(db.config.logger, Warning, "Can't save in background: fork: {}", e);
+            Response::Error(format!("ERR Can't save in background: fork: {}", e))
+        }
+    }
 }
 
-fn bgrewriteaof(parser: &mut ParsedCommand, _db: &mut Database) -> Response {
+fn bgrewriteaof(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     validate_arguments_exact!(parser, 1);
-    // TODO: Implement background AOF rewrite with threading
-    // For now, return appropriate response
-    Response::Status("Background append only file rewriting started by pid 0".to_owned())
+    if db.aof_child_pid.is_some() {
+        return Response::Error(
+            "ERR Background append only file rewriting already in progress".to_owned(),
+        );
+    }
+    if db.rdb_child_pid.is_some() {
+        db.aof_rewrite_scheduled = true;
+        return Response::Status("Background append only file rewriting scheduled".to_owned());
+    }
+    match db.aof_bgrewrite() {
+        Ok(_) => Response::Status("Background append only file rewriting started".to_owned()),
+        Err(e) => {
+            logger::log!(
+                db.config.logger,
+                Warning,
+                "Can't rewrite append only file in background: fork: {}",
+                e
+            );
+            Response::Error("ERR Can't execute an AOF background rewriting. Please check the server logs for more information.".to_owned())
+        }
+    }
 }
 
-fn shutdown(parser: &mut ParsedCommand, _db: &mut Database) -> Response {
+/// SHUTDOWN saves a snapshot when SAVE is given or there are save points,
+/// unless NOSAVE is given, and exits the process without replying.
+fn shutdown(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     validate_arguments_lte!(parser, 2);
-    // TODO: Implement actual shutdown - would need access to the server
-    // For now, return OK - actual shutdown would need access to the server
-    // Note: In a real implementation, this would need to signal the server to stop
-    Response::Status("OK".to_owned())
+    let save = if parser.argv.len() == 2 {
+        match &*try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase() {
+            "save" => true,
+            "nosave" => false,
+            _ => return Response::Error("ERR syntax error".to_owned()),
+        }
+    } else {
+        !db.config.save.is_empty()
+    };
+    logger::log!(db.config.logger, Warning, "User requested shutdown...");
+    db.rdb_bgsave_kill();
+    db.aof_rewrite_kill();
+    if save && db.sentinel.is_none() {
+        logger::log!(db.config.logger, Notice, "Saving the final RDB snapshot before exiting.");
+        let filename = db.config.dbfilename.clone();
+        if let Err(e) = db.rdb_save(&*filename) {
+            logger::log!(db.config.logger, Warning, "Error trying to save the DB, can't exit: {}", e);
+            return Response::Error("ERR Errors trying to SHUTDOWN. Check logs.".to_owned());
+        }
+    }
+    if let Some(aof) = &mut db.aof {
+        logger::log!(db.config.logger, Notice, "Calling fsync() on the AOF file.");
+        if let Err(e) = aof.sync() {
+            logger::log!(db.config.logger, Warning, "Fail to fsync the AOF file: {}", e);
+        }
+    }
+    if db.config.daemonize {
+        let _ = std::fs::remove_file(&*db.config.pidfile);
+    }
+    logger::log!(db.config.logger, Warning, "rsedis is now ready to exit, bye bye...");
+    process::exit(0);
+}
+
+/// Runs `parser` while another connection has held the database for longer
+/// than `busy-reply-threshold`. Only the commands that do not need the
+/// database are served; the others get a -BUSY error. SHUTDOWN NOSAVE exits
+/// right away, as the command holding the database may never finish.
+pub fn busy_command(parser: &mut ParsedCommand, busy: &Busy, client: &mut Client) -> Response {
+    let name = match parser.get_str(0) {
+        Ok(name) => name.to_ascii_lowercase(),
+        Err(_) => return Response::Error("ERR unknown command".to_owned()),
+    };
+    let subcommand = parser.get_str(1).map(|s| s.to_ascii_lowercase()).unwrap_or_default();
+    let (running, script) = match busy.running() {
+        Some(running) => (running.name, running.script),
+        None => (String::new(), false),
+    };
+    match (&*name, &*subcommand) {
+        ("ping", _) => ping(parser, client),
+        ("info", _) => busy_info(parser, busy),
+        ("client", "kill") => client_kill(parser, busy, client),
+        ("shutdown", "nosave") if parser.argv.len() == 2 => {
+            busy.shutdown_cleanup();
+            process::exit(0);
+        }
+        ("script", "kill") | ("function", "kill") if parser.argv.len() == 2 => match busy.kill_script() {
+            Ok(()) => Response::Status("OK".to_owned()),
+            Err(e) => Response::Error(e),
+        },
+        _ if script => Response::Error(format!(
+            "BUSY Redis is busy running a script. You can only call {} KILL or SHUTDOWN NOSAVE.",
+            if running.starts_with("fcall") { "FUNCTION" } else { "SCRIPT" }
+        )),
+        _ => Response::Error(format!(
+            "BUSY Redis is busy running '{}'. You can only call PING, INFO, CLIENT KILL or SHUTDOWN NOSAVE.",
+            running
+        )),
+    }
+}
+
+/// INFO while the server is busy: the server section, which does not need
+/// the database, and what keeps it busy.
+fn busy_info(parser: &mut ParsedCommand, busy: &Busy) -> Response {
+    validate_arguments_lte!(parser, 2);
+    let section = &*(if parser.argv.len() == 1 {
+        "default".to_owned()
+    } else {
+        try_validate!(parser.get_str(1), "Invalid section").to_ascii_lowercase()
+    });
+    let uptime = busy.start.elapsed().as_secs();
+    let mut out = vec![];
+    if section == "default" || section == "all" || section == "server" {
+        try_validate!(
+            write!(
+                out,
+                "# Server\r\nprocess_id:{}\r\nrun_id:{}\r\ntcp_port:{}\r\nuptime_in_seconds:{}\r\nuptime_in_days:{}\r\n\r\n",
+                getpid(),
+                busy.run_id,
+                busy.port,
+                uptime,
+                uptime / (60 * 60 * 24),
+            ),
+            "ERR unexpected"
+        );
+    }
+    if section == "default" || section == "all" || section == "busy" {
+        if let Some(running) = busy.running() {
+            try_validate!(
+                write!(
+                    out,
+                    "# Busy\r\nbusy_client_id:{}\r\nbusy_command:{}\r\nbusy_script:{}\r\nbusy_time_ms:{}\r\n\r\n",
+                    running.client_id,
+                    running.name,
+                    running.script as u8,
+                    running.since.elapsed().as_millis(),
+                ),
+                "ERR unexpected"
+            );
+        }
+    }
+    Response::Data(out)
 }
 
 fn lastsave(parser: &mut ParsedCommand, db: &mut Database) -> Response {
@@ -3769,6 +4892,10 @@ fn config(parser: &mut ParsedCommand, db: &mut Database) -> Response {
                     result.push(Response::Data(b"appendonly".to_vec()));
                     result.push(Response::Data(if db.config.appendonly { b"yes".to_vec() } else { b"no".to_vec() }));
                 }
+                "aof-use-rdb-preamble" => {
+                    result.push(Response::Data(b"aof-use-rdb-preamble".to_vec()));
+                    result.push(Response::Data(if db.config.aof_use_rdb_preamble { b"yes".to_vec() } else { b"no".to_vec() }));
+                }
                 "appendfilename" => {
                     result.push(Response::Data(b"appendfilename".to_vec()));
                     result.push(Response::Data(db.config.appendfilename.clone().into_bytes()));
@@ -3891,6 +5018,46 @@ fn config(parser: &mut ParsedCommand, db: &mut Database) -> Response {
                     result.push(Response::Data(b"notify-keyspace-events".to_vec()));
                     result.push(Response::Data(db.config.notify_keyspace_events.clone().into_bytes()));
                 }
+                "repl-backlog-size" => {
+                    result.push(Response::Data(b"repl-backlog-size".to_vec()));
+                    result.push(Response::Data(db.config.repl_backlog_size.to_string().into_bytes()));
+                }
+                "slave-read-only" | "replica-read-only" => {
+                    result.push(Response::Data(param_lower.clone().into_bytes()));
+                    result.push(Response::Data(if db.config.slave_read_only { b"yes".to_vec() } else { b"no".to_vec() }));
+                }
+                "slave-priority" | "replica-priority" => {
+                    result.push(Response::Data(param_lower.clone().into_bytes()));
+                    result.push(Response::Data(db.config.slave_priority.to_string().into_bytes()));
+                }
+                "busy-reply-threshold" | "lua-time-limit" => {
+                    result.push(Response::Data(param_lower.clone().into_bytes()));
+                    result.push(Response::Data(db.config.busy_reply_threshold.to_string().into_bytes()));
+                }
+                "masterauth" => {
+                    result.push(Response::Data(b"masterauth".to_vec()));
+                    result.push(Response::Data(db.config.masterauth.clone().unwrap_or_default().into_bytes()));
+                }
+                "repl-timeout" => {
+                    result.push(Response::Data(b"repl-timeout".to_vec()));
+                    result.push(Response::Data(db.config.repl_timeout.to_string().into_bytes()));
+                }
+                "repl-diskless-sync" => {
+                    result.push(Response::Data(b"repl-diskless-sync".to_vec()));
+                    result.push(Response::Data(if db.config.repl_diskless_sync { b"yes".to_vec() } else { b"no".to_vec() }));
+                }
+                "repl-diskless-sync-delay" => {
+                    result.push(Response::Data(b"repl-diskless-sync-delay".to_vec()));
+                    result.push(Response::Data(db.config.repl_diskless_sync_delay.to_string().into_bytes()));
+                }
+                "min-slaves-to-write" | "min-replicas-to-write" => {
+                    result.push(Response::Data(param_lower.clone().into_bytes()));
+                    result.push(Response::Data(db.config.min_slaves_to_write.to_string().into_bytes()));
+                }
+                "min-slaves-max-lag" | "min-replicas-max-lag" => {
+                    result.push(Response::Data(param_lower.clone().into_bytes()));
+                    result.push(Response::Data(db.config.min_slaves_max_lag.to_string().into_bytes()));
+                }
                 _ => return Response::Array(Vec::new()),
             }
             Response::Array(result)
@@ -3908,6 +5075,13 @@ fn config(parser: &mut ParsedCommand, db: &mut Database) -> Response {
                         _ => return Response::Error("ERR Invalid argument for CONFIG SET 'appendonly'".to_owned()),
                     };
                 }
+                "aof-use-rdb-preamble" => {
+                    db.config.aof_use_rdb_preamble = match &*value.to_ascii_lowercase() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Response::Error("ERR Invalid argument for CONFIG SET 'aof-use-rdb-preamble'".to_owned()),
+                    };
+                }
                 "appendfilename" => {
                     db.config.appendfilename = value.to_owned();
                 }
@@ -3933,6 +5107,11 @@ fn config(parser: &mut ParsedCommand, db: &mut Database) -> Response {
                     match &*value.to_ascii_lowercase() {
                         "always" | "everysec" | "no" => {
                             db.config.appendfsync = value.to_owned();
+                            if let Some(aof) = &mut db.aof {
+                                if let Err(e) = aof.set_fsync(value.parse().unwrap()) {
+                                    return Response::Error(format!("ERR {}", e));
+                                }
+                            }
                         }
                         _ => return Response::Error("ERR Invalid argument for CONFIG SET 'appendfsync'".to_owned()),
                     }
@@ -3997,6 +5176,66 @@ fn config(parser: &mut ParsedCommand, db: &mut Database) -> Response {
                     }
                     db.config.notify_keyspace_events = value.to_owned();
                 }
+                "repl-backlog-size" => {
+                    match value.parse::<usize>() {
+                        Ok(v) if v > 0 => db.repl_backlog_resize(v),
+                        _ => return Response::Error("ERR Invalid argument for CONFIG SET 'repl-backlog-size'".to_owned()),
+                    }
+                }
+                "slave-read-only" | "replica-read-only" => {
+                    db.config.slave_read_only = match &*value.to_ascii_lowercase() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Response::Error(format!("ERR Invalid argument for CONFIG SET '{}'", param_lower)),
+                    };
+                }
+                "slave-priority" | "replica-priority" => {
+                    db.config.slave_priority = match value.parse() {
+                        Ok(v) => v,
+                        _ => return Response::Error(format!("ERR Invalid argument for CONFIG SET '{}'", param_lower)),
+                    };
+                }
+                "busy-reply-threshold" | "lua-time-limit" => {
+                    db.config.busy_reply_threshold = match value.parse() {
+                        Ok(v) => v,
+                        _ => return Response::Error(format!("ERR Invalid argument for CONFIG SET '{}'", param_lower)),
+                    };
+                    db.busy.set_threshold(db.config.busy_reply_threshold);
+                }
+                "masterauth" => {
+                    db.config.masterauth = if value.is_empty() { None } else { Some(value.to_owned()) };
+                }
+                "repl-timeout" => {
+                    match value.parse::<u64>() {
+                        Ok(v) if v > 0 => db.config.repl_timeout = v,
+                        _ => return Response::Error("ERR Invalid argument for CONFIG SET 'repl-timeout'".to_owned()),
+                    }
+                }
+                "repl-diskless-sync" => {
+                    db.config.repl_diskless_sync = match &*value.to_ascii_lowercase() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Response::Error("ERR Invalid argument for CONFIG SET 'repl-diskless-sync'".to_owned()),
+                    };
+                }
+                "repl-diskless-sync-delay" => {
+                    match value.parse::<u64>() {
+                        Ok(v) => db.config.repl_diskless_sync_delay = v,
+                        _ => return Response::Error("ERR Invalid argument for CONFIG SET 'repl-diskless-sync-delay'".to_owned()),
+                    }
+                }
+                "min-slaves-to-write" | "min-replicas-to-write" => {
+                    match value.parse::<usize>() {
+                        Ok(v) => db.config.min_slaves_to_write = v,
+                        _ => return Response::Error(format!("ERR Invalid argument for CONFIG SET '{}'", param_lower)),
+                    }
+                }
+                "min-slaves-max-lag" | "min-replicas-max-lag" => {
+                    match value.parse::<u64>() {
+                        Ok(v) => db.config.min_slaves_max_lag = v,
+                        _ => return Response::Error(format!("ERR Invalid argument for CONFIG SET '{}'", param_lower)),
+                    }
+                }
                 _ => return Response::Error(format!("ERR CONFIG SET failed (possibly unknown parameter '{}')", param)),
             }
             Response::Status("OK".to_owned())
@@ -4016,6 +5255,23 @@ pub struct Client {
     pub watched_keys: HashSet<(usize, Vec<u8>)>,
     pub id: usize,
     pub rawsender: Sender<Option<Response>>,
+    /// Address of the peer, as `ip:port`
+    pub addr: String,
+    /// Information sent with REPLCONF by a replica before syncing
+    pub replconf: ReplicaConf,
+    /// Is it the connection to the master this server replicates
+    pub is_master: bool,
+    /// Replication offset right after the last write of this client
+    pub woff: u64,
+    /// Milliseconds when a blocked WAIT times out, 0 if it never does
+    pub wait_deadline: Option<i64>,
+    /// The next command may use a slot being imported, after an ASK
+    /// redirection
+    pub asking: bool,
+    /// Accepts reads from a cluster replica instead of being redirected
+    pub readonly: bool,
+    /// EXEC wraps the writes of the running command in MULTI/EXEC already
+    pub exec_wrapped: bool,
 }
 
 impl Client {
@@ -4034,6 +5290,14 @@ impl Client {
             id,
             watched_keys: HashSet::new(),
             rawsender,
+            addr: String::new(),
+            replconf: ReplicaConf::default(),
+            is_master: false,
+            woff: 0,
+            wait_deadline: None,
+            asking: false,
+            readonly: false,
+            exec_wrapped: false,
         }
     }
 }
@@ -4194,7 +5458,7 @@ fn multi(client: &mut Client) -> Response {
     }
 }
 
-fn exec(db: &mut Database, client: &mut Client) -> Response {
+fn exec(db: &mut Database, client: &mut Client, asking: bool) -> Response {
     if !client.multi {
         return Response::Error("ERR EXEC without MULTI".to_owned());
     }
@@ -4203,11 +5467,44 @@ fn exec(db: &mut Database, client: &mut Client) -> Response {
     if !generic_unwatch(db, client.id, &mut client.watched_keys) {
         return Response::Nil;
     }
-    Response::Array(
+    // the writes are wrapped in MULTI/EXEC in the append only file and the
+    // replication stream, so they are applied atomically too
+    let propagate = c.iter().any(|c| is_write(&c.get_command(), db));
+    if propagate {
+        propagate_raw(db, client.dbindex, b"*1\r\n$5\r\nMULTI\r\n");
+    }
+    client.exec_wrapped = propagate;
+    let r = Response::Array(
         c.iter()
-            .map(|c| command(c.get_command(), db, client).unwrap())
+            .map(|c| {
+                // the whole transaction follows the ASKING before EXEC
+                client.asking = asking;
+                command(c.get_command(), db, client).unwrap()
+            })
             .collect(),
-    )
+    );
+    client.exec_wrapped = false;
+    if propagate {
+        propagate_raw(db, client.dbindex, b"*1\r\n$4\r\nEXEC\r\n");
+    }
+    r
+}
+
+fn is_write(parser: &ParsedCommand, db: &Database) -> bool {
+    let name = match parser.get_str(0) {
+        Ok(name) => name.to_ascii_lowercase(),
+        Err(_) => return false,
+    };
+    match db.mapped_command(&name) {
+        Some(c) => find_properties(db, &c).flags.contains(CommandFlags::WRITE),
+        None => false,
+    }
+}
+
+fn propagate_raw(db: &mut Database, dbindex: usize, data: &[u8]) {
+    if let Ok((parser, _)) = parse(data) {
+        db.propagate(dbindex, &parser);
+    }
 }
 
 fn discard(db: &mut Database, client: &mut Client) -> Response {
@@ -4221,7 +5518,812 @@ fn discard(db: &mut Database, client: &mut Client) -> Response {
     }
 }
 
-fn command_cmd(parser: &mut ParsedCommand, _db: &Database) -> Response {
+/// Runs a command called by a script, with the restrictions scripts have.
+/// The first write propagates MULTI when `multi` is set, and clears it.
+fn script_call(
+    db: &mut Database,
+    client: &mut Client,
+    args: Vec<Vec<u8>>,
+    multi: &mut bool,
+    readonly: bool,
+) -> Response {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let properties = match db.mapped_command(&name) {
+        Some(name) => find_properties(db, &name),
+        None => command_properties(""),
+    };
+    if properties.arity == 0 {
+        return Response::Error("ERR Unknown Redis command called from Lua script".to_owned());
+    }
+    if properties.flags.contains(CommandFlags::NOSCRIPT) {
+        return Response::Error("ERR This Redis command is not allowed from scripts".to_owned());
+    }
+    let argc = args.len() as i64;
+    if (properties.arity > 0 && argc != properties.arity) || argc < -properties.arity {
+        return Response::Error("ERR Wrong number of args calling Redis command From Lua script".to_owned());
+    }
+    if properties.flags.contains(CommandFlags::WRITE) {
+        if readonly {
+            return Response::Error("ERR Write commands are not allowed from read-only scripts.".to_owned());
+        }
+        if not_enough_replicas(db) {
+            return Response::Error("NOREPLICAS Not enough good replicas to write.".to_owned());
+        }
+        db.busy.script_write();
+        if *multi {
+            propagate_raw(db, client.dbindex, b"*1\r\n$5\r\nMULTI\r\n");
+            *multi = false;
+        }
+    }
+
+    let args = args.iter().map(|arg| &arg[..]).collect::<Vec<_>>();
+    let mut data = vec![];
+    write_command(&mut data, &args).unwrap();
+    let parser = match parse(&data) {
+        Ok((parser, _)) => parser,
+        Err(_) => return Response::Error("ERR Protocol error".to_owned()),
+    };
+    let mut r = command(parser, db, client).unwrap_or(Response::Nil);
+    // the output of commands returning elements in no particular order is
+    // sorted, so the script behaves the same on every run
+    if properties.flags.contains(CommandFlags::SORT_FOR_SCRIPT) {
+        if let Response::Array(ref mut items) = r {
+            items.sort_by(|a, b| match (a, b) {
+                (Response::Data(a), Response::Data(b)) => a.cmp(b),
+                _ => Ordering::Equal,
+            });
+        }
+    }
+    r
+}
+
+/// The keys and the arguments of EVAL or FCALL, after the number of keys.
+fn script_args(parser: &ParsedCommand) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>), Response> {
+    let error = |e: &str| Err(Response::Error(e.to_owned()));
+    let numkeys = match parser.get_i64(2) {
+        Ok(numkeys) => numkeys,
+        Err(_) => return error("ERR value is not an integer or out of range"),
+    };
+    if numkeys < 0 {
+        return error("ERR Number of keys can't be negative");
+    }
+    if numkeys as usize > parser.argv.len() - 3 {
+        return error("ERR Number of keys can't be greater than number of args");
+    }
+    let mut keys = Vec::with_capacity(parser.argv.len() - 3);
+    for i in 3..parser.argv.len() {
+        match parser.get_vec(i) {
+            Ok(arg) => keys.push(arg),
+            Err(_) => return error("ERR syntax error"),
+        }
+    }
+    let args = keys.split_off(numkeys as usize);
+    Ok((keys, args))
+}
+
+/// Runs a script or a function with `run`, which gets the closure that
+/// executes the commands it calls. The writes are propagated instead of the
+/// script itself, wrapped in MULTI/EXEC. A `readonly` script cannot write.
+fn script_run<F>(db: &mut Database, client: &Client, readonly: bool, run: F) -> Result<Response, String>
+where
+    F: FnOnce(&Logger, Arc<Busy>, &mut dyn FnMut(Vec<Vec<u8>>) -> Response) -> Result<Response, String>,
+{
+    let mut script_client = Client::mock();
+    script_client.auth = true;
+    script_client.dbindex = client.dbindex;
+    let mut multi = !client.exec_wrapped;
+    let logger = db.config.logger.clone();
+    let busy = db.busy.clone();
+    busy.script_begin();
+    let r = run(&logger, busy, &mut |args| {
+        script_call(db, &mut script_client, args, &mut multi, readonly)
+    });
+    if !multi && !client.exec_wrapped {
+        propagate_raw(db, script_client.dbindex, b"*1\r\n$4\r\nEXEC\r\n");
+    }
+    r
+}
+
+/// EVAL runs the script in the command, EVALSHA the one cached with the
+/// digest given.
+fn eval(parser: &mut ParsedCommand, db: &mut Database, client: &Client, evalsha: bool) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let (sha, body) = if evalsha {
+        let sha = try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase();
+        match db.scripts.get(&sha) {
+            Some(body) => (sha, body.clone()),
+            None => return Response::Error("NOSCRIPT No matching script. Please use EVAL.".to_owned()),
+        }
+    } else {
+        let body = try_validate!(parser.get_vec(1), "ERR syntax error");
+        (scripting::sha1hex(&body), body)
+    };
+    let (keys, argv) = match script_args(parser) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let r = script_run(db, client, false, |logger, busy, call| {
+        scripting::run(&body, &sha, &keys, &argv, logger, busy, call)
+    });
+    match r {
+        Ok(r) => {
+            if !evalsha {
+                db.scripts.insert(sha, body);
+            }
+            r
+        }
+        Err(e) => Response::Error(e),
+    }
+}
+
+fn script(parser: &mut ParsedCommand, db: &mut Database) -> Response {
+    validate_arguments_gte!(parser, 2);
+    let subcommand = try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase();
+    let argc = parser.argv.len();
+    match &*subcommand {
+        "load" if argc == 3 => {
+            let body = try_validate!(parser.get_vec(2), "ERR syntax error");
+            if let Err(e) = scripting::compile(&body) {
+                return Response::Error(e);
+            }
+            let sha = scripting::sha1hex(&body);
+            db.scripts.insert(sha.clone(), body);
+            Response::Data(sha.into_bytes())
+        }
+        "exists" if argc >= 3 => {
+            let mut exists = Vec::with_capacity(argc - 2);
+            for i in 2..argc {
+                let sha = try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+                exists.push(Response::Integer(db.scripts.contains_key(&sha) as i64));
+            }
+            Response::Array(exists)
+        }
+        "flush" if argc == 2 => {
+            db.scripts.clear();
+            Response::Status("OK".to_owned())
+        }
+        "kill" if argc == 2 => match db.busy.kill_script() {
+            Ok(()) => Response::Status("OK".to_owned()),
+            Err(e) => Response::Error(e),
+        },
+        _ => Response::Error("ERR Unknown SCRIPT subcommand or wrong # of args.".to_owned()),
+    }
+}
+
+/// FCALL calls a function of the loaded libraries. FCALL_RO only calls the
+/// ones flagged `no-writes`, which cannot write either.
+fn fcall(parser: &mut ParsedCommand, db: &mut Database, client: &Client, readonly: bool) -> Response {
+    validate_arguments_gte!(parser, 3);
+    let name = try_validate!(parser.get_str(1), "ERR syntax error").to_owned();
+    let (keys, args) = match script_args(parser) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let (body, no_writes) = match db.functions.get(&name) {
+        Some((library, function)) => (library.body().to_vec(), function.has_flag("no-writes")),
+        None => return Response::Error("ERR Function not found".to_owned()),
+    };
+    validate!(
+        no_writes || !readonly,
+        "ERR Can not execute a script with write flag using *_ro command."
+    );
+    validate!(
+        no_writes || db.master.is_none() || !db.config.slave_read_only || client.is_master,
+        "READONLY You can't write against a read only replica."
+    );
+    let r = script_run(db, client, no_writes, |logger, busy, call| {
+        scripting::call_function(&body, &name, &keys, &args, logger, busy, call)
+    });
+    r.unwrap_or_else(Response::Error)
+}
+
+fn function_list(parser: &ParsedCommand, db: &Database) -> Response {
+    let mut withcode = false;
+    let mut pattern = None;
+    let mut i = 2;
+    while i < parser.argv.len() {
+        match &*try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase() {
+            "withcode" if !withcode => withcode = true,
+            "libraryname" if pattern.is_none() && i + 1 < parser.argv.len() => {
+                i += 1;
+                pattern = Some(try_validate!(parser.get_vec(i), "ERR syntax error"));
+            }
+            _ => return Response::Error("ERR Unknown argument".to_owned()),
+        }
+        i += 1;
+    }
+    let data = |s: &str| Response::Data(s.as_bytes().to_vec());
+    let mut libraries = vec![];
+    for library in db.functions.libraries() {
+        if let Some(pattern) = &pattern {
+            if !glob_match(pattern, library.name.as_bytes(), false) {
+                continue;
+            }
+        }
+        let functions = library
+            .functions
+            .values()
+            .map(|function| {
+                Response::Array(vec![
+                    data("name"),
+                    data(&function.name),
+                    data("description"),
+                    function.description.as_ref().map_or(Response::Nil, |d| data(d)),
+                    data("flags"),
+                    Response::Array(function.flags.iter().map(|flag| data(flag)).collect()),
+                ])
+            })
+            .collect();
+        let mut fields = vec![
+            data("library_name"),
+            data(&library.name),
+            data("engine"),
+            data(&library.engine),
+            data("functions"),
+            Response::Array(functions),
+        ];
+        if withcode {
+            fields.push(data("library_code"));
+            fields.push(Response::Data(library.code.clone()));
+        }
+        libraries.push(Response::Array(fields));
+    }
+    Response::Array(libraries)
+}
+
+/// FUNCTION manages the libraries. The subcommands that change them are
+/// propagated as they are.
+fn function_cmd(parser: &mut ParsedCommand, db: &mut Database, client: &Client) -> Response {
+    validate_arguments_gte!(parser, 2);
+    let subcommand = try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase();
+    let argc = parser.argv.len();
+    let write = ["load", "delete", "flush", "restore"].contains(&&*subcommand);
+    validate!(
+        !write || db.master.is_none() || !db.config.slave_read_only || client.is_master,
+        "READONLY You can't write against a read only replica."
+    );
+    let logger = db.config.logger.clone();
+    let r = match &*subcommand {
+        "load" if argc == 3 || argc == 4 => {
+            if argc == 4 {
+                let option = try_validate!(parser.get_str(2), "ERR syntax error");
+                validate!(option.eq_ignore_ascii_case("replace"), "ERR Unknown option given");
+            }
+            let code = try_validate!(parser.get_vec(argc - 1), "ERR syntax error");
+            match db.functions.load(&code, argc == 4, &logger) {
+                Ok(name) => Response::Data(name.into_bytes()),
+                Err(e) => Response::Error(e),
+            }
+        }
+        "delete" if argc == 3 => {
+            let name = try_validate!(parser.get_str(2), "ERR syntax error");
+            validate!(db.functions.delete(name), "ERR Library not found");
+            Response::Status("OK".to_owned())
+        }
+        "flush" if argc <= 3 => {
+            if argc == 3 {
+                let mode = try_validate!(parser.get_str(2), "ERR syntax error").to_ascii_lowercase();
+                validate!(
+                    mode == "sync" || mode == "async",
+                    "ERR FUNCTION FLUSH only supports SYNC|ASYNC option"
+                );
+            }
+            db.functions.flush();
+            Response::Status("OK".to_owned())
+        }
+        "list" => function_list(parser, db),
+        "dump" if argc == 2 => Response::Data(db.functions.dump()),
+        "restore" if argc == 3 || argc == 4 => {
+            let policy = if argc == 4 {
+                try_validate!(parser.get_str(3), "ERR syntax error").to_ascii_lowercase()
+            } else {
+                "append".to_owned()
+            };
+            validate!(
+                policy == "flush" || policy == "append" || policy == "replace",
+                "ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE."
+            );
+            let payload = try_validate!(parser.get_vec(2), "ERR syntax error");
+            match db.functions.restore(&payload, &policy, &logger) {
+                Ok(()) => Response::Status("OK".to_owned()),
+                Err(e) => Response::Error(e),
+            }
+        }
+        "stats" if argc == 2 => {
+            let libraries = db.functions.libraries().len();
+            Response::Array(vec![
+                Response::Data(b"running_script".to_vec()),
+                Response::Nil,
+                Response::Data(b"engines".to_vec()),
+                Response::Array(vec![
+                    Response::Data(b"LUA".to_vec()),
+                    Response::Array(vec![
+                        Response::Data(b"libraries_count".to_vec()),
+                        Response::Integer(libraries as i64),
+                        Response::Data(b"functions_count".to_vec()),
+                        Response::Integer(db.functions.function_count() as i64),
+                    ]),
+                ]),
+            ])
+        }
+        "kill" if argc == 2 => match db.busy.kill_script() {
+            Ok(()) => Response::Status("OK".to_owned()),
+            Err(e) => Response::Error(e),
+        },
+        _ => Response::Error("ERR Unknown FUNCTION subcommand or wrong # of args.".to_owned()),
+    };
+    if write && !r.is_error() {
+        db.propagate(client.dbindex, parser);
+        db.dirty += 1;
+    }
+    r
+}
+
+fn asking_cmd(parser: &mut ParsedCommand, db: &Database, client: &mut Client) -> Response {
+    validate_arguments_exact!(parser, 1);
+    validate!(db.cluster.is_some(), "ERR This instance has cluster support disabled");
+    client.asking = true;
+    Response::Status("OK".to_owned())
+}
+
+fn readonly(parser: &mut ParsedCommand, db: &Database, client: &mut Client) -> Response {
+    validate_arguments_exact!(parser, 1);
+    validate!(db.cluster.is_some(), "ERR This instance has cluster support disabled");
+    client.readonly = true;
+    Response::Status("OK".to_owned())
+}
+
+fn readwrite(parser: &mut ParsedCommand, db: &Database, client: &mut Client) -> Response {
+    validate_arguments_exact!(parser, 1);
+    validate!(db.cluster.is_some(), "ERR This instance has cluster support disabled");
+    client.readonly = false;
+    Response::Status("OK".to_owned())
+}
+
+fn parse_slot(parser: &ParsedCommand, pos: usize) -> Option<u16> {
+    match parser.get_i64(pos) {
+        Ok(slot) if slot >= 0 && (slot as usize) < CLUSTER_SLOTS => Some(slot as u16),
+        _ => None,
+    }
+}
+
+fn parse_slots(parser: &ParsedCommand) -> Option<Vec<u16>> {
+    (2..parser.argv.len()).map(|pos| parse_slot(parser, pos)).collect()
+}
+
+fn cluster_node_response(node: &ClusterNode) -> Response {
+    Response::Array(vec![
+        Response::Data(node.ip.clone().into_bytes()),
+        Response::Integer(node.port as i64),
+        Response::Data(node.id.clone().into_bytes()),
+    ])
+}
+
+fn cluster_slots(cluster: &Cluster) -> Response {
+    let mut masters = cluster
+        .nodes
+        .values()
+        .filter(|node| node.master.is_none() && !node.handshake)
+        .collect::<Vec<_>>();
+    masters.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut ranges = vec![];
+    for master in masters {
+        for (start, end) in cluster.slot_ranges(&master.id) {
+            let mut range = vec![
+                Response::Integer(start as i64),
+                Response::Integer(end as i64),
+                cluster_node_response(master),
+            ];
+            range.extend(cluster.replicas(&master.id).into_iter().map(cluster_node_response));
+            ranges.push((start, Response::Array(range)));
+        }
+    }
+    ranges.sort_by_key(|range| range.0);
+    Response::Array(ranges.into_iter().map(|range| range.1).collect())
+}
+
+fn cluster_shard_node(node: &ClusterNode) -> Response {
+    let role: &[u8] = if node.master.is_some() { b"replica" } else { b"master" };
+    Response::Array(vec![
+        Response::Data(b"id".to_vec()),
+        Response::Data(node.id.clone().into_bytes()),
+        Response::Data(b"port".to_vec()),
+        Response::Integer(node.port as i64),
+        Response::Data(b"ip".to_vec()),
+        Response::Data(node.ip.clone().into_bytes()),
+        Response::Data(b"endpoint".to_vec()),
+        Response::Data(node.ip.clone().into_bytes()),
+        Response::Data(b"role".to_vec()),
+        Response::Data(role.to_vec()),
+        Response::Data(b"health".to_vec()),
+        Response::Data(b"online".to_vec()),
+    ])
+}
+
+fn cluster_shards(cluster: &Cluster) -> Response {
+    let mut masters = cluster
+        .nodes
+        .values()
+        .filter(|node| node.master.is_none() && !node.handshake)
+        .collect::<Vec<_>>();
+    masters.sort_by(|a, b| a.id.cmp(&b.id));
+    Response::Array(
+        masters
+            .into_iter()
+            .map(|master| {
+                let slots = cluster
+                    .slot_ranges(&master.id)
+                    .into_iter()
+                    .flat_map(|(start, end)| vec![Response::Integer(start as i64), Response::Integer(end as i64)])
+                    .collect();
+                let mut nodes = vec![cluster_shard_node(master)];
+                nodes.extend(cluster.replicas(&master.id).into_iter().map(cluster_shard_node));
+                Response::Array(vec![
+                    Response::Data(b"slots".to_vec()),
+                    Response::Array(slots),
+                    Response::Data(b"nodes".to_vec()),
+                    Response::Array(nodes),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn cluster_cmd(parser: &mut ParsedCommand, db: &mut Database) -> Response {
+    validate!(db.cluster.is_some(), "ERR This instance has cluster support disabled");
+    let subcommand = try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase();
+    let argc = parser.argv.len();
+    let r = match &*subcommand {
+        "keyslot" => {
+            validate!(argc == 3, "ERR Wrong number of arguments for CLUSTER KEYSLOT");
+            let key = try_validate!(parser.get_vec(2), "ERR syntax error");
+            return Response::Integer(key_hash_slot(&key) as i64);
+        }
+        "countkeysinslot" => {
+            validate!(argc == 3, "ERR Wrong number of arguments for CLUSTER COUNTKEYSINSLOT");
+            let slot = try_validate!(parse_slot(parser, 2).ok_or(()), "ERR Invalid slot");
+            return Response::Integer(db.count_keys_in_slot(slot) as i64);
+        }
+        "getkeysinslot" => {
+            validate!(argc == 4, "ERR Wrong number of arguments for CLUSTER GETKEYSINSLOT");
+            let slot = parse_slot(parser, 2);
+            let count = parser.get_i64(3);
+            let (slot, count) = match (slot, count) {
+                (Some(slot), Ok(count)) if count >= 0 => (slot, count as usize),
+                _ => return Response::Error("ERR Invalid slot or number of keys".to_owned()),
+            };
+            return Response::Array(
+                db.get_keys_in_slot(slot, count)
+                    .into_iter()
+                    .map(Response::Data)
+                    .collect(),
+            );
+        }
+        "nodes" => {
+            let nodes = db.cluster.as_ref().unwrap().nodes_description();
+            return Response::Data(nodes.into_bytes());
+        }
+        "slots" => return cluster_slots(db.cluster.as_ref().unwrap()),
+        "shards" => return cluster_shards(db.cluster.as_ref().unwrap()),
+        "info" => return Response::Data(db.cluster.as_ref().unwrap().info().into_bytes()),
+        "saveconfig" => {
+            return match db.cluster_save_config() {
+                Ok(()) => Response::Status("OK".to_owned()),
+                Err(err) => Response::Error(format!("ERR error saving the cluster node config: {}", err)),
+            };
+        }
+        "addslots" | "delslots" => {
+            validate!(argc > 2, format!("ERR Wrong number of arguments for CLUSTER {}", subcommand.to_ascii_uppercase()));
+            let slots = try_validate!(parse_slots(parser).ok_or(()), "ERR Invalid or out of range slot");
+            let cluster = db.cluster.as_mut().unwrap();
+            if subcommand == "addslots" {
+                cluster.add_slots(&slots)
+            } else {
+                cluster.del_slots(&slots)
+            }
+        }
+        "setslot" => {
+            validate!(argc >= 4, "ERR Wrong number of arguments for CLUSTER SETSLOT");
+            let slot = try_validate!(parse_slot(parser, 2).ok_or(()), "ERR Invalid or out of range slot");
+            let action = try_validate!(parser.get_str(3), "ERR syntax error").to_ascii_lowercase();
+            let id = if action == "stable" {
+                validate!(argc == 4, "ERR syntax error");
+                String::new()
+            } else {
+                validate!(argc == 5, "ERR syntax error");
+                try_validate!(parser.get_str(4), "ERR syntax error").to_owned()
+            };
+            let keys = db.count_keys_in_slot(slot);
+            let cluster = db.cluster.as_mut().unwrap();
+            validate!(
+                cluster.myself().master.is_none(),
+                "ERR Please use SETSLOT only with masters."
+            );
+            match &*action {
+                "migrating" => cluster.set_slot_migrating(slot, &id),
+                "importing" => cluster.set_slot_importing(slot, &id),
+                "stable" => {
+                    cluster.set_slot_stable(slot);
+                    Ok(())
+                }
+                "node" => {
+                    let mine = cluster.slots[slot as usize].as_ref() == Some(&cluster.myself);
+                    if mine && id != cluster.myself && keys > 0 {
+                        return Response::Error(format!(
+                            "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                            slot
+                        ));
+                    }
+                    cluster.set_slot_node(slot, &id)
+                }
+                _ => return Response::Error("ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP".to_owned()),
+            }
+        }
+        "meet" => {
+            validate!(argc == 4 || argc == 5, "ERR Wrong number of arguments for CLUSTER MEET");
+            let ip = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            let port = try_validate!(parser.get_i64(3), "ERR Invalid base port specified");
+            validate!(port > 0 && port < 65536, format!("ERR Invalid base port specified: {}", port));
+            let cport = if argc == 5 {
+                let cport = try_validate!(parser.get_i64(4), "ERR Invalid bus port specified");
+                validate!(cport > 0 && cport < 65536, format!("ERR Invalid bus port specified: {}", cport));
+                cport
+            } else {
+                port + 10000
+            };
+            validate!(cport < 65536, format!("ERR Invalid bus port specified: {}", cport));
+            db.cluster.as_mut().unwrap().meet(&ip, port as u16, cport as u16)
+        }
+        "forget" => {
+            validate!(argc == 3, "ERR Wrong number of arguments for CLUSTER FORGET");
+            let id = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            db.cluster.as_mut().unwrap().forget(&id)
+        }
+        "replicate" => {
+            validate!(argc == 3, "ERR Wrong number of arguments for CLUSTER REPLICATE");
+            let id = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            validate!(
+                db.dbsize(0) == 0 || db.cluster.as_ref().unwrap().myself().master.is_some(),
+                "ERR To set a master the node must be empty and without assigned slots."
+            );
+            let cluster = db.cluster.as_mut().unwrap();
+            if let Err(err) = cluster.replicate(&id) {
+                return Response::Error(err);
+            }
+            let (ip, port) = (cluster.nodes[&id].ip.clone(), cluster.nodes[&id].port);
+            db.repl_set_master(ip, port);
+            Ok(())
+        }
+        _ => return Response::Error(format!("ERR Unknown subcommand or wrong number of arguments for '{}'", subcommand)),
+    };
+    if let Err(err) = r {
+        return Response::Error(err);
+    }
+    if let Err(err) = db.cluster_save_config() {
+        logger::log!(db.config.logger, Warning, "Could not save the cluster config file: {}", err);
+    }
+    Response::Status("OK".to_owned())
+}
+
+/// The fields describing the master, or one of its replicas or sentinels,
+/// as name/value pairs.
+fn sentinel_instance(master: &Master, kind: &str, name: &str, instance: &Instance, now: i64) -> Response {
+    let since = |time: i64| if time == 0 { 0 } else { now - time };
+    let mut fields = vec![
+        ("name", name.to_owned()),
+        ("ip", instance.ip.clone()),
+        ("port", instance.port.to_string()),
+        ("runid", instance.run_id.clone()),
+        ("flags", master.flags(kind, instance)),
+        ("link-pending-commands", instance.pending_commands().to_string()),
+        ("last-ping-sent", since(instance.ping_sent).to_string()),
+        ("last-ok-ping-reply", since(instance.last_avail).to_string()),
+        ("down-after-milliseconds", master.down_after.to_string()),
+    ];
+    if instance.sdown_since != 0 {
+        fields.push(("s-down-time", since(instance.sdown_since).to_string()));
+    }
+    match kind {
+        "master" => {
+            if master.odown_since != 0 {
+                fields.push(("o-down-time", since(master.odown_since).to_string()));
+            }
+            fields.extend(vec![
+                ("info-refresh", since(instance.info_refresh).to_string()),
+                ("role-reported", "master".to_owned()),
+                ("role-reported-time", since(instance.role_reported_time).to_string()),
+                ("config-epoch", master.config_epoch.to_string()),
+                ("num-slaves", master.replicas.len().to_string()),
+                ("num-other-sentinels", master.sentinels.len().to_string()),
+                ("quorum", master.quorum.to_string()),
+                ("failover-timeout", master.failover_timeout.to_string()),
+                ("parallel-syncs", master.parallel_syncs.to_string()),
+            ]);
+            if master.failover_state != FailoverState::None {
+                fields.push(("failover-state", master.failover_state.as_str().to_owned()));
+            }
+        }
+        "slave" => fields.extend(vec![
+            ("info-refresh", since(instance.info_refresh).to_string()),
+            ("role-reported", if instance.role_master { "master" } else { "slave" }.to_owned()),
+            ("role-reported-time", since(instance.role_reported_time).to_string()),
+            ("master-link-status", if instance.master_link_up { "ok" } else { "err" }.to_owned()),
+            ("master-host", instance.master_host.clone()),
+            ("master-port", instance.master_port.to_string()),
+            ("slave-priority", instance.priority.to_string()),
+            ("slave-repl-offset", instance.offset.to_string()),
+        ]),
+        _ => fields.extend(vec![
+            ("last-hello-message", since(instance.last_hello).to_string()),
+            ("voted-leader", instance.leader.clone().unwrap_or_else(|| "?".to_owned())),
+            ("voted-leader-epoch", instance.leader_epoch.to_string()),
+        ]),
+    }
+    Response::Array(
+        fields
+            .into_iter()
+            .flat_map(|(field, value)| vec![Response::Data(field.as_bytes().to_vec()), Response::Data(value.into_bytes())])
+            .collect(),
+    )
+}
+
+fn sentinel_cmd(parser: &mut ParsedCommand, db: &mut Database) -> Response {
+    validate!(db.sentinel.is_some(), "ERR unknown command 'sentinel'");
+    let subcommand = try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase();
+    let argc = parser.argv.len();
+    let wrong_arity = format!(
+        "ERR Unknown subcommand or wrong number of arguments for '{}'",
+        subcommand
+    );
+    let now = mstime();
+    let r = match &*subcommand {
+        "masters" | "master" | "replicas" | "slaves" | "sentinels" => {
+            let sentinel = db.sentinel.as_ref().unwrap();
+            if subcommand == "masters" {
+                validate!(argc == 2, wrong_arity);
+                return Response::Array(
+                    sentinel
+                        .masters
+                        .values()
+                        .map(|master| sentinel_instance(master, "master", &master.name, &master.instance, now))
+                        .collect(),
+                );
+            }
+            validate!(argc == 3, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error");
+            let master = match sentinel.get(name) {
+                Ok(master) => master,
+                Err(err) => return Response::Error(format!("ERR {}", err)),
+            };
+            return match &*subcommand {
+                "master" => sentinel_instance(master, "master", &master.name, &master.instance, now),
+                "sentinels" => Response::Array(
+                    master
+                        .sentinels
+                        .iter()
+                        .map(|(id, instance)| sentinel_instance(master, "sentinel", id, instance, now))
+                        .collect(),
+                ),
+                _ => Response::Array(
+                    master
+                        .replicas
+                        .iter()
+                        .map(|(addr, instance)| sentinel_instance(master, "slave", addr, instance, now))
+                        .collect(),
+                ),
+            };
+        }
+        "get-master-addr-by-name" => {
+            validate!(argc == 3, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error");
+            return match db.sentinel.as_ref().unwrap().get(name) {
+                Ok(master) => {
+                    let (ip, port) = master.current_address();
+                    Response::Array(vec![
+                        Response::Data(ip.into_bytes()),
+                        Response::Data(port.to_string().into_bytes()),
+                    ])
+                }
+                Err(_) => Response::Nil,
+            };
+        }
+        "is-master-down-by-addr" => {
+            validate!(argc == 6, wrong_arity);
+            let ip = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            let port = try_validate!(parser.get_i64(3), "ERR value is not an integer or out of range");
+            let epoch = try_validate!(parser.get_i64(4), "ERR value is not an integer or out of range");
+            let runid = try_validate!(parser.get_str(5), "ERR syntax error").to_owned();
+            validate!(
+                port > 0 && port < 65536 && epoch >= 0,
+                "ERR value is not an integer or out of range"
+            );
+            let (down, leader, leader_epoch) = db.sentinel.as_mut().unwrap().is_master_down(
+                &ip,
+                port as u16,
+                epoch as u64,
+                &runid,
+                now,
+            );
+            db.sentinel_flush();
+            return Response::Array(vec![
+                Response::Integer(down as i64),
+                Response::Data(leader.unwrap_or_else(|| "*".to_owned()).into_bytes()),
+                Response::Integer(leader_epoch as i64),
+            ]);
+        }
+        "failover" => {
+            validate!(argc == 3, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            let r = db.sentinel.as_mut().unwrap().failover(&name, now);
+            db.sentinel_flush();
+            return match r {
+                Ok(()) => Response::Status("OK".to_owned()),
+                // the errors carry their own code
+                Err(ref err) if err.starts_with("INPROG") || err.starts_with("NOGOODSLAVE") => {
+                    Response::Error(err.clone())
+                }
+                Err(err) => Response::Error(format!("ERR {}", err)),
+            };
+        }
+        "ckquorum" => {
+            validate!(argc == 3, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error");
+            return match db.sentinel.as_ref().unwrap().check_quorum(name) {
+                Ok(usable) => Response::Status(format!("OK {} usable Sentinels. Quorum and failover authorization can be reached", usable)),
+                Err(ref err) if err.starts_with("NOQUORUM") => Response::Error(err.clone()),
+                Err(err) => Response::Error(format!("ERR {}", err)),
+            };
+        }
+        "myid" => {
+            validate!(argc == 2, wrong_arity);
+            return Response::Data(db.sentinel.as_ref().unwrap().myid.clone().into_bytes());
+        }
+        "monitor" => {
+            validate!(argc == 6, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            let ip = try_validate!(parser.get_str(3), "ERR syntax error").to_owned();
+            let port = try_validate!(parser.get_i64(4), "ERR Invalid port number");
+            let quorum = try_validate!(parser.get_i64(5), "ERR Quorum must be 1 or greater.");
+            validate!(port > 0 && port < 65536, "ERR Invalid port number");
+            validate!(quorum > 0, "ERR Quorum must be 1 or greater.");
+            db.sentinel.as_mut().unwrap().monitor(&name, &ip, port as u16, quorum as usize)
+        }
+        "remove" => {
+            validate!(argc == 3, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            db.sentinel.as_mut().unwrap().remove(&name)
+        }
+        "set" => {
+            validate!(argc >= 5 && argc % 2 == 1, wrong_arity);
+            let name = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            let mut r = Ok(());
+            for i in (3..argc).step_by(2) {
+                let option = try_validate!(parser.get_str(i), "ERR syntax error").to_ascii_lowercase();
+                let value = try_validate!(parser.get_str(i + 1), "ERR syntax error").to_owned();
+                r = db.sentinel.as_mut().unwrap().set(&name, &option, &value);
+                if r.is_err() {
+                    break;
+                }
+            }
+            r
+        }
+        "flushconfig" => {
+            validate!(argc == 2, wrong_arity);
+            if let Err(err) = db.sentinel_save_config() {
+                return Response::Error(format!("ERR Failed to save config: {}", err));
+            }
+            Ok(())
+        }
+        _ => return Response::Error(wrong_arity),
+    };
+    db.sentinel_flush();
+    match r {
+        Ok(()) => Response::Status("OK".to_owned()),
+        Err(err) => Response::Error(format!("ERR {}", err)),
+    }
+}
+
+fn command_cmd(parser: &mut ParsedCommand, db: &Database) -> Response {
     if parser.argv.len() == 1 {
         // COMMAND - return all commands
         let mut result = Vec::new();
@@ -4237,7 +6339,8 @@ fn command_cmd(parser: &mut ParsedCommand, _db: &Database) -> Response {
             "zrevrangebyscore", "zrangebylex", "zrevrangebylex", "zcount", "zlexcount",
             "zrevrange", "zcard", "zscore", "zrank", "zrevrank", "zscan", "hset", "hsetnx",
             "hget", "hmset", "hmget", "hincrby", "hincrbyfloat", "hdel", "hlen", "hstrlen",
-            "hkeys", "hvals", "hgetall", "hexists", "hscan", "incrby", "decrby", "incrbyfloat",
+            "hkeys", "hvals", "hgetall", "hexists", "hscan", "xadd", "xlen", "xrange",
+            "xrevrange", "xdel", "xtrim", "xread", "incrby", "decrby", "incrbyfloat",
             "getset", "mset", "msetnx", "randomkey", "select", "move", "rename", "renamenx",
             "expire", "expireat", "pexpire", "pexpireat", "keys", "scan", "dbsize", "auth",
             "ping", "echo", "save", "bgsave", "bgrewriteaof", "shutdown", "lastsave", "type",
@@ -4245,10 +6348,16 @@ fn command_cmd(parser: &mut ParsedCommand, _db: &Database) -> Response {
             "ttl", "pttl", "persist", "slaveof", "role", "config", "subscribe", "unsubscribe",
             "psubscribe", "punsubscribe", "publish", "pubsub", "watch", "unwatch", "restore",
             "dump", "object", "client", "time", "bitop", "bitcount", "bitpos", "wait", "command",
-            "pfadd", "pfcount", "pfmerge",
+            "pfadd", "pfcount", "pfmerge", "module",
         ];
-        for cmd_name in commands {
-            let props = command_properties(cmd_name);
+        let module_commands = db
+            .modules
+            .modules()
+            .into_iter()
+            .flat_map(|module| module.command_names())
+            .collect::<Vec<_>>();
+        for cmd_name in commands.into_iter().chain(module_commands) {
+            let props = find_properties(db, cmd_name);
             let mut cmd_info = Vec::new();
             cmd_info.push(Response::Data(cmd_name.as_bytes().to_vec()));
             cmd_info.push(Response::Data(props.arity.to_string().into_bytes()));
@@ -4310,7 +6419,7 @@ fn command_cmd(parser: &mut ParsedCommand, _db: &Database) -> Response {
                 // COMMAND GETKEYS <command> <args...>
                 validate_arguments_gte!(parser, 3);
                 let cmd_name = try_validate!(parser.get_str(2), "Invalid command");
-                let props = command_properties(&cmd_name.to_ascii_lowercase());
+                let props = find_properties(db, &cmd_name.to_ascii_lowercase());
                 if props.first_key_index == 0 && props.last_key_index == 0 {
                     return Response::Array(Vec::new());
                 }
@@ -4340,7 +6449,7 @@ fn command_cmd(parser: &mut ParsedCommand, _db: &Database) -> Response {
                 let mut result = Vec::new();
                 for i in 2..parser.argv.len() {
                     let cmd_name = try_validate!(parser.get_str(i), "Invalid command");
-                    let props = command_properties(&cmd_name.to_ascii_lowercase());
+                    let props = find_properties(db, &cmd_name.to_ascii_lowercase());
                     let mut cmd_info = Vec::new();
                     cmd_info.push(Response::Data(cmd_name.as_bytes().to_vec()));
                     cmd_info.push(Response::Data(props.arity.to_string().into_bytes()));
@@ -4390,28 +6499,149 @@ fn command_cmd(parser: &mut ParsedCommand, _db: &Database) -> Response {
                     cmd_info.push(Response::Data(props.key_step.to_string().into_bytes()));
                     result.push(Response::Array(cmd_info));
                 }
-                Response::Array(result)
+                Response::Array(result)
+            }
+            _ => Response::Error("ERR unknown subcommand".to_owned()),
+        }
+    }
+}
+
+fn wait_cmd(
+    parser: &mut ParsedCommand,
+    db: &mut Database,
+    client: &mut Client,
+) -> Result<Response, ResponseError> {
+    // WAIT <numreplicas> <timeout>
+    opt_validate!(parser.argv.len() == 3, "Wrong number of parameters");
+    let numreplicas = try_opt_validate!(
+        parser.get_i64(1),
+        "ERR value is not an integer or out of range"
+    );
+    let timeout = try_opt_validate!(
+        parser.get_i64(2),
+        "ERR timeout is not an integer or out of range"
+    );
+    opt_validate!(timeout >= 0, "ERR timeout is negative");
+    opt_validate!(
+        db.master.is_none(),
+        "ERR WAIT cannot be used with replica instances."
+    );
+
+    // a WAIT executed again after waking up keeps its original deadline
+    let time = mstime();
+    let deadline = client
+        .wait_deadline
+        .take()
+        .unwrap_or(if timeout > 0 { time + timeout } else { 0 });
+    let acked = db.repl_acked(client.woff);
+    if acked as i64 >= numreplicas || (deadline > 0 && time >= deadline) {
+        return Ok(Response::Integer(acked as i64));
+    }
+
+    let (txack, rxack) = channel();
+    let (txcommand, rxcommand) = channel();
+    db.repl_wait(client.id, client.woff, numreplicas as usize, txack);
+    client.wait_deadline = Some(deadline);
+    let command = parser.to_owned();
+    thread::spawn(move || {
+        if deadline == 0 {
+            let _ = rxack.recv();
+        } else {
+            let _ = rxack.recv_timeout(Duration::from_millis((deadline - time) as u64));
+        }
+        let _ = txcommand.send(Some(command));
+    });
+
+    Err(ResponseError::Wait(rxcommand))
+}
+
+/// Runs a command registered by a module, once its arity is checked.
+fn module_command(
+    parser: &ParsedCommand,
+    db: &mut Database,
+    dbindex: usize,
+    name: &str,
+    command: &Command,
+) -> Response {
+    let arity = command.properties.arity;
+    let argc = parser.argv.len() as i64;
+    validate!(
+        if arity > 0 { argc == arity } else { argc >= -arity },
+        format!("ERR wrong number of arguments for '{}' command", name)
+    );
+    command.call(db, dbindex, parser)
+}
+
+/// Loads the module at `path` and registers its commands and types. It
+/// returns the name of the module.
+pub fn module_load(db: &mut Database, path: &str, args: Vec<Vec<u8>>) -> Result<String, String> {
+    let module = Module::open(path, args)?;
+    if let Some(name) = module.command_names().into_iter().find(|name| builtin_command(name)) {
+        return Err(format!("command '{}' already exists", name));
+    }
+    let name = module.name().to_owned();
+    db.modules.add(module)?;
+    Ok(name)
+}
+
+fn module_cmd(parser: &mut ParsedCommand, db: &mut Database) -> Response {
+    let subcommand = try_validate!(parser.get_str(1), "ERR syntax error").to_ascii_lowercase();
+    let argc = parser.argv.len();
+    match &*subcommand {
+        "load" if argc >= 3 => {
+            let path = try_validate!(parser.get_str(2), "ERR syntax error").to_owned();
+            let args = (3..argc).filter_map(|i| parser.get_vec(i).ok()).collect();
+            match module_load(db, &path, args) {
+                Ok(name) => {
+                    logger::log!(db.config.logger, Notice, "Module '{}' loaded from {}", name, path);
+                    Response::Status("OK".to_owned())
+                }
+                Err(e) => {
+                    logger::log!(db.config.logger, Warning, "Module {} failed to load: {}", path, e);
+                    Response::Error("ERR Error loading the extension. Please check the server logs.".to_owned())
+                }
+            }
+        }
+        "unload" if argc == 3 => {
+            let name = try_validate!(parser.get_str(2), "ERR syntax error");
+            match db.modules.remove(name) {
+                Ok(()) => {
+                    logger::log!(db.config.logger, Notice, "Module {} unloaded", name);
+                    Response::Status("OK".to_owned())
+                }
+                Err(e) => Response::Error(format!("ERR Error unloading module: {}", e)),
             }
-            _ => Response::Error("ERR unknown subcommand".to_owned()),
         }
+        "list" if argc == 2 => {
+            let data = |s: &str| Response::Data(s.as_bytes().to_vec());
+            Response::Array(
+                db.modules
+                    .modules()
+                    .into_iter()
+                    .map(|module| {
+                        Response::Array(vec![
+                            data("name"),
+                            data(module.name()),
+                            data("ver"),
+                            Response::Integer(module.version()),
+                            data("path"),
+                            data(module.path()),
+                            data("args"),
+                            Response::Array(
+                                module.args().iter().map(|arg| Response::Data(arg.clone())).collect(),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+        _ => Response::Error(format!(
+            "ERR Unknown subcommand or wrong number of arguments for '{}'. Try MODULE HELP.",
+            subcommand
+        )),
     }
 }
 
-fn wait_cmd(parser: &mut ParsedCommand, _db: &mut Database) -> Response {
-    // WAIT <numreplicas> <timeout>
-    validate_arguments_exact!(parser, 3);
-    let numreplicas = try_validate!(parser.get_i64(1), "Invalid numreplicas");
-    let _timeout = try_validate!(parser.get_i64(2), "Invalid timeout");
-    
-    if numreplicas < 0 {
-        return Response::Error("ERR numreplicas must be >= 0".to_owned());
-    }
-    
-    // For now, we don't have replication implemented, so we return 0
-    // In a full implementation, this would wait for replicas to acknowledge writes
-    Response::Data(b"0".to_vec())
-}
-
 fn slowlog(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     validate!(parser.argv.len() >= 2, "Wrong number of parameters");
     let subcommand = try_validate!(parser.get_str(1), "Invalid subcommand");
@@ -4463,61 +6693,6 @@ fn slowlog(parser: &mut ParsedCommand, db: &mut Database) -> Response {
     }
 }
 
-bitflags! {
-    struct CommandFlags: u16 {
-        /// write command (may modify the key space).
-        const WRITE = 1;
-        /// read command  (will never modify the key space).
-        const READONLY = 2;
-        /// may increase memory usage once called. Don't allow if out of memory.
-        const DENYOOM = 4;
-        /// admin command, like SAVE or SHUTDOWN.
-        const ADMIN = 8;
-        /// Pub/Sub related command.
-        const PUBSUB = 16;
-        /// command not allowed in scripts.
-        const NOSCRIPT = 32;
-        /// random command. Command is not deterministic, that is, the same command
-        /// with the same arguments, with the same key space, may have different
-        /// results. For instance SPOP and RANDOMKEY are two random commands.
-        const RANDOM = 64;
-        /// Sort command output array if called from script, so that the output
-        /// is deterministic.
-        const SORT_FOR_SCRIPT = 128;
-        /// Allow command while loading the database.
-        const LOADING = 256;
-        /// Allow command while a slave has stale data but is not allowed to
-        /// server this data. Normally no command is accepted in this condition
-        /// but just a few.
-        const STALE = 512;
-        /// Do not automatically propagate the command on MONITOR.
-        const SKIP_MONITOR = 1024;
-        /// Perform an implicit ASKING for this command, so the command will be
-        /// accepted in cluster mode if the slot is marked as 'importing'.
-        const ASKING = 2048;
-        /// Fast command(1) or O(log(N)) command that should never delay
-        /// its execution as long as the kernel scheduler is giving us time.
-        /// Note that commands that may trigger a DEL as a side effect (like SET)
-        /// are not fast commands.
-        const FAST = 4096;
-    }
-}
-
-// TODO: Only `flags` is ever used
-#[allow(dead_code)]
-struct CommandProperties {
-    arity: i64,
-    /// Flags as bitmask. Computed by Redis using the 'sflags' field.
-    flags: CommandFlags,
-    /// First argument that is a key
-    first_key_index: i64,
-    /// Last argument that is a key
-    last_key_index: i64,
-    /// Step to get all the keys from first to last argument. For instance
-    ///           in MSET the step is two since arguments are key,val,key,val,...
-    key_step: i64,
-}
-
 fn command_properties(command_name: &str) -> CommandProperties {
     const ADMIN: CommandFlags = CommandFlags::ADMIN;
     const ASKING: CommandFlags = CommandFlags::ASKING;
@@ -4611,7 +6786,7 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "zrank" => (3, fr, 1, 1, 1),
         "zrevrank" => (3, fr, 1, 1, 1),
         "zscan" => (-3, READONLY | RANDOM, 1, 1, 1),
-        "hset" => (4, wmf, 1, 1, 1),
+        "hset" => (-4, wmf, 1, 1, 1),
         "hsetnx" => (4, wmf, 1, 1, 1),
         "hget" => (3, fr, 1, 1, 1),
         "hmset" => (-4, wm, 1, 1, 1),
@@ -4626,6 +6801,13 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "hgetall" => (2, READONLY, 1, 1, 1),
         "hexists" => (3, fr, 1, 1, 1),
         "hscan" => (-3, READONLY | RANDOM, 1, 1, 1),
+        "xadd" => (-5, wmf, 1, 1, 1),
+        "xlen" => (2, fr, 1, 1, 1),
+        "xrange" => (-4, READONLY, 1, 1, 1),
+        "xrevrange" => (-4, READONLY, 1, 1, 1),
+        "xdel" => (-3, wf, 1, 1, 1),
+        "xtrim" => (-4, WRITE, 1, 1, 1),
+        "xread" => (-4, READONLY | NOSCRIPT, 1, 1, 1),
         "incrby" => (3, wmf, 1, 1, 1),
         "decrby" => (3, wmf, 1, 1, 1),
         "incrbyfloat" => (3, wmf, 1, 1, 1),
@@ -4668,7 +6850,8 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "pttl" => (2, fr, 1, 1, 1),
         "persist" => (2, wf, 1, 1, 1),
         "slaveof" => (3, ADMIN | NOSCRIPT | STALE, 0, 0, 0),
-        "role" => (1, STALE | LOADING | NOSCRIPT, 0, 0, 0),
+        "replicaof" => (3, ADMIN | NOSCRIPT | STALE, 0, 0, 0),
+        "role" => (1, READONLY | STALE | LOADING | NOSCRIPT, 0, 0, 0),
         "debug" => (-2, ADMIN | NOSCRIPT, 0, 0, 0),
         "config" => (-2, ADMIN | READONLY | STALE, 0, 0, 0),
         "subscribe" => (-2, READONLY | PUBSUB | NOSCRIPT | LOADING | STALE, 0, 0, 0),
@@ -4680,6 +6863,7 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "watch" => (-2, fr | NOSCRIPT, 1, -1, 1),
         "unwatch" => (1, fr | NOSCRIPT, 0, 0, 0),
         "cluster" => (-2, ADMIN | READONLY, 0, 0, 0),
+        "sentinel" => (-2, ADMIN | READONLY, 0, 0, 0),
         "restore" => (-4, wm, 1, 1, 1),
         "restore-asking" => (-4, wm | ASKING, 1, 1, 1),
         "migrate" => (-6, WRITE, 0, 0, 0),
@@ -4693,6 +6877,9 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "evalsha" => (-3, NOSCRIPT, 0, 0, 0),
         "slowlog" => (-2, READONLY, 0, 0, 0),
         "script" => (-2, READONLY | NOSCRIPT, 0, 0, 0),
+        "fcall" => (-3, NOSCRIPT, 0, 0, 0),
+        "fcall_ro" => (-3, READONLY | NOSCRIPT, 0, 0, 0),
+        "function" => (-2, NOSCRIPT, 0, 0, 0),
         "time" => (1, READONLY | RANDOM | FAST, 0, 0, 0),
         "bitop" => (-4, wm, 2, -1, 1),
         "bitcount" => (-2, READONLY, 1, 1, 1),
@@ -4711,6 +6898,7 @@ fn command_properties(command_name: &str) -> CommandProperties {
         "pfmerge" => (-2, wm, 1, -1, 1),
         "pfdebug" => (-3, WRITE, 0, 0, 0),
         "latency" => (-2, ars | ls, 0, 0, 0),
+        "module" => (-2, ADMIN | NOSCRIPT, 0, 0, 0),
         _ => (0, CommandFlags::empty(), 0, 0, 0),
     };
 
@@ -4723,6 +6911,20 @@ fn command_properties(command_name: &str) -> CommandProperties {
     }
 }
 
+/// The properties of a builtin command, or of one registered by a module.
+fn find_properties(db: &Database, command_name: &str) -> CommandProperties {
+    match db.modules.command(command_name) {
+        Some(command) => command.properties,
+        None => command_properties(command_name),
+    }
+}
+
+/// Whether `command_name` is a builtin command, which modules can't replace.
+fn builtin_command(command_name: &str) -> bool {
+    let properties = command_properties(command_name);
+    properties.arity != 0 || !properties.flags.is_empty()
+}
+
 #[test]
 fn command_has_flags_test() {
     assert!(command_properties("set")
@@ -4736,6 +6938,111 @@ fn command_has_flags_test() {
         .contains(CommandFlags::READONLY));
 }
 
+/// Returns the keys of a command, found with the key positions in its
+/// properties or with its own arguments when the keys are variable.
+fn command_keys(db: &Database, command_name: &str, parser: &ParsedCommand) -> Vec<Vec<u8>> {
+    let argc = parser.argv.len() as i64;
+    let (first, last, step) = match command_name {
+        // the number of keys is an argument, before them
+        "zunionstore" | "zinterstore" | "eval" | "evalsha" | "fcall" | "fcall_ro" => {
+            let numkeys = match parser.get_i64(2) {
+                Ok(numkeys) if numkeys > 0 => numkeys,
+                _ => 0,
+            };
+            let mut keys = vec![];
+            if command_name.starts_with('z') {
+                keys.extend(parser.get_vec(1));
+            }
+            for i in 3..(3 + numkeys).min(argc) {
+                keys.extend(parser.get_vec(i as usize));
+            }
+            return keys;
+        }
+        // a single key, or the ones after KEYS when it is empty
+        "migrate" => {
+            if parser.get_vec(3).map_or(false, |key| !key.is_empty()) {
+                return parser.get_vec(3).into_iter().collect();
+            }
+            let mut keys = vec![];
+            let mut found = false;
+            for i in 6..argc as usize {
+                if found {
+                    keys.extend(parser.get_vec(i));
+                } else {
+                    found = parser.get_str(i).map_or(false, |s| s.eq_ignore_ascii_case("keys"));
+                }
+            }
+            return keys;
+        }
+        // the first half of the arguments after STREAMS
+        "xread" => {
+            let streams = (1..argc as usize)
+                .find(|&i| parser.get_str(i).map_or(false, |s| s.eq_ignore_ascii_case("streams")));
+            return match streams {
+                Some(i) => {
+                    let rest = argc as usize - i - 1;
+                    (i + 1..i + 1 + rest / 2).filter_map(|j| parser.get_vec(j).ok()).collect()
+                }
+                None => vec![],
+            };
+        }
+        _ => {
+            let props = find_properties(db, command_name);
+            (props.first_key_index, props.last_key_index, props.key_step)
+        }
+    };
+    if first == 0 {
+        return vec![];
+    }
+    let last = if last < 0 { argc + last } else { last };
+    let mut keys = vec![];
+    let mut i = first;
+    while i <= last && i < argc {
+        keys.extend(parser.get_vec(i as usize));
+        i += step.max(1);
+    }
+    keys
+}
+
+/// In cluster mode, checks that this node serves the keys of the command,
+/// or of the whole transaction for EXEC.
+fn cluster_check(
+    parser: &ParsedCommand,
+    db: &Database,
+    client: &Client,
+    command_name: &str,
+    asking: bool,
+) -> Result<(), String> {
+    let flags = find_properties(db, command_name).flags;
+    let mut keys = vec![];
+    let mut readonly = client.readonly;
+    if command_name == "exec" {
+        for c in client.multi_commands.iter() {
+            let c = c.get_command();
+            let name = match c.get_str(0).map(|name| db.mapped_command(&name.to_ascii_lowercase())) {
+                Ok(Some(name)) => name,
+                _ => continue,
+            };
+            keys.extend(command_keys(db, &name, &c));
+            readonly = readonly && find_properties(db, &name).flags.contains(CommandFlags::READONLY);
+        }
+    } else {
+        keys = command_keys(db, command_name, parser);
+        readonly = readonly && flags.contains(CommandFlags::READONLY);
+    }
+    let asking = asking || flags.contains(CommandFlags::ASKING);
+    db.cluster_check(client.dbindex, &keys, asking, readonly)
+}
+
+/// Whether this is a master without enough replicas following it, which
+/// refuses writes so they are not lost on a failover.
+fn not_enough_replicas(db: &Database) -> bool {
+    db.master.is_none()
+        && db.config.min_slaves_to_write > 0
+        && db.config.min_slaves_max_lag > 0
+        && db.repl_good_replicas() < db.config.min_slaves_to_write
+}
+
 fn execute_command(
     parser: &mut ParsedCommand,
     db: &mut Database,
@@ -4753,9 +7060,9 @@ fn execute_command(
         None => return Ok(Response::Error("unknown command".to_owned())),
     };
 
-    *write = !command_properties(command_name)
+    *write = find_properties(db, command_name)
         .flags
-        .contains(CommandFlags::READONLY);
+        .contains(CommandFlags::WRITE);
 
     if db.config.requirepass.is_none() {
         client.auth = true;
@@ -4785,13 +7092,53 @@ fn execute_command(
         ));
     }
 
+    // a sentinel only understands the commands needed to monitor and
+    // discover the masters
+    if db.sentinel.is_some() {
+        match command_name {
+            "ping" | "sentinel" | "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe"
+            | "publish" | "info" | "role" | "client" | "shutdown" | "command" => {}
+            _ => {
+                return Ok(Response::Error(format!(
+                    "ERR unknown command '{}'",
+                    command_name
+                )))
+            }
+        }
+    }
+
+    // ASKING only applies to the command right after it
+    let asking = replace(&mut client.asking, false);
+    if db.cluster.is_some() && !client.is_master {
+        if let Err(e) = cluster_check(parser, db, client, command_name, asking) {
+            if command_name == "exec" {
+                discard(db, client);
+            }
+            return Ok(Response::Error(e));
+        }
+    }
+
+    // only the master can write into a read only replica
+    if *write && db.master.is_some() && db.config.slave_read_only && !client.is_master {
+        return Ok(Response::Error(
+            "READONLY You can't write against a read only replica.".to_owned(),
+        ));
+    }
+
     // commands that are not executed inside MULTI
     match command_name {
         "multi" => return Ok(multi(client)),
         "discard" => return Ok(discard(db, client)),
-        "exec" => return Ok(exec(db, client)),
+        "exec" => return Ok(exec(db, client, asking)),
         _ => {}
     }
+
+    // scripts are checked by script_call, as they call write commands
+    if *write && not_enough_replicas(db) {
+        return Ok(Response::Error(
+            "NOREPLICAS Not enough good replicas to write.".to_owned(),
+        ));
+    }
     if client.multi {
         if command_name == "watch" || command_name == "unwatch" {
             return Ok(Response::Error(
@@ -4799,6 +7146,7 @@ fn execute_command(
             ));
         }
         client.multi_commands.push(parser.to_owned());
+        *write = false;
         return Ok(Response::Status("QUEUED".to_owned()));
     }
     if command_name == "select" {
@@ -4807,11 +7155,14 @@ fn execute_command(
         if dbindex > db.config.databases as usize {
             return Ok(Response::Error("ERR invalid DB index".to_owned()));
         }
+        if db.cluster.is_some() && dbindex != 0 {
+            return Ok(Response::Error("ERR SELECT is not allowed in cluster mode".to_owned()));
+        }
         client.dbindex = dbindex;
         return Ok(Response::Status("OK".to_owned()));
     }
     let dbindex = client.dbindex;
-    Ok(match command_name {
+    let response = match command_name {
         "pexpireat" => pexpireat(parser, db, dbindex),
         "pexpire" => pexpire(parser, db, dbindex),
         "expireat" => expireat(parser, db, dbindex),
@@ -4823,7 +7174,7 @@ fn execute_command(
         "persist" => persist(parser, db, dbindex),
         "type" => dbtype(parser, db, dbindex),
         "role" => role(parser, db),
-        "slaveof" => slaveof(parser, db),
+        "slaveof" | "replicaof" => slaveof(parser, db),
         "object" => object(parser, db, dbindex),
         "bitop" => bitop(parser, db, dbindex),
         "bitcount" => bitcount(parser, db, dbindex),
@@ -4856,7 +7207,12 @@ fn execute_command(
         "pfdebug" => pfdebug(parser, db, dbindex),
         "exists" => exists(parser, db, dbindex),
         "ping" => ping(parser, client),
-        "client" => client_cmd(parser, client),
+        "client" => client_cmd(parser, &db.busy, client),
+        "asking" => asking_cmd(parser, db, client),
+        "readonly" => readonly(parser, db, client),
+        "readwrite" => readwrite(parser, db, client),
+        "cluster" => cluster_cmd(parser, db),
+        "sentinel" => sentinel_cmd(parser, db),
         "flushdb" => flushdb(parser, db, dbindex),
         "flushall" => flushall(parser, db, dbindex),
         "sort" => sort(parser, db, dbindex),
@@ -4926,6 +7282,13 @@ fn execute_command(
         "hincrby" => hincrby(parser, db, dbindex),
         "hincrbyfloat" => hincrbyfloat(parser, db, dbindex),
         "hscan" => hscan(parser, db, dbindex),
+        "xadd" => xadd(parser, db, dbindex),
+        "xlen" => xlen(parser, db, dbindex),
+        "xrange" => xrange(parser, db, dbindex),
+        "xrevrange" => xrevrange(parser, db, dbindex),
+        "xdel" => xdel(parser, db, dbindex),
+        "xtrim" => xtrim(parser, db, dbindex),
+        "xread" => xread(parser, db, dbindex)?,
         "getset" => getset(parser, db, dbindex),
         "mset" => mset(parser, db, dbindex),
         "msetnx" => msetnx(parser, db, dbindex),
@@ -4934,7 +7297,18 @@ fn execute_command(
         "rename" => rename(parser, db, dbindex),
         "renamenx" => renamenx(parser, db, dbindex),
         "dump" => dump(parser, db, dbindex),
-        "restore" => restore(parser, db, dbindex),
+        "restore" | "restore-asking" => restore(parser, db, dbindex),
+        "eval" => eval(parser, db, client, false),
+        "evalsha" => eval(parser, db, client, true),
+        "script" => script(parser, db),
+        "fcall" => fcall(parser, db, client, false),
+        "fcall_ro" => fcall(parser, db, client, true),
+        "function" => function_cmd(parser, db, client),
+        "migrate" => {
+            // MIGRATE propagates the deletion of the keys it moved
+            *write = false;
+            migrate(parser, db, dbindex)
+        }
         "keys" => keys(parser, db, dbindex),
         "scan" => scan(parser, db, dbindex),
         "sscan" => sscan(parser, db, dbindex),
@@ -4976,6 +7350,14 @@ fn execute_command(
             monitor(parser, db, client.rawsender.clone())
         }
         "info" => info(parser, db),
+        "sync" | "psync" => {
+            *log = false;
+            sync(parser, db, client)?
+        }
+        "replconf" => {
+            *log = false;
+            replconf(parser, db, client)?
+        }
         "save" => save(parser, db),
         "bgsave" => bgsave(parser, db),
         "bgrewriteaof" => bgrewriteaof(parser, db),
@@ -4983,10 +7365,18 @@ fn execute_command(
         "lastsave" => lastsave(parser, db),
         "config" => config(parser, db),
         "command" => command_cmd(parser, db),
-        "wait" => wait_cmd(parser, db),
+        "wait" => wait_cmd(parser, db, client)?,
         "slowlog" => slowlog(parser, db),
-        cmd => Response::Error(format!("ERR unknown command \"{}\"", cmd)),
-    })
+        "module" => module_cmd(parser, db),
+        cmd => match db.modules.command(cmd).cloned() {
+            Some(command) => module_command(parser, db, client.dbindex, cmd, &command),
+            None => Response::Error(format!("ERR unknown command \"{}\"", cmd)),
+        },
+    };
+    if *write && !response.is_error() {
+        db.dirty += 1;
+    }
+    Ok(response)
 }
 
 pub fn command(
@@ -4999,12 +7389,22 @@ pub fn command(
     
     // Track execution time for slowlog
     let start_time = ustime();
+    let offset = db.master_repl_offset;
     let r = execute_command(&mut parser, db, client, &mut log, &mut write);
     let duration_us = (ustime() - start_time) as u64;
     
     // TODO: only log if there's anyone listening
     if log {
+        // failed commands did not change anything
+        let write = write
+            && match &r {
+                Ok(response) => !response.is_error(),
+                Err(_) => false,
+            };
         db.log_command(client.dbindex, &parser, write);
+        if db.master_repl_offset != offset {
+            client.woff = db.master_repl_offset;
+        }
         
         // Add to slowlog if threshold exceeded
         let client_addr = "127.0.0.1:0".to_string(); // TODO: Get actual client address
@@ -5023,9 +7423,11 @@ mod test_command {
     use std::thread;
 
     use config::Config;
+    use database::aof::write_command;
     use database::{Database, Value};
     use logger::{Level, Logger};
-    use parser::{Argument, ParsedCommand};
+    use parser::{parse, Argument, ParsedCommand};
+    use rdbutil::crc64::crc64;
     use response::{Response, ResponseError};
     use util::mstime;
 
@@ -5049,7 +7451,7 @@ mod test_command {
 
     fn getstr(database: &Database, key: &[u8]) -> String {
         match database.get(0, &key.to_vec()).unwrap() {
-            &Value::String(value) => from_utf8(&*value.to_vec()).unwrap().to_owned(),
+            &Value::String(ref value) => from_utf8(&*value.to_vec()).unwrap().to_owned(),
             _ => panic!("Got non-string"),
         }
     }
@@ -6264,7 +8666,7 @@ mod test_command {
                 let mut array = arr
                     .iter()
                     .map(|x| match x {
-                        &Response::Data(d) => d.clone(),
+                        &Response::Data(ref d) => d.clone(),
                         _ => panic!("Expected data"),
                     })
                     .collect::<Vec<_>>();
@@ -6418,7 +8820,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                &Response::Data(ref el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -6473,7 +8875,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                &Response::Data(ref el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -6495,7 +8897,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                &Response::Data(ref el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -6558,7 +8960,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                &Response::Data(ref el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -6580,7 +8982,7 @@ mod test_command {
         let mut r = arr
             .iter()
             .map(|el| match el {
-                &Response::Data(el) => el.clone(),
+                &Response::Data(ref el) => el.clone(),
                 _ => panic!("Expected data"),
             })
             .collect::<Vec<_>>();
@@ -7785,8 +10187,331 @@ mod test_command {
             .is_ok());
         assert_eq!(
             command(parser!(b"dump key"), &mut db, &mut Client::mock()).unwrap(),
-            Response::Data(b"\x00\xc0\x01\x07\x00\xd9J2E\xd9\xcb\xc4\xe6".to_vec())
+            Response::Data(b"\x00\xc0\x01\x0a\x00&x\xe7\xb6\xd1\x01\x11\xfc".to_vec())
+        );
+    }
+
+    /// Runs a command whose arguments may contain spaces or binary data.
+    fn run(db: &mut Database, args: &[&[u8]]) -> Response {
+        let mut data = vec![];
+        write_command(&mut data, args).unwrap();
+        let (parser, _) = parse(&data).unwrap();
+        command(parser, db, &mut Client::mock()).unwrap()
+    }
+
+    #[test]
+    fn noreplicas_only_fences_writes() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        db.config.min_slaves_to_write = 1;
+        db.config.min_slaves_max_lag = 10;
+        let noreplicas = Response::Error("NOREPLICAS Not enough good replicas to write.".to_owned());
+        assert_eq!(run(&mut db, &[b"set", b"key", b"value"]), noreplicas);
+        assert_eq!(run(&mut db, &[b"get", b"key"]), Response::Nil);
+        assert_eq!(
+            run(&mut db, &[b"nosuchcommand"]),
+            Response::Error("ERR unknown command \"nosuchcommand\"".to_owned())
+        );
+        assert_eq!(run(&mut db, &[b"function", b"list"]), Response::Array(vec![]));
+
+        // scripts are refused only when they call a write command
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return redis.call('get', KEYS[1])", b"1", b"key"]),
+            Response::Nil
+        );
+        match run(&mut db, &[b"eval", b"return redis.call('set', KEYS[1], 'v')", b"1", b"key"]) {
+            Response::Error(e) => assert!(e.contains("NOREPLICAS"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return redis.pcall('set', KEYS[1], 'v')", b"1", b"key"]),
+            noreplicas
+        );
+        assert!(db.get(0, b"key").is_none());
+
+        // an operator can still demote the fenced master
+        assert_eq!(
+            run(&mut db, &[b"replicaof", b"127.0.0.1", b"6380"]),
+            Response::Status("OK".to_owned())
+        );
+        assert!(db.master.is_some());
+    }
+
+    /// Builds the module in tests/modules/counter, and returns the path of
+    /// its library.
+    fn build_counter_module() -> String {
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+        let target = format!("{}/target/modules", root);
+        let status = std::process::Command::new(option_env!("CARGO").unwrap_or("cargo"))
+            .args(&["build", "--quiet", "--manifest-path"])
+            .arg(format!("{}/tests/modules/counter/Cargo.toml", root))
+            .arg("--target-dir")
+            .arg(&target)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        format!(
+            "{}/debug/{}counter{}",
+            target,
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        )
+    }
+
+    #[test]
+    fn module_load_fixture() {
+        let path = build_counter_module();
+        let mut db = Database::mock();
+        assert_eq!(
+            run(&mut db, &[b"module", b"load", path.as_bytes(), b"a", b"1"]),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            run(&mut db, &[b"counter.args"]),
+            Response::Array(vec![Response::Data(b"a".to_vec()), Response::Data(b"1".to_vec())])
+        );
+        match run(&mut db, &[b"module", b"list"]) {
+            Response::Array(modules) => assert_eq!(modules.len(), 1),
+            r => panic!("Unexpected response {:?}", r),
+        }
+
+        assert_eq!(run(&mut db, &[b"counter.get", b"key"]), Response::Nil);
+        assert_eq!(run(&mut db, &[b"COUNTER.INCR", b"key"]), Response::Integer(1));
+        assert_eq!(run(&mut db, &[b"counter.incr", b"key"]), Response::Integer(2));
+        assert_eq!(run(&mut db, &[b"counter.get", b"key"]), Response::Integer(2));
+        assert_eq!(run(&mut db, &[b"type", b"key"]), Response::Data(b"counter-t".to_vec()));
+        assert_eq!(
+            run(&mut db, &[b"counter.incr"]),
+            Response::Error("ERR wrong number of arguments for 'counter.incr' command".to_owned())
+        );
+        run(&mut db, &[b"set", b"string", b"1"]);
+        match run(&mut db, &[b"counter.incr", b"string"]) {
+            Response::Error(e) => assert!(e.starts_with("WRONGTYPE"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+
+        // the values go through DUMP and the RDB file with the module code
+        let dump = match run(&mut db, &[b"dump", b"key"]) {
+            Response::Data(dump) => dump,
+            r => panic!("Unexpected response {:?}", r),
+        };
+        assert_eq!(run(&mut db, &[b"restore", b"copy", b"0", &dump]), Response::Status("OK".to_owned()));
+        assert_eq!(run(&mut db, &[b"counter.get", b"copy"]), Response::Integer(2));
+        let mut rdb = vec![];
+        db.rdb_dump(&mut rdb).unwrap();
+        let mut db2 = Database::mock();
+        assert!(db2.rdb_load(&mut &rdb[..]).is_err());
+        assert_eq!(
+            run(&mut db2, &[b"module", b"load", path.as_bytes()]),
+            Response::Status("OK".to_owned())
+        );
+        db2.rdb_load(&mut &rdb[..]).unwrap();
+        assert_eq!(run(&mut db2, &[b"counter.get", b"key"]), Response::Integer(2));
+        assert_eq!(run(&mut db2, &[b"counter.args"]), Response::Array(vec![]));
+
+        match run(&mut db, &[b"module", b"unload", b"counter"]) {
+            Response::Error(e) => assert!(e.contains("can't unload"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(
+            run(&mut db, &[b"module", b"load", path.as_bytes()]),
+            Response::Error("ERR Error loading the extension. Please check the server logs.".to_owned())
+        );
+    }
+
+    #[test]
+    fn restore_huge_length() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        // a set declaring 2^32 - 1 members, with none behind
+        let mut payload = b"\x02\x80\xff\xff\xff\xff\x07\x00".to_vec();
+        let crc = crc64(0, &payload);
+        payload.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            run(&mut db, &[b"restore", b"key", b"0", &payload]),
+            Response::Error("ERR Bad data format".to_owned())
+        );
+        assert!(db.get(0, b"key").is_none());
+    }
+
+    #[test]
+    fn save_error() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        db.config.dbfilename = "/nonexistent/rsedis/dump.rdb".to_owned();
+        match run(&mut db, &[b"save"]) {
+            Response::Error(e) => {
+                assert!(e.starts_with("ERR Error saving DB on disk: "), "{}", e);
+                assert!(e.len() > "ERR Error saving DB on disk: ".len(), "{}", e);
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn restore_ttl_overflow() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let mut value = Value::Nil;
+        value.set(b"value".to_vec()).unwrap();
+        let mut payload = vec![];
+        value.dump(&mut payload).unwrap();
+        let max = i64::MAX.to_string();
+        let max = max.as_bytes();
+        assert_eq!(
+            run(&mut db, &[b"restore", b"key", max, &payload]),
+            Response::Error("ERR invalid expire time in 'restore' command".to_owned())
+        );
+        assert_eq!(
+            run(&mut db, &[b"restore", b"key", b"0", &payload, b"IDLETIME", max]),
+            Response::Error("ERR Invalid IDLETIME value, out of range".to_owned())
+        );
+        assert!(db.get(0, b"key").is_none());
+
+        // as an absolute time it is just far in the future
+        assert_eq!(
+            run(&mut db, &[b"restore", b"key", max, &payload, b"ABSTTL"]),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(db.get_msexpiration(0, b"key"), Some(&i64::MAX));
+    }
+
+    /// Listens on a loopback port as the target of a MIGRATE. Once a client
+    /// connects, it answers each command it reads with the next of
+    /// `replies`, and returns the arguments of the commands.
+    fn migrate_target(replies: Vec<&'static str>) -> (u16, thread::JoinHandle<Vec<Vec<Vec<u8>>>>) {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let th = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut commands = vec![];
+            let mut buf = vec![];
+            for reply in replies {
+                loop {
+                    if let Ok((command, used)) = parse(&buf) {
+                        commands.push((0..command.argv.len()).map(|i| command.get_vec(i).unwrap()).collect());
+                        buf.drain(..used);
+                        break;
+                    }
+                    let mut chunk = [0; 1024];
+                    let n = stream.read(&mut chunk).unwrap();
+                    if n == 0 {
+                        return commands;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                // the client may be gone after an error reply
+                let _ = stream.write_all(reply.as_bytes());
+            }
+            commands
+        });
+        (port, th)
+    }
+
+    #[test]
+    fn migrate_command() {
+        use database::replication::Backlog;
+
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        db.repl_backlog = Some(Backlog::new(1 << 16, 0));
+        for key in &[b"a", b"b", b"c"] {
+            assert_eq!(run(&mut db, &[b"set", *key, b"value"]), Response::Status("OK".to_owned()));
+        }
+        let offset = db.repl_backlog.as_ref().unwrap().histlen() as u64;
+        let mut dump = vec![];
+        db.get(0, b"a").unwrap().dump(&mut dump).unwrap();
+
+        // no key to move, no connection
+        assert_eq!(
+            run(&mut db, &[b"migrate", b"127.0.0.1", b"1", b"missing", b"0", b"100"]),
+            Response::Status("NOKEY".to_owned())
+        );
+
+        // COPY keeps the key here
+        let (port, th) = migrate_target(vec!["+OK\r\n", "+OK\r\n"]);
+        let port = port.to_string();
+        assert_eq!(
+            run(&mut db, &[b"migrate", b"127.0.0.1", port.as_bytes(), b"a", b"3", b"1000", b"COPY"]),
+            Response::Status("OK".to_owned())
+        );
+        assert_eq!(
+            th.join().unwrap(),
+            vec![
+                vec![b"SELECT".to_vec(), b"3".to_vec()],
+                vec![b"RESTORE".to_vec(), b"a".to_vec(), b"0".to_vec(), dump.clone()],
+            ]
+        );
+        assert!(db.get(0, b"a").is_some());
+
+        // AUTH, REPLACE and KEYS, deleting the keys moved
+        let (port, th) = migrate_target(vec!["+OK\r\n", "+OK\r\n", "+OK\r\n", "+OK\r\n"]);
+        let port = port.to_string();
+        assert_eq!(
+            run(
+                &mut db,
+                &[
+                    b"migrate", b"127.0.0.1", port.as_bytes(), b"", b"0", b"1000", b"REPLACE", b"AUTH", b"secret",
+                    b"KEYS", b"a", b"missing", b"b",
+                ]
+            ),
+            Response::Status("OK".to_owned())
+        );
+        let commands = th.join().unwrap();
+        assert_eq!(commands.len(), 4);
+        assert_eq!(commands[0], vec![b"AUTH".to_vec(), b"secret".to_vec()]);
+        assert_eq!(commands[1], vec![b"SELECT".to_vec(), b"0".to_vec()]);
+        assert_eq!(
+            commands[2],
+            vec![b"RESTORE".to_vec(), b"a".to_vec(), b"0".to_vec(), dump.clone(), b"REPLACE".to_vec()]
+        );
+        assert_eq!(commands[3][1], b"b".to_vec());
+        assert!(db.get(0, b"a").is_none());
+        assert!(db.get(0, b"b").is_none());
+
+        // the replicas only see the keys deleted
+        let mut expected = vec![];
+        write_command(&mut expected, &[b"DEL", b"a", b"b"]).unwrap();
+        assert_eq!(db.repl_backlog.as_ref().unwrap().since(offset).unwrap(), expected);
+
+        // an error of the target is forwarded, and the key stays
+        let (port, th) = migrate_target(vec!["+OK\r\n", "-BUSYKEY Target key name already exists.\r\n"]);
+        let port = port.to_string();
+        assert_eq!(
+            run(&mut db, &[b"migrate", b"127.0.0.1", port.as_bytes(), b"c", b"0", b"1000"]),
+            Response::Error("ERR Target instance replied with error: BUSYKEY Target key name already exists.".to_owned())
+        );
+        th.join().unwrap();
+        assert!(db.get(0, b"c").is_some());
+
+        let (port, th) = migrate_target(vec!["-ERR invalid password\r\n", "+OK\r\n", "+OK\r\n"]);
+        let port = port.to_string();
+        assert_eq!(
+            run(&mut db, &[b"migrate", b"127.0.0.1", port.as_bytes(), b"c", b"0", b"1000", b"AUTH", b"wrong"]),
+            Response::Error("ERR Target instance replied with error: ERR invalid password".to_owned())
+        );
+        th.join().unwrap();
+        assert!(db.get(0, b"c").is_some());
+        assert_eq!(db.repl_backlog.as_ref().unwrap().since(offset).unwrap(), expected);
+    }
+
+    #[test]
+    fn migrate_timeout() {
+        use std::net::TcpListener;
+        use std::time::Instant;
+
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        assert_eq!(run(&mut db, &[b"set", b"key", b"value"]), Response::Status("OK".to_owned()));
+
+        // the target accepts the connection but never replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let start = Instant::now();
+        assert_eq!(
+            run(&mut db, &[b"migrate", b"127.0.0.1", port.as_bytes(), b"key", b"0", b"100"]),
+            Response::Error("IOERR timeout reading from target instance".to_owned())
         );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(db.get(0, b"key").is_some());
+        drop(listener);
     }
 
     #[test]
@@ -8005,6 +10730,192 @@ mod test_command {
         );
     }
 
+    #[test]
+    fn eval_converts_replies() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        // from Lua to RESP: numbers are truncated, false is nil and the
+        // tables with an ok or err field are status and error replies
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return {1, 'a', 3.7, true, false, {ok='OK'}, {err='ERR e'}, {2, {3}}}", b"0"]),
+            Response::Array(vec![
+                Response::Integer(1),
+                Response::Data(b"a".to_vec()),
+                Response::Integer(3),
+                Response::Integer(1),
+                Response::Nil,
+                Response::Status("OK".to_owned()),
+                Response::Error("ERR e".to_owned()),
+                Response::Array(vec![Response::Integer(2), Response::Array(vec![Response::Integer(3)])]),
+            ])
+        );
+        // arrays stop at the first nil
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return {1, nil, 2}", b"0"]),
+            Response::Array(vec![Response::Integer(1)])
+        );
+        assert_eq!(run(&mut db, &[b"eval", b"return nil", b"0"]), Response::Nil);
+
+        // from RESP to Lua
+        assert_eq!(run(&mut db, &[b"rpush", b"list", b"a", b"b"]), Response::Integer(2));
+        assert_eq!(
+            run(
+                &mut db,
+                &[
+                    b"eval",
+                    b"return {redis.call('set', KEYS[1], ARGV[1]).ok, redis.call('get', KEYS[1]), \
+                      type(redis.call('incr', 'counter')), tostring(redis.call('get', 'missing')), \
+                      redis.pcall('incr', KEYS[1]).err, #redis.call('lrange', 'list', 0, -1)}",
+                    b"1",
+                    b"key",
+                    b"value",
+                ]
+            ),
+            Response::Array(vec![
+                Response::Data(b"OK".to_vec()),
+                Response::Data(b"value".to_vec()),
+                Response::Data(b"number".to_vec()),
+                Response::Data(b"false".to_vec()),
+                Response::Data(b"ERR value is not a valid integer".to_vec()),
+                Response::Integer(2),
+            ])
+        );
+
+        // redis.call raises the errors, redis.pcall returns them
+        match run(&mut db, &[b"eval", b"return redis.call('incr', KEYS[1])", b"1", b"key"]) {
+            Response::Error(e) => assert!(e.contains("ERR value is not a valid integer"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        match run(&mut db, &[b"eval", b"return redis.call()", b"0"]) {
+            Response::Error(e) => assert!(e.contains("Please specify at least one argument"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return redis.pcall()", b"0"]),
+            Response::Error("Please specify at least one argument for redis.call()".to_owned())
+        );
+        assert_eq!(
+            run(&mut db, &[b"eval", b"local r = redis.pcall({}) return r.err", b"0"]),
+            Response::Data(b"Lua redis() command arguments must be strings or integers".to_vec())
+        );
+    }
+
+    #[test]
+    fn evalsha_script_cache() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        let body: &[u8] = b"return ARGV[1]";
+        let sha = b"098e0f0d1448c0a81dafe820f66d460eb09263da";
+        let noscript = Response::Error("NOSCRIPT No matching script. Please use EVAL.".to_owned());
+        assert_eq!(run(&mut db, &[b"evalsha", sha, b"0", b"a"]), noscript);
+        assert_eq!(run(&mut db, &[b"script", b"load", body]), Response::Data(sha.to_vec()));
+        assert_eq!(
+            run(&mut db, &[b"script", b"exists", sha, b"0000000000000000000000000000000000000000"]),
+            Response::Array(vec![Response::Integer(1), Response::Integer(0)])
+        );
+        assert_eq!(run(&mut db, &[b"evalsha", sha, b"0", b"a"]), Response::Data(b"a".to_vec()));
+        // the digest is not case sensitive
+        assert_eq!(
+            run(&mut db, &[b"evalsha", &sha.to_ascii_uppercase(), b"0", b"b"]),
+            Response::Data(b"b".to_vec())
+        );
+
+        assert_eq!(run(&mut db, &[b"script", b"flush"]), Response::Status("OK".to_owned()));
+        assert_eq!(
+            run(&mut db, &[b"script", b"exists", sha]),
+            Response::Array(vec![Response::Integer(0)])
+        );
+        assert_eq!(run(&mut db, &[b"evalsha", sha, b"0", b"a"]), noscript);
+
+        // EVAL caches the scripts too, unless they do not compile
+        assert_eq!(run(&mut db, &[b"eval", body, b"0", b"c"]), Response::Data(b"c".to_vec()));
+        assert_eq!(run(&mut db, &[b"evalsha", sha, b"0", b"d"]), Response::Data(b"d".to_vec()));
+        match run(&mut db, &[b"script", b"load", b"return ("]) {
+            Response::Error(e) => assert!(e.starts_with("ERR Error compiling script"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(run(&mut db, &[b"script", b"flush"]), Response::Status("OK".to_owned()));
+        match run(&mut db, &[b"eval", b"return (", b"0"]) {
+            Response::Error(e) => assert!(e.starts_with("ERR Error compiling script"), "{}", e),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert!(db.scripts.is_empty());
+    }
+
+    #[test]
+    fn eval_rejects_noscript_commands() {
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        for name in &["multi", "eval", "script", "subscribe", "blpop"] {
+            let body = format!("return redis.pcall('{}', 'a', '0')", name);
+            assert_eq!(
+                run(&mut db, &[b"eval", body.as_bytes(), b"0"]),
+                Response::Error("ERR This Redis command is not allowed from scripts".to_owned())
+            );
+            let body = format!("return redis.call('{}', 'a', '0')", name);
+            match run(&mut db, &[b"eval", body.as_bytes(), b"0"]) {
+                Response::Error(e) => assert!(e.contains("This Redis command is not allowed from scripts"), "{}", e),
+                r => panic!("Unexpected response {:?}", r),
+            }
+        }
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return redis.pcall('nosuchcommand')", b"0"]),
+            Response::Error("ERR Unknown Redis command called from Lua script".to_owned())
+        );
+    }
+
+    #[test]
+    fn eval_propagates_writes() {
+        use database::replication::Backlog;
+        use persistence::aof::Aof;
+        use std::env::temp_dir;
+        use std::fs::{read, remove_file};
+
+        let mut path = temp_dir();
+        path.push("rsedis-command-test-eval-propagates.aof");
+        let _ = remove_file(&path);
+
+        let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
+        db.aof = Some(Aof::new(&path).unwrap());
+        db.repl_backlog = Some(Backlog::new(1 << 16, 0));
+
+        // a script that only reads propagates nothing
+        assert_eq!(
+            run(&mut db, &[b"eval", b"return redis.call('get', KEYS[1])", b"1", b"key"]),
+            Response::Nil
+        );
+        assert_eq!(db.repl_backlog.as_ref().unwrap().histlen(), 0);
+
+        // the writes are propagated instead of the script, atomically
+        assert_eq!(
+            run(
+                &mut db,
+                &[
+                    b"eval",
+                    b"redis.call('get', KEYS[1]) redis.call('set', KEYS[1], ARGV[1]) return redis.call('incr', KEYS[2])",
+                    b"2",
+                    b"key",
+                    b"counter",
+                    b"value",
+                ]
+            ),
+            Response::Integer(1)
+        );
+        let mut expected = vec![];
+        write_command(&mut expected, &[b"MULTI"]).unwrap();
+        write_command(&mut expected, &[b"set", b"key", b"value"]).unwrap();
+        write_command(&mut expected, &[b"incr", b"counter"]).unwrap();
+        write_command(&mut expected, &[b"EXEC"]).unwrap();
+
+        let stream = db.repl_backlog.as_ref().unwrap().since(0).unwrap();
+        let mut select = vec![];
+        write_command(&mut select, &[b"SELECT", b"0"]).unwrap();
+        assert_eq!(stream, [&select[..], &expected[..]].concat());
+
+        db.aof = None;
+        let aof = read(&path).unwrap();
+        assert!(aof.ends_with(&expected), "{:?}", String::from_utf8_lossy(&aof));
+        assert!(!String::from_utf8_lossy(&aof).to_ascii_lowercase().contains("eval"));
+        let _ = remove_file(&path);
+    }
+
     #[test]
     fn monitor() {
         let mut db = Database::new(Config::new(Logger::new(Level::Warning)));
@@ -1,4 +1,5 @@
 pub mod aof;
 pub mod command;
+pub mod scripting;
 
 pub use command::*;
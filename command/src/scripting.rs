@@ -0,0 +1,493 @@
+//! Lua scripting for EVAL and EVALSHA, and the engine of the FUNCTION
+//! libraries. Every script, and every call to a function, runs in a fresh
+//! Lua 5.1 interpreter, with the commands it calls dispatched by the caller
+//! while it holds the database.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use database::busy::Busy;
+use database::function::{valid_name, FunctionInfo, FLAGS};
+use logger::{Level, Logger};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+use response::Response;
+use sha1_smol::Sha1;
+
+/// Defines the helpers of the `redis` table around the command dispatcher,
+/// and forbids scripts to use global variables.
+const PRELUDE: &str = r#"
+local command = redis.command
+local killed = redis.killed
+redis.command = nil
+redis.killed = nil
+
+-- once SCRIPT KILL is called, the errors raised to stop the script can no
+-- longer be caught
+local function rethrow(ok, ...)
+    if not ok and killed() then error((...), 0) end
+    return ok, ...
+end
+local protected, xprotected = pcall, xpcall
+function pcall(...) return rethrow(protected(...)) end
+function xpcall(...) return rethrow(xprotected(...)) end
+
+function redis.pcall(...)
+    local reply, err = command(...)
+    if err then return redis.error_reply(err) end
+    return reply
+end
+
+function redis.call(...)
+    local reply, err = command(...)
+    if err then error(err, 2) end
+    if type(reply) == 'table' and reply.err then error(reply.err, 2) end
+    return reply
+end
+
+function redis.error_reply(err)
+    return {err = err}
+end
+
+function redis.status_reply(ok)
+    return {ok = ok}
+end
+
+redis.LOG_DEBUG = 0
+redis.LOG_VERBOSE = 1
+redis.LOG_NOTICE = 2
+redis.LOG_WARNING = 3
+
+-- the same script always generates the same random numbers
+math.randomseed(0)
+dofile = nil
+loadfile = nil
+
+setmetatable(_G, {
+    __index = function(_, name)
+        error("Script attempted to access unexisting global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __newindex = function(_, name)
+        error("Script attempted to create global variable '" .. tostring(name) .. "'", 2)
+    end,
+})
+"#;
+
+/// The SHA1 digest of `data`, in hexadecimal.
+pub fn sha1hex(data: &[u8]) -> String {
+    Sha1::from(data).digest().to_string()
+}
+
+fn new_lua() -> mlua::Result<Lua> {
+    Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::new())
+}
+
+/// Compiles `body` without running it, to report syntax errors.
+pub fn compile(body: &[u8]) -> Result<(), String> {
+    let lua = new_lua().map_err(|e| format!("ERR Error creating the script environment: {}", e))?;
+    load(&lua, body, "@user_script", "ERR Error compiling script (new function)").map(|_| ())
+}
+
+/// Compiles `body` as the chunk `name`. The errors start with `prefix`.
+fn load<'lua>(lua: &'lua Lua, body: &[u8], name: &str, prefix: &str) -> Result<Function<'lua>, String> {
+    lua.load(body).set_name(name).into_function().map_err(|e| match e {
+        mlua::Error::SyntaxError { message, .. } => format!("{}: {}", prefix, message),
+        e => format!("{}: {}", prefix, e),
+    })
+}
+
+/// Converts a reply into the Lua value a script sees: status and error
+/// replies become tables with an `ok` or `err` field, and nil is `false`.
+fn to_lua<'lua>(lua: &'lua Lua, response: Response) -> mlua::Result<Value<'lua>> {
+    Ok(match response {
+        Response::Nil => Value::Boolean(false),
+        Response::Integer(i) => Value::Integer(i),
+        Response::Data(data) | Response::Raw(data) => Value::String(lua.create_string(&data)?),
+        Response::Status(status) => {
+            let table = lua.create_table()?;
+            table.raw_set("ok", status)?;
+            Value::Table(table)
+        }
+        Response::Error(err) => {
+            let table = lua.create_table()?;
+            table.raw_set("err", err)?;
+            Value::Table(table)
+        }
+        Response::Array(items) => {
+            let table = lua.create_table()?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+/// Converts the value returned by a script into a reply. Numbers are
+/// truncated to integers, and arrays stop at the first nil.
+fn from_lua(value: Value) -> Response {
+    match value {
+        Value::Boolean(true) => Response::Integer(1),
+        Value::Integer(i) => Response::Integer(i),
+        Value::Number(n) => Response::Integer(n as i64),
+        Value::String(s) => Response::Data(s.as_bytes().to_vec()),
+        Value::Table(table) => {
+            if let Ok(Value::String(err)) = table.raw_get("err") {
+                return Response::Error(err.to_string_lossy().into_owned());
+            }
+            if let Ok(Value::String(ok)) = table.raw_get("ok") {
+                return Response::Status(ok.to_string_lossy().into_owned());
+            }
+            let mut items = vec![];
+            for i in 1.. {
+                match table.raw_get(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(value) => items.push(from_lua(value)),
+                }
+            }
+            Response::Array(items)
+        }
+        _ => Response::Nil,
+    }
+}
+
+/// The arguments of `redis.call`, or the error to raise.
+fn command_args(args: MultiValue) -> Result<Vec<Vec<u8>>, &'static str> {
+    if args.is_empty() {
+        return Err("Please specify at least one argument for redis.call()");
+    }
+    args.into_iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(s.as_bytes().to_vec()),
+            Value::Integer(i) => Ok(i.to_string().into_bytes()),
+            Value::Number(n) => Ok(n.to_string().into_bytes()),
+            _ => Err("Lua redis() command arguments must be strings or integers"),
+        })
+        .collect()
+}
+
+fn array<'lua>(lua: &'lua Lua, items: &[Vec<u8>]) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    for (i, item) in items.iter().enumerate() {
+        table.raw_set(i + 1, lua.create_string(item)?)?;
+    }
+    Ok(table)
+}
+
+/// The message of an error raised from Rust, on a single line.
+fn error_message(err: &mlua::Error) -> String {
+    match err {
+        mlua::Error::RuntimeError(message) => message.lines().next().unwrap_or_default().to_owned(),
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        err => err.to_string().lines().next().unwrap_or_default().to_owned(),
+    }
+}
+
+/// Name of the table, in the registry of the interpreter, with the callbacks
+/// of the functions registered by a library
+const CALLBACKS: &str = "callbacks";
+
+/// The functions registered by a library while it loads.
+struct Registrar {
+    /// Only a library being loaded can register functions, and it cannot
+    /// call commands meanwhile
+    loading: Cell<bool>,
+    functions: RefCell<Vec<FunctionInfo>>,
+}
+
+impl Registrar {
+    fn new() -> Rc<Registrar> {
+        Rc::new(Registrar {
+            loading: Cell::new(false),
+            functions: RefCell::new(vec![]),
+        })
+    }
+}
+
+fn raise<T>(message: &str) -> mlua::Result<T> {
+    Err(mlua::Error::RuntimeError(message.to_owned()))
+}
+
+/// `redis.register_function(name, callback)`, or with a table with the
+/// `function_name`, `callback`, `flags` and `description` fields.
+fn register_function<'lua>(lua: &'lua Lua, registrar: Rc<Registrar>) -> mlua::Result<Function<'lua>> {
+    lua.create_function(move |lua, args: MultiValue| {
+        if !registrar.loading.get() {
+            return raise("ERR redis.register_function can only be called on FUNCTION LOAD command");
+        }
+        let mut args = args.into_iter();
+        let (name, callback, flags, description) = match (args.next(), args.next(), args.next()) {
+            (Some(Value::String(name)), Some(Value::Function(callback)), None) => {
+                (name.to_string_lossy().into_owned(), callback, vec![], None)
+            }
+            (Some(Value::Table(table)), None, None) => {
+                let (mut name, mut callback, mut flags, mut description) = (None, None, vec![], None);
+                for pair in table.pairs::<String, Value>() {
+                    match pair? {
+                        (key, Value::String(s)) if key == "function_name" => {
+                            name = Some(s.to_string_lossy().into_owned())
+                        }
+                        (key, Value::String(s)) if key == "description" => {
+                            description = Some(s.to_string_lossy().into_owned())
+                        }
+                        (key, Value::Function(f)) if key == "callback" => callback = Some(f),
+                        (key, Value::Table(t)) if key == "flags" => {
+                            for flag in t.sequence_values::<String>() {
+                                flags.push(flag?);
+                            }
+                        }
+                        (key, _) if key == "function_name" || key == "description" => {
+                            return raise(&format!("ERR {} argument given to redis.register_function must be a string", key));
+                        }
+                        (key, _) if key == "callback" => {
+                            return raise("ERR callback argument given to redis.register_function must be a function");
+                        }
+                        (key, _) if key == "flags" => {
+                            return raise("ERR flags argument to redis.register_function must be a table representing function flags");
+                        }
+                        _ => return raise("ERR unknown argument given to redis.register_function"),
+                    }
+                }
+                match (name, callback) {
+                    (Some(name), Some(callback)) => (name, callback, flags, description),
+                    (None, _) => return raise("ERR redis.register_function must get a function name argument"),
+                    (_, None) => return raise("ERR redis.register_function must get a callback argument"),
+                }
+            }
+            _ => return raise("ERR wrong arguments given to redis.register_function"),
+        };
+        if !valid_name(&name) {
+            return raise(
+                "ERR Function names can only contain letters, numbers, or underscores(_) \
+                 and must be at least one character long",
+            );
+        }
+        if flags.iter().any(|flag| !FLAGS.contains(&&**flag)) {
+            return raise("ERR unknown flag given");
+        }
+        let mut functions = registrar.functions.borrow_mut();
+        if functions.iter().any(|function| function.name == name) {
+            return raise("ERR Function already exists in the library");
+        }
+        let callbacks: Table = lua.named_registry_value(CALLBACKS)?;
+        callbacks.raw_set(&*name, callback)?;
+        functions.push(FunctionInfo {
+            name,
+            description,
+            flags,
+        });
+        Ok(())
+    })
+}
+
+/// What runs once the interpreter is set up.
+enum Entry<'a> {
+    /// The chunk of EVAL, whose digest is `sha`, with the `KEYS` and
+    /// `ARGV` tables
+    Script {
+        body: &'a [u8],
+        sha: &'a str,
+        keys: &'a [Vec<u8>],
+        argv: &'a [Vec<u8>],
+    },
+    /// The body of a library, to register its functions
+    Library { body: &'a [u8] },
+    /// The function `name` of a library, called with the keys and the
+    /// arguments
+    Function {
+        body: &'a [u8],
+        name: &'a str,
+        keys: &'a [Vec<u8>],
+        args: &'a [Vec<u8>],
+    },
+}
+
+/// The reply for what a script or a function returned, or for the error
+/// it raised. `label` names what was running in the error.
+fn reply(label: &str, ok: bool, value: Value) -> Response {
+    if ok {
+        return from_lua(value);
+    }
+    let message = match value {
+        Value::Table(table) => match table.raw_get("err") {
+            Ok(Value::String(err)) => return Response::Error(err.to_string_lossy().into_owned()),
+            _ => "unknown error".to_owned(),
+        },
+        Value::String(err) => err.to_string_lossy().into_owned(),
+        Value::Error(err) => error_message(&err),
+        _ => "unknown error".to_owned(),
+    };
+    Response::Error(format!("ERR Error running {}: {}", label, message))
+}
+
+/// Runs `entry` in a fresh interpreter. Every command called goes through
+/// `call`, and the script stops once `busy` says it was killed.
+/// Fails when the code does not compile, or when a library cannot register
+/// its functions.
+fn execute(
+    entry: Entry,
+    registrar: &Rc<Registrar>,
+    logger: &Logger,
+    busy: Option<Arc<Busy>>,
+    call: &mut dyn FnMut(Vec<Vec<u8>>) -> Response,
+) -> Result<Response, String> {
+    let lua = new_lua().map_err(|e| format!("ERR Error creating the script environment: {}", e))?;
+    let function = match entry {
+        Entry::Script { body, .. } => load(&lua, body, "@user_script", "ERR Error compiling script (new function)")?,
+        Entry::Library { body } | Entry::Function { body, .. } => {
+            load(&lua, body, "@user_function", "ERR Error compiling function")?
+        }
+    };
+    if let Some(busy) = &busy {
+        // the hook keeps failing, so the script cannot catch the error with pcall
+        let hook = busy.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(100_000), move |_, _| {
+            if hook.script_killed() {
+                raise("Script killed by user with SCRIPT KILL...")
+            } else {
+                Ok(())
+            }
+        });
+    }
+    let r = lua.scope(|scope| {
+        let globals = lua.globals();
+        let redis = lua.create_table()?;
+        redis.set(
+            "command",
+            scope.create_function_mut(|lua, args: MultiValue| {
+                if registrar.loading.get() {
+                    return Ok((Value::Nil, Some("redis.call is not allowed while loading a library")));
+                }
+                match command_args(args) {
+                    Ok(args) => Ok((to_lua(lua, call(args))?, None)),
+                    Err(err) => Ok((Value::Nil, Some(err))),
+                }
+            })?,
+        )?;
+        redis.set(
+            "log",
+            lua.create_function({
+                let logger = logger.clone();
+                move |_, (level, message): (i64, MultiValue)| {
+                    let level = match level {
+                        0 => Level::Debug,
+                        1 => Level::Verbose,
+                        2 => Level::Notice,
+                        3 => Level::Warning,
+                        _ => return raise("Invalid debug level."),
+                    };
+                    let message = message
+                        .into_iter()
+                        .filter_map(|part| match part {
+                            Value::String(s) => Some(s.to_string_lossy().into_owned()),
+                            Value::Integer(i) => Some(i.to_string()),
+                            Value::Number(n) => Some(n.to_string()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    logger.log(level, message, None);
+                    Ok(())
+                }
+            })?,
+        )?;
+        redis.set(
+            "killed",
+            lua.create_function(move |_, ()| Ok(busy.as_ref().map_or(false, |busy| busy.script_killed())))?,
+        )?;
+        redis.set(
+            "sha1hex",
+            lua.create_function(|_, data: mlua::String| Ok(sha1hex(data.as_bytes())))?,
+        )?;
+        redis.set("register_function", register_function(&lua, registrar.clone())?)?;
+        lua.set_named_registry_value(CALLBACKS, lua.create_table()?)?;
+        globals.set("redis", redis)?;
+        if let Entry::Script { keys, argv, .. } = entry {
+            globals.set("KEYS", array(&lua, keys)?)?;
+            globals.set("ARGV", array(&lua, argv)?)?;
+        }
+        let pcall: Function = globals.get("pcall")?;
+        lua.load(PRELUDE).set_name("=prelude").exec()?;
+
+        let (name, keys, args) = match entry {
+            Entry::Script { sha, .. } => {
+                let (ok, value): (bool, Value) = pcall.call(function)?;
+                return Ok(Ok(reply(&format!("script (call to f_{})", sha), ok, value)));
+            }
+            Entry::Library { .. } => (None, &[][..], &[][..]),
+            Entry::Function { name, keys, args, .. } => (Some(name), keys, args),
+        };
+
+        registrar.loading.set(true);
+        let (ok, value): (bool, Value) = pcall.call(function)?;
+        registrar.loading.set(false);
+        if !ok {
+            let message = match value {
+                Value::String(err) => err.to_string_lossy().into_owned(),
+                Value::Error(err) => error_message(&err),
+                _ => "unknown error".to_owned(),
+            };
+            return Ok(Err(if message.starts_with("ERR ") {
+                message
+            } else {
+                format!("ERR Error registering functions: {}", message)
+            }));
+        }
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(Ok(Response::Nil)),
+        };
+        let callbacks: Table = lua.named_registry_value(CALLBACKS)?;
+        let callback = match callbacks.raw_get(name)? {
+            Value::Function(callback) => callback,
+            _ => return Ok(Err("ERR Function not found".to_owned())),
+        };
+        let (ok, value): (bool, Value) = pcall.call((callback, array(&lua, keys)?, array(&lua, args)?))?;
+        Ok(Ok(reply(&format!("function (call to {})", name), ok, value)))
+    });
+    match r {
+        Ok(r) => r,
+        Err(e) => Ok(Response::Error(format!("ERR Error running script: {}", error_message(&e)))),
+    }
+}
+
+/// Runs the script `body`, whose digest is `sha`, with the `KEYS` and
+/// `ARGV` tables. Every command the script calls goes through `call`, and
+/// the script stops once `busy` says it was killed.
+/// Fails when the script does not compile.
+pub fn run(
+    body: &[u8],
+    sha: &str,
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+    logger: &Logger,
+    busy: Arc<Busy>,
+    call: &mut dyn FnMut(Vec<Vec<u8>>) -> Response,
+) -> Result<Response, String> {
+    let registrar = Registrar::new();
+    execute(Entry::Script { body, sha, keys, argv }, &registrar, logger, Some(busy), call)
+}
+
+/// Runs the body of a library, returning the functions it registers. It is
+/// the engine of the FUNCTION libraries.
+pub fn register(body: &[u8], logger: &Logger) -> Result<Vec<FunctionInfo>, String> {
+    let registrar = Registrar::new();
+    execute(Entry::Library { body }, &registrar, logger, None, &mut |_| Response::Nil)?;
+    let functions = registrar.functions.replace(vec![]);
+    Ok(functions)
+}
+
+/// Calls the function `name` of the library whose body is `body`, with the
+/// keys and the arguments as its two parameters. Like `run`, the commands
+/// go through `call` and `busy` can stop it.
+pub fn call_function(
+    body: &[u8],
+    name: &str,
+    keys: &[Vec<u8>],
+    args: &[Vec<u8>],
+    logger: &Logger,
+    busy: Arc<Busy>,
+    call: &mut dyn FnMut(Vec<Vec<u8>>) -> Response,
+) -> Result<Response, String> {
+    let registrar = Registrar::new();
+    execute(Entry::Function { body, name, keys, args }, &registrar, logger, Some(busy), call)
+}
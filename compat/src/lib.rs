@@ -16,6 +16,16 @@ pub use win::*;
 
 #[cfg(not(any(unix, windows)))]
 pub mod other;
+#[cfg(not(any(unix, windows)))]
+pub use other::*;
+
+/// The process a `fork` call returns in.
+#[derive(Debug, PartialEq)]
+pub enum Fork {
+    /// The original process, with the id of the new child.
+    Parent(u32),
+    Child,
+}
 
 #[test]
 fn getpid_test() {
@@ -26,3 +36,55 @@ fn getpid_test() {
 fn getos_test() {
     getos();
 }
+
+#[cfg(unix)]
+#[test]
+fn fork_test() {
+    match fork().unwrap() {
+        Fork::Child => exit_child(3),
+        Fork::Parent(pid) => loop {
+            if let Some(success) = waitpid_nohang(pid) {
+                assert!(!success);
+                break;
+            }
+        },
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn waitpid_test() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    match fork().unwrap() {
+        Fork::Child => {
+            sleep(Duration::from_millis(50));
+            exit_child(0)
+        }
+        Fork::Parent(pid) => assert!(waitpid(pid)),
+    }
+    match fork().unwrap() {
+        Fork::Child => loop {
+            sleep(Duration::from_secs(1));
+        },
+        Fork::Parent(pid) => {
+            kill(pid);
+            assert!(!waitpid(pid));
+            assert_eq!(waitpid_nohang(pid), Some(false));
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn pipe_test() {
+    use std::io::{Read, Write};
+
+    let (mut reader, mut writer) = pipe().unwrap();
+    writer.write_all(b"hello").unwrap();
+    drop(writer);
+    let mut data = vec![];
+    reader.read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"hello");
+}
@@ -1,3 +1,8 @@
+use std::fs::File;
+use std::io;
+
+use Fork;
+
 pub fn getpid() -> u32 {
     0
 }
@@ -9,3 +14,25 @@ pub fn getos() -> (String, String, String) {
         "Unknown".to_owned(),
     )
 }
+
+pub fn fork() -> io::Result<Fork> {
+    Err(io::Error::new(io::ErrorKind::Other, "fork is not supported"))
+}
+
+pub fn pipe() -> io::Result<(File, File)> {
+    Err(io::Error::new(io::ErrorKind::Other, "pipe is not supported"))
+}
+
+pub fn waitpid_nohang(_pid: u32) -> Option<bool> {
+    Some(false)
+}
+
+pub fn waitpid(_pid: u32) -> bool {
+    false
+}
+
+pub fn exit_child(code: i32) -> ! {
+    ::std::process::exit(code)
+}
+
+pub fn kill(_pid: u32) {}
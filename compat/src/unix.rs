@@ -1,6 +1,17 @@
-use libc::funcs::posix88::unistd;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+
+use libc::c_int;
+use libc::funcs::c95::stdlib;
+use libc::funcs::posix88::{signal, unistd};
 use utsname::uname;
 
+use Fork;
+
+const WNOHANG: c_int = 1;
+const SIGKILL: c_int = 9;
+
 pub fn getpid() -> u32 {
     unsafe { unistd::getpid() as u32 }
 }
@@ -13,3 +24,58 @@ pub fn getos() -> (String, String, String) {
         name.machine().to_owned(),
     )
 }
+
+pub fn fork() -> io::Result<Fork> {
+    match unsafe { unistd::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(Fork::Child),
+        pid => Ok(Fork::Parent(pid as u32)),
+    }
+}
+
+/// Creates a pipe, returning its reading and writing ends.
+pub fn pipe() -> io::Result<(File, File)> {
+    let mut fds: [c_int; 2] = [0; 2];
+    if unsafe { unistd::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Checks whether the child process `pid` has finished, without blocking.
+/// Returns `None` while it is still running, or whether it exited with a zero
+/// status code.
+pub fn waitpid_nohang(pid: u32) -> Option<bool> {
+    let mut status: c_int = 0;
+    match unsafe { unistd::waitpid(pid as _, &mut status, WNOHANG) } {
+        0 => None,
+        -1 => Some(false),
+        // exited normally (not by a signal) and with status code zero
+        _ => Some(status & 0x7f == 0 && (status >> 8) & 0xff == 0),
+    }
+}
+
+/// Waits for the child process `pid` to finish, and returns whether it exited
+/// with a zero status code.
+pub fn waitpid(pid: u32) -> bool {
+    let mut status: c_int = 0;
+    loop {
+        match unsafe { unistd::waitpid(pid as _, &mut status, 0) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return false,
+            _ => return status & 0x7f == 0 && (status >> 8) & 0xff == 0,
+        }
+    }
+}
+
+/// Terminates the current process immediately, without running any cleanup.
+/// Meant to be used by forked children.
+pub fn exit_child(code: i32) -> ! {
+    unsafe { stdlib::_exit(code as c_int) }
+}
+
+pub fn kill(pid: u32) {
+    unsafe {
+        signal::kill(pid as _, SIGKILL);
+    }
+}
@@ -5,7 +5,7 @@ extern crate time;
 extern crate util;
 
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{canonicalize, File};
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Error as IOError;
@@ -43,6 +43,7 @@ pub struct Config {
     pub appendonly: bool,
     pub appendfilename: String,
     pub aof_load_truncated: bool,
+    pub aof_use_rdb_preamble: bool,
     pub dbfilename: String,
     pub save: Vec<(u64, u64)>, // (seconds, changes) pairs
     pub maxmemory: Option<u64>,
@@ -66,6 +67,32 @@ pub struct Config {
     pub zset_max_ziplist_entries: usize,
     pub zset_max_ziplist_value: usize,
     pub notify_keyspace_events: String,
+    pub repl_backlog_size: usize,
+    pub slaveof: Option<(String, u16)>,
+    pub masterauth: Option<String>,
+    pub slave_read_only: bool,
+    pub repl_timeout: u64,
+    pub repl_diskless_sync: bool,
+    pub repl_diskless_sync_delay: u64,
+    pub min_slaves_to_write: usize,
+    pub min_slaves_max_lag: u64,
+    pub cluster_enabled: bool,
+    pub cluster_config_file: String,
+    pub cluster_node_timeout: u64,
+    pub slave_priority: u32,
+    /// Milliseconds a command can hold the database before the other
+    /// connections get -BUSY errors, 0 to let them wait
+    pub busy_reply_threshold: u64,
+    /// Whether the server runs as a sentinel, monitoring other servers
+    /// instead of serving data
+    pub sentinel_mode: bool,
+    /// Arguments of the `sentinel` directives, in order
+    pub sentinel: Vec<Vec<String>>,
+    /// Absolute path of the configuration file, rewritten by a sentinel to
+    /// save its state
+    pub configfile: Option<String>,
+    /// Path and arguments of the modules to load at startup
+    pub loadmodule: Vec<(String, Vec<Vec<u8>>)>,
 }
 
 #[derive(Debug)]
@@ -129,6 +156,7 @@ impl Config {
             appendonly: false,
             appendfilename: "appendonly.aof".to_owned(),
             aof_load_truncated: false,
+            aof_use_rdb_preamble: false,
             dbfilename: "dump.rdb".to_owned(),
             save: vec![(900, 1), (300, 10), (60, 10000)], // Default Redis save points
             maxmemory: None,
@@ -151,6 +179,24 @@ impl Config {
             list_max_ziplist_value: 64,
             zset_max_ziplist_entries: 128,
             zset_max_ziplist_value: 64,
+            repl_backlog_size: 1024 * 1024,
+            slaveof: None,
+            masterauth: None,
+            slave_read_only: true,
+            repl_timeout: 60,
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: 5,
+            min_slaves_to_write: 0,
+            min_slaves_max_lag: 10,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_owned(),
+            cluster_node_timeout: 15000,
+            slave_priority: 100,
+            busy_reply_threshold: 5000,
+            sentinel_mode: false,
+            sentinel: vec![],
+            configfile: None,
+            loadmodule: vec![],
             notify_keyspace_events: "".to_owned(), // Empty = disabled
         }
     }
@@ -174,6 +220,13 @@ impl Config {
                 return Err(ConfigError::FileNotFound);
             }
         });
+        if self.configfile.is_none() {
+            self.configfile = canonicalize(path)
+                .ok()
+                .and_then(|path| path.to_str().map(|path| path.to_owned()));
+        }
+        // the first save directive replaces the default save points
+        let mut save_configured = false;
         for line_iter in file.lines() {
             let lline = line_iter?;
             let line = lline.trim();
@@ -250,9 +303,16 @@ impl Config {
                 b"appendonly" => self.appendonly = read_bool(args)?,
                 b"appendfilename" => self.appendfilename = read_string(args)?.to_owned(),
                 b"aof-load-truncated" => self.aof_load_truncated = read_bool(args)?,
+                b"aof-use-rdb-preamble" => self.aof_use_rdb_preamble = read_bool(args)?,
                 b"dbfilename" => self.dbfilename = read_string(args)?.to_owned(),
                 b"save" => {
-                    if args.len() == 4 {
+                    if !save_configured {
+                        self.save.clear();
+                        save_configured = true;
+                    }
+                    if args.len() == 2 && args[1].is_empty() {
+                        // save "" disables snapshotting
+                    } else if args.len() == 3 {
                         let seconds_str = from_utf8(&*args[1])?.to_owned();
                         let changes_str = from_utf8(&*args[2])?.to_owned();
                         let seconds: u64 = seconds_str.parse().map_err(|_| ConfigError::InvalidParameter)?;
@@ -295,6 +355,50 @@ impl Config {
                 b"list-max-ziplist-value" => self.list_max_ziplist_value = read_parse(args)?,
                 b"zset-max-ziplist-entries" => self.zset_max_ziplist_entries = read_parse(args)?,
                 b"zset-max-ziplist-value" => self.zset_max_ziplist_value = read_parse(args)?,
+                b"repl-backlog-size" => self.repl_backlog_size = read_parse(args)?,
+                b"slaveof" | b"replicaof" => {
+                    if args.len() != 3 {
+                        return Err(ConfigError::InvalidFormat);
+                    }
+                    let host = from_utf8(&*args[1])?.to_owned();
+                    let port = from_utf8(&*args[2])?.parse()?;
+                    self.slaveof = Some((host, port));
+                }
+                b"masterauth" => self.masterauth = Some(read_string(args)?.to_owned()),
+                b"slave-read-only" | b"replica-read-only" => self.slave_read_only = read_bool(args)?,
+                b"repl-timeout" => self.repl_timeout = read_parse(args)?,
+                b"repl-diskless-sync" => self.repl_diskless_sync = read_bool(args)?,
+                b"repl-diskless-sync-delay" => self.repl_diskless_sync_delay = read_parse(args)?,
+                b"min-slaves-to-write" | b"min-replicas-to-write" => {
+                    self.min_slaves_to_write = read_parse(args)?
+                }
+                b"min-slaves-max-lag" | b"min-replicas-max-lag" => {
+                    self.min_slaves_max_lag = read_parse(args)?
+                }
+                b"cluster-enabled" => self.cluster_enabled = read_bool(args)?,
+                b"cluster-config-file" => self.cluster_config_file = read_string(args)?,
+                b"cluster-node-timeout" => self.cluster_node_timeout = read_parse(args)?,
+                b"slave-priority" | b"replica-priority" => self.slave_priority = read_parse(args)?,
+                b"busy-reply-threshold" | b"lua-time-limit" => {
+                    self.busy_reply_threshold = read_parse(args)?
+                }
+                b"sentinel" => {
+                    if args.len() < 2 {
+                        return Err(ConfigError::InvalidFormat);
+                    }
+                    let mut directive = vec![];
+                    for arg in &args[1..] {
+                        directive.push(from_utf8(arg)?.to_owned());
+                    }
+                    self.sentinel.push(directive);
+                }
+                b"loadmodule" => {
+                    if args.len() < 2 {
+                        return Err(ConfigError::InvalidFormat);
+                    }
+                    let path = from_utf8(&*args[1])?.to_owned();
+                    self.loadmodule.push((path, args[2..].to_vec()));
+                }
                 b"notify-keyspace-events" => self.notify_keyspace_events = read_string(args)?.to_owned(),
                 b"include" => {
                     if args.len() != 2 {
@@ -383,6 +487,67 @@ mod tests {
         assert_eq!(config.port, 6379);
     }
 
+    #[test]
+    fn parse_save() {
+        let config = config!(b"save 900 1\nsave 60 100", Logger::new(Level::Warning));
+        assert_eq!(config.save, vec![(900, 1), (60, 100)]);
+    }
+
+    #[test]
+    fn parse_save_disabled() {
+        let config = config!(b"save \"\"", Logger::new(Level::Warning));
+        assert!(config.save.is_empty());
+    }
+
+    #[test]
+    fn parse_slaveof() {
+        let config = config!(b"replicaof 10.0.0.1 6380\nslave-read-only no", Logger::new(Level::Warning));
+        assert_eq!(config.slaveof, Some(("10.0.0.1".to_owned(), 6380)));
+        assert!(!config.slave_read_only);
+    }
+
+    #[test]
+    fn parse_cluster_enabled() {
+        let config = config!(
+            b"cluster-enabled yes\ncluster-config-file nodes-7000.conf\ncluster-node-timeout 5000",
+            Logger::new(Level::Warning)
+        );
+        assert!(config.cluster_enabled);
+        assert_eq!(config.cluster_config_file, "nodes-7000.conf");
+        assert_eq!(config.cluster_node_timeout, 5000);
+    }
+
+    #[test]
+    fn parse_sentinel() {
+        let config = config!(
+            b"sentinel monitor mymaster 127.0.0.1 6379 2\nsentinel down-after-milliseconds mymaster 5000\nreplica-priority 10",
+            Logger::new(Level::Warning)
+        );
+        assert_eq!(
+            config.sentinel,
+            vec![
+                vec!["monitor", "mymaster", "127.0.0.1", "6379", "2"],
+                vec!["down-after-milliseconds", "mymaster", "5000"],
+            ]
+        );
+        assert_eq!(config.slave_priority, 10);
+        assert!(config.configfile.unwrap().ends_with(".conf"));
+    }
+
+    #[test]
+    fn parse_diskless_sync() {
+        let config = config!(b"repl-diskless-sync yes\nrepl-diskless-sync-delay 0", Logger::new(Level::Warning));
+        assert!(config.repl_diskless_sync);
+        assert_eq!(config.repl_diskless_sync_delay, 0);
+    }
+
+    #[test]
+    fn parse_min_slaves() {
+        let config = config!(b"min-replicas-to-write 2\nmin-slaves-max-lag 5", Logger::new(Level::Warning));
+        assert_eq!(config.min_slaves_to_write, 2);
+        assert_eq!(config.min_slaves_max_lag, 5);
+    }
+
     #[test]
     fn parse_port() {
         let config = config!(b"port 12345", Logger::new(Level::Warning));
@@ -454,6 +619,14 @@ mod tests {
         assert_eq!(config.timeout, 23456);
     }
 
+    #[test]
+    fn parse_busy_reply_threshold() {
+        let config = config!(b"busy-reply-threshold 100", Logger::new(Level::Warning));
+        assert_eq!(config.busy_reply_threshold, 100);
+        let config = config!(b"lua-time-limit 200", Logger::new(Level::Warning));
+        assert_eq!(config.busy_reply_threshold, 200);
+    }
+
     #[test]
     fn parse_unixsocket() {
         let config = config!(
@@ -486,4 +659,19 @@ mod tests {
         );
         assert_eq!(config.requirepass, Some("THISISASTRONGPASSWORD".to_owned()));
     }
+
+    #[test]
+    fn parse_loadmodule() {
+        let config = config!(
+            b"loadmodule /tmp/a.so\nloadmodule /tmp/b.so x 1",
+            Logger::new(Level::Warning)
+        );
+        assert_eq!(
+            config.loadmodule,
+            vec![
+                ("/tmp/a.so".to_owned(), vec![]),
+                ("/tmp/b.so".to_owned(), vec![b"x".to_vec(), b"1".to_vec()]),
+            ]
+        );
+    }
 }
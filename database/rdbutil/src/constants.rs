@@ -1,8 +1,11 @@
 use std::u32;
 
 /* The current RDB version. When the format changes in a way that is no longer
- * backward compatible this number gets incremented. */
-pub const VERSION: u16 = 7;
+ * backward compatible this number gets incremented. It is the version of the
+ * newest item written: module values (TYPE_MODULE_2) arrived in 8, streams in
+ * 9 and function libraries (OPCODE_FUNCTION2) in 10. Files and DUMP payloads
+ * up to this version are accepted. */
+pub const VERSION: u16 = 10;
 
 /* Defines related to the dump file format. To store 32 bits lengths for short
  * keys requires a lot of space, so we check the most significant 2 bits of
@@ -20,6 +23,8 @@ pub const VERSION: u16 = 7;
 pub const BITLEN6: u8 = 0;
 pub const BITLEN14: u8 = 1;
 pub const BITLEN32: u8 = 2;
+/* A full byte with this value is followed by a 64 bit big endian length. */
+pub const LEN64: u8 = 0x81;
 pub const ENCVAL: u8 = 3;
 pub const LENERR: u32 = u32::MAX;
 
@@ -38,6 +43,9 @@ pub const TYPE_LIST: u8 = 1;
 pub const TYPE_SET: u8 = 2;
 pub const TYPE_ZSET: u8 = 3;
 pub const TYPE_HASH: u8 = 4;
+/* Value of a type defined by a module: the 64 bit module type id as a
+ * length, then its data as MODULE_OPCODE_* items up to MODULE_OPCODE_EOF. */
+pub const TYPE_MODULE_2: u8 = 7;
 /* NOTE: WHEN ADDING NEW RDB TYPE, UPDATE rdbIsObjectType() BELOW */
 
 /* Object types for encoded objects. */
@@ -47,9 +55,21 @@ pub const TYPE_SET_INTSET: u8 = 11;
 pub const TYPE_ZSET_ZIPLIST: u8 = 12;
 pub const TYPE_HASH_ZIPLIST: u8 = 13;
 pub const TYPE_LIST_QUICKLIST: u8 = 14;
+/* Stream of listpacks indexed by the ID of their first entry. */
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
 /* NOTE: WHEN ADDING NEW RDB TYPE, UPDATE rdbIsObjectType() BELOW */
 
+/* Items of the data of a module value, each one preceded by its opcode
+ * saved as a length. */
+pub const MODULE_OPCODE_EOF: u8 = 0; /* End of module value. */
+pub const MODULE_OPCODE_SINT: u8 = 1; /* Signed integer. */
+pub const MODULE_OPCODE_UINT: u8 = 2; /* Unsigned integer. */
+pub const MODULE_OPCODE_FLOAT: u8 = 3; /* Float. */
+pub const MODULE_OPCODE_DOUBLE: u8 = 4; /* Double. */
+pub const MODULE_OPCODE_STRING: u8 = 5; /* String. */
+
 /* Special RDB opcodes (saved/loaded with rdbSaveType/rdbLoadType). */
+pub const OPCODE_FUNCTION2: u8 = 245;
 pub const OPCODE_AUX: u8 = 250;
 pub const OPCODE_RESIZEDB: u8 = 251;
 pub const OPCODE_EXPIRETIME_MS: u8 = 252;
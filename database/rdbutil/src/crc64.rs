@@ -0,0 +1,53 @@
+//! CRC64 with the "Jones" coefficients, as used by Redis for RDB files and
+//! DUMP payloads.
+//!
+//! The table driven version in the `crc64` crate reads unaligned words and
+//! panics on arbitrary slices, so a byte at a time version is used instead.
+
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u64; 256] = make_table();
+
+/// Updates `crc` with the contents of `data`. Start with `0`.
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, b| {
+        TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod test_crc64 {
+    use super::crc64;
+
+    #[test]
+    fn check() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn incremental() {
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn unaligned() {
+        let data = b"x123456789";
+        assert_eq!(crc64(0, &data[1..]), 0xe9c6d914c4b8d9ca);
+    }
+}
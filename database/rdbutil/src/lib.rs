@@ -1,8 +1,14 @@
 extern crate util;
 pub mod constants;
+pub mod crc64;
+pub mod listpack;
+pub mod lzf;
+pub mod ziplist;
 
+use std::error::Error;
+use std::fmt;
 use std::i64;
-use std::io;
+use std::io::{self, Read};
 use std::str::from_utf8;
 use std::u32;
 #[cfg(test)]
@@ -119,17 +125,16 @@ pub fn encode_u64_to_slice_u8<W: io::Write>(value: u64, enc: &mut W) -> Result<(
 }
 
 pub fn encode_len<W: io::Write>(len: usize, enc: &mut W) -> Result<(), EncodeError> {
-    if len > u32::MAX as usize {
-        panic!("Length does not fit in four bytes");
-    }
-
     if len < (1 << 6) {
         enc.write_all(&[((len & 0xFF) as u8) | (BITLEN6 << 6)])?;
     } else if len < (1 << 14) {
         enc.write_all(&[((len >> 8) as u8) | (BITLEN14 << 6), (len & 0xFF) as u8])?;
-    } else {
+    } else if len <= u32::MAX as usize {
         enc.write_all(&[BITLEN32 << 6])?;
         enc.write_all(&htonl(len as u32))?;
+    } else {
+        enc.write_all(&[LEN64])?;
+        enc.write_all(&(len as u64).to_be_bytes())?;
     }
 
     Ok(())
@@ -143,7 +148,9 @@ pub fn encode_slice_u8<W: io::Write>(
     if as_int && data.len() <= 11 {
         if let Some(()) = from_utf8(data)
             .ok()
-            .and_then(|s| s.parse().ok())
+            .and_then(|s| s.parse::<i64>().ok())
+            // only if the decoded value is identical, e.g.: not "007"
+            .filter(|i| format!("{}", i).as_bytes() == data)
             .and_then(|i| encode_i64(i, enc).ok())
         {
             return Ok(());
@@ -158,24 +165,174 @@ pub fn encode_slice_u8<W: io::Write>(
     Ok(())
 }
 
+#[derive(Debug)]
+pub enum DecodeError {
+    IOError(io::Error),
+    /// The input ended before the value was complete
+    UnexpectedEOF,
+    /// The input is not a valid encoding
+    InvalidData(String),
+    /// The stored checksum does not match the data
+    ChecksumMismatch { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::IOError(ref err) => write!(f, "{}", err),
+            DecodeError::UnexpectedEOF => write!(f, "Unexpected end of file, the file is truncated"),
+            DecodeError::InvalidData(ref msg) => write!(f, "{}", msg),
+            DecodeError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Wrong checksum: expected {:016x}, got {:016x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            DecodeError::UnexpectedEOF
+        } else {
+            DecodeError::IOError(err)
+        }
+    }
+}
+
+pub fn decode_u8<R: io::Read>(dec: &mut R) -> Result<u8, DecodeError> {
+    let mut buf = [0; 1];
+    dec.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn decode_u16_from_slice_u8<R: io::Read>(dec: &mut R) -> Result<u16, DecodeError> {
+    let mut buf = [0; 2];
+    dec.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+pub fn decode_u32_from_slice_u8<R: io::Read>(dec: &mut R) -> Result<u32, DecodeError> {
+    let mut buf = [0; 4];
+    dec.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn decode_u64_from_slice_u8<R: io::Read>(dec: &mut R) -> Result<u64, DecodeError> {
+    let mut buf = [0; 8];
+    dec.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads a length prefix. If the two most significant bits are `ENCVAL`, the
+/// special encoding (one of `ENC_*`) is returned instead as an `Err`.
+fn decode_len_or_encoding<R: io::Read>(dec: &mut R) -> Result<Result<usize, u8>, DecodeError> {
+    let first = decode_u8(dec)?;
+    let len = match first >> 6 {
+        BITLEN6 => (first & 0x3F) as usize,
+        BITLEN14 => (((first & 0x3F) as usize) << 8) | decode_u8(dec)? as usize,
+        BITLEN32 if first == LEN64 => {
+            let mut buf = [0; 8];
+            dec.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf) as usize
+        }
+        BITLEN32 => {
+            let mut buf = [0; 4];
+            dec.read_exact(&mut buf)?;
+            u32::from_be_bytes(buf) as usize
+        }
+        _ => return Ok(Err(first & 0x3F)),
+    };
+    Ok(Ok(len))
+}
+
+/// The most items preallocated for a length read from the input. Bigger
+/// values grow as their items are read, so a corrupt length fails when the
+/// input runs out instead of exhausting the memory up front.
+pub const MAX_PREALLOC: usize = 4096;
+
+/// The capacity to reserve for `len` items declared by the input.
+pub fn prealloc_len(len: usize) -> usize {
+    len.min(MAX_PREALLOC)
+}
+
+/// Reads exactly `len` bytes, failing if the input ends before.
+fn read_bytes<R: io::Read>(dec: &mut R, len: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut data = Vec::with_capacity(prealloc_len(len));
+    dec.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(DecodeError::UnexpectedEOF);
+    }
+    Ok(data)
+}
+
+/// Reads a length written with `encode_len`.
+pub fn decode_len<R: io::Read>(dec: &mut R) -> Result<usize, DecodeError> {
+    match decode_len_or_encoding(dec)? {
+        Ok(len) => Ok(len),
+        Err(_) => Err(DecodeError::InvalidData("Expected a length, found an encoded value".to_owned())),
+    }
+}
+
+fn decode_int<R: io::Read>(encoding: u8, dec: &mut R) -> Result<i64, DecodeError> {
+    Ok(match encoding {
+        ENC_INT8 => decode_u8(dec)? as i8 as i64,
+        ENC_INT16 => decode_u16_from_slice_u8(dec)? as i16 as i64,
+        ENC_INT32 => decode_u32_from_slice_u8(dec)? as i32 as i64,
+        _ => {
+            return Err(DecodeError::InvalidData(format!(
+                "Unknown integer encoding {}",
+                encoding
+            )))
+        }
+    })
+}
+
+/// Reads an integer written with `encode_i64`.
+pub fn decode_i64<R: io::Read>(dec: &mut R) -> Result<i64, DecodeError> {
+    match decode_len_or_encoding(dec)? {
+        Err(encoding) => decode_int(encoding, dec),
+        Ok(_) => Err(DecodeError::InvalidData("Expected an encoded integer".to_owned())),
+    }
+}
+
+/// Reads a string written with `encode_slice_u8`. Integer encoded strings
+/// are returned in their decimal representation, and LZF compressed ones
+/// are decompressed.
+pub fn decode_slice_u8<R: io::Read>(dec: &mut R) -> Result<Vec<u8>, DecodeError> {
+    match decode_len_or_encoding(dec)? {
+        Ok(len) => read_bytes(dec, len),
+        Err(ENC_LZF) => {
+            let clen = decode_len(dec)?;
+            let len = decode_len(dec)?;
+            let compressed = read_bytes(dec, clen)?;
+            lzf::decompress(&compressed, len)
+        }
+        Err(encoding) => Ok(format!("{}", decode_int(encoding, dec)?).into_bytes()),
+    }
+}
+
 #[test]
 fn test_encode_i64() {
     let mut v = vec![];
-    assert_eq!(encode_i64(1, &mut v).unwrap(), 2);
+    encode_i64(1, &mut v).unwrap();
     assert_eq!(v, vec![192, 1]);
 }
 
 #[test]
 fn test_encode_i64_2bytes() {
     let mut v = vec![];
-    assert_eq!(encode_i64(260, &mut v).unwrap(), 3);
+    encode_i64(260, &mut v).unwrap();
     assert_eq!(v, b"\xc1\x04\x01");
 }
 
 #[test]
 fn test_encode_i64_4bytes() {
     let mut v = vec![];
-    assert_eq!(encode_i64(70000, &mut v).unwrap(), 5);
+    encode_i64(70000, &mut v).unwrap();
     assert_eq!(v, b"\xc2p\x11\x01\x00");
 }
 
@@ -191,7 +348,7 @@ fn test_encode_i64_overflow() {
 #[test]
 fn test_encode_usize() {
     let mut v = vec![];
-    assert_eq!(encode_usize(123, &mut v).unwrap(), 2);
+    encode_usize(123, &mut v).unwrap();
     assert_eq!(v, vec![192, 123]);
 }
 
@@ -207,13 +364,66 @@ fn test_encode_usize_overflow() {
 #[test]
 fn test_encode_slice_u8_integer() {
     let mut v = vec![];
-    assert_eq!(encode_slice_u8(b"1", &mut v, true).unwrap(), 2);
+    encode_slice_u8(b"1", &mut v, true).unwrap();
     assert_eq!(v, vec![192, 1]);
 }
 
 #[test]
 fn test_encode_slice_u8_data() {
     let mut v = vec![];
-    assert_eq!(encode_slice_u8(b"hello world", &mut v, true).unwrap(), 12);
+    encode_slice_u8(b"hello world", &mut v, true).unwrap();
     assert_eq!(v, b"\x0bhello world");
 }
+
+#[test]
+fn test_decode_len() {
+    for len in [0, 1, 63, 64, 16383, 16384, 70000, 1 << 40].iter() {
+        let mut v = vec![];
+        encode_len(*len, &mut v).unwrap();
+        assert_eq!(decode_len(&mut &*v).unwrap(), *len);
+    }
+}
+
+#[test]
+fn test_decode_i64() {
+    for i in [0, 1, -1, 127, -128, 260, -260, 70000, -70000].iter() {
+        let mut v = vec![];
+        encode_i64(*i, &mut v).unwrap();
+        assert_eq!(decode_i64(&mut &*v).unwrap(), *i);
+    }
+}
+
+#[test]
+fn test_decode_slice_u8() {
+    for data in [&b"1"[..], b"-12345", b"007", b"hello world", b""].iter() {
+        let mut v = vec![];
+        encode_slice_u8(data, &mut v, true).unwrap();
+        assert_eq!(decode_slice_u8(&mut &*v).unwrap(), data.to_vec());
+    }
+}
+
+#[test]
+fn test_decode_slice_u8_lzf() {
+    let v = b"\xc3\x06\x09\x02abc\x80\x02";
+    assert_eq!(decode_slice_u8(&mut &v[..]).unwrap(), b"abcabcabc".to_vec());
+}
+
+#[test]
+fn test_decode_truncated() {
+    match decode_slice_u8(&mut &b"\x0bhello"[..]).unwrap_err() {
+        DecodeError::UnexpectedEOF => (),
+        e => panic!("Unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_decode_huge_length() {
+    // a 2^40 bytes string and a 2^32 - 1 bytes compressed one, in a few bytes
+    let inputs = [&b"\x81\x00\x00\x01\x00\x00\x00\x00\x00hello"[..], b"\xc3\x80\xff\xff\xff\xff\x09abc"];
+    for input in inputs.iter() {
+        match decode_slice_u8(&mut &input[..]).unwrap_err() {
+            DecodeError::UnexpectedEOF => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+}
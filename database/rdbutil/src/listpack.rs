@@ -0,0 +1,261 @@
+//! Encoding for the listpack blobs that hold the entries of a stream.
+//!
+//! <total-bytes:u32><num-elements:u16><entry>...<0xff>
+//!
+//! Each entry is its encoding, its data and then the length of both, so the
+//! listpack can be walked backwards. The encodings are:
+//!
+//! 0xxxxxxx => integer between 0 and 127
+//! 10pppppp => string of up to 63 bytes
+//! 110xxxxx yyyyyyyy => 13 bit signed integer
+//! 1110pppp qqqqqqqq => string of up to 4095 bytes
+//! 11110000 <u32> => string of up to 2^32 - 1 bytes
+//! 11110001 => i16, 11110010 => i24, 11110011 => i32, 11110100 => i64
+//!
+//! The trailing length uses 7 bits per byte, the most significant ones
+//! first, and all of its bytes but the first have the high bit set.
+//!
+//! All integers are little endian unless otherwise noted.
+
+use std::str::from_utf8;
+
+use DecodeError;
+
+const END: u8 = 0xff;
+const HEADER_SIZE: usize = 6;
+
+fn invalid(msg: &str) -> DecodeError {
+    DecodeError::InvalidData(format!("Invalid listpack: {}", msg))
+}
+
+fn read_le(data: &[u8], pos: usize, len: usize) -> Result<i64, DecodeError> {
+    if pos + len > data.len() {
+        return Err(invalid("truncated entry"));
+    }
+    let mut buf = [0; 8];
+    buf[..len].copy_from_slice(&data[pos..pos + len]);
+    // sign extend narrower integers
+    let shift = 64 - len * 8;
+    Ok((i64::from_le_bytes(buf) << shift) >> shift)
+}
+
+/// Number of bytes used to store `len` as the trailing length of an entry.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Decodes all the entries in a listpack. Integers are returned in their
+/// decimal representation.
+pub fn decode(data: &[u8]) -> Result<Vec<Vec<u8>>, DecodeError> {
+    if data.len() < HEADER_SIZE + 1 {
+        return Err(invalid("too short"));
+    }
+    if read_le(data, 0, 4)? as u32 as usize != data.len() {
+        return Err(invalid("wrong length"));
+    }
+    let count = read_le(data, 4, 2)? as u16 as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = HEADER_SIZE;
+    loop {
+        let start = pos;
+        let encoding = *data.get(pos).ok_or_else(|| invalid("missing end marker"))?;
+        if encoding == END {
+            break;
+        }
+        pos += 1;
+        let entry = if encoding & 0x80 == 0 {
+            format!("{}", encoding).into_bytes()
+        } else if encoding & 0xe0 == 0xc0 {
+            let low = read_le(data, pos, 1)? as u8;
+            pos += 1;
+            let value = ((((encoding & 0x1f) as i16) << 8 | low as i16) << 3) >> 3;
+            format!("{}", value).into_bytes()
+        } else {
+            let len = match encoding {
+                0x80..=0xbf => Some((encoding & 0x3f) as usize),
+                0xe0..=0xef => {
+                    let low = read_le(data, pos, 1)? as u8;
+                    pos += 1;
+                    Some(((encoding & 0x0f) as usize) << 8 | low as usize)
+                }
+                0xf0 => {
+                    let len = read_le(data, pos, 4)? as u32 as usize;
+                    pos += 4;
+                    Some(len)
+                }
+                _ => None,
+            };
+            match len {
+                Some(len) => {
+                    if pos + len > data.len() {
+                        return Err(invalid("truncated entry"));
+                    }
+                    pos += len;
+                    data[pos - len..pos].to_vec()
+                }
+                None => {
+                    let len = match encoding {
+                        0xf1 => 2,
+                        0xf2 => 3,
+                        0xf3 => 4,
+                        0xf4 => 8,
+                        _ => return Err(invalid("unknown encoding")),
+                    };
+                    let value = read_le(data, pos, len)?;
+                    pos += len;
+                    format!("{}", value).into_bytes()
+                }
+            }
+        };
+        pos += backlen_size(pos - start);
+        if pos > data.len() {
+            return Err(invalid("truncated entry"));
+        }
+        entries.push(entry);
+    }
+
+    if pos + 1 != data.len() {
+        return Err(invalid("data after end marker"));
+    }
+    // the count saturates at u16::MAX, in that case all entries are counted
+    if count != 0xffff && count != entries.len() {
+        return Err(invalid("wrong number of entries"));
+    }
+    Ok(entries)
+}
+
+fn encode_backlen(len: usize, out: &mut Vec<u8>) {
+    let size = backlen_size(len);
+    for i in (0..size).rev() {
+        let bits = ((len >> (7 * i)) & 0x7f) as u8;
+        out.push(if i == size - 1 { bits } else { bits | 0x80 });
+    }
+}
+
+fn encode_entry(entry: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+    let int = from_utf8(entry)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|i| format!("{}", i).as_bytes() == entry);
+    match int {
+        Some(i) if i >= 0 && i <= 127 => out.push(i as u8),
+        Some(i) if i >= -4096 && i <= 4095 => {
+            out.push(0xc0 | ((i >> 8) & 0x1f) as u8);
+            out.push(i as u8);
+        }
+        Some(i) if i >= i16::min_value() as i64 && i <= i16::max_value() as i64 => {
+            out.push(0xf1);
+            out.extend_from_slice(&(i as i16).to_le_bytes());
+        }
+        Some(i) if i >= -(1 << 23) && i < (1 << 23) => {
+            out.push(0xf2);
+            out.extend_from_slice(&(i as i32).to_le_bytes()[..3]);
+        }
+        Some(i) if i >= i32::min_value() as i64 && i <= i32::max_value() as i64 => {
+            out.push(0xf3);
+            out.extend_from_slice(&(i as i32).to_le_bytes());
+        }
+        Some(i) => {
+            out.push(0xf4);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        None => {
+            let len = entry.len();
+            if len < (1 << 6) {
+                out.push(0x80 | len as u8);
+            } else if len < (1 << 12) {
+                out.push(0xe0 | (len >> 8) as u8);
+                out.push((len & 0xff) as u8);
+            } else {
+                out.push(0xf0);
+                out.extend_from_slice(&(len as u32).to_le_bytes());
+            }
+            out.extend_from_slice(entry);
+        }
+    }
+    let len = out.len() - start;
+    encode_backlen(len, out);
+}
+
+/// Encodes `entries` into a listpack. Strings that represent an integer are
+/// stored as integers.
+pub fn encode(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0; HEADER_SIZE];
+    for entry in entries {
+        encode_entry(entry, &mut out);
+    }
+    out.push(END);
+
+    let len = out.len() as u32;
+    out[0..4].copy_from_slice(&len.to_le_bytes());
+    let count = if entries.len() < 0xffff { entries.len() } else { 0xffff };
+    out[4..6].copy_from_slice(&(count as u16).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test_listpack {
+    use super::{decode, encode};
+
+    #[test]
+    fn roundtrip() {
+        let entries: Vec<Vec<u8>> = [
+            &b"hello"[..],
+            b"0",
+            b"127",
+            b"128",
+            b"-1",
+            b"-4096",
+            b"4095",
+            b"-100000",
+            b"100000000",
+            b"-10000000000",
+            b"007",
+            b"",
+        ]
+        .iter()
+        .map(|e| e.to_vec())
+        .collect();
+        assert_eq!(decode(&encode(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn long_entries() {
+        let entries = vec![vec![b'a'; 300], vec![b'b'; 20000], b"c".to_vec()];
+        assert_eq!(decode(&encode(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(encode(&[]), b"\x07\x00\x00\x00\x00\x00\xff".to_vec());
+        assert!(decode(&encode(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn redis_payload() {
+        // listpack with "a", 1 and -2 as written by redis
+        let data = b"\x0f\x00\x00\x00\x03\x00\x81a\x02\x01\x01\xdf\xfe\x02\xff";
+        assert_eq!(
+            decode(&data[..]).unwrap(),
+            vec![b"a".to_vec(), b"1".to_vec(), b"-2".to_vec()]
+        );
+        assert_eq!(
+            encode(&[b"a".to_vec(), b"1".to_vec(), b"-2".to_vec()]),
+            data.to_vec()
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(&[b"hello".to_vec()]);
+        assert!(decode(&data[..data.len() - 2]).is_err());
+    }
+}
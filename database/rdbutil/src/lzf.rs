@@ -0,0 +1,103 @@
+//! Decompression for strings stored with `ENC_LZF`.
+//!
+//! The compressed stream is a sequence of chunks, each starting with a
+//! control byte:
+//!
+//! 000LLLLL <L+1 literal bytes>
+//! LLLooooo oooooooo => back reference of L+2 bytes, offset o+1
+//! 111ooooo LLLLLLLL oooooooo => back reference of L+9 bytes, offset o+1
+
+use DecodeError;
+
+/// Decompresses `input`, which must expand to exactly `len` bytes.
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+    // every 3 input bytes expand to at most 264, so bigger lengths are wrong
+    let mut output = Vec::with_capacity(len.min(input.len() * 88));
+    let mut pos = 0;
+    let truncated = || DecodeError::InvalidData("Truncated LZF compressed string".to_owned());
+
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+
+        if ctrl < (1 << 5) {
+            let run = ctrl + 1;
+            if pos + run > input.len() {
+                return Err(truncated());
+            }
+            output.extend_from_slice(&input[pos..pos + run]);
+            pos += run;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(pos).ok_or_else(truncated)? as usize;
+                pos += 1;
+            }
+            let low = *input.get(pos).ok_or_else(truncated)? as usize;
+            pos += 1;
+            let offset = ((ctrl & 0x1f) << 8) + low + 1;
+            if offset > output.len() {
+                return Err(DecodeError::InvalidData(
+                    "Invalid LZF back reference".to_owned(),
+                ));
+            }
+            // the referenced range may overlap with the bytes being copied
+            let start = output.len() - offset;
+            for i in 0..run + 2 {
+                let b = output[start + i];
+                output.push(b);
+            }
+        }
+
+        if output.len() > len {
+            break;
+        }
+    }
+
+    if output.len() != len {
+        return Err(DecodeError::InvalidData(format!(
+            "LZF decompressed length mismatch: expected {}, got {}",
+            len,
+            output.len()
+        )));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test_lzf {
+    use super::decompress;
+
+    #[test]
+    fn literal() {
+        assert_eq!(decompress(b"\x02abc", 3).unwrap(), b"abc".to_vec());
+    }
+
+    #[test]
+    fn back_reference() {
+        // "abc" followed by a 6 bytes back reference at offset 3
+        assert_eq!(
+            decompress(b"\x02abc\x80\x02", 9).unwrap(),
+            b"abcabcabc".to_vec()
+        );
+    }
+
+    #[test]
+    fn long_back_reference() {
+        // "a" followed by a 7 + 3 + 2 bytes back reference at offset 1
+        assert_eq!(
+            decompress(b"\x00a\xe0\x03\x00", 13).unwrap(),
+            b"aaaaaaaaaaaaa".to_vec()
+        );
+    }
+
+    #[test]
+    fn length_mismatch() {
+        assert!(decompress(b"\x02abc", 4).is_err());
+    }
+
+    #[test]
+    fn invalid_reference() {
+        assert!(decompress(b"\x00a\x20\x05", 4).is_err());
+    }
+}
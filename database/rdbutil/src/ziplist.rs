@@ -0,0 +1,242 @@
+//! Encoding for the ziplist blobs used by compact lists, hashes and sorted
+//! sets.
+//!
+//! <zlbytes:u32><zltail:u32><zllen:u16><entry>...<0xff>
+//!
+//! Each entry starts with the length of the previous entry (one byte, or
+//! 0xfe followed by four bytes) and then its encoding:
+//!
+//! 00pppppp => string of up to 63 bytes
+//! 01pppppp qqqqqqqq => string of up to 16383 bytes, big endian length
+//! 10000000 <u32 big endian> => string of up to 2^32 - 1 bytes
+//! 11000000 => i16, 11010000 => i32, 11100000 => i64, 11110000 => i24,
+//! 11111110 => i8, 1111xxxx => immediate value xxxx - 1
+//!
+//! All integers are little endian unless otherwise noted.
+
+use std::str::from_utf8;
+
+use DecodeError;
+
+const END: u8 = 0xff;
+const BIGLEN: u8 = 0xfe;
+const HEADER_SIZE: usize = 10;
+
+fn invalid(msg: &str) -> DecodeError {
+    DecodeError::InvalidData(format!("Invalid ziplist: {}", msg))
+}
+
+fn read_le(data: &[u8], pos: usize, len: usize) -> Result<i64, DecodeError> {
+    if pos + len > data.len() {
+        return Err(invalid("truncated entry"));
+    }
+    let mut buf = [0; 8];
+    buf[..len].copy_from_slice(&data[pos..pos + len]);
+    // sign extend narrower integers
+    let shift = 64 - len * 8;
+    Ok((i64::from_le_bytes(buf) << shift) >> shift)
+}
+
+/// Decodes all the entries in a ziplist. Integers are returned in their
+/// decimal representation.
+pub fn decode(data: &[u8]) -> Result<Vec<Vec<u8>>, DecodeError> {
+    if data.len() < HEADER_SIZE + 1 {
+        return Err(invalid("too short"));
+    }
+    if read_le(data, 0, 4)? as u32 as usize != data.len() {
+        return Err(invalid("wrong length"));
+    }
+    let count = read_le(data, 8, 2)? as u16 as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = HEADER_SIZE;
+    loop {
+        match data.get(pos) {
+            None => return Err(invalid("missing end marker")),
+            Some(&END) => break,
+            Some(&BIGLEN) => pos += 5,
+            Some(_) => pos += 1,
+        }
+
+        let encoding = *data.get(pos).ok_or_else(|| invalid("truncated entry"))?;
+        pos += 1;
+        let entry = match encoding >> 6 {
+            0 | 1 | 2 => {
+                let len = match encoding >> 6 {
+                    0 => (encoding & 0x3f) as usize,
+                    1 => {
+                        let next = *data.get(pos).ok_or_else(|| invalid("truncated entry"))?;
+                        pos += 1;
+                        (((encoding & 0x3f) as usize) << 8) | next as usize
+                    }
+                    _ => {
+                        if pos + 4 > data.len() {
+                            return Err(invalid("truncated entry"));
+                        }
+                        let mut buf = [0; 4];
+                        buf.copy_from_slice(&data[pos..pos + 4]);
+                        pos += 4;
+                        u32::from_be_bytes(buf) as usize
+                    }
+                };
+                if pos + len > data.len() {
+                    return Err(invalid("truncated entry"));
+                }
+                pos += len;
+                data[pos - len..pos].to_vec()
+            }
+            _ => {
+                let (value, len) = match encoding {
+                    0xc0 => (read_le(data, pos, 2)?, 2),
+                    0xd0 => (read_le(data, pos, 4)?, 4),
+                    0xe0 => (read_le(data, pos, 8)?, 8),
+                    0xf0 => (read_le(data, pos, 3)?, 3),
+                    0xfe => (read_le(data, pos, 1)?, 1),
+                    0xf1..=0xfd => ((encoding & 0x0f) as i64 - 1, 0),
+                    _ => return Err(invalid("unknown encoding")),
+                };
+                pos += len;
+                format!("{}", value).into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+
+    if pos + 1 != data.len() {
+        return Err(invalid("data after end marker"));
+    }
+    // the count saturates at u16::MAX, in that case all entries are counted
+    if count != 0xffff && count != entries.len() {
+        return Err(invalid("wrong number of entries"));
+    }
+    Ok(entries)
+}
+
+fn encode_prevlen(prevlen: usize, out: &mut Vec<u8>) {
+    if prevlen < BIGLEN as usize {
+        out.push(prevlen as u8);
+    } else {
+        out.push(BIGLEN);
+        out.extend_from_slice(&(prevlen as u32).to_le_bytes());
+    }
+}
+
+fn encode_entry(entry: &[u8], out: &mut Vec<u8>) {
+    let int = from_utf8(entry)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|i| format!("{}", i).as_bytes() == entry);
+    match int {
+        Some(i) if i >= 0 && i <= 12 => out.push(0xf1 + i as u8),
+        Some(i) if i >= i8::min_value() as i64 && i <= i8::max_value() as i64 => {
+            out.push(0xfe);
+            out.push(i as u8);
+        }
+        Some(i) if i >= i16::min_value() as i64 && i <= i16::max_value() as i64 => {
+            out.push(0xc0);
+            out.extend_from_slice(&(i as i16).to_le_bytes());
+        }
+        Some(i) if i >= -(1 << 23) && i < (1 << 23) => {
+            out.push(0xf0);
+            out.extend_from_slice(&(i as i32).to_le_bytes()[..3]);
+        }
+        Some(i) if i >= i32::min_value() as i64 && i <= i32::max_value() as i64 => {
+            out.push(0xd0);
+            out.extend_from_slice(&(i as i32).to_le_bytes());
+        }
+        Some(i) => {
+            out.push(0xe0);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        None => {
+            let len = entry.len();
+            if len < (1 << 6) {
+                out.push(len as u8);
+            } else if len < (1 << 14) {
+                out.push(0x40 | (len >> 8) as u8);
+                out.push((len & 0xff) as u8);
+            } else {
+                out.push(0x80);
+                out.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+            out.extend_from_slice(entry);
+        }
+    }
+}
+
+/// Encodes `entries` into a ziplist. Strings that represent an integer are
+/// stored as integers.
+pub fn encode(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0; HEADER_SIZE];
+    let mut prevlen = 0;
+    let mut tail = HEADER_SIZE;
+    for entry in entries {
+        let start = out.len();
+        tail = start;
+        encode_prevlen(prevlen, &mut out);
+        encode_entry(entry, &mut out);
+        prevlen = out.len() - start;
+    }
+    out.push(END);
+
+    let len = out.len() as u32;
+    out[0..4].copy_from_slice(&len.to_le_bytes());
+    out[4..8].copy_from_slice(&(tail as u32).to_le_bytes());
+    let count = if entries.len() < 0xffff { entries.len() } else { 0xffff };
+    out[8..10].copy_from_slice(&(count as u16).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test_ziplist {
+    use super::{decode, encode};
+
+    #[test]
+    fn roundtrip() {
+        let entries: Vec<Vec<u8>> = [
+            &b"hello"[..],
+            b"0",
+            b"12",
+            b"13",
+            b"-100",
+            b"1000",
+            b"-100000",
+            b"100000000",
+            b"-10000000000",
+            b"007",
+            b"",
+        ]
+        .iter()
+        .map(|e| e.to_vec())
+        .collect();
+        assert_eq!(decode(&encode(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn long_entries() {
+        let entries = vec![vec![b'a'; 300], vec![b'b'; 20000], b"c".to_vec()];
+        assert_eq!(decode(&encode(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(encode(&[]), b"\x0b\x00\x00\x00\x0a\x00\x00\x00\x00\x00\xff".to_vec());
+        assert!(decode(&encode(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn redis_payload() {
+        // ziplist with "a", 1 and "b" as written by redis
+        let data = b"\x13\x00\x00\x00\x0f\x00\x00\x00\x03\x00\x00\x01a\x03\xf2\x02\x01b\xff";
+        assert_eq!(
+            decode(&data[..]).unwrap(),
+            vec![b"a".to_vec(), b"1".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(&[b"hello".to_vec()]);
+        assert!(decode(&data[..data.len() - 2]).is_err());
+    }
+}
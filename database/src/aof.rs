@@ -0,0 +1,437 @@
+//! Rewrites the append only file with the shortest command stream that
+//! recreates the current keyspace.
+use std::fs::{remove_file, rename, File};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use compat::{exit_child, fork, kill, waitpid, waitpid_nohang, Fork};
+use logger::Level;
+use util::mstime;
+
+use list::ValueList;
+use zset::ValueSortedSet;
+
+use super::{Database, Value};
+
+/// Maximum number of elements added by a single variadic command
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// Writes `args` as a command in the protocol format.
+pub fn write_command<W: Write>(writer: &mut W, args: &[&[u8]]) -> io::Result<()> {
+    write!(writer, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(writer, "${}\r\n", arg.len())?;
+        writer.write_all(arg)?;
+        writer.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+/// Writes `name key item...` commands, splitting the items in batches.
+fn write_batched<W: Write>(
+    writer: &mut W,
+    name: &[u8],
+    key: &[u8],
+    items: &[Vec<u8>],
+    per_item: usize,
+) -> io::Result<()> {
+    for chunk in items.chunks(ITEMS_PER_COMMAND * per_item) {
+        let mut args: Vec<&[u8]> = Vec::with_capacity(chunk.len() + 2);
+        args.push(name);
+        args.push(key);
+        args.extend(chunk.iter().map(|item| &item[..]));
+        write_command(writer, &args)?;
+    }
+    Ok(())
+}
+
+fn write_value<W: Write>(writer: &mut W, key: &[u8], value: &Value) -> io::Result<()> {
+    match value {
+        Value::Nil => Ok(()),
+        Value::String(s) => write_command(writer, &[b"SET", key, &s.to_vec()]),
+        Value::List(ValueList::Data(list)) => {
+            let items = list.iter().cloned().collect::<Vec<_>>();
+            write_batched(writer, b"RPUSH", key, &items, 1)
+        }
+        Value::Set(s) => write_batched(writer, b"SADD", key, &s.smembers(), 1),
+        Value::SortedSet(ValueSortedSet::Data(_, scores)) => {
+            let mut items = Vec::with_capacity(scores.len() * 2);
+            for (member, score) in scores.iter() {
+                items.push(format!("{}", score).into_bytes());
+                items.push(member.clone());
+            }
+            write_batched(writer, b"ZADD", key, &items, 2)
+        }
+        Value::Hash(h) => write_batched(writer, b"HSET", key, &h.hgetall(), 2),
+        // only the module knows how to build its values, and a stream
+        // rebuilt with XADD would lose the ID of deleted entries
+        Value::Module(_) | Value::Stream(_) => {
+            let mut payload = vec![];
+            if let Err(e) = value.dump(&mut payload) {
+                return Err(io::Error::new(io::ErrorKind::Other, e));
+            }
+            write_command(writer, &[b"RESTORE", key, b"0", &payload])
+        }
+    }
+}
+
+impl Database {
+    /// Writes the commands needed to rebuild the function libraries and
+    /// every database into `writer`.
+    /// Keys that are already expired are skipped, the rest get an absolute
+    /// `PEXPIREAT`.
+    pub fn aof_rewrite_dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let now = mstime();
+        for library in self.functions.libraries() {
+            write_command(writer, &[b"FUNCTION", b"LOAD", &library.code])?;
+        }
+        for (index, data) in self.data.iter().enumerate() {
+            if data.is_empty() {
+                continue;
+            }
+            let expirations = &self.data_expiration_ms[index];
+            write_command(writer, &[b"SELECT", format!("{}", index).as_bytes()])?;
+            for (key, value) in data.iter() {
+                let expiration = expirations.get(key);
+                if let Some(expiration) = expiration {
+                    if *expiration <= now {
+                        continue;
+                    }
+                }
+                write_value(writer, key, value)?;
+                if let Some(expiration) = expiration {
+                    write_command(writer, &[b"PEXPIREAT", key, format!("{}", expiration).as_bytes()])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts rewriting `appendfilename` in a forked child process,
+    /// returning its pid. Writes that arrive meanwhile are buffered by the
+    /// `Aof` and appended once the child finishes.
+    pub fn aof_bgrewrite(&mut self) -> io::Result<u32> {
+        match fork()? {
+            Fork::Child => {
+                let tmppath = self.aof_rewrite_tmppath(process::id());
+                let code = match self.aof_rewrite_tmp(&tmppath) {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                };
+                exit_child(code);
+            }
+            Fork::Parent(pid) => {
+                log!(
+                    self.config.logger,
+                    Notice,
+                    "Background append only file rewriting started by pid {}",
+                    pid
+                );
+                if let Some(aof) = &mut self.aof {
+                    aof.rewrite_start();
+                }
+                self.aof_child_pid = Some(pid);
+                self.busy.child_started(pid, Some(self.aof_rewrite_tmppath(pid)));
+                self.aof_rewrite_scheduled = false;
+                self.aof_rewrite_time_start = mstime();
+                Ok(pid)
+            }
+        }
+    }
+
+    /// Checks whether the rewrite child finished, without blocking, and
+    /// swaps in the rewritten file. Starts a scheduled rewrite once no child
+    /// is running.
+    pub fn aof_rewrite_check(&mut self) {
+        if let Some(pid) = self.aof_child_pid {
+            let success = match waitpid_nohang(pid) {
+                Some(success) => success,
+                None => return,
+            };
+            self.aof_child_pid = None;
+            self.busy.child_ended(pid);
+            self.aof_last_rewrite_time_sec = (mstime() - self.aof_rewrite_time_start) / 1000;
+            let tmppath = self.aof_rewrite_tmppath(pid);
+            let r = if success {
+                log!(self.config.logger, Notice, "Background AOF rewrite terminated with success");
+                self.aof_rewrite_done(&tmppath)
+            } else {
+                Err(io::Error::new(io::ErrorKind::Other, "child process failed"))
+            };
+            self.aof_last_bgrewrite_ok = r.is_ok();
+            match r {
+                Ok(()) => {
+                    log!(self.config.logger, Notice, "Background AOF rewrite finished successfully");
+                }
+                Err(e) => {
+                    if let Some(aof) = &mut self.aof {
+                        aof.rewrite_abort();
+                    }
+                    let _ = remove_file(&tmppath);
+                    log!(self.config.logger, Warning, "Background AOF rewrite error: {}", e);
+                }
+            }
+        }
+
+        if self.aof_rewrite_scheduled && self.aof_child_pid.is_none() && self.rdb_child_pid.is_none() {
+            if let Err(e) = self.aof_bgrewrite() {
+                log!(self.config.logger, Warning, "Can't rewrite append only file in background: fork: {}", e);
+            }
+        }
+    }
+
+    /// Starts a rewrite once the file grew `auto_aof_rewrite_percentage`
+    /// percent over its size after the last rewrite, and it is at least
+    /// `auto_aof_rewrite_min_size` bytes long.
+    pub fn aof_auto_rewrite(&mut self) {
+        if self.aof_child_pid.is_some() || self.rdb_child_pid.is_some() {
+            return;
+        }
+        let percentage = self.config.auto_aof_rewrite_percentage;
+        let (current, base) = match &self.aof {
+            Some(aof) => (aof.current_size(), aof.base_size()),
+            None => return,
+        };
+        if percentage <= 0 || current <= self.config.auto_aof_rewrite_min_size {
+            return;
+        }
+        let base = if base == 0 { 1 } else { base };
+        let growth = (current * 100 / base) as i64 - 100;
+        if growth >= percentage {
+            log!(
+                self.config.logger,
+                Notice,
+                "Starting automatic rewriting of AOF on {}% growth",
+                growth
+            );
+            if let Err(e) = self.aof_bgrewrite() {
+                log!(self.config.logger, Warning, "Can't rewrite append only file in background: fork: {}", e);
+            }
+        }
+    }
+
+    /// Kills the rewrite child, if any, discarding its work.
+    pub fn aof_rewrite_kill(&mut self) {
+        if let Some(pid) = self.aof_child_pid.take() {
+            kill(pid);
+            // reap the killed child
+            waitpid(pid);
+            self.busy.child_ended(pid);
+            if let Some(aof) = &mut self.aof {
+                aof.rewrite_abort();
+            }
+            let _ = remove_file(self.aof_rewrite_tmppath(pid));
+        }
+    }
+
+    /// Seconds since the current rewrite started, -1 if there is none.
+    pub fn aof_current_rewrite_time_sec(&self) -> i64 {
+        match self.aof_child_pid {
+            Some(_) => (mstime() - self.aof_rewrite_time_start) / 1000,
+            None => -1,
+        }
+    }
+
+    fn aof_rewrite_tmppath(&self, pid: u32) -> PathBuf {
+        Path::new(&*self.config.appendfilename).with_file_name(format!("temp-rewriteaof-bg-{}.aof", pid))
+    }
+
+    fn aof_rewrite_tmp(&self, tmppath: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(tmppath)?);
+        // with the preamble, only the writes buffered during the rewrite
+        // are kept as commands
+        if self.config.aof_use_rdb_preamble {
+            self.rdb_dump(&mut writer)?;
+        } else {
+            self.aof_rewrite_dump(&mut writer)?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+
+    fn aof_rewrite_done(&mut self, tmppath: &Path) -> io::Result<()> {
+        match &mut self.aof {
+            Some(aof) => {
+                let buffered = aof.rewrite_buffer_len();
+                aof.rewrite_finish(tmppath)?;
+                log!(
+                    self.config.logger,
+                    Notice,
+                    "Residual parent diff successfully flushed to the rewritten AOF ({} bytes)",
+                    buffered
+                );
+                Ok(())
+            }
+            // the append only file is not in use, there is nothing to buffer
+            None => rename(tmppath, &*self.config.appendfilename),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_aof {
+    use std::env::temp_dir;
+    use std::fs::{remove_file, File};
+    use std::io::Read;
+
+    use parser::parse;
+    use persistence::aof::Aof;
+
+    use super::super::Database;
+
+    #[test]
+    fn write_error_keeps_aof() {
+        // every write into /dev/full fails with ENOSPC
+        let mut db = Database::mock();
+        db.aof = Some(Aof::new("/dev/full").unwrap());
+        let command = parse(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n").unwrap().0;
+        db.propagate(0, &command);
+        assert!(!db.aof_last_write_ok);
+        db.aof_retry_write();
+        assert!(!db.aof_last_write_ok);
+        assert!(db.aof.is_some());
+
+        // the status recovers with the first write that succeeds
+        let mut path = temp_dir();
+        path.push("rsedis-aof-test-write-error.aof");
+        let _ = remove_file(&path);
+        db.aof = Some(Aof::new(&path).unwrap());
+        db.propagate(0, &command);
+        assert!(db.aof_last_write_ok);
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn rewrite_dump() {
+        let mut db = Database::mock();
+        db.get_or_create(0, b"string").set(b"value".to_vec()).unwrap();
+        db.get_or_create(1, b"list").push(b"a".to_vec(), true).unwrap();
+        db.get_or_create(1, b"list").push(b"b".to_vec(), true).unwrap();
+        db.get_or_create(1, b"zset").zadd(-1.5, b"m".to_vec(), false, false, false, false).unwrap();
+        db.get_or_create(2, b"hash").hset(b"f".to_vec(), b"v".to_vec()).unwrap();
+        db.set_msexpiration(2, b"hash".to_vec(), 0x7fff_ffff_ffff);
+        db.get_or_create(2, b"expired").set(b"value".to_vec()).unwrap();
+        db.set_msexpiration(2, b"expired".to_vec(), 1);
+
+        let mut v = vec![];
+        db.aof_rewrite_dump(&mut v).unwrap();
+        let contains = |needle: &[u8]| v.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nSET\r\n$6\r\nstring\r\n$5\r\nvalue\r\n"));
+        assert!(contains(b"*4\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n$1\r\nb\r\n"));
+        assert!(contains(b"*4\r\n$4\r\nZADD\r\n$4\r\nzset\r\n$4\r\n-1.5\r\n$1\r\nm\r\n"));
+        assert!(contains(b"*4\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$1\r\nf\r\n$1\r\nv\r\n\
+                           *3\r\n$9\r\nPEXPIREAT\r\n$4\r\nhash\r\n$15\r\n140737488355327\r\n"));
+        assert!(!contains(b"expired"));
+    }
+
+    #[test]
+    fn rewrite_dump_batches() {
+        let mut db = Database::mock();
+        for i in 0..100 {
+            db.get_or_create(0, b"set").sadd(format!("m{}", i).into_bytes(), 512).unwrap();
+        }
+        let mut v = vec![];
+        db.aof_rewrite_dump(&mut v).unwrap();
+        assert!(v.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*66\r\n$4\r\nSADD\r\n"));
+        assert!(v.windows(10).any(|w| w == b"*38\r\n$4\r\nS"));
+    }
+
+    #[test]
+    fn bgrewrite() {
+        let mut path = temp_dir();
+        path.push("rsedis-aof-test-bgrewrite.aof");
+        let _ = remove_file(&path);
+
+        let mut db = Database::mock();
+        db.config.appendfilename = path.to_str().unwrap().to_owned();
+        db.aof = Some(Aof::new(&path).unwrap());
+        db.get_or_create(0, b"key").set(b"value".to_vec()).unwrap();
+        db.aof_bgrewrite().unwrap();
+        assert!(db.aof_child_pid.is_some());
+        while db.aof_child_pid.is_some() {
+            db.aof_rewrite_check();
+        }
+        assert!(db.aof_last_bgrewrite_ok);
+
+        let mut contents = vec![];
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        let mut expected = vec![];
+        db.aof_rewrite_dump(&mut expected).unwrap();
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn bgrewrite_rdb_preamble() {
+        let mut path = temp_dir();
+        path.push("rsedis-aof-test-bgrewrite-preamble.aof");
+        let _ = remove_file(&path);
+
+        let mut db = Database::mock();
+        db.config.appendfilename = path.to_str().unwrap().to_owned();
+        db.config.aof_use_rdb_preamble = true;
+        db.aof = Some(Aof::new(&path).unwrap());
+        db.get_or_create(0, b"key").set(b"value".to_vec()).unwrap();
+        db.aof_bgrewrite().unwrap();
+        let command = parse(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nother\r\n").unwrap().0;
+        db.log_command(0, &command, true);
+        while db.aof_child_pid.is_some() {
+            db.aof_rewrite_check();
+        }
+        assert!(db.aof_last_bgrewrite_ok);
+
+        let mut contents = vec![];
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents.starts_with(b"REDIS0010"));
+        assert!(contents.ends_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n\
+                                    *3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nother\r\n"));
+    }
+
+    #[test]
+    fn auto_rewrite() {
+        let mut path = temp_dir();
+        path.push("rsedis-aof-test-auto-rewrite.aof");
+        let _ = remove_file(&path);
+
+        let mut db = Database::mock();
+        db.config.appendfilename = path.to_str().unwrap().to_owned();
+        db.config.auto_aof_rewrite_min_size = 100;
+        db.aof = Some(Aof::new(&path).unwrap());
+        db.get_or_create(0, b"key").set(b"value".to_vec()).unwrap();
+        let command = parse(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n").unwrap().0;
+        db.log_command(0, &command, true);
+        db.aof_auto_rewrite();
+        assert!(db.aof_child_pid.is_none());
+
+        for _ in 0..3 {
+            db.log_command(0, &command, true);
+        }
+        db.aof_auto_rewrite();
+        assert!(db.aof_child_pid.is_some());
+        while db.aof_child_pid.is_some() {
+            db.aof_rewrite_check();
+        }
+        // SELECT 0 and a single SET
+        let aof = db.aof.as_ref().unwrap();
+        assert_eq!(aof.current_size(), 23 + 33);
+        assert_eq!(aof.base_size(), 23 + 33);
+    }
+
+    #[test]
+    fn bgrewrite_scheduled() {
+        let mut path = temp_dir();
+        path.push("rsedis-aof-test-bgrewrite-scheduled.aof");
+
+        let mut db = Database::mock();
+        db.config.appendfilename = path.to_str().unwrap().to_owned();
+        db.aof_rewrite_scheduled = true;
+        db.aof_rewrite_check();
+        assert!(!db.aof_rewrite_scheduled);
+        while db.aof_child_pid.is_some() {
+            db.aof_rewrite_check();
+        }
+        assert!(db.aof_last_bgrewrite_ok);
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,357 @@
+//! State shared by the connections outside of the database lock. A command
+//! that holds the database for longer than `busy-reply-threshold` makes the
+//! server busy: the other connections get `-BUSY` errors instead of waiting,
+//! except for the few commands that can be served from here.
+use std::collections::HashMap;
+use std::fs::remove_file;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use compat::{kill, waitpid};
+
+/// The command holding the database.
+#[derive(Clone, Debug)]
+pub struct Running {
+    /// Connection that sent it
+    pub client_id: usize,
+    /// Name of the command, in lower case
+    pub name: String,
+    /// When it started to run
+    pub since: Instant,
+    /// Whether it is running a script
+    pub script: bool,
+    /// Whether the script already wrote into the database, so it can no
+    /// longer be killed
+    pub script_wrote: bool,
+}
+
+/// A connection that can be closed by another one.
+struct Connection {
+    addr: String,
+    close: Box<dyn Fn() + Send>,
+}
+
+pub struct Busy {
+    /// Milliseconds a command can hold the database before the server is
+    /// busy, 0 to wait for it forever
+    threshold: AtomicU64,
+    running: Mutex<Option<Running>>,
+    /// Signaled when the running command releases the database
+    ended: Condvar,
+    /// The running script was asked to stop
+    kill: AtomicBool,
+    connections: Mutex<HashMap<usize, Connection>>,
+    /// Background save and rewrite children, with the temporary file each
+    /// one writes
+    children: Mutex<HashMap<u32, Option<PathBuf>>>,
+    /// The file with the pid of the server, if it wrote one
+    pidfile: Mutex<Option<PathBuf>>,
+    /// Run id of the server, for INFO
+    pub run_id: String,
+    /// Port of the server, for INFO
+    pub port: u16,
+    /// When the server started
+    pub start: Instant,
+}
+
+impl Busy {
+    pub fn new(threshold: u64, run_id: String, port: u16) -> Busy {
+        Busy {
+            threshold: AtomicU64::new(threshold),
+            running: Mutex::new(None),
+            ended: Condvar::new(),
+            kill: AtomicBool::new(false),
+            connections: Mutex::new(HashMap::new()),
+            children: Mutex::new(HashMap::new()),
+            pidfile: Mutex::new(None),
+            run_id: run_id,
+            port: port,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_threshold(&self, threshold: u64) {
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    /// Records that `client_id` holds the database to run `name`.
+    pub fn begin(&self, client_id: usize, name: String) {
+        *self.running.lock().unwrap() = Some(Running {
+            client_id: client_id,
+            name: name,
+            since: Instant::now(),
+            script: false,
+            script_wrote: false,
+        });
+    }
+
+    /// Records that the running command released the database.
+    pub fn end(&self) {
+        *self.running.lock().unwrap() = None;
+        self.kill.store(false, Ordering::Relaxed);
+        self.ended.notify_all();
+    }
+
+    /// The command holding the database, if any.
+    pub fn running(&self) -> Option<Running> {
+        self.running.lock().unwrap().clone()
+    }
+
+    /// Whether the running command has held the database for longer than
+    /// the threshold.
+    pub fn is_busy(&self) -> bool {
+        let threshold = self.threshold();
+        threshold > 0
+            && self
+                .running
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map_or(false, |r| r.since.elapsed() >= Duration::from_millis(threshold))
+    }
+
+    /// Waits while the running command holds the database, until it ends or
+    /// makes the server busy. Returns whether the server is busy.
+    pub fn wait_busy(&self) -> bool {
+        let mut running = self.running.lock().unwrap();
+        loop {
+            let threshold = self.threshold();
+            let since = match *running {
+                Some(ref r) if threshold > 0 => r.since,
+                _ => return false,
+            };
+            let elapsed = since.elapsed();
+            let threshold = Duration::from_millis(threshold);
+            if elapsed >= threshold {
+                return true;
+            }
+            running = self.ended.wait_timeout(running, threshold - elapsed).unwrap().0;
+        }
+    }
+
+    /// Marks the running command as a script, which SCRIPT KILL can stop.
+    pub fn script_begin(&self) {
+        if let Some(ref mut running) = *self.running.lock().unwrap() {
+            running.script = true;
+        }
+    }
+
+    /// Marks the running script as one that wrote into the database.
+    pub fn script_write(&self) {
+        if let Some(ref mut running) = *self.running.lock().unwrap() {
+            running.script_wrote = true;
+        }
+    }
+
+    /// Whether the running script was asked to stop.
+    pub fn script_killed(&self) -> bool {
+        self.kill.load(Ordering::Relaxed)
+    }
+
+    /// Asks the running script to stop. It fails if there is no script
+    /// running, or if it already wrote into the database.
+    pub fn kill_script(&self) -> Result<(), String> {
+        match *self.running.lock().unwrap() {
+            Some(ref running) if running.script && running.script_wrote => Err(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. \
+                 You can either wait the script termination or kill the server in a hard way \
+                 using the SHUTDOWN NOSAVE command."
+                    .to_owned(),
+            ),
+            Some(ref running) if running.script => {
+                self.kill.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err("NOTBUSY No scripts in execution right now.".to_owned()),
+        }
+    }
+
+    /// Records a background child writing into `tmppath`.
+    pub fn child_started(&self, pid: u32, tmppath: Option<PathBuf>) {
+        self.children.lock().unwrap().insert(pid, tmppath);
+    }
+
+    /// Records that the child `pid` was reaped.
+    pub fn child_ended(&self, pid: u32) {
+        self.children.lock().unwrap().remove(&pid);
+    }
+
+    pub fn set_pidfile(&self, pidfile: PathBuf) {
+        *self.pidfile.lock().unwrap() = Some(pidfile);
+    }
+
+    /// Kills the background children, and removes their temporary files
+    /// and the pid file. For SHUTDOWN NOSAVE while the database is held by
+    /// a command that may never finish.
+    pub fn shutdown_cleanup(&self) {
+        for (pid, tmppath) in self.children.lock().unwrap().drain() {
+            kill(pid);
+            waitpid(pid);
+            if let Some(tmppath) = tmppath {
+                let _ = remove_file(tmppath);
+            }
+        }
+        if let Some(pidfile) = self.pidfile.lock().unwrap().take() {
+            let _ = remove_file(pidfile);
+        }
+    }
+
+    /// Registers the connection `id` from `addr`. `close` shuts it down.
+    pub fn add_connection(&self, id: usize, addr: String, close: Box<dyn Fn() + Send>) {
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                addr: addr,
+                close: close,
+            },
+        );
+    }
+
+    pub fn remove_connection(&self, id: usize) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Closes the connections whose id and address match `filter`, and
+    /// returns how many.
+    pub fn kill_connections<F: Fn(usize, &str) -> bool>(&self, filter: F) -> usize {
+        let connections = self.connections.lock().unwrap();
+        let mut killed = 0;
+        for (id, connection) in connections.iter() {
+            if filter(*id, &connection.addr) {
+                (connection.close)();
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+#[cfg(test)]
+mod test_busy {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Busy;
+
+    #[test]
+    fn busy_after_threshold() {
+        let busy = Busy::new(20, "runid".to_owned(), 6379);
+        assert!(!busy.is_busy());
+        busy.begin(1, "debug".to_owned());
+        assert!(!busy.is_busy());
+        thread::sleep(Duration::from_millis(30));
+        assert!(busy.is_busy());
+        assert_eq!(busy.running().unwrap().client_id, 1);
+        busy.end();
+        assert!(!busy.is_busy());
+        assert!(busy.running().is_none());
+
+        busy.set_threshold(0);
+        busy.begin(1, "debug".to_owned());
+        thread::sleep(Duration::from_millis(30));
+        assert!(!busy.is_busy());
+    }
+
+    #[test]
+    fn wait_busy() {
+        use std::sync::Arc;
+        use std::time::Instant;
+
+        let busy = Arc::new(Busy::new(50, "runid".to_owned(), 6379));
+        assert!(!busy.wait_busy());
+
+        // wakes up once the threshold passes
+        busy.begin(1, "debug".to_owned());
+        let start = Instant::now();
+        assert!(busy.wait_busy());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        busy.end();
+
+        // or as soon as the command ends
+        busy.begin(1, "debug".to_owned());
+        let busy1 = busy.clone();
+        let th = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            busy1.end();
+        });
+        assert!(!busy.wait_busy());
+        assert!(busy.running().is_none());
+        th.join().unwrap();
+    }
+
+    #[test]
+    fn kill_script() {
+        let busy = Busy::new(20, "runid".to_owned(), 6379);
+        assert!(busy.kill_script().unwrap_err().starts_with("NOTBUSY"));
+        busy.begin(1, "eval".to_owned());
+        assert!(busy.kill_script().unwrap_err().starts_with("NOTBUSY"));
+        busy.script_begin();
+        busy.kill_script().unwrap();
+        assert!(busy.script_killed());
+        busy.end();
+        assert!(!busy.script_killed());
+
+        busy.begin(1, "eval".to_owned());
+        busy.script_begin();
+        busy.script_write();
+        assert!(busy.kill_script().unwrap_err().starts_with("UNKILLABLE"));
+        assert!(!busy.script_killed());
+    }
+
+    #[test]
+    fn shutdown_cleanup() {
+        use compat::{fork, waitpid_nohang, Fork};
+        use std::env::temp_dir;
+        use std::fs::write;
+
+        let busy = Busy::new(20, "runid".to_owned(), 6379);
+        let mut tmppath = temp_dir();
+        tmppath.push("rsedis-busy-test-child.rdb");
+        let mut pidfile = temp_dir();
+        pidfile.push("rsedis-busy-test.pid");
+        write(&tmppath, b"partial").unwrap();
+        write(&pidfile, b"1").unwrap();
+        busy.set_pidfile(pidfile.clone());
+        let pid = match fork().unwrap() {
+            Fork::Child => loop {
+                thread::sleep(Duration::from_secs(1));
+            },
+            Fork::Parent(pid) => pid,
+        };
+        busy.child_started(pid, Some(tmppath.clone()));
+        busy.shutdown_cleanup();
+        // the child was killed and reaped
+        assert_eq!(waitpid_nohang(pid), Some(false));
+        assert!(!tmppath.exists());
+        assert!(!pidfile.exists());
+    }
+
+    #[test]
+    fn kill_connections() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let busy = Busy::new(20, "runid".to_owned(), 6379);
+        let closed = Arc::new(AtomicUsize::new(0));
+        for id in 0..3 {
+            let closed = closed.clone();
+            busy.add_connection(
+                id,
+                format!("127.0.0.1:{}", 5000 + id),
+                Box::new(move || {
+                    closed.fetch_add(1, Ordering::Relaxed);
+                }),
+            );
+        }
+        busy.remove_connection(2);
+        assert_eq!(busy.kill_connections(|_, addr| addr == "127.0.0.1:5001"), 1);
+        assert_eq!(busy.kill_connections(|_, _| true), 2);
+        assert_eq!(closed.load(Ordering::Relaxed), 3);
+    }
+}
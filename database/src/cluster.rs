@@ -0,0 +1,2070 @@
+//! Cluster mode. The keyspace is split into hash slots, each of them served
+//! by one master. A node only executes commands on the keys of its own
+//! slots, and redirects the clients to the right node for the others.
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::fs::{remove_file, rename, File};
+use std::io::{self, Read, Write};
+use std::mem::replace;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use rand;
+
+use logger::Level;
+use parser::ParsedCommand;
+use util::{get_random_hex_chars, mstime};
+
+use aof::write_command;
+
+use super::Database;
+
+/// Number of hash slots the keyspace is split into
+pub const CLUSTER_SLOTS: usize = 16384;
+
+/// How long a forgotten node cannot be added back by gossip, in milliseconds
+const FORGET_TTL_MS: i64 = 60_000;
+
+/// How often every node is pinged, in milliseconds
+const PING_PERIOD_MS: i64 = 1000;
+
+const fn make_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u16; 256] = make_table();
+
+/// CRC16 with the XMODEM parameters, used to find the slot of a key.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, b| {
+        (crc << 8) ^ TABLE[(((crc >> 8) as u8) ^ *b) as usize]
+    })
+}
+
+/// Returns the hash slot of `key`. When the key has a non empty `{...}`
+/// section only that part is hashed, so related keys can share a slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|c| *c == b'{').and_then(|start| {
+        let tag = &key[start + 1..];
+        match tag.iter().position(|c| *c == b'}') {
+            Some(len) if len > 0 => Some(&tag[..len]),
+            _ => None,
+        }
+    });
+    crc16(tag.unwrap_or(key)) & (CLUSTER_SLOTS as u16 - 1)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterNode {
+    /// Random identifier, unique in the cluster
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    /// Port of the cluster bus
+    pub cport: u16,
+    /// Identifier of its master, if it is a replica
+    pub master: Option<String>,
+    /// Met but its identity is not known yet
+    pub handshake: bool,
+    /// Version of the slots it claims, to settle conflicts
+    pub config_epoch: u64,
+    /// Milliseconds when the pending ping was sent, 0 if none
+    pub ping_sent: i64,
+    /// Milliseconds when the last pong was received
+    pub pong_received: i64,
+    /// Did not answer a ping within the node timeout
+    pub pfail: bool,
+    /// Enough masters agree it is unreachable
+    pub fail: bool,
+    /// Milliseconds when it was marked as failed
+    pub fail_time: i64,
+    /// Masters that reported it as unreachable, with the time of the report
+    pub fail_reports: HashMap<String, i64>,
+    /// Replication offset it advertised
+    pub repl_offset: u64,
+    /// Milliseconds when this node last voted for one of its replicas
+    pub voted_time: i64,
+    /// Milliseconds when it was added
+    pub ctime: i64,
+}
+
+impl ClusterNode {
+    pub fn new(id: String, ip: String, port: u16) -> ClusterNode {
+        ClusterNode {
+            id,
+            ip,
+            port,
+            cport: port.wrapping_add(10000),
+            master: None,
+            handshake: false,
+            config_epoch: 0,
+            ping_sent: 0,
+            pong_received: 0,
+            pfail: false,
+            fail: false,
+            fail_time: 0,
+            fail_reports: HashMap::new(),
+            repl_offset: 0,
+            voted_time: 0,
+            ctime: mstime(),
+        }
+    }
+
+    /// Address clients are redirected to.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// Connection opened to the cluster bus of another node.
+struct Link {
+    /// Identifies the connection, even if its node gets renamed
+    serial: u64,
+    /// Messages to send on the connection
+    sender: Sender<Vec<u8>>,
+    /// Milliseconds when it was opened
+    ctime: i64,
+}
+
+/// Types of the messages exchanged on the cluster bus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    Ping,
+    Pong,
+    /// A ping that asks the receiver to add the sender to its nodes
+    Meet,
+    /// Tells that a node failed
+    Fail,
+    /// A replica asks for a vote to replace its failed master
+    AuthRequest,
+    /// A master votes for the replica that asked
+    AuthAck,
+}
+
+impl MessageType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MessageType::Ping => "ping",
+            MessageType::Pong => "pong",
+            MessageType::Meet => "meet",
+            MessageType::Fail => "fail",
+            MessageType::AuthRequest => "auth-request",
+            MessageType::AuthAck => "auth-ack",
+        }
+    }
+
+    fn parse(s: &str) -> Option<MessageType> {
+        match s {
+            "ping" => Some(MessageType::Ping),
+            "pong" => Some(MessageType::Pong),
+            "meet" => Some(MessageType::Meet),
+            "fail" => Some(MessageType::Fail),
+            "auth-request" => Some(MessageType::AuthRequest),
+            "auth-ack" => Some(MessageType::AuthAck),
+            _ => None,
+        }
+    }
+
+    /// Whether the message carries gossip and may be answered with a pong.
+    fn is_ping(self) -> bool {
+        self == MessageType::Ping || self == MessageType::Pong || self == MessageType::Meet
+    }
+}
+
+/// What the sender of a message knows about another node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gossip {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub cport: u16,
+    pub pfail: bool,
+    pub fail: bool,
+}
+
+/// A message of the cluster bus. Every message describes its sender, so
+/// nodes keep learning about each other whatever they exchange.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub sender: String,
+    pub port: u16,
+    pub cport: u16,
+    /// Master of the sender, if it is a replica
+    pub master: Option<String>,
+    pub current_epoch: u64,
+    /// Config epoch of the sender, or of its master if it is a replica
+    pub config_epoch: u64,
+    /// Replication offset of the sender
+    pub offset: u64,
+    /// Slots of the sender, or of its master if it is a replica
+    pub slots: Vec<(u16, u16)>,
+    /// The node that failed, in FAIL messages
+    pub failed: Option<String>,
+    pub gossip: Vec<Gossip>,
+}
+
+impl Message {
+    /// Encodes the message as an array of bulk strings.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut args = vec![
+            self.kind.as_str().to_owned(),
+            self.sender.clone(),
+            format!("{}", self.port),
+            format!("{}", self.cport),
+            self.master.clone().unwrap_or_else(|| "-".to_owned()),
+            format!("{}", self.current_epoch),
+            format!("{}", self.config_epoch),
+            format!("{}", self.offset),
+            format_ranges(&self.slots),
+            self.failed.clone().unwrap_or_else(|| "-".to_owned()),
+        ];
+        for gossip in &self.gossip {
+            let flags = if gossip.fail {
+                "fail"
+            } else if gossip.pfail {
+                "fail?"
+            } else {
+                "-"
+            };
+            args.push(gossip.id.clone());
+            args.push(gossip.ip.clone());
+            args.push(format!("{}", gossip.port));
+            args.push(format!("{}", gossip.cport));
+            args.push(flags.to_owned());
+        }
+        let args = args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>();
+        let mut data = vec![];
+        write_command(&mut data, &args).unwrap();
+        data
+    }
+
+    /// Decodes a message received from another node.
+    pub fn decode(parser: &ParsedCommand) -> Option<Message> {
+        let argc = parser.argv.len();
+        if argc < 10 || (argc - 10) % 5 != 0 {
+            return None;
+        }
+        let arg = move |pos: usize| parser.get_str(pos).ok();
+        let optional = move |pos: usize| {
+            arg(pos).map(|s| if s == "-" { None } else { Some(s.to_owned()) })
+        };
+        let gossip = (10..argc)
+            .step_by(5)
+            .map(|pos| {
+                let flags = arg(pos + 4)?;
+                Some(Gossip {
+                    id: arg(pos)?.to_owned(),
+                    ip: arg(pos + 1)?.to_owned(),
+                    port: arg(pos + 2)?.parse().ok()?,
+                    cport: arg(pos + 3)?.parse().ok()?,
+                    pfail: flags == "fail?",
+                    fail: flags == "fail",
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Message {
+            kind: MessageType::parse(arg(0)?)?,
+            sender: arg(1)?.to_owned(),
+            port: arg(2)?.parse().ok()?,
+            cport: arg(3)?.parse().ok()?,
+            master: optional(4)?,
+            current_epoch: arg(5)?.parse().ok()?,
+            config_epoch: arg(6)?.parse().ok()?,
+            offset: arg(7)?.parse().ok()?,
+            slots: parse_ranges(arg(8)?)?,
+            failed: optional(9)?,
+            gossip,
+        })
+    }
+}
+
+pub struct Cluster {
+    /// Identifier of this node
+    pub myself: String,
+    /// Known nodes, this one included
+    pub nodes: HashMap<String, ClusterNode>,
+    /// Identifier of the node serving each slot
+    pub slots: Vec<Option<String>>,
+    /// Slots being moved from this node, with the node they go to
+    pub migrating: HashMap<u16, String>,
+    /// Slots being moved into this node, with the node they come from
+    pub importing: HashMap<u16, String>,
+    /// Highest epoch seen in the cluster
+    pub current_epoch: u64,
+    /// Epoch this node last voted in a failover
+    pub last_vote_epoch: u64,
+    /// Connections to the other nodes
+    links: HashMap<String, Link>,
+    /// Last serial given to a link
+    link_counter: u64,
+    /// Forgotten nodes, with the time until which gossip cannot add them
+    blacklist: HashMap<String, i64>,
+    /// Milliseconds when this replica starts or started its election, 0
+    /// when its master is fine
+    failover_auth_time: i64,
+    /// Whether the votes were requested for the current election
+    failover_auth_sent: bool,
+    /// Epoch of the current election
+    failover_auth_epoch: u64,
+    /// Votes received in the current election
+    failover_auth_count: usize,
+    /// Whether the configuration changed since it was saved
+    todo_save: bool,
+}
+
+impl Cluster {
+    /// Creates a cluster of a single node, listening on `port` and serving
+    /// no slot.
+    pub fn new(port: u16) -> Cluster {
+        let myself = ClusterNode::new(get_random_hex_chars(40), String::new(), port);
+        let mut nodes = HashMap::new();
+        let id = myself.id.clone();
+        nodes.insert(id.clone(), myself);
+        Cluster {
+            myself: id,
+            nodes,
+            slots: vec![None; CLUSTER_SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            links: HashMap::new(),
+            link_counter: 0,
+            blacklist: HashMap::new(),
+            failover_auth_time: 0,
+            failover_auth_sent: false,
+            failover_auth_epoch: 0,
+            failover_auth_count: 0,
+            todo_save: false,
+        }
+    }
+
+    /// Restores the cluster saved by `config`, for a node listening on
+    /// `port`.
+    pub fn from_config(config: &str, port: u16) -> Result<Cluster, String> {
+        let mut cluster = Cluster::new(port);
+        cluster.nodes.clear();
+        cluster.myself.clear();
+        for line in config.lines() {
+            let argv = line.split_whitespace().collect::<Vec<_>>();
+            if argv.is_empty() {
+                continue;
+            }
+            if argv[0] == "vars" {
+                for pair in argv[1..].chunks(2) {
+                    let value = pair.get(1).and_then(|v| v.parse().ok());
+                    match (pair[0], value) {
+                        ("currentEpoch", Some(v)) => cluster.current_epoch = v,
+                        ("lastVoteEpoch", Some(v)) => cluster.last_vote_epoch = v,
+                        _ => return Err(format!("invalid vars: {}", line)),
+                    }
+                }
+                continue;
+            }
+            if argv.len() < 8 {
+                return Err(format!("invalid line: {}", line));
+            }
+            let node = cluster.load_node(&argv).ok_or_else(|| format!("invalid line: {}", line))?;
+            if argv[2].split(',').any(|flag| flag == "myself") {
+                cluster.myself = node.clone();
+            }
+            cluster.load_slots(&node, &argv[8..]).ok_or_else(|| format!("invalid slots: {}", line))?;
+        }
+        match cluster.nodes.get_mut(&cluster.myself) {
+            Some(myself) => myself.port = port,
+            None => return Err("myself not found".to_owned()),
+        }
+        Ok(cluster)
+    }
+
+    /// Adds the node described in a line of the configuration, returning its
+    /// identifier.
+    fn load_node(&mut self, argv: &[&str]) -> Option<String> {
+        let (addr, cport) = match argv[1].find('@') {
+            Some(pos) => (&argv[1][..pos], Some(argv[1][pos + 1..].parse().ok()?)),
+            None => (argv[1], None),
+        };
+        let pos = addr.rfind(':')?;
+        let mut node = ClusterNode::new(argv[0].to_owned(), addr[..pos].to_owned(), addr[pos + 1..].parse().ok()?);
+        if let Some(cport) = cport {
+            node.cport = cport;
+        }
+        node.handshake = argv[2].split(',').any(|flag| flag == "handshake");
+        if argv[2].split(',').any(|flag| flag == "fail") {
+            node.fail = true;
+            node.fail_time = mstime();
+        }
+        if argv[3] != "-" {
+            node.master = Some(argv[3].to_owned());
+        }
+        node.ping_sent = argv[4].parse().ok()?;
+        node.pong_received = argv[5].parse().ok()?;
+        node.config_epoch = argv[6].parse().ok()?;
+        let id = node.id.clone();
+        self.nodes.insert(id.clone(), node);
+        Some(id)
+    }
+
+    /// Assigns the slots in a line of the configuration to the node `id`.
+    fn load_slots(&mut self, id: &str, slots: &[&str]) -> Option<()> {
+        for slot in slots {
+            if slot.starts_with('[') {
+                // [slot->-target] or [slot-<-source]
+                let inner = slot.trim_start_matches('[').trim_end_matches(']');
+                if let Some(pos) = inner.find("->-") {
+                    self.migrating.insert(parse_slot(&inner[..pos])?, inner[pos + 3..].to_owned());
+                } else if let Some(pos) = inner.find("-<-") {
+                    self.importing.insert(parse_slot(&inner[..pos])?, inner[pos + 3..].to_owned());
+                } else {
+                    return None;
+                }
+                continue;
+            }
+            let (start, end) = parse_range(slot)?;
+            for slot in start..=end {
+                self.slots[slot as usize] = Some(id.to_owned());
+            }
+        }
+        Some(())
+    }
+
+    pub fn myself(&self) -> &ClusterNode {
+        &self.nodes[&self.myself]
+    }
+
+    /// The node serving `slot`, if any.
+    pub fn slot_node(&self, slot: u16) -> Option<&ClusterNode> {
+        self.slots[slot as usize]
+            .as_ref()
+            .and_then(|id| self.nodes.get(id))
+    }
+
+    /// The ranges of consecutive slots served by the node `id`.
+    pub fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_ref().map(|owner| owner == id) != Some(true) {
+                continue;
+            }
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some(range) if range.1 + 1 == slot => range.1 = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// Number of slots served by some node.
+    pub fn slots_assigned(&self) -> usize {
+        self.slots.iter().filter(|owner| owner.is_some()).count()
+    }
+
+    /// The replicas of the node `id`.
+    pub fn replicas(&self, id: &str) -> Vec<&ClusterNode> {
+        let mut replicas = self
+            .nodes
+            .values()
+            .filter(|node| node.master.as_ref().map(|m| m == id) == Some(true))
+            .collect::<Vec<_>>();
+        replicas.sort_by(|a, b| a.id.cmp(&b.id));
+        replicas
+    }
+
+    /// Describes a node in the format of CLUSTER NODES and the configuration
+    /// file.
+    pub fn node_description(&self, node: &ClusterNode) -> String {
+        let mut flags = vec![];
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        if node.handshake {
+            flags.push("handshake");
+        } else if node.master.is_some() {
+            flags.push("slave");
+        } else {
+            flags.push("master");
+        }
+        if node.fail {
+            flags.push("fail");
+        } else if node.pfail {
+            flags.push("fail?");
+        }
+        let connected = node.id == self.myself || self.links.contains_key(&node.id);
+        let mut description = format!(
+            "{} {}:{}@{} {} {} {} {} {} {}",
+            node.id,
+            node.ip,
+            node.port,
+            node.cport,
+            flags.join(","),
+            node.master.as_ref().map(|m| &**m).unwrap_or("-"),
+            node.ping_sent,
+            node.pong_received,
+            node.config_epoch,
+            if connected { "connected" } else { "disconnected" },
+        );
+        for (start, end) in self.slot_ranges(&node.id) {
+            description.push(' ');
+            description.push_str(&format_range(start, end));
+        }
+        if node.id == self.myself {
+            let mut migrating = self.migrating.iter().collect::<Vec<_>>();
+            migrating.sort();
+            for (slot, id) in migrating {
+                description.push_str(&format!(" [{}->-{}]", slot, id));
+            }
+            let mut importing = self.importing.iter().collect::<Vec<_>>();
+            importing.sort();
+            for (slot, id) in importing {
+                description.push_str(&format!(" [{}-<-{}]", slot, id));
+            }
+        }
+        description
+    }
+
+    /// Describes all the nodes, one per line, this node first.
+    pub fn nodes_description(&self) -> String {
+        let mut nodes = self.nodes.values().collect::<Vec<_>>();
+        nodes.sort_by_key(|node| (node.id != self.myself, node.id.clone()));
+        nodes
+            .into_iter()
+            .map(|node| self.node_description(node) + "\n")
+            .collect()
+    }
+
+    /// Starts a handshake with the node at `ip:port`. Its identifier is
+    /// unknown until it answers, so a random one is used meanwhile.
+    pub fn meet(&mut self, ip: &str, port: u16, cport: u16) -> Result<(), String> {
+        if ip.parse::<IpAddr>().is_err() {
+            return Err(format!("ERR Invalid node address specified: {}:{}", ip, port));
+        }
+        let mut node = ClusterNode::new(get_random_hex_chars(40), ip.to_owned(), port);
+        node.cport = cport;
+        node.handshake = true;
+        self.nodes.insert(node.id.clone(), node);
+        Ok(())
+    }
+
+    /// Removes the node `id`. The slots it served become unassigned.
+    pub fn forget(&mut self, id: &str) -> Result<(), String> {
+        if id == self.myself {
+            return Err("ERR I tried hard but I can't forget myself...".to_owned());
+        }
+        if !self.nodes.contains_key(id) {
+            return Err(format!("ERR Unknown node {}", id));
+        }
+        if self.myself().master.as_ref().map(|m| m == id) == Some(true) {
+            return Err("ERR Can't forget my master!".to_owned());
+        }
+        self.nodes.remove(id);
+        self.links.remove(id);
+        self.blacklist.insert(id.to_owned(), mstime() + FORGET_TTL_MS);
+        for owner in self.slots.iter_mut() {
+            if owner.as_ref().map(|owner| owner == id) == Some(true) {
+                *owner = None;
+            }
+        }
+        self.migrating.retain(|_, node| node != id);
+        self.importing.retain(|_, node| node != id);
+        Ok(())
+    }
+
+    /// Assigns `slots` to this node. None of them may be served already.
+    pub fn add_slots(&mut self, slots: &[u16]) -> Result<(), String> {
+        self.check_slots(slots, true)?;
+        for slot in slots {
+            self.slots[*slot as usize] = Some(self.myself.clone());
+            self.importing.remove(slot);
+        }
+        Ok(())
+    }
+
+    /// Unassigns `slots`. All of them must be served by some node.
+    pub fn del_slots(&mut self, slots: &[u16]) -> Result<(), String> {
+        self.check_slots(slots, false)?;
+        for slot in slots {
+            self.slots[*slot as usize] = None;
+        }
+        Ok(())
+    }
+
+    fn check_slots(&self, slots: &[u16], unassigned: bool) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for slot in slots {
+            if !seen.insert(slot) {
+                return Err(format!("ERR Slot {} specified multiple times", slot));
+            }
+            match (&self.slots[*slot as usize], unassigned) {
+                (Some(_), true) => return Err(format!("ERR Slot {} is already busy", slot)),
+                (None, false) => return Err(format!("ERR Slot {} is already unassigned", slot)),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_known(&self, id: &str) -> Result<(), String> {
+        if self.nodes.contains_key(id) {
+            Ok(())
+        } else {
+            Err(format!("ERR I don't know about node {}", id))
+        }
+    }
+
+    /// Starts moving `slot` from this node to the node `id`.
+    pub fn set_slot_migrating(&mut self, slot: u16, id: &str) -> Result<(), String> {
+        if self.slots[slot as usize].as_ref() != Some(&self.myself) {
+            return Err(format!("ERR I'm not the owner of hash slot {}", slot));
+        }
+        self.check_known(id)?;
+        self.migrating.insert(slot, id.to_owned());
+        Ok(())
+    }
+
+    /// Starts moving `slot` from the node `id` into this node.
+    pub fn set_slot_importing(&mut self, slot: u16, id: &str) -> Result<(), String> {
+        if self.slots[slot as usize].as_ref() == Some(&self.myself) {
+            return Err(format!("ERR I'm already the owner of hash slot {}", slot));
+        }
+        self.check_known(id)?;
+        self.importing.insert(slot, id.to_owned());
+        Ok(())
+    }
+
+    /// Cancels moving `slot`.
+    pub fn set_slot_stable(&mut self, slot: u16) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    /// Assigns `slot` to the node `id`, ending its migration. When this node
+    /// finishes importing it, it takes a new epoch so the rest of the
+    /// cluster prefers its claim over the one of the previous owner.
+    pub fn set_slot_node(&mut self, slot: u16, id: &str) -> Result<(), String> {
+        self.check_known(id)?;
+        if id != self.myself {
+            self.migrating.remove(&slot);
+        } else if self.importing.remove(&slot).is_some() {
+            self.bump_epoch();
+        }
+        self.slots[slot as usize] = Some(id.to_owned());
+        Ok(())
+    }
+
+    /// Takes a config epoch greater than any other node's, unless this node
+    /// already has the greatest one.
+    pub fn bump_epoch(&mut self) {
+        let max = self.nodes.values().map(|node| node.config_epoch).max().unwrap_or(0);
+        let myself = self.myself.clone();
+        let node = self.nodes.get_mut(&myself).unwrap();
+        if node.config_epoch == 0 || node.config_epoch != max {
+            self.current_epoch = max.max(self.current_epoch) + 1;
+            node.config_epoch = self.current_epoch;
+        }
+    }
+
+    /// Makes this node a replica of the master `id`. It cannot serve slots
+    /// itself.
+    pub fn replicate(&mut self, id: &str) -> Result<(), String> {
+        let master = match self.nodes.get(id) {
+            Some(master) => master,
+            None => return Err(format!("ERR Unknown node {}", id)),
+        };
+        if id == self.myself {
+            return Err("ERR Can't replicate myself".to_owned());
+        }
+        if master.master.is_some() {
+            return Err("ERR I can only replicate a master, not a replica.".to_owned());
+        }
+        if self.myself().master.is_none() && !self.slot_ranges(&self.myself).is_empty() {
+            return Err("ERR To set a master the node must be empty and without assigned slots.".to_owned());
+        }
+        let myself = self.myself.clone();
+        self.nodes.get_mut(&myself).unwrap().master = Some(id.to_owned());
+        Ok(())
+    }
+
+    /// The master whose slots this node serves, itself or its master.
+    fn my_master(&self) -> String {
+        self.myself().master.clone().unwrap_or_else(|| self.myself.clone())
+    }
+
+    /// Number of masters serving slots. They are the ones voting to fail
+    /// a node or to elect a replica.
+    pub fn size(&self) -> usize {
+        self.slots.iter().flatten().collect::<HashSet<_>>().len()
+    }
+
+    /// Whether the owner of `slot` is marked as failed.
+    fn slot_failed(&self, slot: usize) -> (bool, bool) {
+        match self.slots[slot].as_ref().and_then(|id| self.nodes.get(id)) {
+            Some(node) => (node.pfail, node.fail),
+            None => (false, false),
+        }
+    }
+
+    /// Whether every slot is served by a node that did not fail.
+    pub fn state_ok(&self) -> bool {
+        self.slots_assigned() == CLUSTER_SLOTS && (0..CLUSTER_SLOTS).all(|slot| !self.slot_failed(slot).1)
+    }
+
+    /// The reply to CLUSTER INFO.
+    pub fn info(&self) -> String {
+        let pfail = (0..CLUSTER_SLOTS).filter(|slot| self.slot_failed(*slot).0).count();
+        let fail = (0..CLUSTER_SLOTS).filter(|slot| self.slot_failed(*slot).1).count();
+        format!(
+            "cluster_state:{}\r\n\
+             cluster_slots_assigned:{}\r\n\
+             cluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:{}\r\n\
+             cluster_slots_fail:{}\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\n\
+             cluster_my_epoch:{}\r\n",
+            if self.state_ok() { "ok" } else { "fail" },
+            self.slots_assigned(),
+            self.slots_assigned() - pfail - fail,
+            pfail,
+            fail,
+            self.nodes.len(),
+            self.size(),
+            self.current_epoch,
+            self.myself().config_epoch,
+        )
+    }
+
+    /// Queues `message` on the link to the node `id`, if there is one.
+    fn send(&mut self, id: &str, message: &Message) {
+        let lost = match self.links.get(id) {
+            Some(link) => link.sender.send(message.encode()).is_err(),
+            None => false,
+        };
+        if lost {
+            self.links.remove(id);
+        }
+    }
+
+    /// Queues `message` on every link.
+    fn broadcast(&mut self, message: &Message) {
+        let data = message.encode();
+        self.links.retain(|_, link| link.sender.send(data.clone()).is_ok());
+    }
+
+    /// Identifier of the node the link `serial` goes to.
+    fn link_node(&self, serial: u64) -> Option<String> {
+        self.links
+            .iter()
+            .find(|(_, link)| link.serial == serial)
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Clears the failure of the node `id` once it is reachable again. A
+    /// master that still serves slots stays failed for a while, giving its
+    /// replicas a chance to replace it.
+    fn clear_failure(&mut self, id: &str, node_timeout: i64) -> bool {
+        let serves = !self.slot_ranges(id).is_empty();
+        let node = match self.nodes.get_mut(id) {
+            Some(node) => node,
+            None => return false,
+        };
+        if node.fail && (node.master.is_some() || !serves || mstime() - node.fail_time > node_timeout * 2) {
+            node.fail = false;
+            self.todo_save = true;
+            return true;
+        }
+        false
+    }
+
+    /// The content of the configuration file.
+    pub fn config(&self) -> String {
+        format!(
+            "{}vars currentEpoch {} lastVoteEpoch {}\n",
+            self.nodes_description(),
+            self.current_epoch,
+            self.last_vote_epoch
+        )
+    }
+}
+
+fn parse_slot(s: &str) -> Option<u16> {
+    match s.parse() {
+        Ok(slot) if (slot as usize) < CLUSTER_SLOTS => Some(slot),
+        _ => None,
+    }
+}
+
+/// Parses `start-end`, or a single slot.
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    match s.find('-') {
+        Some(pos) => Some((parse_slot(&s[..pos])?, parse_slot(&s[pos + 1..])?)),
+        None => parse_slot(s).map(|slot| (slot, slot)),
+    }
+}
+
+fn format_range(start: u16, end: u16) -> String {
+    if start == end {
+        format!("{}", start)
+    } else {
+        format!("{}-{}", start, end)
+    }
+}
+
+/// Formats slot ranges as `0-10,12`, or `-` when there is none.
+fn format_ranges(ranges: &[(u16, u16)]) -> String {
+    if ranges.is_empty() {
+        return "-".to_owned();
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| format_range(start, end))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_ranges(s: &str) -> Option<Vec<(u16, u16)>> {
+    if s == "-" {
+        return Some(vec![]);
+    }
+    s.split(',').map(parse_range).collect()
+}
+
+impl Database {
+    /// Writes the nodes of the cluster into `cluster-config-file`, through a
+    /// temporary file so a crash never leaves it half written.
+    pub fn cluster_save_config(&self) -> io::Result<()> {
+        let cluster = match self.cluster {
+            Some(ref cluster) => cluster,
+            None => return Ok(()),
+        };
+        let filename = &*self.config.cluster_config_file;
+        let path = Path::new(filename);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("nodes.conf");
+        let tmppath = path.with_file_name(format!("temp-{}-{}", process::id(), name));
+        let r = File::create(&tmppath)
+            .and_then(|mut file| {
+                file.write_all(cluster.config().as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| rename(&tmppath, filename));
+        if r.is_err() {
+            let _ = remove_file(&tmppath);
+        }
+        r
+    }
+
+    /// Restores the nodes saved in `cluster-config-file`. When it does not
+    /// exist yet, it is created with the identity of this node so it keeps
+    /// it after a restart.
+    pub fn cluster_load_config(&mut self) -> Result<(), String> {
+        if self.cluster.is_none() {
+            return Ok(());
+        }
+        let filename = self.config.cluster_config_file.clone();
+        let mut contents = String::new();
+        match File::open(&filename) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents).map_err(|err| err.to_string())?;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.to_string()),
+        }
+        if !contents.trim().is_empty() {
+            self.cluster = Some(Cluster::from_config(&contents, self.config.port)?);
+        }
+        let master = {
+            let cluster = self.cluster.as_ref().unwrap();
+            cluster
+                .myself()
+                .master
+                .as_ref()
+                .and_then(|id| cluster.nodes.get(id))
+                .map(|master| (master.ip.clone(), master.port))
+        };
+        if let Some((ip, port)) = master {
+            self.repl_set_master(ip, port);
+        }
+        self.cluster_save_config().map_err(|err| err.to_string())
+    }
+
+    /// Saves the configuration if it changed.
+    fn cluster_save_if_needed(&mut self) {
+        let todo_save = match self.cluster {
+            Some(ref mut cluster) => replace(&mut cluster.todo_save, false),
+            None => false,
+        };
+        if todo_save {
+            if let Err(err) = self.cluster_save_config() {
+                log!(self.config.logger, Warning, "Could not save the cluster config file: {}", err);
+            }
+        }
+    }
+
+    /// Builds a message of type `kind` describing this node.
+    fn cluster_message(&self, kind: MessageType) -> Message {
+        let cluster = self.cluster.as_ref().unwrap();
+        let myself = cluster.myself();
+        let master = cluster.my_master();
+        let gossip = if kind.is_ping() {
+            cluster
+                .nodes
+                .values()
+                .filter(|node| node.id != cluster.myself && !node.handshake)
+                .map(|node| Gossip {
+                    id: node.id.clone(),
+                    ip: node.ip.clone(),
+                    port: node.port,
+                    cport: node.cport,
+                    pfail: node.pfail,
+                    fail: node.fail,
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        Message {
+            kind,
+            sender: myself.id.clone(),
+            port: myself.port,
+            cport: myself.cport,
+            master: myself.master.clone(),
+            current_epoch: cluster.current_epoch,
+            config_epoch: cluster.nodes.get(&master).map_or(0, |node| node.config_epoch),
+            offset: self.master_repl_offset,
+            slots: cluster.slot_ranges(&master),
+            failed: None,
+            gossip,
+        }
+    }
+
+    /// Whether the link `serial` is still wanted.
+    pub fn cluster_link_alive(&self, serial: u64) -> bool {
+        match self.cluster {
+            Some(ref cluster) => cluster.link_node(serial).is_some(),
+            None => false,
+        }
+    }
+
+    /// Drops the link `serial` after its connection was lost. The cron opens
+    /// a new one.
+    pub fn cluster_link_lost(&mut self, serial: u64) {
+        if let Some(ref mut cluster) = self.cluster {
+            if let Some(id) = cluster.link_node(serial) {
+                cluster.links.remove(&id);
+            }
+        }
+    }
+
+    /// Periodic cluster tasks: connects to the nodes and pings them, flags
+    /// the ones that do not answer, and runs the election of this node if
+    /// its master failed.
+    /// Returns the links to connect, with their serial, the address of the
+    /// cluster bus and the receiver of the messages to send on them.
+    pub fn cluster_cron(&mut self) -> Vec<(u64, String, u16, Receiver<Vec<u8>>)> {
+        if self.cluster.is_none() {
+            return vec![];
+        }
+        let now = mstime();
+        let node_timeout = self.config.cluster_node_timeout as i64;
+        let mut connect = vec![];
+        let mut ping = vec![];
+        let mut pfail = vec![];
+        {
+            let cluster = self.cluster.as_mut().unwrap();
+            cluster.blacklist.retain(|_, until| *until > now);
+            let ids = cluster
+                .nodes
+                .keys()
+                .filter(|id| **id != cluster.myself)
+                .cloned()
+                .collect::<Vec<_>>();
+            for id in ids {
+                let node = cluster.nodes.get_mut(&id).unwrap();
+                if node.handshake && now - node.ctime > max(node_timeout, 1000) {
+                    log!(self.config.logger, Verbose, "Handshake with {}:{} timed out", node.ip, node.port);
+                    cluster.nodes.remove(&id);
+                    cluster.links.remove(&id);
+                    continue;
+                }
+                if node.ip.is_empty() {
+                    continue;
+                }
+                // a connection waiting too long for a pong may be stuck
+                let stuck = node.ping_sent != 0 && now - node.ping_sent > node_timeout / 2;
+                if stuck && cluster.links.get(&id).map_or(false, |link| now - link.ctime > node_timeout) {
+                    cluster.links.remove(&id);
+                }
+                if !cluster.links.contains_key(&id) {
+                    let (sender, receiver) = channel();
+                    cluster.link_counter += 1;
+                    let link = Link {
+                        serial: cluster.link_counter,
+                        sender,
+                        ctime: now,
+                    };
+                    cluster.links.insert(id.clone(), link);
+                    connect.push((cluster.link_counter, node.ip.clone(), node.cport, receiver));
+                    // a new connection always starts with a ping
+                    ping.push(id.clone());
+                } else if node.ping_sent == 0 && now - node.pong_received > PING_PERIOD_MS {
+                    ping.push(id.clone());
+                }
+                if node.ping_sent != 0 && now - node.ping_sent > node_timeout && !node.pfail && !node.fail {
+                    log!(self.config.logger, Notice, "*** NODE {} possibly failing", id);
+                    node.pfail = true;
+                    pfail.push(id);
+                }
+            }
+        }
+        for id in ping {
+            let handshake = self.cluster.as_ref().unwrap().nodes[&id].handshake;
+            let message = self.cluster_message(if handshake { MessageType::Meet } else { MessageType::Ping });
+            let cluster = self.cluster.as_mut().unwrap();
+            let node = cluster.nodes.get_mut(&id).unwrap();
+            if node.ping_sent == 0 {
+                node.ping_sent = now;
+            }
+            cluster.send(&id, &message);
+        }
+        for id in pfail {
+            self.cluster_mark_failing(&id);
+        }
+        self.cluster_failover_cron();
+        self.cluster_save_if_needed();
+        connect
+    }
+
+    /// Handles a message received from the node at `ip`, which reached this
+    /// node at `local_ip`. `serial` is the link the message arrived on, if
+    /// this node opened the connection.
+    /// Returns the reply to send back on the same connection.
+    pub fn cluster_process(
+        &mut self,
+        message: &Message,
+        serial: Option<u64>,
+        ip: &str,
+        local_ip: &str,
+    ) -> Option<Message> {
+        let now = mstime();
+        let node_timeout = self.config.cluster_node_timeout as i64;
+        let mut failing = vec![];
+        let mut claim = None;
+        {
+            let cluster = self.cluster.as_mut()?;
+            let kind = message.kind;
+            if kind == MessageType::Meet || (kind == MessageType::Ping && cluster.myself().ip.is_empty()) {
+                // the address the other node used is ours
+                let myself = cluster.myself.clone();
+                let node = cluster.nodes.get_mut(&myself).unwrap();
+                if node.ip != local_ip {
+                    node.ip = local_ip.to_owned();
+                    cluster.todo_save = true;
+                }
+            }
+            if kind == MessageType::Meet && message.sender != cluster.myself && !cluster.nodes.contains_key(&message.sender) {
+                let mut node = ClusterNode::new(message.sender.clone(), ip.to_owned(), message.port);
+                node.cport = message.cport;
+                cluster.nodes.insert(node.id.clone(), node);
+                cluster.todo_save = true;
+            }
+            // the first pong of a node met by address tells its identifier
+            let handshake = serial
+                .and_then(|serial| cluster.link_node(serial))
+                .filter(|id| cluster.nodes.get(id).map_or(false, |node| node.handshake));
+            if let (MessageType::Pong, Some(id)) = (kind, handshake) {
+                let node = cluster.nodes.remove(&id).unwrap();
+                let link = cluster.links.remove(&id).unwrap();
+                if message.sender != cluster.myself && !cluster.nodes.contains_key(&message.sender) {
+                    log!(
+                        self.config.logger,
+                        Notice,
+                        "Handshake with node {}:{} completed, its identifier is {}",
+                        node.ip,
+                        node.port,
+                        message.sender
+                    );
+                    let mut node = node;
+                    node.id = message.sender.clone();
+                    node.handshake = false;
+                    cluster.nodes.insert(node.id.clone(), node);
+                    cluster.links.insert(message.sender.clone(), link);
+                }
+                cluster.todo_save = true;
+            }
+
+            if message.sender != cluster.myself && cluster.nodes.contains_key(&message.sender) {
+                if message.current_epoch > cluster.current_epoch {
+                    cluster.current_epoch = message.current_epoch;
+                    cluster.todo_save = true;
+                }
+                if kind.is_ping() {
+                    let node = cluster.nodes.get_mut(&message.sender).unwrap();
+                    node.repl_offset = message.offset;
+                    if node.ip.is_empty() {
+                        node.ip = ip.to_owned();
+                    }
+                    if kind == MessageType::Pong {
+                        node.pong_received = now;
+                        node.ping_sent = 0;
+                        node.pfail = false;
+                    }
+                    if cluster.clear_failure(&message.sender, node_timeout) {
+                        log!(self.config.logger, Notice, "Clear FAIL state for node {}", message.sender);
+                    }
+                    match message.master {
+                        None => {
+                            let node = cluster.nodes.get_mut(&message.sender).unwrap();
+                            if node.master.take().is_some() {
+                                cluster.todo_save = true;
+                            }
+                            if node.config_epoch != message.config_epoch {
+                                node.config_epoch = message.config_epoch;
+                                cluster.todo_save = true;
+                            }
+                            claim = Some((message.sender.clone(), message.slots.clone(), message.config_epoch));
+                        }
+                        Some(ref master) => {
+                            if cluster.nodes[&message.sender].master.as_ref() != Some(master) {
+                                // a master that became a replica gives up its slots
+                                for owner in cluster.slots.iter_mut() {
+                                    if owner.as_ref() == Some(&message.sender) {
+                                        *owner = None;
+                                    }
+                                }
+                                cluster.nodes.get_mut(&message.sender).unwrap().master = Some(master.clone());
+                                cluster.todo_save = true;
+                            }
+                        }
+                    }
+
+                    // only masters take part in the failure detection
+                    let reporter = message.master.is_none();
+                    for gossip in &message.gossip {
+                        if gossip.id == cluster.myself {
+                            continue;
+                        }
+                        match cluster.nodes.get_mut(&gossip.id) {
+                            Some(node) => {
+                                if !reporter {
+                                    continue;
+                                }
+                                if gossip.pfail || gossip.fail {
+                                    node.fail_reports.insert(message.sender.clone(), now);
+                                    failing.push(gossip.id.clone());
+                                } else {
+                                    node.fail_reports.remove(&message.sender);
+                                }
+                            }
+                            None => {
+                                if gossip.ip.is_empty() || cluster.blacklist.contains_key(&gossip.id) {
+                                    continue;
+                                }
+                                log!(self.config.logger, Notice, "Discovered node {} at {}:{}", gossip.id, gossip.ip, gossip.port);
+                                let mut node = ClusterNode::new(gossip.id.clone(), gossip.ip.clone(), gossip.port);
+                                node.cport = gossip.cport;
+                                cluster.nodes.insert(node.id.clone(), node);
+                                cluster.todo_save = true;
+                            }
+                        }
+                    }
+                }
+                if let Some(ref failed) = message.failed {
+                    if let Some(node) = cluster.nodes.get_mut(failed) {
+                        if *failed != cluster.myself && !node.fail {
+                            log!(
+                                self.config.logger,
+                                Notice,
+                                "FAIL message received from {} about {}",
+                                message.sender,
+                                failed
+                            );
+                            node.fail = true;
+                            node.fail_time = now;
+                            node.pfail = false;
+                            cluster.todo_save = true;
+                        }
+                    }
+                }
+                if kind == MessageType::AuthAck
+                    && cluster.failover_auth_sent
+                    && message.current_epoch >= cluster.failover_auth_epoch
+                    && message.master.is_none()
+                    && !message.slots.is_empty()
+                {
+                    cluster.failover_auth_count += 1;
+                }
+            }
+        }
+        if let Some((sender, slots, config_epoch)) = claim {
+            self.cluster_update_slots(&sender, &slots, config_epoch);
+            self.cluster_epoch_collision(&sender);
+        }
+        for id in failing {
+            self.cluster_mark_failing(&id);
+        }
+        let reply = match message.kind {
+            MessageType::Ping | MessageType::Meet => Some(self.cluster_message(MessageType::Pong)),
+            MessageType::AuthRequest => self.cluster_vote(message),
+            _ => None,
+        };
+        self.cluster_save_if_needed();
+        reply
+    }
+
+    /// Applies the `slots` claimed by the master `sender`. A claim wins over
+    /// the one of a node with an older config epoch. If this node, or its
+    /// master, loses its last slot to the sender, it becomes its replica.
+    fn cluster_update_slots(&mut self, sender: &str, slots: &[(u16, u16)], config_epoch: u64) {
+        let new_master = {
+            let cluster = self.cluster.as_mut().unwrap();
+            let my_master = cluster.my_master();
+            let mut lost = false;
+            for &(start, end) in slots {
+                for slot in start..=end {
+                    let owner = cluster.slots[slot as usize].clone();
+                    if owner.as_ref().map(|id| id == sender) == Some(true) || cluster.importing.contains_key(&slot) {
+                        continue;
+                    }
+                    let owner_epoch = owner
+                        .as_ref()
+                        .and_then(|id| cluster.nodes.get(id))
+                        .map(|node| node.config_epoch);
+                    if owner_epoch.map_or(false, |epoch| epoch >= config_epoch) {
+                        continue;
+                    }
+                    if owner.as_ref() == Some(&my_master) {
+                        lost = true;
+                    }
+                    cluster.slots[slot as usize] = Some(sender.to_owned());
+                    cluster.migrating.remove(&slot);
+                    cluster.todo_save = true;
+                }
+            }
+            if lost && cluster.slot_ranges(&my_master).is_empty() {
+                let myself = cluster.myself.clone();
+                cluster.nodes.get_mut(&myself).unwrap().master = Some(sender.to_owned());
+                let master = &cluster.nodes[sender];
+                Some((master.ip.clone(), master.port))
+            } else {
+                None
+            }
+        };
+        if let Some((ip, port)) = new_master {
+            log!(
+                self.config.logger,
+                Notice,
+                "Configuration change detected. Reconfiguring myself as a replica of {}",
+                sender
+            );
+            self.repl_set_master(ip, port);
+        }
+    }
+
+    /// Two masters with the same config epoch could claim the same slots
+    /// without a winner. The one with the lower identifier takes a new
+    /// epoch.
+    fn cluster_epoch_collision(&mut self, sender: &str) {
+        let cluster = self.cluster.as_mut().unwrap();
+        let myself = cluster.myself.clone();
+        let epoch = cluster.nodes[sender].config_epoch;
+        let node = cluster.nodes.get_mut(&myself).unwrap();
+        if node.master.is_some() || node.config_epoch != epoch || myself.as_str() >= sender {
+            return;
+        }
+        cluster.current_epoch += 1;
+        node.config_epoch = cluster.current_epoch;
+        cluster.todo_save = true;
+        log!(
+            self.config.logger,
+            Verbose,
+            "WARNING: configEpoch collision with node {}. configEpoch set to {}",
+            sender,
+            cluster.current_epoch
+        );
+    }
+
+    /// Marks the node `id` as failed once a majority of the masters,
+    /// counting this one, reported it as unreachable, and tells every node.
+    fn cluster_mark_failing(&mut self, id: &str) {
+        let now = mstime();
+        let validity = self.config.cluster_node_timeout as i64 * 2;
+        {
+            let cluster = self.cluster.as_mut().unwrap();
+            let quorum = cluster.size() / 2 + 1;
+            let voter = cluster.myself().master.is_none();
+            let node = match cluster.nodes.get_mut(id) {
+                Some(node) => node,
+                None => return,
+            };
+            if !node.pfail || node.fail {
+                return;
+            }
+            node.fail_reports.retain(|_, time| now - *time <= validity);
+            if node.fail_reports.len() + (voter as usize) < quorum {
+                return;
+            }
+            log!(self.config.logger, Notice, "Marking node {} as failing (quorum reached).", id);
+            node.pfail = false;
+            node.fail = true;
+            node.fail_time = now;
+            cluster.todo_save = true;
+        }
+        let mut message = self.cluster_message(MessageType::Fail);
+        message.failed = Some(id.to_owned());
+        self.cluster.as_mut().unwrap().broadcast(&message);
+    }
+
+    /// Answers the `request` of a replica to replace its failed master. A
+    /// master votes once per epoch, and for one replica of a given master
+    /// every two node timeouts.
+    fn cluster_vote(&mut self, request: &Message) -> Option<Message> {
+        let now = mstime();
+        let node_timeout = self.config.cluster_node_timeout as i64;
+        {
+            let cluster = self.cluster.as_mut()?;
+            if cluster.myself().master.is_some() || cluster.slot_ranges(&cluster.myself).is_empty() {
+                return None;
+            }
+            if request.current_epoch < cluster.current_epoch || cluster.last_vote_epoch == cluster.current_epoch {
+                return None;
+            }
+            let master = request.master.as_ref()?;
+            match cluster.nodes.get(master) {
+                Some(master) if master.fail && now - master.voted_time >= node_timeout * 2 => (),
+                _ => return None,
+            }
+            // the replica must know the latest owners of its master's slots
+            for &(start, end) in &request.slots {
+                for slot in start..=end {
+                    let owner = cluster.slots[slot as usize].as_ref().and_then(|id| cluster.nodes.get(id));
+                    if owner.map_or(false, |owner| owner.config_epoch > request.config_epoch) {
+                        return None;
+                    }
+                }
+            }
+            cluster.last_vote_epoch = cluster.current_epoch;
+            cluster.nodes.get_mut(master).unwrap().voted_time = now;
+            log!(
+                self.config.logger,
+                Notice,
+                "Failover auth granted to {} for epoch {}",
+                request.sender,
+                cluster.current_epoch
+            );
+        }
+        // the vote must not be forgotten if this node restarts
+        if let Err(err) = self.cluster_save_config() {
+            log!(self.config.logger, Warning, "Could not save the cluster config file: {}", err);
+        }
+        Some(self.cluster_message(MessageType::AuthAck))
+    }
+
+    /// Runs the election of this replica when its master failed. Replicas
+    /// with less data wait longer before asking the masters for their
+    /// votes, and the first one with a majority takes over the slots.
+    fn cluster_failover_cron(&mut self) {
+        let now = mstime();
+        let node_timeout = self.config.cluster_node_timeout as i64;
+        let offset = self.master_repl_offset;
+        let request = {
+            let cluster = self.cluster.as_mut().unwrap();
+            let master = match cluster.myself().master.as_ref().and_then(|id| cluster.nodes.get(id)) {
+                Some(master) if master.fail && !cluster.slot_ranges(&master.id).is_empty() => master.id.clone(),
+                _ => {
+                    cluster.failover_auth_time = 0;
+                    return;
+                }
+            };
+            let auth_timeout = max(node_timeout * 2, 2000);
+            if cluster.failover_auth_time == 0 || now - cluster.failover_auth_time > auth_timeout * 2 {
+                let rank = cluster
+                    .replicas(&master)
+                    .iter()
+                    .filter(|replica| replica.id != cluster.myself && replica.repl_offset > offset)
+                    .count() as i64;
+                let delay = 500 + (rand::random::<u64>() % 500) as i64 + rank * 1000;
+                cluster.failover_auth_time = now + delay;
+                cluster.failover_auth_sent = false;
+                cluster.failover_auth_count = 0;
+                log!(
+                    self.config.logger,
+                    Notice,
+                    "Start of election delayed for {} milliseconds (rank #{}, offset {}).",
+                    delay,
+                    rank,
+                    offset
+                );
+                return;
+            }
+            if now < cluster.failover_auth_time || now - cluster.failover_auth_time > auth_timeout {
+                return;
+            }
+            if cluster.failover_auth_sent {
+                if cluster.failover_auth_count < cluster.size() / 2 + 1 {
+                    return;
+                }
+                false
+            } else {
+                cluster.current_epoch += 1;
+                cluster.failover_auth_epoch = cluster.current_epoch;
+                cluster.failover_auth_sent = true;
+                cluster.todo_save = true;
+                log!(
+                    self.config.logger,
+                    Notice,
+                    "Starting a failover election for epoch {}.",
+                    cluster.current_epoch
+                );
+                true
+            }
+        };
+        if request {
+            self.cluster_save_if_needed();
+            let message = self.cluster_message(MessageType::AuthRequest);
+            self.cluster.as_mut().unwrap().broadcast(&message);
+        } else {
+            self.cluster_failover();
+        }
+    }
+
+    /// Takes over the slots of the failed master after winning the election.
+    fn cluster_failover(&mut self) {
+        {
+            let cluster = self.cluster.as_mut().unwrap();
+            let myself = cluster.myself.clone();
+            let epoch = cluster.failover_auth_epoch;
+            let node = cluster.nodes.get_mut(&myself).unwrap();
+            let old = node.master.take().unwrap();
+            node.config_epoch = max(node.config_epoch, epoch);
+            for owner in cluster.slots.iter_mut() {
+                if owner.as_ref() == Some(&old) {
+                    *owner = Some(myself.clone());
+                }
+            }
+            cluster.failover_auth_time = 0;
+            cluster.todo_save = true;
+        }
+        log!(self.config.logger, Notice, "Failover election won. I'm the new master.");
+        self.repl_unset_master();
+        self.cluster_save_if_needed();
+        // everyone learns about the new owner of the slots right away
+        let message = self.cluster_message(MessageType::Pong);
+        self.cluster.as_mut().unwrap().broadcast(&message);
+    }
+
+    /// Adds `key` to the index of its slot. Only the database 0 is indexed,
+    /// since it is the only one available in cluster mode.
+    pub fn slot_key_added(&mut self, index: usize, key: &[u8]) {
+        if index == 0 && !self.slot_keys.is_empty() {
+            self.slot_keys[key_hash_slot(key) as usize].insert(key.to_vec());
+        }
+    }
+
+    /// Removes `key` from the index of its slot.
+    pub fn slot_key_removed(&mut self, index: usize, key: &[u8]) {
+        if index == 0 && !self.slot_keys.is_empty() {
+            self.slot_keys[key_hash_slot(key) as usize].remove(key);
+        }
+    }
+
+    /// Empties the index of every slot.
+    pub fn slot_keys_clear(&mut self, index: usize) {
+        if index == 0 {
+            for keys in self.slot_keys.iter_mut() {
+                keys.clear();
+            }
+        }
+    }
+
+    /// Number of keys in `slot`.
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.slot_keys.get(slot as usize).map_or(0, |keys| keys.len())
+    }
+
+    /// Up to `count` keys in `slot`.
+    pub fn get_keys_in_slot(&self, slot: u16, count: usize) -> Vec<Vec<u8>> {
+        self.slot_keys
+            .get(slot as usize)
+            .map_or(vec![], |keys| keys.iter().take(count).cloned().collect())
+    }
+
+    /// Checks whether this node can execute a command on `keys` of the
+    /// database `index`, returning the error to reply otherwise.
+    /// The keys must share a slot. If it is served by another node the
+    /// client is sent there with MOVED. While the slot is migrating, keys
+    /// that are no longer here are looked up on the target node with ASK.
+    /// `asking` is set for the command following an ASKING, which may use
+    /// an importing slot, and `readonly` if the command only reads and the
+    /// client accepts data from a replica.
+    pub fn cluster_check(
+        &self,
+        index: usize,
+        keys: &[Vec<u8>],
+        asking: bool,
+        readonly: bool,
+    ) -> Result<(), String> {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+        let slot = match keys.first() {
+            Some(key) => key_hash_slot(key),
+            None => return Ok(()),
+        };
+        if keys.iter().any(|key| key_hash_slot(key) != slot) {
+            return Err("CROSSSLOT Keys in request don't hash to the same slot".to_owned());
+        }
+        let node = match cluster.slot_node(slot) {
+            Some(node) => node,
+            None => return Err("CLUSTERDOWN Hash slot not served".to_owned()),
+        };
+        if node.fail {
+            return Err("CLUSTERDOWN The cluster is down".to_owned());
+        }
+        let missing = || keys.iter().filter(|key| self.get(index, key).is_none()).count();
+
+        if node.id == cluster.myself {
+            if let Some(target) = cluster.migrating.get(&slot).and_then(|id| cluster.nodes.get(id)) {
+                if missing() > 0 {
+                    return Err(format!("ASK {} {}", slot, target.addr()));
+                }
+            }
+            return Ok(());
+        }
+        if asking && cluster.importing.contains_key(&slot) {
+            if keys.len() > 1 && missing() > 0 {
+                return Err("TRYAGAIN Multiple keys request during rehashing of slot".to_owned());
+            }
+            return Ok(());
+        }
+        if readonly && cluster.myself().master.as_ref() == Some(&node.id) {
+            return Ok(());
+        }
+        Err(format!("MOVED {} {}", slot, node.addr()))
+    }
+}
+
+#[cfg(test)]
+mod test_cluster {
+    use super::super::Database;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+
+    use parser::parse;
+
+    use super::{crc16, key_hash_slot, Cluster, ClusterNode, Gossip, Message, MessageType};
+
+    fn cluster_db() -> Database {
+        let mut db = Database::mock();
+        let mut cluster = Cluster::new(7000);
+        let other = ClusterNode::new("b".repeat(40), "127.0.0.1".to_owned(), 7001);
+        cluster.nodes.insert(other.id.clone(), other);
+        for slot in 0..8192 {
+            cluster.slots[slot] = Some(cluster.myself.clone());
+        }
+        for slot in 8192..16384 {
+            cluster.slots[slot] = Some("b".repeat(40));
+        }
+        db.cluster = Some(cluster);
+        db
+    }
+
+    /// A node listening on `port`, saving its configuration in a file of its
+    /// own.
+    fn bus_db(name: &str, port: u16) -> Database {
+        let mut path = temp_dir();
+        path.push(format!("rsedis-test-nodes-{}-{}.conf", name, port));
+        let mut db = Database::mock();
+        db.config.cluster_config_file = path.to_str().unwrap().to_owned();
+        db.config.cluster_node_timeout = 1000;
+        db.cluster = Some(Cluster::new(port));
+        db
+    }
+
+    fn myself(db: &Database) -> String {
+        db.cluster.as_ref().unwrap().myself.clone()
+    }
+
+    /// Adds the node of `other` to the nodes of `db`.
+    fn add_node(db: &mut Database, other: &Database, master: Option<&Database>) {
+        let cluster = other.cluster.as_ref().unwrap();
+        let mut node = cluster.myself().clone();
+        node.ip = "127.0.0.1".to_owned();
+        node.master = master.map(myself);
+        db.cluster.as_mut().unwrap().nodes.insert(node.id.clone(), node);
+    }
+
+    fn assign(db: &mut Database, id: &str, slots: ::std::ops::Range<usize>) {
+        for slot in slots {
+            db.cluster.as_mut().unwrap().slots[slot] = Some(id.to_owned());
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let message = Message {
+            kind: MessageType::Ping,
+            sender: "a".repeat(40),
+            port: 7000,
+            cport: 17000,
+            master: Some("b".repeat(40)),
+            current_epoch: 5,
+            config_epoch: 3,
+            offset: 1234,
+            slots: vec![(0, 100), (200, 200)],
+            failed: None,
+            gossip: vec![Gossip {
+                id: "c".repeat(40),
+                ip: "127.0.0.1".to_owned(),
+                port: 7002,
+                cport: 17002,
+                pfail: true,
+                fail: false,
+            }],
+        };
+        let data = message.encode();
+        let (parser, len) = parse(&data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(Message::decode(&parser), Some(message));
+        let (parser, _) = parse(b"*2\r\n$4\r\nping\r\n$1\r\na\r\n").unwrap();
+        assert_eq!(Message::decode(&parser), None);
+    }
+
+    #[test]
+    fn meet_handshake() {
+        let mut a = bus_db("meet", 7000);
+        let mut b = bus_db("meet", 7001);
+        a.cluster.as_mut().unwrap().meet("127.0.0.1", 7001, 17001).unwrap();
+        let links = a.cluster_cron();
+        assert_eq!(links.len(), 1);
+        let (serial, ref ip, cport, ref rx) = links[0];
+        assert_eq!((&**ip, cport), ("127.0.0.1", 17001));
+
+        let data = rx.try_recv().unwrap();
+        let meet = Message::decode(&parse(&data).unwrap().0).unwrap();
+        assert_eq!(meet.kind, MessageType::Meet);
+        let pong = b.cluster_process(&meet, None, "127.0.0.1", "127.0.0.1").unwrap();
+        assert_eq!(pong.kind, MessageType::Pong);
+        assert!(b.cluster.as_ref().unwrap().nodes.contains_key(&myself(&a)));
+        assert_eq!(b.cluster.as_ref().unwrap().myself().ip, "127.0.0.1");
+
+        assert_eq!(a.cluster_process(&pong, Some(serial), "127.0.0.1", "127.0.0.1"), None);
+        let cluster = a.cluster.as_ref().unwrap();
+        let node = &cluster.nodes[&myself(&b)];
+        assert!(!node.handshake);
+        assert_eq!(node.ping_sent, 0);
+        assert_eq!(cluster.nodes.len(), 2);
+        assert!(a.cluster_link_alive(serial));
+    }
+
+    #[test]
+    fn failure_detection() {
+        let mut a = bus_db("failure", 7000);
+        let b = bus_db("failure", 7001);
+        let c = bus_db("failure", 7002);
+        add_node(&mut a, &b, None);
+        add_node(&mut a, &c, None);
+        let (ida, idb, idc) = (myself(&a), myself(&b), myself(&c));
+        assign(&mut a, &ida, 0..5000);
+        assign(&mut a, &idb, 5000..10000);
+        assign(&mut a, &idc, 10000..16384);
+
+        // c stops answering the pings of a
+        a.cluster.as_mut().unwrap().nodes.get_mut(&idc).unwrap().ping_sent = 1;
+        a.cluster_cron();
+        assert!(a.cluster.as_ref().unwrap().nodes[&idc].pfail);
+        assert!(!a.cluster.as_ref().unwrap().nodes[&idc].fail);
+        assert!(a.cluster.as_ref().unwrap().state_ok());
+
+        // b agrees, which makes a majority of the three masters
+        let mut ping = b.cluster_message(MessageType::Ping);
+        ping.gossip = vec![Gossip {
+            id: idc.clone(),
+            ip: "127.0.0.1".to_owned(),
+            port: 7002,
+            cport: 17002,
+            pfail: true,
+            fail: false,
+        }];
+        assert!(a.cluster_process(&ping, None, "127.0.0.1", "127.0.0.1").is_some());
+        let cluster = a.cluster.as_ref().unwrap();
+        assert!(cluster.nodes[&idc].fail);
+        assert!(!cluster.state_ok());
+        assert_eq!(
+            a.cluster_check(0, &[b"foo".to_vec()], false, false),
+            Err("CLUSTERDOWN The cluster is down".to_owned())
+        );
+    }
+
+    #[test]
+    fn fail_message() {
+        let mut a = bus_db("fail", 7000);
+        let b = bus_db("fail", 7001);
+        let c = bus_db("fail", 7002);
+        add_node(&mut a, &b, None);
+        add_node(&mut a, &c, None);
+        let mut fail = b.cluster_message(MessageType::Fail);
+        fail.failed = Some(myself(&c));
+        assert_eq!(a.cluster_process(&fail, None, "127.0.0.1", "127.0.0.1"), None);
+        assert!(a.cluster.as_ref().unwrap().nodes[&myself(&c)].fail);
+    }
+
+    #[test]
+    fn vote() {
+        let mut a = bus_db("vote", 7000);
+        let b = bus_db("vote", 7001);
+        let mut r = bus_db("vote", 7002);
+        add_node(&mut a, &b, None);
+        add_node(&mut a, &r, Some(&b));
+        add_node(&mut r, &b, None);
+        let (ida, idb) = (myself(&a), myself(&b));
+        assign(&mut a, &ida, 0..8192);
+        assign(&mut a, &idb, 8192..16384);
+        assign(&mut r, &idb, 8192..16384);
+        r.cluster.as_mut().unwrap().replicate(&idb).unwrap();
+
+        let mut request = r.cluster_message(MessageType::AuthRequest);
+        request.current_epoch = 1;
+        assert_eq!(request.slots, vec![(8192, 16383)]);
+        // the master of the replica did not fail
+        assert_eq!(a.cluster_process(&request, None, "127.0.0.1", "127.0.0.1"), None);
+
+        a.cluster.as_mut().unwrap().nodes.get_mut(&idb).unwrap().fail = true;
+        request.current_epoch = 2;
+        let ack = a.cluster_process(&request, None, "127.0.0.1", "127.0.0.1").unwrap();
+        assert_eq!(ack.kind, MessageType::AuthAck);
+        assert_eq!(a.cluster.as_ref().unwrap().last_vote_epoch, 2);
+        // a single vote per epoch
+        assert_eq!(a.cluster_process(&request, None, "127.0.0.1", "127.0.0.1"), None);
+    }
+
+    #[test]
+    fn failover() {
+        let mut a = bus_db("failover", 7000);
+        let b = bus_db("failover", 7001);
+        let mut c = bus_db("failover", 7002);
+        let mut r = bus_db("failover", 7003);
+        let (ida, idb, idc, idr) = (myself(&a), myself(&b), myself(&c), myself(&r));
+        for db in vec![&mut a, &mut c, &mut r] {
+            add_node(db, &b, None);
+            assign(db, &ida, 0..5000);
+            assign(db, &idb, 5000..10000);
+            assign(db, &idc, 10000..16384);
+            db.cluster.as_mut().unwrap().nodes.get_mut(&idb).unwrap().fail = true;
+        }
+        add_node(&mut r, &a, None);
+        add_node(&mut r, &c, None);
+        add_node(&mut a, &c, None);
+        add_node(&mut a, &r, Some(&b));
+        add_node(&mut c, &a, None);
+        add_node(&mut c, &r, Some(&b));
+        r.cluster.as_mut().unwrap().replicate(&idb).unwrap();
+
+        // the election starts after a random delay
+        r.cluster_cron();
+        let auth_time = r.cluster.as_ref().unwrap().failover_auth_time;
+        assert!(auth_time > 0);
+        r.cluster.as_mut().unwrap().failover_auth_time -= 1000;
+        r.cluster_cron();
+        let cluster = r.cluster.as_ref().unwrap();
+        assert!(cluster.failover_auth_sent);
+        assert_eq!(cluster.failover_auth_epoch, 1);
+
+        // one vote is not a majority of the three masters, two are
+        let request = r.cluster_message(MessageType::AuthRequest);
+        let ack = a.cluster_process(&request, None, "127.0.0.1", "127.0.0.1").unwrap();
+        assert_eq!(r.cluster_process(&ack, None, "127.0.0.1", "127.0.0.1"), None);
+        r.cluster_cron();
+        assert_eq!(r.cluster.as_ref().unwrap().myself().master, Some(idb.clone()));
+        let ack = c.cluster_process(&request, None, "127.0.0.1", "127.0.0.1").unwrap();
+        r.cluster_process(&ack, None, "127.0.0.1", "127.0.0.1");
+        r.cluster_cron();
+        let cluster = r.cluster.as_ref().unwrap();
+        assert_eq!(cluster.myself().master, None);
+        assert_eq!(cluster.myself().config_epoch, 1);
+        assert_eq!(cluster.slot_node(5000).unwrap().id, idr);
+        assert!(r.master.is_none());
+
+        // its pong announces the new owner of the slots
+        let pong = r.cluster_message(MessageType::Pong);
+        a.cluster_process(&pong, None, "127.0.0.1", "127.0.0.1");
+        assert_eq!(a.cluster.as_ref().unwrap().slot_node(5000).unwrap().id, idr);
+        assert_eq!(a.cluster.as_ref().unwrap().nodes[&idr].master, None);
+    }
+
+    #[test]
+    fn newer_claim_makes_replica() {
+        let mut a = bus_db("claim", 7000);
+        let b = bus_db("claim", 7001);
+        add_node(&mut a, &b, None);
+        let (ida, idb) = (myself(&a), myself(&b));
+        assign(&mut a, &ida, 0..100);
+        a.cluster.as_mut().unwrap().nodes.get_mut(&ida).unwrap().config_epoch = 1;
+
+        let mut ping = b.cluster_message(MessageType::Ping);
+        ping.slots = vec![(0, 99)];
+        ping.config_epoch = 1;
+        a.cluster_process(&ping, None, "127.0.0.1", "127.0.0.1");
+        // same epoch: the current owner keeps the slots
+        assert_eq!(a.cluster.as_ref().unwrap().slot_node(0).unwrap().id, ida);
+
+        ping.config_epoch = 2;
+        a.cluster_process(&ping, None, "127.0.0.1", "127.0.0.1");
+        let cluster = a.cluster.as_ref().unwrap();
+        assert_eq!(cluster.slot_node(0).unwrap().id, idb);
+        assert_eq!(cluster.myself().master, Some(idb));
+        assert_eq!(a.master.as_ref().unwrap().port, 7001);
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn hash_slot() {
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"bar"), 5061);
+        assert_eq!(key_hash_slot(b"{user1000}.following"), key_hash_slot(b"user1000"));
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+        // an empty tag hashes the whole key
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & 16383);
+        assert_eq!(key_hash_slot(b"foo{bar"), crc16(b"foo{bar") & 16383);
+    }
+
+    #[test]
+    fn check_slots() {
+        let db = cluster_db();
+        assert_eq!(db.cluster_check(0, &[], false, false), Ok(()));
+        assert_eq!(db.cluster_check(0, &[b"bar".to_vec()], false, false), Ok(()));
+        assert_eq!(
+            db.cluster_check(0, &[b"foo".to_vec()], false, false),
+            Err("MOVED 12182 127.0.0.1:7001".to_owned())
+        );
+        assert_eq!(
+            db.cluster_check(0, &[b"bar".to_vec(), b"foo".to_vec()], false, false),
+            Err("CROSSSLOT Keys in request don't hash to the same slot".to_owned())
+        );
+        assert_eq!(
+            db.cluster_check(0, &[b"{bar}1".to_vec(), b"{bar}2".to_vec()], false, false),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn check_unassigned() {
+        let mut db = cluster_db();
+        db.cluster.as_mut().unwrap().slots[5061] = None;
+        assert_eq!(
+            db.cluster_check(0, &[b"bar".to_vec()], false, false),
+            Err("CLUSTERDOWN Hash slot not served".to_owned())
+        );
+    }
+
+    #[test]
+    fn check_migrating() {
+        let mut db = cluster_db();
+        db.cluster.as_mut().unwrap().migrating.insert(5061, "b".repeat(40));
+        db.get_or_create(0, b"{bar}1").set(b"1".to_vec()).unwrap();
+        assert_eq!(db.cluster_check(0, &[b"{bar}1".to_vec()], false, false), Ok(()));
+        assert_eq!(
+            db.cluster_check(0, &[b"{bar}1".to_vec(), b"{bar}2".to_vec()], false, false),
+            Err("ASK 5061 127.0.0.1:7001".to_owned())
+        );
+    }
+
+    #[test]
+    fn check_importing() {
+        let mut db = cluster_db();
+        db.cluster.as_mut().unwrap().importing.insert(12182, "b".repeat(40));
+        db.get_or_create(0, b"{foo}1").set(b"1".to_vec()).unwrap();
+        assert_eq!(
+            db.cluster_check(0, &[b"foo".to_vec()], false, false),
+            Err("MOVED 12182 127.0.0.1:7001".to_owned())
+        );
+        assert_eq!(db.cluster_check(0, &[b"foo".to_vec()], true, false), Ok(()));
+        assert_eq!(
+            db.cluster_check(0, &[b"{foo}1".to_vec(), b"{foo}2".to_vec()], true, false),
+            Err("TRYAGAIN Multiple keys request during rehashing of slot".to_owned())
+        );
+    }
+
+    #[test]
+    fn check_readonly_replica() {
+        let mut db = cluster_db();
+        {
+            let cluster = db.cluster.as_mut().unwrap();
+            let myself = cluster.myself.clone();
+            cluster.nodes.get_mut(&myself).unwrap().master = Some("b".repeat(40));
+        }
+        assert!(db.cluster_check(0, &[b"foo".to_vec()], false, false).is_err());
+        assert_eq!(db.cluster_check(0, &[b"foo".to_vec()], false, true), Ok(()));
+    }
+
+    #[test]
+    fn slot_ranges() {
+        let mut cluster = Cluster::new(7000);
+        let myself = cluster.myself.clone();
+        cluster.add_slots(&[0, 1, 2, 5, 16383]).unwrap();
+        assert_eq!(cluster.slot_ranges(&myself), vec![(0, 2), (5, 5), (16383, 16383)]);
+        assert_eq!(cluster.slots_assigned(), 5);
+        assert!(!cluster.state_ok());
+    }
+
+    #[test]
+    fn add_del_slots() {
+        let mut cluster = Cluster::new(7000);
+        cluster.add_slots(&[1, 2]).unwrap();
+        assert_eq!(cluster.add_slots(&[3, 2]), Err("ERR Slot 2 is already busy".to_owned()));
+        assert_eq!(cluster.slots[3], None);
+        assert_eq!(
+            cluster.add_slots(&[4, 4]),
+            Err("ERR Slot 4 specified multiple times".to_owned())
+        );
+        cluster.del_slots(&[1]).unwrap();
+        assert_eq!(cluster.del_slots(&[1]), Err("ERR Slot 1 is already unassigned".to_owned()));
+    }
+
+    #[test]
+    fn forget() {
+        let mut db = cluster_db();
+        let cluster = db.cluster.as_mut().unwrap();
+        let myself = cluster.myself.clone();
+        assert!(cluster.forget(&myself).is_err());
+        assert_eq!(cluster.forget("c"), Err("ERR Unknown node c".to_owned()));
+        cluster.forget(&"b".repeat(40)).unwrap();
+        assert_eq!(cluster.nodes.len(), 1);
+        assert_eq!(cluster.slots_assigned(), 8192);
+    }
+
+    #[test]
+    fn set_slot() {
+        let mut db = cluster_db();
+        let cluster = db.cluster.as_mut().unwrap();
+        let other = "b".repeat(40);
+        assert_eq!(
+            cluster.set_slot_migrating(12182, &other),
+            Err("ERR I'm not the owner of hash slot 12182".to_owned())
+        );
+        assert_eq!(
+            cluster.set_slot_importing(5061, &other),
+            Err("ERR I'm already the owner of hash slot 5061".to_owned())
+        );
+        assert_eq!(
+            cluster.set_slot_importing(12182, "c"),
+            Err("ERR I don't know about node c".to_owned())
+        );
+        cluster.set_slot_importing(12182, &other).unwrap();
+        let myself = cluster.myself.clone();
+        cluster.set_slot_node(12182, &myself).unwrap();
+        assert!(cluster.importing.is_empty());
+        assert_eq!(cluster.slot_node(12182).unwrap().id, myself);
+        assert_eq!(cluster.current_epoch, 1);
+        assert_eq!(cluster.myself().config_epoch, 1);
+
+        cluster.set_slot_migrating(5061, &other).unwrap();
+        cluster.set_slot_node(5061, &other).unwrap();
+        assert!(cluster.migrating.is_empty());
+        assert_eq!(cluster.slot_node(5061).unwrap().id, other);
+    }
+
+    #[test]
+    fn config_roundtrip() {
+        let mut db = cluster_db();
+        {
+            let cluster = db.cluster.as_mut().unwrap();
+            cluster.migrating.insert(5061, "b".repeat(40));
+            cluster.importing.insert(12182, "b".repeat(40));
+            cluster.current_epoch = 5;
+            cluster.last_vote_epoch = 3;
+            cluster.meet("127.0.0.1", 7002, 17002).unwrap();
+        }
+        let cluster = db.cluster.as_ref().unwrap();
+        let config = cluster.config();
+        assert!(config.starts_with(&format!(
+            "{} :7000@17000 myself,master - 0 0 0 connected 0-8191 [5061->-{}] [12182-<-{}]\n",
+            cluster.myself,
+            "b".repeat(40),
+            "b".repeat(40)
+        )));
+        assert!(config.ends_with("vars currentEpoch 5 lastVoteEpoch 3\n"));
+
+        let loaded = Cluster::from_config(&config, 7005).unwrap();
+        assert_eq!(loaded.myself, cluster.myself);
+        assert_eq!(loaded.myself().port, 7005);
+        assert_eq!(loaded.slots, cluster.slots);
+        assert_eq!(loaded.migrating, cluster.migrating);
+        assert_eq!(loaded.importing, cluster.importing);
+        assert_eq!(loaded.current_epoch, 5);
+        assert_eq!(loaded.last_vote_epoch, 3);
+        assert_eq!(loaded.nodes.len(), 3);
+        assert!(loaded.nodes.values().any(|node| node.handshake && node.port == 7002));
+
+        assert!(Cluster::from_config("garbage\n", 7000).is_err());
+        assert!(Cluster::from_config("vars currentEpoch 1\n", 7000).is_err());
+    }
+
+    #[test]
+    fn save_load_config() {
+        let mut path = temp_dir();
+        path.push("rsedis-test-nodes.conf");
+        let _ = remove_file(&path);
+        let mut db = cluster_db();
+        db.config.cluster_config_file = path.to_str().unwrap().to_owned();
+        let myself = db.cluster.as_ref().unwrap().myself.clone();
+        // a missing file is created with the current nodes
+        db.cluster_load_config().unwrap();
+        db.cluster.as_mut().unwrap().del_slots(&[0]).unwrap();
+        db.cluster_load_config().unwrap();
+        let cluster = db.cluster.as_ref().unwrap();
+        assert_eq!(cluster.myself, myself);
+        assert_eq!(cluster.slots_assigned(), 16384);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn slot_keys() {
+        let mut db = cluster_db();
+        db.slot_keys = vec![Default::default(); 16384];
+        db.get_or_create(0, b"{foo}1").set(b"1".to_vec()).unwrap();
+        db.get_or_create(0, b"{foo}2").set(b"1".to_vec()).unwrap();
+        db.get_or_create(0, b"{foo}2").set(b"2".to_vec()).unwrap();
+        db.get_or_create(0, b"bar").set(b"1".to_vec()).unwrap();
+        assert_eq!(db.count_keys_in_slot(12182), 2);
+        assert_eq!(db.get_keys_in_slot(12182, 1).len(), 1);
+        let mut keys = db.get_keys_in_slot(12182, 10);
+        keys.sort();
+        assert_eq!(keys, vec![b"{foo}1".to_vec(), b"{foo}2".to_vec()]);
+        db.remove(0, b"{foo}1");
+        assert_eq!(db.get_keys_in_slot(12182, 10), vec![b"{foo}2".to_vec()]);
+        assert_eq!(db.count_keys_in_slot(5061), 1);
+    }
+
+    #[test]
+    fn slot_keys_clear() {
+        let mut db = cluster_db();
+        db.slot_keys = vec![Default::default(); 16384];
+        db.get_or_create(0, b"foo").set(b"1".to_vec()).unwrap();
+        db.get_or_create(0, b"bar").set(b"1".to_vec()).unwrap();
+        db.clear(0);
+        assert_eq!(db.count_keys_in_slot(12182), 0);
+        assert_eq!(db.count_keys_in_slot(5061), 0);
+    }
+}
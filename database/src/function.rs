@@ -0,0 +1,358 @@
+//! Libraries of functions loaded with FUNCTION LOAD. A library is the code
+//! given by the user, starting with a `#!<engine> name=<library>` line; the
+//! engine runs it once to learn which functions it registers, and again
+//! every time one of them is called. The libraries are kept by name, and
+//! the functions of every library share a single namespace.
+use std::collections::{BTreeMap, HashMap};
+
+use logger::Logger;
+use rdbutil::constants::{OPCODE_FUNCTION2, VERSION};
+use rdbutil::crc64::crc64;
+use rdbutil::{decode_slice_u8, decode_u8, encode_slice_u8, encode_u64_to_slice_u8};
+
+/// The only engine libraries can be written for
+pub const ENGINE: &str = "LUA";
+
+/// Flags a function can be registered with
+pub const FLAGS: &[&str] = &["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+/// Runs the body of a library and returns the functions it registers.
+pub type Engine = fn(&[u8], &Logger) -> Result<Vec<FunctionInfo>, String>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Library {
+    pub name: String,
+    pub engine: String,
+    /// The code as given, metadata included
+    pub code: Vec<u8>,
+    pub functions: BTreeMap<String, FunctionInfo>,
+}
+
+impl Library {
+    /// The code without the metadata. The line itself is kept, empty, so
+    /// the line numbers in the errors match the ones of the code.
+    pub fn body(&self) -> &[u8] {
+        match self.code.iter().position(|c| *c == b'\n') {
+            Some(pos) => &self.code[pos..],
+            None => b"",
+        }
+    }
+}
+
+/// Whether `name` can name a library or a function.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_')
+}
+
+/// Reads the engine and the library name in the first line of `code`.
+fn parse_metadata(code: &[u8]) -> Result<(String, String), String> {
+    let line = match code.iter().position(|c| *c == b'\n') {
+        Some(pos) => &code[..pos],
+        None => code,
+    };
+    if !line.starts_with(b"#!") {
+        return Err("ERR Missing library metadata".to_owned());
+    }
+    let line = String::from_utf8_lossy(&line[2..]);
+    let mut parts = line.split(' ').filter(|part| !part.is_empty());
+    let engine = parts.next().unwrap_or("").to_owned();
+    let mut name = None;
+    for part in parts {
+        if part.starts_with("name=") {
+            name = Some(part["name=".len()..].to_owned());
+        } else {
+            return Err(format!("ERR Invalid metadata value given: {}", part));
+        }
+    }
+    match name {
+        Some(name) => Ok((engine, name)),
+        None => Err("ERR Library name was not given".to_owned()),
+    }
+}
+
+/// The loaded libraries.
+#[derive(Clone)]
+pub struct Functions {
+    engine: Option<Engine>,
+    libraries: BTreeMap<String, Library>,
+    /// Maps every function to the name of its library
+    functions: HashMap<String, String>,
+}
+
+impl Functions {
+    pub fn new() -> Functions {
+        Functions {
+            engine: None,
+            libraries: BTreeMap::new(),
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Sets the engine that runs the libraries. Without one, no library
+    /// can be loaded.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = Some(engine);
+    }
+
+    /// Runs `code` to create a library, without adding it.
+    pub fn create(&self, code: &[u8], logger: &Logger) -> Result<Library, String> {
+        let (engine, name) = parse_metadata(code)?;
+        let run = match self.engine {
+            Some(run) if engine.eq_ignore_ascii_case(ENGINE) => run,
+            _ => return Err(format!("ERR Engine '{}' not found", engine)),
+        };
+        if !valid_name(&name) {
+            return Err("ERR Library names can only contain letters, numbers, or underscores(_) \
+                        and must be at least one character long"
+                .to_owned());
+        }
+        let mut library = Library {
+            name: name,
+            engine: ENGINE.to_owned(),
+            code: code.to_vec(),
+            functions: BTreeMap::new(),
+        };
+        for function in run(library.body(), logger)? {
+            library.functions.insert(function.name.clone(), function);
+        }
+        if library.functions.is_empty() {
+            return Err("ERR No functions registered".to_owned());
+        }
+        Ok(library)
+    }
+
+    /// Adds `library`, or replaces the one with the same name if `replace`.
+    /// Fails if one of its functions belongs to another library.
+    pub fn add(&mut self, library: Library, replace: bool) -> Result<(), String> {
+        if self.libraries.contains_key(&library.name) && !replace {
+            return Err(format!("ERR Library '{}' already exists", library.name));
+        }
+        for name in library.functions.keys() {
+            match self.functions.get(name) {
+                Some(owner) if *owner != library.name => {
+                    return Err(format!("ERR Function {} already exists", name));
+                }
+                _ => (),
+            }
+        }
+        self.delete(&library.name);
+        for name in library.functions.keys() {
+            self.functions.insert(name.clone(), library.name.clone());
+        }
+        self.libraries.insert(library.name.clone(), library);
+        Ok(())
+    }
+
+    /// Creates a library from `code` and adds it, returning its name.
+    pub fn load(&mut self, code: &[u8], replace: bool, logger: &Logger) -> Result<String, String> {
+        let library = self.create(code, logger)?;
+        let name = library.name.clone();
+        self.add(library, replace)?;
+        Ok(name)
+    }
+
+    /// Removes a library and its functions. Returns false if it does not
+    /// exist.
+    pub fn delete(&mut self, name: &str) -> bool {
+        match self.libraries.remove(name) {
+            Some(library) => {
+                for function in library.functions.keys() {
+                    self.functions.remove(function);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+        self.functions.clear();
+    }
+
+    /// The library of the function `name`, and the function.
+    pub fn get(&self, name: &str) -> Option<(&Library, &FunctionInfo)> {
+        let library = &self.libraries[self.functions.get(name)?];
+        Some((library, &library.functions[name]))
+    }
+
+    /// The libraries, sorted by name.
+    pub fn libraries(&self) -> Vec<&Library> {
+        self.libraries.values().collect()
+    }
+
+    pub fn function_count(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// Serializes every library as FUNCTION DUMP does: their code in RDB
+    /// format, followed by the RDB version and a crc64.
+    pub fn dump(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for library in self.libraries.values() {
+            data.push(OPCODE_FUNCTION2);
+            encode_slice_u8(&library.code, &mut data, false).unwrap();
+        }
+        data.push((VERSION & 0xff) as u8);
+        data.push(((VERSION >> 8) & 0xff) as u8);
+        let crc = crc64(0, &data);
+        encode_u64_to_slice_u8(crc, &mut data).unwrap();
+        data
+    }
+
+    /// Loads the libraries serialized by `dump`. With `policy` FLUSH the
+    /// current libraries are deleted first, with REPLACE the ones with the
+    /// same name are replaced, and with APPEND any conflict is an error.
+    /// Nothing changes if it fails.
+    pub fn restore(&mut self, data: &[u8], policy: &str, logger: &Logger) -> Result<(), String> {
+        let len = data.len();
+        let wrong_payload = || "ERR payload version or checksum are wrong".to_owned();
+        if len < 10 {
+            return Err(wrong_payload());
+        }
+        let version = (data[len - 10] as u16) | ((data[len - 9] as u16) << 8);
+        let mut crc = [0; 8];
+        crc.copy_from_slice(&data[len - 8..]);
+        if version > VERSION || u64::from_le_bytes(crc) != crc64(0, &data[..len - 8]) {
+            return Err(wrong_payload());
+        }
+
+        let mut functions = self.clone();
+        if policy == "flush" {
+            functions.flush();
+        }
+        let mut payload = &data[..len - 10];
+        while !payload.is_empty() {
+            let not_a_dump = |_| "ERR given payload is not a function dump".to_owned();
+            if decode_u8(&mut payload).map_err(not_a_dump)? != OPCODE_FUNCTION2 {
+                return Err("ERR given payload is not a function dump".to_owned());
+            }
+            let code = decode_slice_u8(&mut payload).map_err(not_a_dump)?;
+            let library = functions.create(&code, logger)?;
+            functions.add(library, policy == "replace")?;
+        }
+        *self = functions;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_function {
+    use logger::{Level, Logger};
+
+    use super::{FunctionInfo, Functions};
+
+    /// Registers a function for every `f` in the body, named after the
+    /// word that follows it.
+    fn engine(body: &[u8], _: &Logger) -> Result<Vec<FunctionInfo>, String> {
+        let body = String::from_utf8_lossy(body);
+        let words = body.split_whitespace().collect::<Vec<_>>();
+        Ok(words
+            .windows(2)
+            .filter(|w| w[0] == "f")
+            .map(|w| FunctionInfo {
+                name: w[1].to_owned(),
+                description: None,
+                flags: vec![],
+            })
+            .collect())
+    }
+
+    fn functions() -> Functions {
+        let mut functions = Functions::new();
+        functions.set_engine(engine);
+        functions
+    }
+
+    #[test]
+    fn metadata() {
+        let logger = Logger::new(Level::Warning);
+        let functions = functions();
+        assert_eq!(functions.create(b"f a", &logger).unwrap_err(), "ERR Missing library metadata");
+        assert_eq!(functions.create(b"#!lua\nf a", &logger).unwrap_err(), "ERR Library name was not given");
+        assert_eq!(
+            functions.create(b"#!lua name=l foo=bar\nf a", &logger).unwrap_err(),
+            "ERR Invalid metadata value given: foo=bar"
+        );
+        assert_eq!(
+            functions.create(b"#!js name=l\nf a", &logger).unwrap_err(),
+            "ERR Engine 'js' not found"
+        );
+        assert!(functions.create(b"#!lua name=l-1\nf a", &logger).is_err());
+        assert_eq!(functions.create(b"#!lua name=l\n", &logger).unwrap_err(), "ERR No functions registered");
+        assert!(Functions::new().create(b"#!lua name=l\nf a", &logger).is_err());
+
+        let library = functions.create(b"#!LUA name=l\nf a f b", &logger).unwrap();
+        assert_eq!(library.name, "l");
+        assert_eq!(library.body(), b"\nf a f b");
+        assert_eq!(library.functions.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn load_delete() {
+        let logger = Logger::new(Level::Warning);
+        let mut functions = functions();
+        assert_eq!(functions.load(b"#!lua name=l1\nf a f b", false, &logger).unwrap(), "l1");
+        assert_eq!(
+            functions.load(b"#!lua name=l1\nf c", false, &logger).unwrap_err(),
+            "ERR Library 'l1' already exists"
+        );
+        assert_eq!(
+            functions.load(b"#!lua name=l2\nf b", false, &logger).unwrap_err(),
+            "ERR Function b already exists"
+        );
+        functions.load(b"#!lua name=l1\nf c", true, &logger).unwrap();
+        assert!(functions.get("a").is_none());
+        assert_eq!(functions.get("c").unwrap().0.name, "l1");
+        functions.load(b"#!lua name=l2\nf b", false, &logger).unwrap();
+        assert_eq!(functions.function_count(), 2);
+
+        assert!(functions.delete("l1"));
+        assert!(!functions.delete("l1"));
+        assert!(functions.get("c").is_none());
+        functions.flush();
+        assert!(functions.libraries().is_empty());
+    }
+
+    #[test]
+    fn dump_restore() {
+        let logger = Logger::new(Level::Warning);
+        let mut functions = functions();
+        functions.load(b"#!lua name=l1\nf a", false, &logger).unwrap();
+        functions.load(b"#!lua name=l2\nf b", false, &logger).unwrap();
+        let dump = functions.dump();
+
+        assert_eq!(
+            functions.restore(&dump, "append", &logger).unwrap_err(),
+            "ERR Library 'l1' already exists"
+        );
+        functions.delete("l2");
+        functions.load(b"#!lua name=l3\nf c", false, &logger).unwrap();
+        functions.restore(&dump, "replace", &logger).unwrap();
+        assert_eq!(functions.libraries().len(), 3);
+        functions.restore(&dump, "flush", &logger).unwrap();
+        assert_eq!(functions.libraries().len(), 2);
+        assert!(functions.get("c").is_none());
+
+        let mut corrupted = dump.clone();
+        corrupted[3] ^= 1;
+        assert_eq!(
+            functions.restore(&corrupted, "flush", &logger).unwrap_err(),
+            "ERR payload version or checksum are wrong"
+        );
+        assert_eq!(functions.libraries().len(), 2);
+    }
+}
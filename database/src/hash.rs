@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 
 use error::OperationError;
 use rdbutil::constants::*;
-use rdbutil::{encode_len, encode_slice_u8, EncodeError};
+use rdbutil::ziplist;
+use rdbutil::{decode_len, decode_slice_u8, encode_len, encode_slice_u8, prealloc_len, DecodeError, EncodeError};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum ValueHash {
@@ -262,7 +263,11 @@ impl ValueHash {
         }
     }
 
-    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+    pub fn rdb_type(&self) -> u8 {
+        TYPE_HASH
+    }
+
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
         match self {
             ValueHash::HashMap(map) => {
@@ -277,8 +282,40 @@ impl ValueHash {
                 encode_len(0, &mut v)?;
             }
         }
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    pub fn rdb_load<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let len = decode_len(reader)?;
+        let mut map = HashMap::with_capacity(prealloc_len(len));
+        for _ in 0..len {
+            let field = decode_slice_u8(reader)?;
+            let value = decode_slice_u8(reader)?;
+            map.insert(field, value);
+        }
+        Ok(ValueHash::HashMap(map))
+    }
+
+    /// Loads a hash stored as a ziplist of alternating fields and values.
+    pub fn rdb_load_ziplist<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let entries = ziplist::decode(&decode_slice_u8(reader)?)?;
+        if entries.len() % 2 != 0 {
+            return Err(DecodeError::InvalidData("Invalid hash ziplist".to_owned()));
+        }
+        let mut map = HashMap::with_capacity(entries.len() / 2);
+        let mut it = entries.into_iter();
+        while let (Some(field), Some(value)) = (it.next(), it.next()) {
+            map.insert(field, value);
+        }
+        Ok(ValueHash::HashMap(map))
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
         let data = [
-            vec![TYPE_HASH],
+            vec![self.rdb_type()],
             v,
             vec![(VERSION & 0xff) as u8],
             vec![((VERSION >> 8) & 0xff) as u8],
@@ -1,8 +1,11 @@
 extern crate basichll;
+#[macro_use]
+extern crate bitflags;
+extern crate compat;
 extern crate config;
-#[macro_use(log)]
+extern crate libloading;
+#[macro_use(log, log_and_exit)]
 extern crate logger;
-extern crate crc64;
 extern crate parser;
 extern crate persistence;
 extern crate rand;
@@ -12,11 +15,20 @@ extern crate response;
 extern crate skiplist;
 extern crate util;
 
+pub mod aof;
+pub mod busy;
+pub mod cluster;
 pub mod dbutil;
 pub mod error;
+pub mod function;
 pub mod hash;
 pub mod list;
+pub mod module;
+pub mod rdb;
+pub mod replication;
+pub mod sentinel;
 pub mod set;
+pub mod stream;
 pub mod string;
 pub mod zset;
 
@@ -24,26 +36,36 @@ use std::collections::Bound;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::iter::FromIterator;
 use std::ops::RangeFull;
 use std::path::Path;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
 use config::Config;
-use crc64::crc64;
+use rdbutil::crc64::crc64;
 use logger::{Level, Logger};
 use parser::ParsedCommand;
-use persistence::aof::Aof;
+use persistence::aof::{Aof, Fsync};
 use rehashinghashmap::RehashingHashMap;
 use response::Response;
 use util::{get_random_hex_chars, glob_match, mstime};
 
+use busy::Busy;
 use error::OperationError;
+use function::Functions;
 use hash::ValueHash;
 use list::ValueList;
-use rdbutil::encode_u64_to_slice_u8;
+use module::{Modules, ValueModule};
+use cluster::{Cluster, CLUSTER_SLOTS};
+use sentinel::Sentinel;
+use replication::{Backlog, MasterLink, Replica};
+use rdbutil::constants::*;
+use rdbutil::{encode_u64_to_slice_u8, DecodeError};
 use set::ValueSet;
+use stream::{NewId, StreamEntry, StreamId, Trim, ValueStream};
 use string::ValueString;
 use zset::ValueSortedSet;
 
@@ -76,6 +98,9 @@ pub enum Value {
     Set(ValueSet),
     SortedSet(ValueSortedSet),
     Hash(ValueHash),
+    Stream(ValueStream),
+    /// A value of a type defined by a module
+    Module(ValueModule),
 }
 
 /// Events relevant for clients in pubsub mode
@@ -1899,6 +1924,149 @@ impl Value {
         Ok(Value::SortedSet(value))
     }
 
+    /// Appends an entry to a stream, and returns its ID.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    /// use database::stream::{NewId, StreamId};
+    ///
+    /// let mut val = Value::Nil;
+    /// let id = val.xadd(NewId::Seq(5), vec![b"f".to_vec(), b"v".to_vec()]).unwrap();
+    /// assert_eq!(id, StreamId::new(5, 0));
+    /// assert!(val.xadd(NewId::Id(id), vec![b"f".to_vec(), b"v".to_vec()]).is_err());
+    /// assert_eq!(val.xlen().unwrap(), 1);
+    /// ```
+    pub fn xadd(&mut self, id: NewId, fields: Vec<Vec<u8>>) -> Result<StreamId, OperationError> {
+        match self {
+            Value::Nil => {
+                let mut stream = ValueStream::new();
+                let id = stream.add(id, fields)?;
+                *self = Value::Stream(stream);
+                Ok(id)
+            }
+            Value::Stream(stream) => stream.add(id, fields),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// Returns the number of entries in a stream.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    /// use database::stream::NewId;
+    ///
+    /// let mut val = Value::Nil;
+    /// assert_eq!(val.xlen().unwrap(), 0);
+    /// val.xadd(NewId::Auto, vec![b"f".to_vec(), b"v".to_vec()]).unwrap();
+    /// assert_eq!(val.xlen().unwrap(), 1);
+    /// ```
+    pub fn xlen(&self) -> Result<usize, OperationError> {
+        match self {
+            Value::Nil => Ok(0),
+            Value::Stream(stream) => Ok(stream.len()),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// Returns the ID of the last entry added to a stream, even if it was
+    /// deleted since.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    /// use database::stream::{NewId, StreamId};
+    ///
+    /// let mut val = Value::Nil;
+    /// assert_eq!(val.xlast_id().unwrap(), StreamId::MIN);
+    /// let id = val.xadd(NewId::Auto, vec![b"f".to_vec(), b"v".to_vec()]).unwrap();
+    /// val.xdel(&[id]).unwrap();
+    /// assert_eq!(val.xlast_id().unwrap(), id);
+    /// ```
+    pub fn xlast_id(&self) -> Result<StreamId, OperationError> {
+        match self {
+            Value::Nil => Ok(StreamId::MIN),
+            Value::Stream(stream) => Ok(stream.last_id()),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// Returns the entries of a stream with an ID between `start` and
+    /// `end`, both included, up to `count` of them. With `rev` they are
+    /// returned from the last one.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    /// use database::stream::{NewId, StreamId};
+    ///
+    /// let mut val = Value::Nil;
+    /// for ms in 1..5 {
+    ///     val.xadd(NewId::Seq(ms), vec![b"f".to_vec(), b"v".to_vec()]).unwrap();
+    /// }
+    /// let range = val.xrange(StreamId::new(2, 0), StreamId::MAX, None, false).unwrap();
+    /// assert_eq!(range.iter().map(|e| e.id.ms).collect::<Vec<_>>(), vec![2, 3, 4]);
+    /// let range = val.xrange(StreamId::MIN, StreamId::MAX, Some(2), true).unwrap();
+    /// assert_eq!(range.iter().map(|e| e.id.ms).collect::<Vec<_>>(), vec![4, 3]);
+    /// ```
+    pub fn xrange(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Result<Vec<&StreamEntry>, OperationError> {
+        match self {
+            Value::Nil => Ok(vec![]),
+            Value::Stream(stream) => Ok(stream.range(start, end, count, rev)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// Deletes entries from a stream. Returns the number of entries removed.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    /// use database::stream::{NewId, StreamId};
+    ///
+    /// let mut val = Value::Nil;
+    /// let id = val.xadd(NewId::Auto, vec![b"f".to_vec(), b"v".to_vec()]).unwrap();
+    /// assert_eq!(val.xdel(&[id, StreamId::new(1, 1)]).unwrap(), 1);
+    /// assert_eq!(val.xlen().unwrap(), 0);
+    /// ```
+    pub fn xdel(&mut self, ids: &[StreamId]) -> Result<usize, OperationError> {
+        match self {
+            Value::Nil => Ok(0),
+            Value::Stream(stream) => Ok(stream.delete(ids)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// Removes entries from the start of a stream. Returns the number of
+    /// entries removed.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    /// use database::stream::{NewId, Trim};
+    ///
+    /// let mut val = Value::Nil;
+    /// for _ in 0..5 {
+    ///     val.xadd(NewId::Auto, vec![b"f".to_vec(), b"v".to_vec()]).unwrap();
+    /// }
+    /// assert_eq!(val.xtrim(Trim::MaxLen(2), false, 0).unwrap(), 3);
+    /// assert_eq!(val.xlen().unwrap(), 2);
+    /// ```
+    pub fn xtrim(&mut self, trim: Trim, approx: bool, limit: usize) -> Result<usize, OperationError> {
+        match self {
+            Value::Nil => Ok(0),
+            Value::Stream(stream) => Ok(stream.trim(trim, approx, limit)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
     /// Serializes and writes into `writer` the object current value.
     /// The serialized version also includes the type, the version and a crc.
     ///
@@ -1910,7 +2078,7 @@ impl Value {
     /// val.set(vec![1, 2, 3]).unwrap();
     /// let mut serialized = vec![];
     /// assert_eq!(val.dump(&mut serialized).unwrap(), 15);
-    /// assert_eq!(serialized, vec![0, 3, 1, 2, 3, 7, 0, 229, 221, 166, 143, 248, 97, 121, 255]);
+    /// assert_eq!(serialized, vec![0, 3, 1, 2, 3, 10, 0, 26, 239, 115, 124, 240, 171, 172, 229]);
     /// ```
     pub fn dump<T: Write>(&self, writer: &mut T) -> Result<usize, OperationError> {
         let mut data = vec![];
@@ -1921,12 +2089,139 @@ impl Value {
             Value::Set(s) => s.dump(&mut data)?,
             Value::SortedSet(s) => s.dump(&mut data)?,
             Value::Hash(h) => h.dump(&mut data)?,
+            Value::Stream(s) => s.dump(&mut data)?,
+            Value::Module(m) => m.dump(&mut data)?,
         };
         let crc = crc64(0, &*data);
         encode_u64_to_slice_u8(crc, &mut data).unwrap();
         Ok(writer.write(&*data)?)
     }
 
+    /// Deserializes a payload created by `dump`.
+    /// Fails if it was created by a newer version, if the checksum does not
+    /// match or if the data is malformed.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val = Value::Nil;
+    /// val.push(b"a".to_vec(), true).unwrap();
+    /// let mut serialized = vec![];
+    /// val.dump(&mut serialized).unwrap();
+    /// assert_eq!(Value::restore(&serialized).unwrap(), val);
+    ///
+    /// serialized[1] = 2;
+    /// assert!(Value::restore(&serialized).is_err());
+    /// ```
+    pub fn restore(data: &[u8]) -> Result<Value, OperationError> {
+        Value::restore_with(data, &Modules::new())
+    }
+
+    /// Deserializes a payload created by `dump`, which may be a value of a
+    /// type registered by one of `modules`.
+    pub fn restore_with(data: &[u8], modules: &Modules) -> Result<Value, OperationError> {
+        let len = data.len();
+        let wrong_payload =
+            || OperationError::ValueError("ERR DUMP payload version or checksum are wrong".to_owned());
+        if len < 11 {
+            return Err(wrong_payload());
+        }
+        let version = (data[len - 10] as u16) | ((data[len - 9] as u16) << 8);
+        let mut crc = [0; 8];
+        crc.copy_from_slice(&data[len - 8..]);
+        if version > VERSION || u64::from_le_bytes(crc) != crc64(0, &data[..len - 8]) {
+            return Err(wrong_payload());
+        }
+
+        let bad_format = || OperationError::ValueError("ERR Bad data format".to_owned());
+        let mut payload = &data[1..len - 10];
+        let value = Value::rdb_load_with(data[0], &mut payload, modules).map_err(|_| bad_format())?;
+        if !payload.is_empty() || value.is_empty() {
+            return Err(bad_format());
+        }
+        Ok(value)
+    }
+
+    /// The RDB type opcode used to store the current value.
+    /// Returns `None` for `Value::Nil`, which is never persisted.
+    pub fn rdb_type(&self) -> Option<u8> {
+        Some(match self {
+            Value::Nil => return None,
+            Value::String(s) => s.rdb_type(),
+            Value::List(l) => l.rdb_type(),
+            Value::Set(s) => s.rdb_type(),
+            Value::SortedSet(s) => s.rdb_type(),
+            Value::Hash(h) => h.rdb_type(),
+            Value::Stream(s) => s.rdb_type(),
+            Value::Module(m) => m.rdb_type(),
+        })
+    }
+
+    /// Writes into `writer` the value payload as stored in an RDB file.
+    /// Unlike `dump`, it does not include the type, the version nor a crc.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let mut val = Value::Nil;
+    /// val.set(vec![1, 2, 3]).unwrap();
+    /// let mut serialized = vec![];
+    /// assert_eq!(val.rdb_save(&mut serialized).unwrap(), 4);
+    /// assert_eq!(serialized, vec![3, 1, 2, 3]);
+    /// ```
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> Result<usize, OperationError> {
+        Ok(match self {
+            Value::Nil => 0,
+            Value::String(s) => s.rdb_save(writer)?,
+            Value::List(l) => l.rdb_save(writer)?,
+            Value::Set(s) => s.rdb_save(writer)?,
+            Value::SortedSet(s) => s.rdb_save(writer)?,
+            Value::Hash(h) => h.rdb_save(writer)?,
+            Value::Stream(s) => s.rdb_save(writer)?,
+            Value::Module(m) => m.rdb_save(writer)?,
+        })
+    }
+
+    /// Reads a value payload of type `rdb_type` written by `rdb_save`.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::Value;
+    ///
+    /// let val = Value::rdb_load(0, &mut &[3, 1, 2, 3][..]).unwrap();
+    /// assert_eq!(val.get().unwrap(), vec![1, 2, 3]);
+    /// ```
+    pub fn rdb_load<T: Read>(rdb_type: u8, reader: &mut T) -> Result<Value, DecodeError> {
+        Value::rdb_load_with(rdb_type, reader, &Modules::new())
+    }
+
+    /// Reads a value payload written by `rdb_save`, which may be a value of
+    /// a type registered by one of `modules`.
+    pub fn rdb_load_with<T: Read>(rdb_type: u8, reader: &mut T, modules: &Modules) -> Result<Value, DecodeError> {
+        Ok(match rdb_type {
+            TYPE_STRING => Value::String(ValueString::rdb_load(reader)?),
+            TYPE_LIST => Value::List(ValueList::rdb_load(reader)?),
+            TYPE_LIST_ZIPLIST => Value::List(ValueList::rdb_load_ziplist(reader)?),
+            TYPE_LIST_QUICKLIST => Value::List(ValueList::rdb_load_quicklist(reader)?),
+            TYPE_SET => Value::Set(ValueSet::rdb_load(reader)?),
+            TYPE_SET_INTSET => Value::Set(ValueSet::rdb_load_intset(reader)?),
+            TYPE_ZSET => Value::SortedSet(ValueSortedSet::rdb_load(reader)?),
+            TYPE_ZSET_ZIPLIST => Value::SortedSet(ValueSortedSet::rdb_load_ziplist(reader)?),
+            TYPE_HASH => Value::Hash(ValueHash::rdb_load(reader)?),
+            TYPE_HASH_ZIPLIST => Value::Hash(ValueHash::rdb_load_ziplist(reader)?),
+            TYPE_STREAM_LISTPACKS => Value::Stream(ValueStream::rdb_load(reader)?),
+            TYPE_MODULE_2 => Value::Module(ValueModule::rdb_load(reader, modules)?),
+            _ => {
+                return Err(DecodeError::InvalidData(format!(
+                    "Unknown RDB encoding type {}",
+                    rdb_type
+                )))
+            }
+        })
+    }
+
     pub fn debug_object(&self) -> String {
         match self {
             Value::Nil => "Value at:0x0000000000 refcount:0 encoding:nil serializedlength:0 lru:0 \
@@ -1937,6 +2232,8 @@ impl Value {
             Value::Set(s) => s.debug_object(),
             Value::SortedSet(s) => s.debug_object(),
             Value::Hash(h) => h.debug_object(),
+            Value::Stream(s) => s.debug_object(),
+            Value::Module(m) => m.debug_object(),
         }
     }
 
@@ -1948,6 +2245,9 @@ impl Value {
             Value::Set(s) => s.scard() == 0,
             Value::SortedSet(s) => s.zcard() == 0,
             Value::Hash(h) => h.is_empty(),
+            // a stream stays after its last entry is deleted
+            Value::Stream(_) => false,
+            Value::Module(_) => false,
         }
     }
 }
@@ -1994,10 +2294,72 @@ pub struct Database {
     pub start_mstime: i64,
     /// Aof reader/writer
     pub aof: Option<Aof>,
+    /// Did the last write to the append only file succeed
+    pub aof_last_write_ok: bool,
     /// Is it loading data from a file
     pub loading: bool,
     /// Unix timestamp in seconds of last successful save
     pub last_save_time: i64,
+    /// Number of changes since the last successful save
+    pub dirty: u64,
+    /// Value of `dirty` when the current background snapshot started
+    pub dirty_before_bgsave: u64,
+    /// Unix timestamp in seconds of the last background snapshot attempt
+    pub rdb_last_bgsave_try: i64,
+    /// Process id of the child writing a background snapshot, if any
+    pub rdb_child_pid: Option<u32>,
+    /// Milliseconds when the current background snapshot started
+    pub rdb_save_time_start: i64,
+    /// Duration in seconds of the last background snapshot, -1 if none
+    pub rdb_last_bgsave_time_sec: i64,
+    /// Did the last background snapshot succeed
+    pub rdb_last_bgsave_ok: bool,
+    /// Process id of the child rewriting the append only file, if any
+    pub aof_child_pid: Option<u32>,
+    /// Start a rewrite as soon as no other child is running
+    pub aof_rewrite_scheduled: bool,
+    /// Milliseconds when the current rewrite started
+    pub aof_rewrite_time_start: i64,
+    /// Duration in seconds of the last rewrite, -1 if none
+    pub aof_last_rewrite_time_sec: i64,
+    /// Did the last rewrite succeed
+    pub aof_last_bgrewrite_ok: bool,
+    /// Identifies the history of the replication stream
+    pub replid: String,
+    /// Number of bytes written into the replication stream
+    pub master_repl_offset: u64,
+    /// Most recent part of the replication stream, created when the first
+    /// replica connects
+    pub repl_backlog: Option<Backlog>,
+    /// Replicas connected to this server
+    pub replicas: Vec<Replica>,
+    /// Database selected in the replication stream, `None` to force a SELECT
+    repl_seldb: Option<usize>,
+    /// Milliseconds when the replicas were last pinged
+    repl_last_ping: i64,
+    /// The master this server replicates, if it is a replica
+    pub master: Option<MasterLink>,
+    /// Last identifier given to a master link
+    repl_link_counter: u64,
+    /// Thread copying a diskless snapshot from the child into the replicas
+    repl_transfer: Option<JoinHandle<()>>,
+    /// Clients blocked in WAIT, with the offset they need acknowledged and
+    /// by how many replicas
+    repl_waiters: Vec<(usize, u64, usize, Sender<bool>)>,
+    /// Slots and nodes of the cluster, in cluster mode
+    pub cluster: Option<Cluster>,
+    /// Keys of the database 0 in each slot, in cluster mode
+    slot_keys: Vec<HashSet<Vec<u8>>>,
+    /// The masters monitored, in sentinel mode
+    pub sentinel: Option<Sentinel>,
+    /// Bodies of the scripts loaded, by their SHA1 digest
+    pub scripts: HashMap<String, Vec<u8>>,
+    /// Libraries loaded with FUNCTION LOAD
+    pub functions: Functions,
+    /// Modules loaded with MODULE LOAD or `loadmodule`
+    pub modules: Modules,
+    /// What the connections see while another one holds the database
+    pub busy: Arc<Busy>,
     /// Approximate memory usage in bytes
     pub used_memory: u64,
     /// Peak memory usage in bytes
@@ -2006,6 +2368,8 @@ pub struct Database {
     pub evicted_keys: u64,
     /// Maps a key to its last access time (for LRU). Time is in milliseconds.
     key_lru: Vec<RehashingHashMap<Vec<u8>, i64>>,
+    /// Maps a key to its access frequency counter (for LFU).
+    key_lfu: Vec<RehashingHashMap<Vec<u8>, u8>>,
     /// Slow log entries (circular buffer)
     slowlog: Vec<SlowLogEntry>,
     /// Next slow log entry ID
@@ -2045,7 +2409,8 @@ impl Database {
         Database::new(Config::default(0, Logger::new(Level::Warning)))
     }
 
-    /// Creates a new empty `Database`.
+    /// Creates a new empty `Database`. If `slaveof` is configured, it starts
+    /// as a replica of that master.
     pub fn new(config: Config) -> Self {
         env::set_current_dir(&Path::new(&*config.dir)).unwrap();
         let size = config.databases as usize;
@@ -2054,20 +2419,35 @@ impl Database {
         let mut key_subscribers = Vec::with_capacity(size);
         let mut watched_keys = Vec::with_capacity(size);
         let mut key_lru = Vec::with_capacity(size);
+        let mut key_lfu = Vec::with_capacity(size);
         for _ in 0..size {
             data.push(RehashingHashMap::new());
             data_expiration_ms.push(RehashingHashMap::new());
             key_subscribers.push(RehashingHashMap::new());
             watched_keys.push(HashMap::new());
             key_lru.push(RehashingHashMap::new());
+            key_lfu.push(RehashingHashMap::new());
         }
         let aof = if config.appendonly {
-            Some(Aof::new(&*config.appendfilename).unwrap())
+            let mut aof = Aof::new(&*config.appendfilename).unwrap();
+            aof.set_fsync(config.appendfsync.parse().unwrap_or(Fsync::EverySec))
+                .unwrap();
+            aof.set_no_fsync_on_rewrite(config.no_appendfsync_on_rewrite);
+            Some(aof)
         } else {
             None
         };
 
-        Database {
+        let slaveof = config.slaveof.clone();
+        let run_id = get_random_hex_chars(40);
+        let busy = Arc::new(Busy::new(config.busy_reply_threshold, run_id.clone(), config.port));
+        let config_sentinel = config.sentinel_mode;
+        let (cluster, slot_keys) = if config.cluster_enabled {
+            (Some(Cluster::new(config.port)), vec![HashSet::new(); CLUSTER_SLOTS])
+        } else {
+            (None, vec![])
+        };
+        let mut db = Database {
             config,
             data,
             data_expiration_ms,
@@ -2082,18 +2462,53 @@ impl Database {
             rustc_version: "",
             git_sha1: "00000000",
             git_dirty: true,
-            run_id: get_random_hex_chars(40),
+            run_id,
             start_mstime: mstime(),
             aof,
+            aof_last_write_ok: true,
             loading: false,
-            last_save_time: 0,
+            last_save_time: mstime() / 1000,
+            dirty: 0,
+            dirty_before_bgsave: 0,
+            rdb_last_bgsave_try: 0,
+            rdb_child_pid: None,
+            rdb_save_time_start: -1,
+            rdb_last_bgsave_time_sec: -1,
+            rdb_last_bgsave_ok: true,
+            aof_child_pid: None,
+            aof_rewrite_scheduled: false,
+            aof_rewrite_time_start: -1,
+            aof_last_rewrite_time_sec: -1,
+            aof_last_bgrewrite_ok: true,
+            replid: get_random_hex_chars(40),
+            master_repl_offset: 0,
+            repl_backlog: None,
+            replicas: Vec::new(),
+            repl_seldb: None,
+            repl_last_ping: 0,
+            master: None,
+            repl_link_counter: 0,
+            repl_transfer: None,
+            repl_waiters: Vec::new(),
+            cluster,
+            slot_keys,
+            sentinel: if config_sentinel { Some(Sentinel::new()) } else { None },
+            scripts: HashMap::new(),
+            functions: Functions::new(),
+            modules: Modules::new(),
+            busy,
             used_memory: 0,
             used_memory_peak: 0,
             evicted_keys: 0,
             key_lru,
+            key_lfu,
             slowlog: Vec::new(),
             slowlog_id: 0,
+        };
+        if let Some((host, port)) = slaveof {
+            db.repl_set_master(host, port);
         }
+        db
     }
 
     pub fn uptime(&self) -> i64 {
@@ -2206,6 +2621,16 @@ impl Database {
                     }
                 };
             }
+            Value::Stream(s) => {
+                // ID and vector overhead per entry + fields and values
+                size += s
+                    .range(StreamId::MIN, StreamId::MAX, None, false)
+                    .iter()
+                    .map(|entry| entry.fields.iter().map(|f| f.len() as u64).sum::<u64>() + 40)
+                    .sum::<u64>();
+            }
+            // opaque to the server, only its module knows its layout
+            Value::Module(_) => size += 64,
             Value::Nil => size += 0,
         }
         
@@ -2398,6 +2823,7 @@ impl Database {
     pub fn remove(&mut self, index: usize, key: &[u8]) -> Option<Value> {
         let was_expired = self.is_expired(index, key);
         let mut r = self.data[index].remove(key);
+        self.slot_key_removed(index, key);
         if was_expired {
             r = None;
             // Publish expired event notification
@@ -2414,6 +2840,7 @@ impl Database {
 
         self.data_expiration_ms[index].remove(key);
         self.key_lru[index].remove(key);
+        self.key_lfu[index].remove(key);
         
         if self.config.active_rehashing {
             if self.data[index].len() * 10 / 12 < self.data[index].capacity() {
@@ -2463,6 +2890,30 @@ impl Database {
         }
         self.data[index].clear();
         self.data_expiration_ms[index].clear();
+        self.key_lru[index].clear();
+        self.key_lfu[index].clear();
+        self.slot_keys_clear(index);
+    }
+
+    /// Sets the last access time of a key as if it had been idle for
+    /// `idle_ms` milliseconds.
+    pub fn set_idletime(&mut self, index: usize, key: &[u8], idle_ms: i64) {
+        self.key_lru[index].insert(key.to_vec(), mstime() - idle_ms);
+    }
+
+    /// Milliseconds since the key was last accessed, if it is tracked.
+    pub fn get_idletime(&self, index: usize, key: &[u8]) -> Option<i64> {
+        self.key_lru[index].get(key).map(|t| mstime() - t)
+    }
+
+    /// Sets the access frequency counter of a key.
+    pub fn set_freq(&mut self, index: usize, key: &[u8], freq: u8) {
+        self.key_lfu[index].insert(key.to_vec(), freq);
+    }
+
+    /// The access frequency counter of a key, if it is tracked.
+    pub fn get_freq(&self, index: usize, key: &[u8]) -> Option<u8> {
+        self.key_lfu[index].get(key).cloned()
     }
 
     /// Returns a mutable reference to a value for a key. If the value was not
@@ -2493,6 +2944,7 @@ impl Database {
         // Check if we need to evict keys before creating a new one
         let is_new = !self.data[index].contains_key(key);
         if is_new {
+            self.slot_key_added(index, key);
             // Estimate memory needed for new key-value pair
             let estimated_size = Database::estimate_value_size(&val, key.len());
             if !self.evict_keys(index, estimated_size) {
@@ -2797,7 +3249,7 @@ impl Database {
         // Handle 'A' alias - expands to all event types
         if events.contains('A') {
             match flag {
-                'g' | '$' | 'l' | 's' | 'h' | 'z' | 'x' | 'e' => return true,
+                'g' | '$' | 'l' | 's' | 'h' | 'z' | 't' | 'x' | 'e' => return true,
                 _ => {}
             }
         }
@@ -2990,20 +3442,59 @@ impl Database {
             .collect::<Vec<_>>();
         self.monitor_senders = tmp;
         if write {
-            let mut err = false;
-            if let Some(w) = &mut self.aof {
-                if let Err(e) = w.write(dbindex, command) {
-                    log!(
+            self.propagate(dbindex, command);
+        }
+    }
+
+    /// Writes a command into the append only file and the replication
+    /// stream.
+    pub fn propagate(&mut self, dbindex: usize, command: &ParsedCommand) {
+        if let Some(r) = self.aof.as_mut().map(|aof| aof.write(dbindex, command)) {
+            self.aof_write_done(r);
+        }
+        self.repl_feed(dbindex, command.get_data());
+    }
+
+    /// Writes again what a failed write left out of the append only file.
+    pub fn aof_retry_write(&mut self) {
+        if self.aof_last_write_ok {
+            return;
+        }
+        if let Some(r) = self.aof.as_mut().map(|aof| aof.retry()) {
+            self.aof_write_done(r);
+        }
+    }
+
+    /// Updates `aof_last_write_ok` after writing into the append only file.
+    /// The file is kept open, and the data that was not written is retried
+    /// by the next write or by the cron.
+    fn aof_write_done(&mut self, r: io::Result<()>) {
+        match r {
+            Ok(()) => {
+                if !self.aof_last_write_ok {
+                    log!(self.config.logger, Warning, "AOF write error looks solved, rsedis can write again.");
+                    self.aof_last_write_ok = true;
+                }
+            }
+            Err(e) => {
+                if self.aof.as_ref().map_or(false, |aof| aof.get_fsync() == Fsync::Always) {
+                    log_and_exit!(
                         self.config.logger,
                         Warning,
-                        "Error writing aof {:?}; stopped writing",
+                        1,
+                        "Can't recover from AOF write error when the AOF fsync policy is 'always': {}. Exiting...",
                         e
                     );
-                    err = true;
+                    // the logger exits once the message is written, and the
+                    // client must not get a reply for the write meanwhile
+                    loop {
+                        thread::park();
+                    }
+                }
+                if self.aof_last_write_ok {
+                    log!(self.config.logger, Warning, "Error writing to the AOF file: {}", e);
+                    self.aof_last_write_ok = false;
                 }
-            }
-            if err {
-                self.aof = None;
             }
         }
     }
@@ -5058,7 +5549,138 @@ mod test_command {
     fn dump_integer() {
         let mut v = vec![];
         Value::String(ValueString::Integer(1)).dump(&mut v).unwrap();
-        assert_eq!(&*v, b"\x00\xc0\x01\x07\x00\xd9J2E\xd9\xcb\xc4\xe6");
+        assert_eq!(&*v, b"\x00\xc0\x01\x0a\x00&x\xe7\xb6\xd1\x01\x11\xfc");
+    }
+
+    fn dump_restore(value: Value) {
+        let mut v = vec![];
+        value.dump(&mut v).unwrap();
+        assert_eq!(Value::restore(&v).unwrap(), value);
+    }
+
+    #[test]
+    fn restore_string() {
+        dump_restore(Value::String(ValueString::Integer(-12345)));
+        dump_restore(Value::String(ValueString::Data(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn restore_linkedlist() {
+        let mut list = ValueList::new();
+        list.push(b"a".to_vec(), true);
+        list.push(b"1".to_vec(), true);
+        dump_restore(Value::List(list));
+    }
+
+    #[test]
+    fn restore_intset() {
+        let mut value = Value::Nil;
+        for item in [&b"1"[..], b"300", b"70000"].iter() {
+            value.sadd(item.to_vec(), 512).unwrap();
+        }
+        dump_restore(value);
+    }
+
+    #[test]
+    fn restore_hashtable_set() {
+        let mut value = Value::Nil;
+        value.sadd(b"a".to_vec(), 512).unwrap();
+        dump_restore(value);
+    }
+
+    #[test]
+    fn restore_skiplist() {
+        let mut value = Value::Nil;
+        value.zadd(-1.5, b"a".to_vec(), false, false, false, false).unwrap();
+        value.zadd(1e300, b"b".to_vec(), false, false, false, false).unwrap();
+        value.zadd(f64::INFINITY, b"c".to_vec(), false, false, false, false).unwrap();
+        dump_restore(value);
+    }
+
+    #[test]
+    fn restore_hash() {
+        let mut value = Value::Nil;
+        value.hset(b"field".to_vec(), b"value".to_vec()).unwrap();
+        dump_restore(value);
+    }
+
+    fn payload(rdb_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut v = vec![rdb_type];
+        v.extend_from_slice(data);
+        v.extend_from_slice(&[7, 0]);
+        let crc = ::rdbutil::crc64::crc64(0, &v);
+        v.extend_from_slice(&crc.to_le_bytes());
+        v
+    }
+
+    fn ziplist_payload(rdb_type: u8, entries: &[&[u8]]) -> Vec<u8> {
+        let entries = entries.iter().map(|e| e.to_vec()).collect::<Vec<_>>();
+        let mut data = vec![];
+        ::rdbutil::encode_slice_u8(&::rdbutil::ziplist::encode(&entries), &mut data, false).unwrap();
+        payload(rdb_type, &data)
+    }
+
+    #[test]
+    fn restore_ziplist_hash() {
+        let value = Value::restore(&ziplist_payload(13, &[b"field", b"value", b"f2", b"2"])).unwrap();
+        assert_eq!(value.hget(b"field").unwrap(), Some(&b"value".to_vec()));
+        assert_eq!(value.hget(b"f2").unwrap(), Some(&b"2".to_vec()));
+        dump_restore(value);
+    }
+
+    #[test]
+    fn restore_ziplist_zset() {
+        let value = Value::restore(&ziplist_payload(12, &[b"a", b"1", b"b", b"2.5"])).unwrap();
+        assert_eq!(value.zscore(b"b".to_vec()).unwrap(), Some(2.5));
+        dump_restore(value);
+    }
+
+    #[test]
+    fn restore_ziplist_list() {
+        let value = Value::restore(&ziplist_payload(10, &[b"a", b"b"])).unwrap();
+        assert_eq!(value.llen().unwrap(), 2);
+        dump_restore(value);
+    }
+
+    #[test]
+    fn restore_wrong_checksum() {
+        let mut v = vec![];
+        Value::String(ValueString::Integer(1)).dump(&mut v).unwrap();
+        let len = v.len();
+        v[len - 1] ^= 1;
+        assert!(Value::restore(&v).is_err());
+    }
+
+    #[test]
+    fn restore_newer_version() {
+        let with_version = |version| {
+            let mut v = payload(0, b"\xc0\x01");
+            v[3] = version;
+            let len = v.len();
+            let crc = ::rdbutil::crc64::crc64(0, &v[..len - 8]);
+            v[len - 8..].copy_from_slice(&crc.to_le_bytes());
+            v
+        };
+        assert!(Value::restore(&payload(0, b"\xc0\x01")).is_ok());
+        // DUMP payloads from Redis 5 (9) and Redis 7 (10)
+        assert!(Value::restore(&with_version(9)).is_ok());
+        assert!(Value::restore(&with_version(10)).is_ok());
+        assert!(Value::restore(&with_version(11)).is_err());
+    }
+
+    #[test]
+    fn restore_trailing_data() {
+        assert!(Value::restore(&payload(0, b"\xc0\x01\x00")).is_err());
+    }
+
+    #[test]
+    fn restore_huge_length() {
+        // lengths of 2^32 - 1 items or bytes with no data behind them
+        for rdb_type in [0, 1, 2, 3, 4, 15].iter() {
+            assert!(Value::restore(&payload(*rdb_type, b"\x80\xff\xff\xff\xff")).is_err());
+        }
+        // a 2^32 - 1 bytes string as the only member of a set
+        assert!(Value::restore(&payload(2, b"\x01\x80\xff\xff\xff\xffa")).is_err());
     }
 
     #[test]
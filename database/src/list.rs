@@ -1,11 +1,12 @@
 use std::collections::LinkedList;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 
 use dbutil::normalize_position;
 use error::OperationError;
 use rdbutil::constants::*;
-use rdbutil::{encode_len, encode_slice_u8};
+use rdbutil::ziplist;
+use rdbutil::{decode_len, decode_slice_u8, encode_len, encode_slice_u8, DecodeError};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum ValueList {
@@ -208,7 +209,11 @@ impl ValueList {
         Ok(())
     }
 
-    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+    pub fn rdb_type(&self) -> u8 {
+        TYPE_LIST
+    }
+
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
         match *self {
             ValueList::Data(ref list) => {
@@ -218,8 +223,44 @@ impl ValueList {
                 }
             }
         };
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    pub fn rdb_load<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let len = decode_len(reader)?;
+        let mut list = ValueList::new();
+        for _ in 0..len {
+            list.push(decode_slice_u8(reader)?, true);
+        }
+        Ok(list)
+    }
+
+    pub fn rdb_load_ziplist<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let mut list = ValueList::new();
+        for item in ziplist::decode(&decode_slice_u8(reader)?)? {
+            list.push(item, true);
+        }
+        Ok(list)
+    }
+
+    /// Loads a list stored as a sequence of ziplists.
+    pub fn rdb_load_quicklist<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let len = decode_len(reader)?;
+        let mut list = ValueList::new();
+        for _ in 0..len {
+            for item in ziplist::decode(&decode_slice_u8(reader)?)? {
+                list.push(item, true);
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
         let data = [
-            vec![TYPE_LIST],
+            vec![self.rdb_type()],
             v,
             vec![(VERSION & 0xff) as u8],
             vec![((VERSION >> 8) & 0xff) as u8],
@@ -255,6 +296,6 @@ mod test_rdb {
             list.push(item.to_vec(), true);
         }
         list.dump(&mut v).unwrap();
-        assert_eq!(v, b"\x01\x05\x01a\x01b\x01c\x01d\x01e\x07\x00".to_vec());
+        assert_eq!(v, b"\x01\x05\x01a\x01b\x01c\x01d\x01e\x0a\x00".to_vec());
     }
 }
@@ -0,0 +1,1526 @@
+//! Native modules: dynamic libraries loaded with MODULE LOAD or the
+//! `loadmodule` directive that add commands and value types to the server.
+//!
+//! A module is a `cdylib` crate that exports its entry point with
+//! `declare_module!`. The server and the module only share the C ABI: the
+//! entry point gets a `ModuleApi`, a table of `extern "C"` functions whose
+//! layout is versioned by `API_VERSION`, and all the module registers
+//! crosses as `#[repr(C)]` data and function pointers. The wrappers in this
+//! module are compiled into the module and hide that behind `ModuleInit`,
+//! `Context` and `ModuleType`, so it does not need the compiler that built
+//! the server.
+//!
+//! The entry point names the module and registers its commands, which the
+//! server dispatches like its own using their `CommandProperties`, and its
+//! types, which are stored in the keyspace as `Value::Module` and serialized
+//! through `dump` and the RDB file.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate database;
+//! extern crate parser;
+//! extern crate response;
+//!
+//! use database::module::{CommandFlags, CommandProperties, Context, ModuleInit, ModuleType};
+//! use parser::ParsedCommand;
+//! use response::Response;
+//!
+//! #[derive(Debug)]
+//! struct Counter(i64);
+//!
+//! impl ModuleType for Counter {
+//!     const NAME: &'static str = "counter-t";
+//!
+//!     fn rdb_save(&self) -> Vec<u8> {
+//!         self.0.to_string().into_bytes()
+//!     }
+//!
+//!     fn rdb_load(data: &[u8], _: u16) -> Result<Self, String> {
+//!         match String::from_utf8_lossy(data).parse() {
+//!             Ok(n) => Ok(Counter(n)),
+//!             Err(e) => Err(e.to_string()),
+//!         }
+//!     }
+//! }
+//!
+//! fn incr(ctx: &mut Context, parser: &ParsedCommand) -> Response {
+//!     let key = parser.get_vec(1).unwrap();
+//!     let n = match ctx.get_typed_mut::<Counter>(&key) {
+//!         Ok(Some(counter)) => {
+//!             counter.0 += 1;
+//!             counter.0
+//!         }
+//!         Ok(None) => match ctx.set_typed(&key, Counter(1)) {
+//!             Ok(()) => 1,
+//!             Err(err) => return Response::Error(err),
+//!         },
+//!         Err(err) => return Response::Error(err.to_string()),
+//!     };
+//!     ctx.key_updated(&key);
+//!     Response::Integer(n)
+//! }
+//!
+//! fn init(module: &mut ModuleInit, _: &[Vec<u8>]) -> Result<(), String> {
+//!     module.set_name("counter", 1);
+//!     module.create_type::<Counter>()?;
+//!     module.create_command(
+//!         "counter.incr",
+//!         CommandProperties {
+//!             arity: 2,
+//!             flags: CommandFlags::WRITE | CommandFlags::FAST,
+//!             first_key_index: 1,
+//!             last_key_index: 1,
+//!             key_step: 1,
+//!         },
+//!         incr,
+//!     )
+//! }
+//!
+//! declare_module!(init);
+//! # fn main() {}
+//! ```
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+use libloading::Library;
+use logger::Level;
+use parser::{Argument, ParsedCommand};
+use rdbutil::constants::*;
+use rdbutil::{decode_len, decode_slice_u8, encode_len, encode_slice_u8, prealloc_len, DecodeError};
+use response::Response;
+
+use error::OperationError;
+
+use super::{Database, Value};
+
+/// Version of the interface between the server and the modules, the layout
+/// of `ModuleApi` and of the types it passes. A module built for another
+/// version is refused.
+pub const API_VERSION: u32 = 2;
+
+/// Characters a type name is made of. Each one is stored in 6 bits of the
+/// type id.
+const TYPE_NAME_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+bitflags! {
+    pub struct CommandFlags: u16 {
+        /// write command (may modify the key space).
+        const WRITE = 1;
+        /// read command  (will never modify the key space).
+        const READONLY = 2;
+        /// may increase memory usage once called. Don't allow if out of memory.
+        const DENYOOM = 4;
+        /// admin command, like SAVE or SHUTDOWN.
+        const ADMIN = 8;
+        /// Pub/Sub related command.
+        const PUBSUB = 16;
+        /// command not allowed in scripts.
+        const NOSCRIPT = 32;
+        /// random command. Command is not deterministic, that is, the same command
+        /// with the same arguments, with the same key space, may have different
+        /// results. For instance SPOP and RANDOMKEY are two random commands.
+        const RANDOM = 64;
+        /// Sort command output array if called from script, so that the output
+        /// is deterministic.
+        const SORT_FOR_SCRIPT = 128;
+        /// Allow command while loading the database.
+        const LOADING = 256;
+        /// Allow command while a slave has stale data but is not allowed to
+        /// server this data. Normally no command is accepted in this condition
+        /// but just a few.
+        const STALE = 512;
+        /// Do not automatically propagate the command on MONITOR.
+        const SKIP_MONITOR = 1024;
+        /// Perform an implicit ASKING for this command, so the command will be
+        /// accepted in cluster mode if the slot is marked as 'importing'.
+        const ASKING = 2048;
+        /// Fast command(1) or O(log(N)) command that should never delay
+        /// its execution as long as the kernel scheduler is giving us time.
+        /// Note that commands that may trigger a DEL as a side effect (like SET)
+        /// are not fast commands.
+        const FAST = 4096;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CommandProperties {
+    /// Number of arguments, the name included. A negative number `-n`
+    /// means at least `n`.
+    pub arity: i64,
+    /// Flags as bitmask. Computed by Redis using the 'sflags' field.
+    pub flags: CommandFlags,
+    /// First argument that is a key
+    pub first_key_index: i64,
+    /// Last argument that is a key
+    pub last_key_index: i64,
+    /// Step to get all the keys from first to last argument. For instance
+    ///           in MSET the step is two since arguments are key,val,key,val,...
+    pub key_step: i64,
+}
+
+/// A value type defined by a module.
+pub trait ModuleType: Any + Send + fmt::Debug + Sized {
+    /// Name of the type, replied by TYPE and stored with every value to
+    /// find the type that loads it. It must be nine characters long, made
+    /// of `A-Z`, `a-z`, `0-9`, `-` and `_`.
+    const NAME: &'static str;
+    /// Version of the serialization, from 0 to 1023, given back to
+    /// `rdb_load` with the data it wrote.
+    const ENCODING_VERSION: u16 = 0;
+
+    /// Serializes the value for DUMP and the RDB file.
+    fn rdb_save(&self) -> Vec<u8>;
+
+    /// Deserializes a value written by `rdb_save` with the encoding version
+    /// `encver`.
+    fn rdb_load(data: &[u8], encver: u16) -> Result<Self, String>;
+}
+
+/// Packs a type name and an encoding version in the 64 bits id stored in
+/// the RDB file: 54 bits for the name and 10 for the version. `None` if the
+/// name or the version are not valid.
+///
+/// # Examples
+/// ```
+/// use database::module::type_id;
+///
+/// assert_eq!(type_id("AAAAAAAAB", 1), Some(1025));
+/// assert_eq!(type_id("short", 0), None);
+/// assert_eq!(type_id("AAAAAAAAB", 1024), None);
+/// ```
+pub fn type_id(name: &str, encver: u16) -> Option<u64> {
+    if name.len() != 9 || encver > 1023 {
+        return None;
+    }
+    let mut id = 0;
+    for c in name.bytes() {
+        id = (id << 6) | TYPE_NAME_CHARSET.iter().position(|x| *x == c)? as u64;
+    }
+    Some((id << 10) | encver as u64)
+}
+
+/// The name packed in a type id.
+fn type_name(id: u64) -> String {
+    let mut name = vec![0; 9];
+    let mut id = id >> 10;
+    for c in name.iter_mut().rev() {
+        *c = TYPE_NAME_CHARSET[(id & 63) as usize];
+        id >>= 6;
+    }
+    String::from_utf8(name).unwrap()
+}
+
+/// Bytes owned by the side that passes them, valid for the duration of the
+/// call.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl RawSlice {
+    pub fn new(data: &[u8]) -> RawSlice {
+        RawSlice {
+            ptr: data.as_ptr(),
+            len: data.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` bytes that outlive the returned slice.
+    pub unsafe fn as_slice<'a>(&self) -> &'a [u8] {
+        slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// A buffer owned by the side that passes it, which the other side fills
+/// by calling `write`.
+#[repr(C)]
+pub struct RawWriter {
+    target: *mut c_void,
+    write: extern "C" fn(target: *mut c_void, data: RawSlice),
+}
+
+impl RawWriter {
+    /// A writer appending to `buffer`, which must outlive it.
+    pub fn new(buffer: &mut Vec<u8>) -> RawWriter {
+        RawWriter {
+            target: buffer as *mut Vec<u8> as *mut c_void,
+            write: write_vec,
+        }
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        (self.write)(self.target, RawSlice::new(data))
+    }
+}
+
+extern "C" fn write_vec(target: *mut c_void, data: RawSlice) {
+    unsafe { (*(target as *mut Vec<u8>)).extend_from_slice(data.as_slice()) }
+}
+
+/// `CommandProperties` as passed to the server.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawCommandProperties {
+    pub arity: i64,
+    pub flags: u32,
+    pub first_key_index: i64,
+    pub last_key_index: i64,
+    pub key_step: i64,
+}
+
+/// Runs a module command with its `argc` arguments, the name included, and
+/// the data given when it was registered. It replies through the `reply_*`
+/// functions of `ModuleApi`.
+pub type RawHandler = extern "C" fn(ctx: *mut c_void, argv: *const RawSlice, argc: usize, data: *mut c_void);
+
+/// Releases data owned by the module.
+pub type RawFree = extern "C" fn(data: *mut c_void);
+
+/// The functions of a type registered by a module, working on its values as
+/// opaque pointers.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawTypeMethods {
+    /// Serializes the value into `out`
+    pub rdb_save: extern "C" fn(value: *const c_void, out: RawWriter),
+    /// Deserializes a value written with the encoding version `encver`.
+    /// On failure it returns null and writes the reason into `err`.
+    pub rdb_load: extern "C" fn(data: RawSlice, encver: u16, err: RawWriter) -> *mut c_void,
+    pub free: RawFree,
+}
+
+/// The functions the server gives to the entry point of a module. `module`
+/// is the module being loaded, and `ctx` the context of a running command.
+/// The fallible ones return 0 on success, and -1 after writing the reason
+/// into `err`.
+#[repr(C)]
+pub struct ModuleApi {
+    /// `API_VERSION` of the server
+    pub version: u32,
+    pub set_name: extern "C" fn(module: *mut c_void, name: RawSlice, version: i64),
+    /// Registers a command. Once it succeeds the server owns `data`, and
+    /// releases it with `free`.
+    pub create_command: extern "C" fn(
+        module: *mut c_void,
+        name: RawSlice,
+        properties: RawCommandProperties,
+        handler: RawHandler,
+        data: *mut c_void,
+        free: RawFree,
+        err: RawWriter,
+    ) -> i32,
+    pub create_type: extern "C" fn(
+        module: *mut c_void,
+        name: RawSlice,
+        encver: u16,
+        methods: RawTypeMethods,
+        err: RawWriter,
+    ) -> i32,
+    pub reply_nil: extern "C" fn(ctx: *mut c_void),
+    pub reply_integer: extern "C" fn(ctx: *mut c_void, n: i64),
+    pub reply_data: extern "C" fn(ctx: *mut c_void, data: RawSlice),
+    pub reply_status: extern "C" fn(ctx: *mut c_void, status: RawSlice),
+    pub reply_error: extern "C" fn(ctx: *mut c_void, error: RawSlice),
+    pub reply_raw: extern "C" fn(ctx: *mut c_void, data: RawSlice),
+    /// Starts an array, filled by the next `len` replies.
+    pub reply_array: extern "C" fn(ctx: *mut c_void, len: usize),
+    pub dbindex: extern "C" fn(ctx: *mut c_void) -> usize,
+    pub log: extern "C" fn(ctx: *mut c_void, level: u8, message: RawSlice),
+    /// Writes the string in `key` into `out`. Returns 1 if there is one, 0
+    /// if the key does not exist and -1 if it holds another type.
+    pub get_string: extern "C" fn(ctx: *mut c_void, key: RawSlice, out: RawWriter) -> i32,
+    pub set_string: extern "C" fn(ctx: *mut c_void, key: RawSlice, value: RawSlice),
+    /// Returns 1 if the key existed, 0 otherwise.
+    pub remove: extern "C" fn(ctx: *mut c_void, key: RawSlice) -> i32,
+    /// Sets `value` to the value of the type `type_name` in `key`. Returns 1
+    /// if there is one, 0 if the key does not exist and -1 if it holds
+    /// another type.
+    pub get_value: extern "C" fn(ctx: *mut c_void, key: RawSlice, type_name: RawSlice, value: *mut *mut c_void) -> i32,
+    /// Stores `value`, of the type `type_name`, in `key`. Returns 0 and
+    /// leaves `value` to the module if the type is not registered.
+    pub set_value: extern "C" fn(ctx: *mut c_void, key: RawSlice, type_name: RawSlice, value: *mut c_void) -> i32,
+    pub key_updated: extern "C" fn(ctx: *mut c_void, key: RawSlice),
+    pub notify_keyspace_event: extern "C" fn(ctx: *mut c_void, event: RawSlice, key: RawSlice, class: u8),
+}
+
+/// The entry point of a module, exported as `rsedis_module_init`. It gets
+/// the server functions, the module being loaded and the arguments given
+/// after its path, and returns 0 on success or -1 after writing the reason
+/// into `err`.
+pub type EntryPoint = extern "C" fn(
+    api: *const ModuleApi,
+    module: *mut c_void,
+    argv: *const RawSlice,
+    argc: usize,
+    err: RawWriter,
+) -> i32;
+
+fn level_code(level: &Level) -> u8 {
+    match *level {
+        Level::Debug => 0,
+        Level::Verbose => 1,
+        Level::Notice => 2,
+        Level::Warning => 3,
+    }
+}
+
+fn code_level(code: u8) -> Level {
+    match code {
+        0 => Level::Debug,
+        1 => Level::Verbose,
+        2 => Level::Notice,
+        _ => Level::Warning,
+    }
+}
+
+/// The server functions, as seen from the module. Set by `init_module`.
+static API: AtomicPtr<ModuleApi> = AtomicPtr::new(ptr::null_mut());
+
+fn api() -> &'static ModuleApi {
+    unsafe { &*API.load(Ordering::Relaxed) }
+}
+
+/// Exports `init`, a `fn(&mut ModuleInit, &[Vec<u8>]) -> Result<(), String>`,
+/// as the entry point of a module. The server calls it with the arguments
+/// given after the path of the module.
+#[macro_export]
+macro_rules! declare_module {
+    ($init: path) => {
+        #[no_mangle]
+        pub static RSEDIS_MODULE_API_VERSION: u32 = $crate::module::API_VERSION;
+
+        #[no_mangle]
+        pub extern "C" fn rsedis_module_init(
+            api: *const $crate::module::ModuleApi,
+            module: *mut ::std::ffi::c_void,
+            argv: *const $crate::module::RawSlice,
+            argc: usize,
+            err: $crate::module::RawWriter,
+        ) -> i32 {
+            unsafe { $crate::module::init_module(api, module, argv, argc, err, $init) }
+        }
+    };
+}
+
+/// Runs `init` as the entry point of a module. `declare_module!` exports a
+/// function calling it.
+///
+/// # Safety
+///
+/// The arguments must be the ones the server gave to the entry point.
+pub unsafe fn init_module(
+    api: *const ModuleApi,
+    module: *mut c_void,
+    argv: *const RawSlice,
+    argc: usize,
+    err: RawWriter,
+    init: fn(&mut ModuleInit, &[Vec<u8>]) -> Result<(), String>,
+) -> i32 {
+    if api.is_null() || (*api).version != API_VERSION {
+        err.write(format!("the module was built for the module API version {}", API_VERSION).as_bytes());
+        return -1;
+    }
+    API.store(api as *mut ModuleApi, Ordering::Relaxed);
+    let args = slice::from_raw_parts(argv, argc)
+        .iter()
+        .map(|arg| arg.as_slice().to_vec())
+        .collect::<Vec<_>>();
+    let mut module = ModuleInit { raw: module };
+    match catch_unwind(AssertUnwindSafe(|| init(&mut module, &args))) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            err.write(e.as_bytes());
+            -1
+        }
+        Err(_) => {
+            err.write(b"the entry point panicked");
+            -1
+        }
+    }
+}
+
+/// The module being loaded, as seen from its entry point.
+pub struct ModuleInit {
+    raw: *mut c_void,
+}
+
+impl ModuleInit {
+    /// Names the module. Loaded modules must have different names.
+    pub fn set_name(&mut self, name: &str, version: i64) {
+        (api().set_name)(self.raw, RawSlice::new(name.as_bytes()), version)
+    }
+
+    /// Registers the command `name`, case insensitive, to be run by
+    /// `handler`. The server checks the arity in `properties` before
+    /// calling it, and propagates the command as is to the append only
+    /// file and the replicas when it has the `WRITE` flag and succeeds.
+    pub fn create_command<F>(&mut self, name: &str, properties: CommandProperties, handler: F) -> Result<(), String>
+    where
+        F: Fn(&mut Context, &ParsedCommand) -> Response + Send + Sync + 'static,
+    {
+        let properties = RawCommandProperties {
+            arity: properties.arity,
+            flags: properties.flags.bits() as u32,
+            first_key_index: properties.first_key_index,
+            last_key_index: properties.last_key_index,
+            key_step: properties.key_step,
+        };
+        let data = Box::into_raw(Box::new(handler)) as *mut c_void;
+        let mut err = vec![];
+        let r = (api().create_command)(
+            self.raw,
+            RawSlice::new(name.as_bytes()),
+            properties,
+            call_command::<F>,
+            data,
+            free_box::<F>,
+            RawWriter::new(&mut err),
+        );
+        if r != 0 {
+            free_box::<F>(data);
+            return Err(String::from_utf8_lossy(&err).into_owned());
+        }
+        Ok(())
+    }
+
+    /// Registers the type `T`, so its values can be loaded.
+    pub fn create_type<T: ModuleType>(&mut self) -> Result<(), String> {
+        let methods = RawTypeMethods {
+            rdb_save: save_value::<T>,
+            rdb_load: load_value::<T>,
+            free: free_box::<T>,
+        };
+        let mut err = vec![];
+        let r = (api().create_type)(
+            self.raw,
+            RawSlice::new(T::NAME.as_bytes()),
+            T::ENCODING_VERSION,
+            methods,
+            RawWriter::new(&mut err),
+        );
+        if r != 0 {
+            return Err(String::from_utf8_lossy(&err).into_owned());
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn free_box<T>(data: *mut c_void) {
+    drop(unsafe { Box::from_raw(data as *mut T) })
+}
+
+extern "C" fn save_value<T: ModuleType>(value: *const c_void, out: RawWriter) {
+    let value = unsafe { &*(value as *const T) };
+    out.write(&value.rdb_save());
+}
+
+extern "C" fn load_value<T: ModuleType>(data: RawSlice, encver: u16, err: RawWriter) -> *mut c_void {
+    match T::rdb_load(unsafe { data.as_slice() }, encver) {
+        Ok(value) => Box::into_raw(Box::new(value)) as *mut c_void,
+        Err(e) => {
+            err.write(e.as_bytes());
+            ptr::null_mut()
+        }
+    }
+}
+
+extern "C" fn call_command<F>(ctx: *mut c_void, argv: *const RawSlice, argc: usize, data: *mut c_void)
+where
+    F: Fn(&mut Context, &ParsedCommand) -> Response,
+{
+    let handler = unsafe { &*(data as *const F) };
+    let mut buffer = vec![];
+    let mut arguments = vec![];
+    for arg in unsafe { slice::from_raw_parts(argv, argc) } {
+        let arg = unsafe { arg.as_slice() };
+        arguments.push(Argument {
+            pos: buffer.len(),
+            len: arg.len(),
+        });
+        buffer.extend_from_slice(arg);
+    }
+    let parser = ParsedCommand::new(&buffer, arguments);
+    let mut context = Context { raw: ctx };
+    let response = match catch_unwind(AssertUnwindSafe(|| handler(&mut context, &parser))) {
+        Ok(response) => response,
+        Err(_) => Response::Error("ERR the module command panicked".to_owned()),
+    };
+    reply(ctx, &response);
+}
+
+/// Sends `response` to the server as the reply of a command.
+fn reply(ctx: *mut c_void, response: &Response) {
+    let api = api();
+    match *response {
+        Response::Nil => (api.reply_nil)(ctx),
+        Response::Integer(n) => (api.reply_integer)(ctx, n),
+        Response::Data(ref data) => (api.reply_data)(ctx, RawSlice::new(data)),
+        Response::Status(ref status) => (api.reply_status)(ctx, RawSlice::new(status.as_bytes())),
+        Response::Error(ref error) => (api.reply_error)(ctx, RawSlice::new(error.as_bytes())),
+        Response::Raw(ref data) => (api.reply_raw)(ctx, RawSlice::new(data)),
+        Response::Array(ref items) => {
+            (api.reply_array)(ctx, items.len());
+            for item in items.iter() {
+                reply(ctx, item);
+            }
+        }
+    }
+}
+
+/// What a module command gets to work on the database selected by the
+/// client that called it.
+pub struct Context {
+    raw: *mut c_void,
+}
+
+impl Context {
+    pub fn dbindex(&self) -> usize {
+        (api().dbindex)(self.raw)
+    }
+
+    /// Logs `message` with the server logger.
+    pub fn log(&self, level: Level, message: &str) {
+        (api().log)(self.raw, level_code(&level), RawSlice::new(message.as_bytes()))
+    }
+
+    /// The string in `key`, if it exists. Fails if it is not a string.
+    pub fn get_string(&self, key: &[u8]) -> Result<Option<Vec<u8>>, OperationError> {
+        let mut value = vec![];
+        match (api().get_string)(self.raw, RawSlice::new(key), RawWriter::new(&mut value)) {
+            0 => Ok(None),
+            1 => Ok(Some(value)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// Stores the string `value` in `key`, replacing any value it had but
+    /// keeping its expiration.
+    pub fn set_string(&mut self, key: &[u8], value: &[u8]) {
+        (api().set_string)(self.raw, RawSlice::new(key), RawSlice::new(value))
+    }
+
+    /// Removes `key`, returning whether it existed.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        (api().remove)(self.raw, RawSlice::new(key)) == 1
+    }
+
+    fn get_value<T: ModuleType>(&self, key: &[u8]) -> Result<Option<*mut T>, OperationError> {
+        let mut value = ptr::null_mut();
+        match (api().get_value)(self.raw, RawSlice::new(key), RawSlice::new(T::NAME.as_bytes()), &mut value) {
+            0 => Ok(None),
+            1 => Ok(Some(value as *mut T)),
+            _ => Err(OperationError::WrongTypeError),
+        }
+    }
+
+    /// The value of `key`, if it exists. Fails if it is not a `T`.
+    pub fn get_typed<T: ModuleType>(&self, key: &[u8]) -> Result<Option<&T>, OperationError> {
+        Ok(self.get_value::<T>(key)?.map(|value| unsafe { &*value }))
+    }
+
+    /// The value of `key`, if it exists. Fails if it is not a `T`.
+    pub fn get_typed_mut<T: ModuleType>(&mut self, key: &[u8]) -> Result<Option<&mut T>, OperationError> {
+        Ok(self.get_value::<T>(key)?.map(|value| unsafe { &mut *value }))
+    }
+
+    /// Stores `value` in `key`, replacing any value it had but keeping its
+    /// expiration. Fails if the module did not register `T`.
+    pub fn set_typed<T: ModuleType>(&mut self, key: &[u8], value: T) -> Result<(), String> {
+        let value = Box::into_raw(Box::new(value)) as *mut c_void;
+        if (api().set_value)(self.raw, RawSlice::new(key), RawSlice::new(T::NAME.as_bytes()), value) != 1 {
+            free_box::<T>(value);
+            return Err(format!("the type '{}' is not registered", T::NAME));
+        }
+        Ok(())
+    }
+
+    /// Wakes up the clients watching or blocked on `key`, and removes it if
+    /// it is empty. Called after every change.
+    pub fn key_updated(&mut self, key: &[u8]) {
+        (api().key_updated)(self.raw, RawSlice::new(key))
+    }
+
+    /// Publishes the keyspace event `event` for `key`, of the class
+    /// `class` of `notify-keyspace-events`.
+    pub fn notify_keyspace_event(&self, event: &str, key: &[u8], class: char) {
+        (api().notify_keyspace_event)(self.raw, RawSlice::new(event.as_bytes()), RawSlice::new(key), class as u8)
+    }
+}
+
+/// A type registered by a module.
+struct TypeInfo {
+    name: String,
+    /// The type id, with the encoding version
+    id: u64,
+    methods: RawTypeMethods,
+}
+
+/// A value of a type defined by a module, owned by the module code.
+pub struct ValueModule {
+    module_type: Arc<TypeInfo>,
+    value: *mut c_void,
+}
+
+// the value is only reached by the thread holding the database
+unsafe impl Send for ValueModule {}
+
+impl Drop for ValueModule {
+    fn drop(&mut self) {
+        (self.module_type.methods.free)(self.value)
+    }
+}
+
+impl fmt::Debug for ValueModule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ValueModule({})", self.module_type.name)
+    }
+}
+
+impl PartialEq for ValueModule {
+    fn eq(&self, other: &ValueModule) -> bool {
+        self.module_type.id == other.module_type.id && self.save() == other.save()
+    }
+}
+
+/// Reads the data of a module value, up to its end opcode. Returns the
+/// strings it holds, or `None` if it also holds other items, which only
+/// the modules of a Redis server write.
+fn decode_module_data<T: Read>(reader: &mut T) -> Result<Option<Vec<u8>>, DecodeError> {
+    let mut data = Some(vec![]);
+    loop {
+        let opcode = decode_len(reader)?;
+        if opcode == MODULE_OPCODE_EOF as usize {
+            return Ok(data);
+        }
+        if opcode == MODULE_OPCODE_STRING as usize {
+            let s = decode_slice_u8(reader)?;
+            if let Some(ref mut data) = data {
+                data.extend_from_slice(&s);
+            }
+            continue;
+        }
+        if opcode == MODULE_OPCODE_SINT as usize || opcode == MODULE_OPCODE_UINT as usize {
+            decode_len(reader)?;
+        } else if opcode == MODULE_OPCODE_FLOAT as usize {
+            reader.read_exact(&mut [0; 4])?;
+        } else if opcode == MODULE_OPCODE_DOUBLE as usize {
+            reader.read_exact(&mut [0; 8])?;
+        } else {
+            return Err(DecodeError::InvalidData(format!("Unknown module opcode {}", opcode)));
+        }
+        data = None;
+    }
+}
+
+impl ValueModule {
+    pub fn type_name(&self) -> &str {
+        &self.module_type.name
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut data = vec![];
+        (self.module_type.methods.rdb_save)(self.value, RawWriter::new(&mut data));
+        data
+    }
+
+    pub fn rdb_type(&self) -> u8 {
+        TYPE_MODULE_2
+    }
+
+    /// Writes the type id, then the data serialized by the type as a
+    /// string item, like Redis does for a module that saves one buffer.
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        encode_len(self.module_type.id as usize, &mut v)?;
+        encode_len(MODULE_OPCODE_STRING as usize, &mut v)?;
+        encode_slice_u8(&self.save(), &mut v, false)?;
+        encode_len(MODULE_OPCODE_EOF as usize, &mut v)?;
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    /// Reads a value written by `rdb_save`, with a type registered by one
+    /// of `modules`.
+    pub fn rdb_load<T: Read>(reader: &mut T, modules: &Modules) -> Result<Self, DecodeError> {
+        let id = decode_len(reader)? as u64;
+        let data = decode_module_data(reader)?;
+        let module_type = match modules.find_type(id) {
+            Some(module_type) => module_type.clone(),
+            None => {
+                return Err(DecodeError::InvalidData(format!(
+                    "The RDB file contains module data for the module type '{}', that the \
+                     server is not able to load. Check for modules not loaded.",
+                    type_name(id)
+                )))
+            }
+        };
+        let data = match data {
+            Some(data) => data,
+            None => {
+                return Err(DecodeError::InvalidData(format!(
+                    "The value of the module type '{}' was not saved as a string",
+                    module_type.name
+                )))
+            }
+        };
+        let mut err = vec![];
+        let encver = (id & 1023) as u16;
+        let value = (module_type.methods.rdb_load)(RawSlice::new(&data), encver, RawWriter::new(&mut err));
+        if value.is_null() {
+            return Err(DecodeError::InvalidData(format!(
+                "Error loading a value of the module type '{}': {}",
+                module_type.name,
+                String::from_utf8_lossy(&err)
+            )));
+        }
+        Ok(ValueModule {
+            module_type: module_type,
+            value: value,
+        })
+    }
+
+    /// Reads past a value written by `rdb_save`, or by a Redis module,
+    /// without loading it.
+    pub fn rdb_skip<T: Read>(reader: &mut T) -> Result<(), DecodeError> {
+        decode_len(reader)?;
+        decode_module_data(reader)?;
+        Ok(())
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
+        let data = [
+            vec![self.rdb_type()],
+            v,
+            vec![(VERSION & 0xff) as u8],
+            vec![((VERSION >> 8) & 0xff) as u8],
+        ]
+        .concat();
+        writer.write(&*data)
+    }
+
+    pub fn debug_object(&self) -> String {
+        let mut serialized_data = vec![];
+        let serialized = self.dump(&mut serialized_data).unwrap();
+        format!(
+            "Value at:0x0000000000 refcount:1 encoding:raw serializedlength:{} lru:0 \
+             lru_seconds_idle:0",
+            serialized
+        )
+    }
+}
+
+/// The function of a module command, with the data it was registered with.
+struct Handler {
+    call: RawHandler,
+    data: *mut c_void,
+    free: RawFree,
+}
+
+// the module declared the data `Send + Sync` when registering the command
+unsafe impl Send for Handler {}
+unsafe impl Sync for Handler {}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        (self.free)(self.data)
+    }
+}
+
+/// A command registered by a module.
+#[derive(Clone)]
+pub struct Command {
+    pub properties: CommandProperties,
+    handler: Arc<Handler>,
+}
+
+impl Command {
+    /// Runs the command on the database `dbindex`, once its arity is
+    /// checked.
+    pub fn call(&self, db: &mut Database, dbindex: usize, parser: &ParsedCommand) -> Response {
+        let argv = (0..parser.argv.len())
+            .map(|i| RawSlice::new(parser.get_slice(i).unwrap_or(&[])))
+            .collect::<Vec<_>>();
+        let mut ctx = CallContext {
+            db: db,
+            dbindex: dbindex,
+            arrays: vec![],
+            reply: None,
+        };
+        (self.handler.call)(
+            &mut ctx as *mut CallContext as *mut c_void,
+            argv.as_ptr(),
+            argv.len(),
+            self.handler.data,
+        );
+        match ctx.reply {
+            Some(reply) if ctx.arrays.is_empty() => reply,
+            _ => Response::Error("ERR the module command did not reply".to_owned()),
+        }
+    }
+}
+
+/// A module, with the commands and types it registers.
+pub struct Module {
+    name: String,
+    version: i64,
+    path: String,
+    args: Vec<Vec<u8>>,
+    commands: BTreeMap<String, Command>,
+    types: Vec<Arc<TypeInfo>>,
+    /// Declared last, so the commands are dropped before the code they run
+    library: Option<Library>,
+}
+
+impl Module {
+    /// Loads the dynamic library at `path` and runs its entry point with
+    /// `args`. The module is not added to any registry.
+    pub fn open(path: &str, args: Vec<Vec<u8>>) -> Result<Module, String> {
+        let library = unsafe { Library::new(path) }.map_err(|e| e.to_string())?;
+        let version = unsafe {
+            match library.get::<*const u32>(b"RSEDIS_MODULE_API_VERSION\0") {
+                Ok(version) => **version,
+                Err(_) => return Err("it does not export a module API version".to_owned()),
+            }
+        };
+        if version != API_VERSION {
+            return Err(format!(
+                "it was built for the module API version {}, the server supports {}",
+                version, API_VERSION
+            ));
+        }
+        let entry = unsafe {
+            match library.get::<EntryPoint>(b"rsedis_module_init\0") {
+                Ok(entry) => *entry,
+                Err(_) => return Err("it does not export an entry point".to_owned()),
+            }
+        };
+        let mut module = Module::load(path, args, entry)?;
+        module.library = Some(library);
+        Ok(module)
+    }
+
+    /// Runs `entry`, the entry point of a module linked into the server,
+    /// with `args`. The module is not added to any registry.
+    pub fn load(path: &str, args: Vec<Vec<u8>>, entry: EntryPoint) -> Result<Module, String> {
+        let argv = args.iter().map(|arg| RawSlice::new(arg)).collect::<Vec<_>>();
+        let mut module = Module {
+            name: String::new(),
+            version: 0,
+            path: path.to_owned(),
+            args: args.clone(),
+            commands: BTreeMap::new(),
+            types: vec![],
+            library: None,
+        };
+        let mut err = vec![];
+        let r = entry(
+            &SERVER_API,
+            &mut module as *mut Module as *mut c_void,
+            argv.as_ptr(),
+            argv.len(),
+            RawWriter::new(&mut err),
+        );
+        if r != 0 {
+            return Err(String::from_utf8_lossy(&err).into_owned());
+        }
+        if module.name.is_empty() {
+            return Err("the module did not set its name".to_owned());
+        }
+        Ok(module)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn args(&self) -> &[Vec<u8>] {
+        &self.args
+    }
+
+    /// Registers a command. It takes the ownership of `data` only if it
+    /// succeeds.
+    fn create_command(
+        &mut self,
+        name: &[u8],
+        properties: CommandProperties,
+        handler: RawHandler,
+        data: *mut c_void,
+        free: RawFree,
+    ) -> Result<(), String> {
+        let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+        if name.is_empty() || name.bytes().any(|c| c <= b' ') {
+            return Err(format!("invalid command name '{}'", name));
+        }
+        if properties.arity == 0 {
+            return Err(format!("invalid arity for the command '{}'", name));
+        }
+        if self.commands.contains_key(&name) {
+            return Err(format!("command '{}' already exists", name));
+        }
+        self.commands.insert(
+            name,
+            Command {
+                properties: properties,
+                handler: Arc::new(Handler {
+                    call: handler,
+                    data: data,
+                    free: free,
+                }),
+            },
+        );
+        Ok(())
+    }
+
+    fn create_type(&mut self, name: &[u8], encver: u16, methods: RawTypeMethods) -> Result<(), String> {
+        let name = String::from_utf8_lossy(name).into_owned();
+        let id = match type_id(&name, encver) {
+            Some(id) => id,
+            None => return Err(format!("invalid name or encoding version for the type '{}'", name)),
+        };
+        if self.types.iter().any(|t| t.id >> 10 == id >> 10) {
+            return Err(format!("type '{}' already exists", name));
+        }
+        self.types.push(Arc::new(TypeInfo {
+            name: name,
+            id: id,
+            methods: methods,
+        }));
+        Ok(())
+    }
+
+    /// Names of the commands, sorted.
+    pub fn command_names(&self) -> Vec<&str> {
+        self.commands.keys().map(|name| &name[..]).collect()
+    }
+
+    pub fn type_names(&self) -> Vec<&str> {
+        self.types.iter().map(|t| &t.name[..]).collect()
+    }
+}
+
+/// The loaded modules.
+#[derive(Default)]
+pub struct Modules {
+    modules: BTreeMap<String, Module>,
+}
+
+impl Modules {
+    pub fn new() -> Modules {
+        Modules {
+            modules: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `module`, unless another one has its name, one of its commands
+    /// or one of its types.
+    pub fn add(&mut self, module: Module) -> Result<(), String> {
+        if self.modules.contains_key(&module.name) {
+            return Err(format!("module '{}' already exists", module.name));
+        }
+        for name in module.commands.keys() {
+            if self.command(name).is_some() {
+                return Err(format!("command '{}' already exists", name));
+            }
+        }
+        for t in module.types.iter() {
+            if self.find_type(t.id).is_some() {
+                return Err(format!("type '{}' already exists", t.name));
+            }
+        }
+        self.modules.insert(module.name.clone(), module);
+        Ok(())
+    }
+
+    /// Removes the module `name` and its commands. Modules with types
+    /// cannot be removed, the keyspace may have values using their code.
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        match self.modules.get(name) {
+            None => return Err("no such module with that name".to_owned()),
+            Some(module) if !module.types.is_empty() => {
+                return Err("the module exports one or more module-side data types, can't unload".to_owned())
+            }
+            Some(_) => (),
+        }
+        self.modules.remove(name);
+        Ok(())
+    }
+
+    /// The modules, sorted by name.
+    pub fn modules(&self) -> Vec<&Module> {
+        self.modules.values().collect()
+    }
+
+    /// The command `name`, in lower case, if a module registered it.
+    pub fn command(&self, name: &str) -> Option<&Command> {
+        self.modules.values().filter_map(|module| module.commands.get(name)).next()
+    }
+
+    /// The type with the name packed in `id`, whatever its encoding version.
+    fn find_type(&self, id: u64) -> Option<&Arc<TypeInfo>> {
+        self.modules
+            .values()
+            .flat_map(|module| module.types.iter())
+            .find(|t| t.id >> 10 == id >> 10)
+    }
+
+    fn find_type_by_name(&self, name: &[u8]) -> Option<&Arc<TypeInfo>> {
+        self.modules
+            .values()
+            .flat_map(|module| module.types.iter())
+            .find(|t| t.name.as_bytes() == name)
+    }
+}
+
+/// What a running module command works on: the database selected by the
+/// client that called it, and the reply being built.
+struct CallContext<'a> {
+    db: &'a mut Database,
+    dbindex: usize,
+    /// Arrays being filled, with the number of items they still miss
+    arrays: Vec<(Vec<Response>, usize)>,
+    /// The first complete reply; the following ones are ignored
+    reply: Option<Response>,
+}
+
+impl<'a> CallContext<'a> {
+    fn reply(&mut self, mut response: Response) {
+        while let Some((mut items, missing)) = self.arrays.pop() {
+            items.push(response);
+            if missing > 1 {
+                self.arrays.push((items, missing - 1));
+                return;
+            }
+            response = Response::Array(items);
+        }
+        if self.reply.is_none() {
+            self.reply = Some(response);
+        }
+    }
+}
+
+/// The server functions, given to the entry point of every module.
+static SERVER_API: ModuleApi = ModuleApi {
+    version: API_VERSION,
+    set_name: api_set_name,
+    create_command: api_create_command,
+    create_type: api_create_type,
+    reply_nil: api_reply_nil,
+    reply_integer: api_reply_integer,
+    reply_data: api_reply_data,
+    reply_status: api_reply_status,
+    reply_error: api_reply_error,
+    reply_raw: api_reply_raw,
+    reply_array: api_reply_array,
+    dbindex: api_dbindex,
+    log: api_log,
+    get_string: api_get_string,
+    set_string: api_set_string,
+    remove: api_remove,
+    get_value: api_get_value,
+    set_value: api_set_value,
+    key_updated: api_key_updated,
+    notify_keyspace_event: api_notify_keyspace_event,
+};
+
+fn module<'a>(module: *mut c_void) -> &'a mut Module {
+    unsafe { &mut *(module as *mut Module) }
+}
+
+fn context<'a, 'b>(ctx: *mut c_void) -> &'a mut CallContext<'b> {
+    unsafe { &mut *(ctx as *mut CallContext) }
+}
+
+fn bytes<'a>(data: RawSlice) -> &'a [u8] {
+    unsafe { data.as_slice() }
+}
+
+fn text(data: RawSlice) -> String {
+    String::from_utf8_lossy(bytes(data)).into_owned()
+}
+
+extern "C" fn api_set_name(m: *mut c_void, name: RawSlice, version: i64) {
+    let m = module(m);
+    m.name = text(name);
+    m.version = version;
+}
+
+extern "C" fn api_create_command(
+    m: *mut c_void,
+    name: RawSlice,
+    properties: RawCommandProperties,
+    handler: RawHandler,
+    data: *mut c_void,
+    free: RawFree,
+    err: RawWriter,
+) -> i32 {
+    let properties = CommandProperties {
+        arity: properties.arity,
+        flags: CommandFlags::from_bits_truncate(properties.flags as u16),
+        first_key_index: properties.first_key_index,
+        last_key_index: properties.last_key_index,
+        key_step: properties.key_step,
+    };
+    match module(m).create_command(bytes(name), properties, handler, data, free) {
+        Ok(()) => 0,
+        Err(e) => {
+            err.write(e.as_bytes());
+            -1
+        }
+    }
+}
+
+extern "C" fn api_create_type(
+    m: *mut c_void,
+    name: RawSlice,
+    encver: u16,
+    methods: RawTypeMethods,
+    err: RawWriter,
+) -> i32 {
+    match module(m).create_type(bytes(name), encver, methods) {
+        Ok(()) => 0,
+        Err(e) => {
+            err.write(e.as_bytes());
+            -1
+        }
+    }
+}
+
+extern "C" fn api_reply_nil(ctx: *mut c_void) {
+    context(ctx).reply(Response::Nil)
+}
+
+extern "C" fn api_reply_integer(ctx: *mut c_void, n: i64) {
+    context(ctx).reply(Response::Integer(n))
+}
+
+extern "C" fn api_reply_data(ctx: *mut c_void, data: RawSlice) {
+    context(ctx).reply(Response::Data(bytes(data).to_vec()))
+}
+
+extern "C" fn api_reply_status(ctx: *mut c_void, status: RawSlice) {
+    context(ctx).reply(Response::Status(text(status)))
+}
+
+extern "C" fn api_reply_error(ctx: *mut c_void, error: RawSlice) {
+    context(ctx).reply(Response::Error(text(error)))
+}
+
+extern "C" fn api_reply_raw(ctx: *mut c_void, data: RawSlice) {
+    context(ctx).reply(Response::Raw(bytes(data).to_vec()))
+}
+
+extern "C" fn api_reply_array(ctx: *mut c_void, len: usize) {
+    let ctx = context(ctx);
+    if len == 0 {
+        ctx.reply(Response::Array(vec![]));
+    } else {
+        ctx.arrays.push((Vec::with_capacity(prealloc_len(len)), len));
+    }
+}
+
+extern "C" fn api_dbindex(ctx: *mut c_void) -> usize {
+    context(ctx).dbindex
+}
+
+extern "C" fn api_log(ctx: *mut c_void, level: u8, message: RawSlice) {
+    context(ctx).db.config.logger.log(code_level(level), text(message), None)
+}
+
+extern "C" fn api_get_string(ctx: *mut c_void, key: RawSlice, out: RawWriter) -> i32 {
+    let ctx = context(ctx);
+    match ctx.db.get(ctx.dbindex, bytes(key)) {
+        None | Some(&Value::Nil) => 0,
+        Some(value) => match value.get() {
+            Ok(value) => {
+                out.write(&value);
+                1
+            }
+            Err(_) => -1,
+        },
+    }
+}
+
+extern "C" fn api_set_string(ctx: *mut c_void, key: RawSlice, value: RawSlice) {
+    let ctx = context(ctx);
+    let _ = ctx.db.get_or_create(ctx.dbindex, bytes(key)).set(bytes(value).to_vec());
+}
+
+extern "C" fn api_remove(ctx: *mut c_void, key: RawSlice) -> i32 {
+    let ctx = context(ctx);
+    ctx.db.remove(ctx.dbindex, bytes(key)).is_some() as i32
+}
+
+extern "C" fn api_get_value(ctx: *mut c_void, key: RawSlice, type_name: RawSlice, value: *mut *mut c_void) -> i32 {
+    let ctx = context(ctx);
+    match ctx.db.get(ctx.dbindex, bytes(key)) {
+        None | Some(&Value::Nil) => 0,
+        Some(&Value::Module(ref m)) if m.type_name().as_bytes() == bytes(type_name) => {
+            unsafe { *value = m.value };
+            1
+        }
+        Some(_) => -1,
+    }
+}
+
+extern "C" fn api_set_value(ctx: *mut c_void, key: RawSlice, type_name: RawSlice, value: *mut c_void) -> i32 {
+    let ctx = context(ctx);
+    let module_type = match ctx.db.modules.find_type_by_name(bytes(type_name)) {
+        Some(module_type) => module_type.clone(),
+        None => return 0,
+    };
+    *ctx.db.get_or_create(ctx.dbindex, bytes(key)) = Value::Module(ValueModule {
+        module_type: module_type,
+        value: value,
+    });
+    1
+}
+
+extern "C" fn api_key_updated(ctx: *mut c_void, key: RawSlice) {
+    let ctx = context(ctx);
+    ctx.db.key_updated(ctx.dbindex, bytes(key));
+}
+
+extern "C" fn api_notify_keyspace_event(ctx: *mut c_void, event: RawSlice, key: RawSlice, class: u8) {
+    let ctx = context(ctx);
+    ctx.db
+        .notify_keyspace_event(ctx.dbindex, &text(event), bytes(key), Some(class as char));
+}
+
+#[cfg(test)]
+mod test_module {
+    use std::ffi::c_void;
+
+    use parser::{parse, ParsedCommand};
+    use rdbutil::constants::TYPE_MODULE_2;
+    use response::Response;
+
+    use super::super::{Database, Value};
+    use super::{init_module, type_id, type_name, CommandFlags, CommandProperties, Context, Module, ModuleApi,
+                ModuleInit, ModuleType, Modules, RawSlice, RawWriter, ValueModule};
+
+    #[derive(Debug, PartialEq)]
+    struct Counter(i64);
+
+    impl ModuleType for Counter {
+        const NAME: &'static str = "counter-t";
+        const ENCODING_VERSION: u16 = 2;
+
+        fn rdb_save(&self) -> Vec<u8> {
+            self.0.to_string().into_bytes()
+        }
+
+        fn rdb_load(data: &[u8], encver: u16) -> Result<Self, String> {
+            assert_eq!(encver, 2);
+            String::from_utf8_lossy(data)
+                .parse()
+                .map(Counter)
+                .map_err(|_| "not a number".to_owned())
+        }
+    }
+
+    fn properties() -> CommandProperties {
+        CommandProperties {
+            arity: 2,
+            flags: CommandFlags::WRITE,
+            first_key_index: 1,
+            last_key_index: 1,
+            key_step: 1,
+        }
+    }
+
+    fn incr(ctx: &mut Context, parser: &ParsedCommand) -> Response {
+        let key = parser.get_vec(1).unwrap();
+        let n = match ctx.get_typed_mut::<Counter>(&key) {
+            Ok(Some(counter)) => {
+                counter.0 += 1;
+                counter.0
+            }
+            Ok(None) => {
+                ctx.set_typed(&key, Counter(1)).unwrap();
+                1
+            }
+            Err(err) => return Response::Error(err.to_string()),
+        };
+        Response::Integer(n)
+    }
+
+    fn info(ctx: &mut Context, parser: &ParsedCommand) -> Response {
+        let key = parser.get_vec(1).unwrap();
+        let counter = match ctx.get_typed::<Counter>(&key) {
+            Ok(counter) => counter.map_or(Response::Nil, |c| Response::Integer(c.0)),
+            Err(err) => return Response::Error(err.to_string()),
+        };
+        Response::Array(vec![
+            Response::Status("counter".to_owned()),
+            Response::Array(vec![counter, Response::Array(vec![])]),
+            Response::Data(ctx.dbindex().to_string().into_bytes()),
+        ])
+    }
+
+    fn counter_init(module: &mut ModuleInit, args: &[Vec<u8>]) -> Result<(), String> {
+        if !args.is_empty() {
+            return Err("unexpected arguments".to_owned());
+        }
+        module.set_name("counter", 1);
+        module.create_type::<Counter>()?;
+        module.create_command("COUNTER.INCR", properties(), incr)?;
+        module.create_command("counter.info", properties(), info)?;
+        assert!(module.create_type::<Counter>().is_err());
+        assert!(module.create_command("counter.incr", properties(), incr).is_err());
+        assert!(module.create_command("bad name", properties(), incr).is_err());
+        Ok(())
+    }
+
+    extern "C" fn counter_entry(
+        api: *const ModuleApi,
+        module: *mut c_void,
+        argv: *const RawSlice,
+        argc: usize,
+        err: RawWriter,
+    ) -> i32 {
+        unsafe { init_module(api, module, argv, argc, err, counter_init) }
+    }
+
+    fn commands_init(module: &mut ModuleInit, _: &[Vec<u8>]) -> Result<(), String> {
+        module.set_name("commands", 1);
+        module.create_command("commands.incr", properties(), incr)
+    }
+
+    extern "C" fn commands_entry(
+        api: *const ModuleApi,
+        module: *mut c_void,
+        argv: *const RawSlice,
+        argc: usize,
+        err: RawWriter,
+    ) -> i32 {
+        unsafe { init_module(api, module, argv, argc, err, commands_init) }
+    }
+
+    fn counter_module() -> Module {
+        Module::load("counter.so", vec![], counter_entry).unwrap()
+    }
+
+    fn call(db: &mut Database, command: &[u8]) -> Response {
+        let parser = parse(command).unwrap().0;
+        let name = parser.get_str(0).unwrap().to_ascii_lowercase();
+        let command = db.modules.command(&name).unwrap().clone();
+        command.call(db, 0, &parser)
+    }
+
+    #[test]
+    fn type_ids() {
+        let id = type_id("counter-t", 2).unwrap();
+        assert_eq!(id & 1023, 2);
+        assert_eq!(type_name(id), "counter-t");
+        assert!(type_id("counter-t!", 0).is_none());
+        assert!(type_id("counter.t", 0).is_none());
+    }
+
+    #[test]
+    fn register() {
+        let module = counter_module();
+        assert_eq!(module.name(), "counter");
+        assert_eq!(module.command_names(), vec!["counter.incr", "counter.info"]);
+        assert_eq!(module.type_names(), vec!["counter-t"]);
+        assert_eq!(
+            Module::load("counter.so", vec![b"x".to_vec()], counter_entry).err().unwrap(),
+            "unexpected arguments"
+        );
+
+        let mut modules = Modules::new();
+        modules.add(module).unwrap();
+        assert!(modules.command("counter.incr").is_some());
+        assert!(modules.add(counter_module()).is_err());
+        let mut other = counter_module();
+        other.name = "other".to_owned();
+        assert_eq!(modules.add(other).unwrap_err(), "command 'counter.incr' already exists");
+
+        assert_eq!(
+            modules.remove("counter").unwrap_err(),
+            "the module exports one or more module-side data types, can't unload"
+        );
+        modules.add(Module::load("commands.so", vec![], commands_entry).unwrap()).unwrap();
+        modules.remove("commands").unwrap();
+        assert!(modules.command("commands.incr").is_none());
+        assert_eq!(modules.remove("commands").unwrap_err(), "no such module with that name");
+    }
+
+    #[test]
+    fn call_commands() {
+        let mut db = Database::mock();
+        db.modules.add(counter_module()).unwrap();
+        let incr = b"*2\r\n$12\r\ncounter.incr\r\n$3\r\nkey\r\n";
+        let info = b"*2\r\n$12\r\ncounter.info\r\n$3\r\nkey\r\n";
+        match call(&mut db, info) {
+            Response::Array(items) => assert_eq!(items[1], Response::Array(vec![Response::Nil, Response::Array(vec![])])),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(call(&mut db, incr), Response::Integer(1));
+        assert_eq!(call(&mut db, incr), Response::Integer(2));
+        assert_eq!(
+            call(&mut db, info),
+            Response::Array(vec![
+                Response::Status("counter".to_owned()),
+                Response::Array(vec![Response::Integer(2), Response::Array(vec![])]),
+                Response::Data(b"0".to_vec()),
+            ])
+        );
+        match db.get(0, b"key") {
+            Some(&Value::Module(ref value)) => assert_eq!(value.type_name(), "counter-t"),
+            value => panic!("Unexpected value {:?}", value),
+        }
+
+        db.get_or_create(0, b"string").set(b"1".to_vec()).unwrap();
+        match call(&mut db, b"*2\r\n$12\r\ncounter.incr\r\n$6\r\nstring\r\n") {
+            Response::Error(e) => assert!(e.starts_with("WRONGTYPE")),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn dump_restore() {
+        let mut db = Database::mock();
+        db.modules.add(counter_module()).unwrap();
+        call(&mut db, b"*2\r\n$12\r\ncounter.incr\r\n$3\r\nkey\r\n");
+        let value = db.get(0, b"key").unwrap();
+        let mut serialized = vec![];
+        value.dump(&mut serialized).unwrap();
+        assert_eq!(&Value::restore_with(&serialized, &db.modules).unwrap(), value);
+        assert!(Value::restore(&serialized).is_err());
+
+        // the type id, then the data as a string item and the end opcode
+        let mut data = vec![];
+        value.rdb_save(&mut data).unwrap();
+        let id = type_id("counter-t", 2).unwrap().to_be_bytes();
+        assert_eq!(data, [&b"\x81"[..], &id, b"\x05\x011\x00"].concat());
+        ValueModule::rdb_skip(&mut &data[..]).unwrap();
+
+        // items saved by a Redis module are skipped, but not loaded
+        let redis = [&b"\x81"[..], &id, b"\x02\x07\x04\x00\x00\x00\x00\x00\x00\xf0\x3f\x05\x01a\x00"].concat();
+        ValueModule::rdb_skip(&mut &redis[..]).unwrap();
+        assert!(Value::rdb_load_with(TYPE_MODULE_2, &mut &redis[..], &db.modules).is_err());
+    }
+}
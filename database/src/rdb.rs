@@ -0,0 +1,917 @@
+//! Reads and writes the whole keyspace in the RDB snapshot format.
+use std::fmt;
+use std::fs::{remove_file, rename, File};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::process;
+use std::str::from_utf8;
+
+use rdbutil::crc64::crc64;
+use compat::{exit_child, fork, kill, waitpid, waitpid_nohang, Fork};
+use logger::Level;
+use rdbutil::constants::*;
+use rdbutil::{decode_len, decode_slice_u8, decode_u32_from_slice_u8, decode_u64_from_slice_u8,
+              decode_u8, prealloc_len, DecodeError};
+use rdbutil::{encode_len, encode_slice_u8, encode_u64_to_slice_u8};
+use util::mstime;
+
+use module::ValueModule;
+
+use super::{Database, Value};
+
+/// Seconds to wait before retrying a failed automatic background save
+const BGSAVE_RETRY_DELAY: i64 = 5;
+
+/// Wraps a writer, keeping a running crc64 of everything written through it.
+struct ChecksumWriter<'a, W: 'a + Write> {
+    writer: &'a mut W,
+    enabled: bool,
+    crc: u64,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+    fn new(writer: &'a mut W, enabled: bool) -> Self {
+        ChecksumWriter {
+            writer,
+            enabled,
+            crc: 0,
+        }
+    }
+}
+
+impl<'a, W: Write> Write for ChecksumWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if self.enabled {
+            self.crc = crc64(self.crc, &buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Wraps a reader, keeping a running crc64 and count of everything read
+/// through it.
+struct ChecksumReader<'a, R: 'a + Read> {
+    reader: &'a mut R,
+    crc: u64,
+    offset: u64,
+}
+
+impl<'a, R: Read> ChecksumReader<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        ChecksumReader {
+            reader,
+            crc: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for ChecksumReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.crc = crc64(self.crc, &buf[..read]);
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Summary of a snapshot validated by `rdb_check`.
+#[derive(Debug, Default, PartialEq)]
+pub struct RdbCheck {
+    pub version: u16,
+    pub keys: u64,
+    pub expires: u64,
+    /// Keys whose expiration time already passed
+    pub already_expired: u64,
+    /// Length of the snapshot in bytes, including the checksum
+    pub len: u64,
+    /// The stored checksum, `None` if it was not computed
+    pub checksum: Option<u64>,
+}
+
+/// Describes the first problem found by `rdb_check`.
+#[derive(Debug)]
+pub struct RdbCheckError {
+    /// Bytes read when the error was found
+    pub offset: u64,
+    /// What was being read
+    pub doing: &'static str,
+    /// The key being read, if any
+    pub key: Option<Vec<u8>>,
+    pub error: DecodeError,
+}
+
+impl fmt::Display for RdbCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[offset {}] {} while doing {}", self.offset, self.error, self.doing)?;
+        if let Some(key) = &self.key {
+            write!(f, " reading key '{}'", String::from_utf8_lossy(key))?;
+        }
+        Ok(())
+    }
+}
+
+fn read_signature<R: Read>(reader: &mut R) -> Result<u16, DecodeError> {
+    let mut header = [0; 9];
+    reader.read_exact(&mut header)?;
+    if &header[..5] != b"REDIS" {
+        return Err(DecodeError::InvalidData(
+            "Wrong signature trying to load DB from file".to_owned(),
+        ));
+    }
+    let version = from_utf8(&header[5..])
+        .ok()
+        .and_then(|s| s.parse::<u16>().ok())
+        .unwrap_or(0);
+    if version < 1 || version > VERSION {
+        return Err(DecodeError::InvalidData(format!(
+            "Can't handle RDB format version {}",
+            String::from_utf8_lossy(&header[5..])
+        )));
+    }
+    Ok(version)
+}
+
+/// Reads a whole snapshot from `reader` without loading it, verifying every
+/// value and the checksum. `info` is called with the offset and a
+/// description of the opcodes that are not keys.
+/// Nothing after the snapshot is read, so it can also check the preamble of
+/// an append only file.
+pub fn rdb_check<R: Read, F: FnMut(u64, &str)>(
+    reader: &mut R,
+    mut info: F,
+) -> Result<RdbCheck, RdbCheckError> {
+    let mut r = ChecksumReader::new(reader);
+    let mut check = RdbCheck::default();
+    let mut doing = "check-header";
+    let mut key = None;
+    let now = mstime();
+    let result = (|| -> Result<(), DecodeError> {
+        check.version = read_signature(&mut r)?;
+        let mut expiration = None;
+        loop {
+            doing = "read-type";
+            key = None;
+            match decode_u8(&mut r)? {
+                OPCODE_EXPIRETIME_MS => {
+                    doing = "read-expire";
+                    expiration = Some(decode_u64_from_slice_u8(&mut r)? as i64);
+                }
+                OPCODE_EXPIRETIME => {
+                    doing = "read-expire";
+                    expiration = Some(decode_u32_from_slice_u8(&mut r)? as i64 * 1000);
+                }
+                OPCODE_SELECTDB => {
+                    doing = "read-selectdb";
+                    let dbindex = decode_len(&mut r)?;
+                    info(r.offset, &format!("Selecting DB ID {}", dbindex));
+                }
+                OPCODE_RESIZEDB => {
+                    doing = "read-resizedb";
+                    decode_len(&mut r)?;
+                    decode_len(&mut r)?;
+                }
+                OPCODE_FUNCTION2 => {
+                    doing = "read-functions";
+                    decode_slice_u8(&mut r)?;
+                    info(r.offset, "Loading function library");
+                }
+                OPCODE_AUX => {
+                    doing = "read-aux";
+                    let name = decode_slice_u8(&mut r)?;
+                    let value = decode_slice_u8(&mut r)?;
+                    info(
+                        r.offset,
+                        &format!(
+                            "AUX FIELD {} = '{}'",
+                            String::from_utf8_lossy(&name),
+                            String::from_utf8_lossy(&value)
+                        ),
+                    );
+                }
+                OPCODE_EOF => break,
+                rdb_type => {
+                    doing = "read-key";
+                    key = Some(decode_slice_u8(&mut r)?);
+                    doing = "read-object-value";
+                    // module values are checked without their module
+                    if rdb_type == TYPE_MODULE_2 {
+                        ValueModule::rdb_skip(&mut r)?;
+                    } else {
+                        Value::rdb_load(rdb_type, &mut r)?;
+                    }
+                    check.keys += 1;
+                    if let Some(ms) = expiration.take() {
+                        check.expires += 1;
+                        if ms <= now {
+                            check.already_expired += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        if check.version >= 5 {
+            doing = "read-checksum";
+            let crc = r.crc;
+            let expected = decode_u64_from_slice_u8(&mut r)?;
+            if expected != 0 {
+                check.checksum = Some(expected);
+                if expected != crc {
+                    return Err(DecodeError::ChecksumMismatch {
+                        expected,
+                        actual: crc,
+                    });
+                }
+            }
+        }
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => {
+            check.len = r.offset;
+            Ok(check)
+        }
+        Err(error) => Err(RdbCheckError {
+            offset: r.offset,
+            doing,
+            key,
+            error,
+        }),
+    }
+}
+
+fn write_aux<W: Write>(writer: &mut W, key: &[u8], value: &[u8]) -> io::Result<()> {
+    writer.write_all(&[OPCODE_AUX])?;
+    encode_slice_u8(key, writer, false)?;
+    encode_slice_u8(value, writer, true)?;
+    Ok(())
+}
+
+impl Database {
+    /// Serializes the function libraries and every database into `writer`
+    /// using the RDB format.
+    /// Keys that are already expired are skipped. The trailing checksum is
+    /// zero if `rdbchecksum` is disabled.
+    pub fn rdb_dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let crc = {
+            let mut w = ChecksumWriter::new(writer, self.config.rdbchecksum);
+            write!(w, "REDIS{:04}", VERSION)?;
+            write_aux(&mut w, b"redis-ver", self.version.as_bytes())?;
+            write_aux(&mut w, b"redis-bits", format!("{}", size_of::<usize>() * 8).as_bytes())?;
+            write_aux(&mut w, b"ctime", format!("{}", mstime() / 1000).as_bytes())?;
+            for library in self.functions.libraries() {
+                w.write_all(&[OPCODE_FUNCTION2])?;
+                encode_slice_u8(&library.code, &mut w, false)?;
+            }
+
+            let now = mstime();
+            for (index, data) in self.data.iter().enumerate() {
+                let expirations = &self.data_expiration_ms[index];
+                // RESIZEDB counts the keys written, without the expired ones
+                let mut keys = 0;
+                let mut expires = 0;
+                for (key, value) in data.iter() {
+                    if value.rdb_type().is_none() {
+                        continue;
+                    }
+                    match expirations.get(key) {
+                        Some(expiration) if *expiration <= now => continue,
+                        Some(_) => expires += 1,
+                        None => (),
+                    }
+                    keys += 1;
+                }
+                if keys == 0 {
+                    continue;
+                }
+                w.write_all(&[OPCODE_SELECTDB])?;
+                encode_len(index, &mut w)?;
+                w.write_all(&[OPCODE_RESIZEDB])?;
+                encode_len(keys, &mut w)?;
+                encode_len(expires, &mut w)?;
+
+                for (key, value) in data.iter() {
+                    let rdb_type = match value.rdb_type() {
+                        Some(t) => t,
+                        None => continue,
+                    };
+                    if let Some(expiration) = expirations.get(key) {
+                        if *expiration <= now {
+                            continue;
+                        }
+                        w.write_all(&[OPCODE_EXPIRETIME_MS])?;
+                        encode_u64_to_slice_u8(*expiration as u64, &mut w)?;
+                    }
+                    w.write_all(&[rdb_type])?;
+                    encode_slice_u8(key, &mut w, true)?;
+                    match value.rdb_save(&mut w) {
+                        Ok(_) => (),
+                        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                    }
+                }
+            }
+            w.write_all(&[OPCODE_EOF])?;
+            w.crc
+        };
+        encode_u64_to_slice_u8(crc, writer)?;
+        Ok(())
+    }
+
+    /// Writes a snapshot into `filename`.
+    /// The data is written to a temporary file first and then renamed, so
+    /// the previous snapshot is kept intact if anything fails.
+    pub fn rdb_save(&self, filename: &str) -> io::Result<()> {
+        let tmppath = Path::new(filename).with_file_name(format!("temp-{}.rdb", process::id()));
+        let r = self.rdb_save_tmp(&tmppath).and_then(|_| rename(&tmppath, filename));
+        if r.is_err() {
+            let _ = remove_file(&tmppath);
+        }
+        r
+    }
+
+    /// Replaces the current data and function libraries with the snapshot
+    /// read from `reader`.
+    /// Fails if the data is truncated, malformed or if the checksum does not
+    /// match, unless `rdbchecksum` is disabled.
+    pub fn rdb_load<R: Read>(&mut self, reader: &mut R) -> Result<(), DecodeError> {
+        self.clearall();
+        self.functions.flush();
+        let (version, crc) = {
+            let mut r = ChecksumReader::new(reader);
+            let version = read_signature(&mut r)?;
+
+            let now = mstime();
+            let mut dbindex = 0;
+            let mut expiration = None;
+            loop {
+                match decode_u8(&mut r)? {
+                    OPCODE_EXPIRETIME_MS => {
+                        expiration = Some(decode_u64_from_slice_u8(&mut r)? as i64);
+                    }
+                    OPCODE_EXPIRETIME => {
+                        expiration = Some(decode_u32_from_slice_u8(&mut r)? as i64 * 1000);
+                    }
+                    OPCODE_SELECTDB => {
+                        dbindex = decode_len(&mut r)?;
+                        if dbindex >= self.data.len() {
+                            return Err(DecodeError::InvalidData(format!(
+                                "FATAL: Data file was created with a Redis server configured \
+                                 to handle more than {} databases. Exiting",
+                                self.data.len()
+                            )));
+                        }
+                    }
+                    OPCODE_RESIZEDB => {
+                        let size = decode_len(&mut r)?;
+                        decode_len(&mut r)?;
+                        self.data[dbindex].reserve(prealloc_len(size));
+                    }
+                    OPCODE_AUX => {
+                        decode_slice_u8(&mut r)?;
+                        decode_slice_u8(&mut r)?;
+                    }
+                    OPCODE_FUNCTION2 => {
+                        let code = decode_slice_u8(&mut r)?;
+                        self.functions
+                            .load(&code, false, &self.config.logger)
+                            .map_err(DecodeError::InvalidData)?;
+                    }
+                    OPCODE_EOF => break,
+                    rdb_type => {
+                        let key = decode_slice_u8(&mut r)?;
+                        let value = Value::rdb_load_with(rdb_type, &mut r, &self.modules)?;
+                        match expiration.take() {
+                            // expired keys are discarded instead of loaded
+                            Some(ms) if ms <= now => continue,
+                            Some(ms) => {
+                                self.data_expiration_ms[dbindex].insert(key.clone(), ms);
+                            }
+                            None => (),
+                        }
+                        self.slot_key_added(dbindex, &key);
+                        self.data[dbindex].insert(key, value);
+                    }
+                }
+            }
+            (version, r.crc)
+        };
+
+        // checksums were added in version 5, zero means it was not computed
+        if version >= 5 {
+            let expected = decode_u64_from_slice_u8(reader)?;
+            if self.config.rdbchecksum && expected != 0 && expected != crc {
+                return Err(DecodeError::ChecksumMismatch {
+                    expected,
+                    actual: crc,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the snapshot stored in `filename`, see `rdb_load`.
+    pub fn rdb_load_file(&mut self, filename: &str) -> Result<(), DecodeError> {
+        let mut reader = BufReader::new(File::open(filename)?);
+        self.loading = true;
+        let r = self.rdb_load(&mut reader);
+        self.loading = false;
+        r
+    }
+
+    /// Starts writing a snapshot of the current data into `dbfilename` in a
+    /// forked child process, returning its pid.
+    pub fn rdb_bgsave(&mut self) -> io::Result<u32> {
+        self.rdb_last_bgsave_try = mstime() / 1000;
+        match fork()? {
+            Fork::Child => {
+                let code = match self.rdb_save(&*self.config.dbfilename) {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                };
+                exit_child(code);
+            }
+            Fork::Parent(pid) => {
+                log!(self.config.logger, Notice, "Background saving started by pid {}", pid);
+                self.rdb_child_pid = Some(pid);
+                let tmppath = Path::new(&*self.config.dbfilename).with_file_name(format!("temp-{}.rdb", pid));
+                self.busy.child_started(pid, Some(tmppath));
+                self.rdb_save_time_start = mstime();
+                self.dirty_before_bgsave = self.dirty;
+                self.repl_bgsave_started();
+                Ok(pid)
+            }
+        }
+    }
+
+    /// Checks whether the background save child finished, without blocking,
+    /// and updates the persistence stats accordingly.
+    pub fn rdb_bgsave_check(&mut self) {
+        let pid = match self.rdb_child_pid {
+            Some(pid) => pid,
+            None => return,
+        };
+        let success = match waitpid_nohang(pid) {
+            Some(success) => success,
+            None => return,
+        };
+        self.rdb_child_pid = None;
+        self.busy.child_ended(pid);
+        if self.repl_transfer.is_some() {
+            // the child sent the snapshot to replicas, nothing was saved
+            if success {
+                log!(self.config.logger, Notice, "Background RDB transfer terminated with success");
+            } else {
+                log!(self.config.logger, Warning, "Background transfer error");
+            }
+        } else {
+            self.rdb_last_bgsave_time_sec = (mstime() - self.rdb_save_time_start) / 1000;
+            self.rdb_last_bgsave_ok = success;
+            if success {
+                self.last_save_time = self.rdb_save_time_start / 1000;
+                self.dirty = self.dirty.saturating_sub(self.dirty_before_bgsave);
+                log!(self.config.logger, Notice, "Background saving terminated with success");
+            } else {
+                log!(self.config.logger, Warning, "Background saving error");
+            }
+        }
+        self.repl_bgsave_done(success);
+    }
+
+    /// Starts a background save if any of the `save` rules is met, that is
+    /// at least `changes` writes happened and `seconds` elapsed since the
+    /// last save.
+    /// After a failed attempt, it waits a few seconds before trying again.
+    pub fn rdb_auto_save(&mut self) {
+        if self.rdb_child_pid.is_some() || self.aof_child_pid.is_some() {
+            return;
+        }
+        let now = mstime() / 1000;
+        let can_retry = self.rdb_last_bgsave_ok || now - self.rdb_last_bgsave_try > BGSAVE_RETRY_DELAY;
+        let rule = self.config.save.iter().find(|&&(seconds, changes)| {
+            self.dirty >= changes && now - self.last_save_time >= seconds as i64
+        });
+        if let (Some(&(seconds, changes)), true) = (rule, can_retry) {
+            log!(
+                self.config.logger,
+                Notice,
+                "{} changes in {} seconds. Saving...",
+                changes,
+                seconds
+            );
+            if let Err(e) = self.rdb_bgsave() {
+                self.rdb_last_bgsave_ok = false;
+                log!(self.config.logger, Warning, "Can't save in background: fork: {}", e);
+            }
+        }
+    }
+
+    /// Kills the background save child, if any.
+    pub fn rdb_bgsave_kill(&mut self) {
+        if let Some(pid) = self.rdb_child_pid.take() {
+            self.repl_transfer = None;
+            kill(pid);
+            // reap the killed child
+            waitpid(pid);
+            self.busy.child_ended(pid);
+            let tmppath = Path::new(&*self.config.dbfilename).with_file_name(format!("temp-{}.rdb", pid));
+            let _ = remove_file(tmppath);
+        }
+    }
+
+    /// Seconds since the current background save started, -1 if there is
+    /// none.
+    pub fn rdb_current_bgsave_time_sec(&self) -> i64 {
+        match self.rdb_child_pid {
+            Some(_) => (mstime() - self.rdb_save_time_start) / 1000,
+            None => -1,
+        }
+    }
+
+    fn rdb_save_tmp(&self, tmppath: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(tmppath)?);
+        self.rdb_dump(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod test_rdb {
+    use std::env::temp_dir;
+    use std::ffi::c_void;
+    use std::fs::File;
+    use std::io::Read;
+
+    use compat::waitpid_nohang;
+    use rdbutil::crc64::crc64;
+    use logger::{Level, Logger};
+    use rdbutil::constants::{TYPE_MODULE_2, VERSION};
+    use rdbutil::DecodeError;
+
+    use super::super::function::FunctionInfo;
+    use super::super::module::{init_module, type_id, Module, ModuleApi, ModuleInit, ModuleType, RawSlice,
+                               RawWriter};
+    use super::super::{Database, Value};
+    use super::{rdb_check, RdbCheck};
+
+    #[test]
+    fn dump_empty() {
+        let db = Database::mock();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        assert_eq!(&v[..9], b"REDIS0010");
+        let len = v.len();
+        assert_eq!(v[len - 9], 255);
+        let mut crc = [0; 8];
+        crc.copy_from_slice(&v[len - 8..]);
+        assert_eq!(u64::from_le_bytes(crc), crc64(0, &v[..len - 8]));
+    }
+
+    #[test]
+    fn dump_keys() {
+        let mut db = Database::mock();
+        db.get_or_create(0, b"key")
+            .set(b"value".to_vec())
+            .unwrap();
+        db.get_or_create(1, b"list")
+            .push(b"a".to_vec(), true)
+            .unwrap();
+        db.set_msexpiration(1, b"list".to_vec(), 0x7fff_ffff_ffff);
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        let contains = |needle: &[u8]| v.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"\xfe\x00\xfb\x01\x00\x00\x03key\x05value"));
+        assert!(contains(b"\xfe\x01\xfb\x01\x01\xfc\xff\xff\xff\xff\xff\x7f\x00\x00\x01\x04list\x01\x01a"));
+    }
+
+    #[test]
+    fn dump_skips_expired() {
+        let mut db = Database::mock();
+        db.get_or_create(0, b"key")
+            .set(b"value".to_vec())
+            .unwrap();
+        db.set_msexpiration(0, b"key".to_vec(), 1);
+        db.get_or_create(1, b"key")
+            .set(b"value".to_vec())
+            .unwrap();
+        db.set_msexpiration(1, b"key".to_vec(), 1);
+        db.get_or_create(1, b"other")
+            .set(b"value".to_vec())
+            .unwrap();
+        db.get_or_create(1, b"volatile")
+            .set(b"value".to_vec())
+            .unwrap();
+        db.set_msexpiration(1, b"volatile".to_vec(), 0x7fff_ffff_ffff);
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        let contains = |needle: &[u8]| v.windows(needle.len()).any(|w| w == needle);
+        assert!(!contains(b"key"));
+        // the databases with only expired keys are skipped, and RESIZEDB
+        // counts the keys written
+        assert!(!contains(b"\xfe\x00\xfb"));
+        assert!(contains(b"\xfe\x01\xfb\x02\x01"));
+    }
+
+    #[test]
+    fn save_file() {
+        let mut path = temp_dir();
+        path.push("rsedis-rdb-test-save.rdb");
+        let filename = path.to_str().unwrap().to_owned();
+
+        let mut db = Database::mock();
+        db.get_or_create(0, b"key")
+            .set(b"value".to_vec())
+            .unwrap();
+        db.rdb_save(&filename).unwrap();
+
+        let mut expected = vec![];
+        db.rdb_dump(&mut expected).unwrap();
+        let mut contents = vec![];
+        File::open(&filename)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        // the ctime aux field may differ between both snapshots
+        assert_eq!(&contents[..9], &expected[..9]);
+        assert_eq!(contents.len(), expected.len());
+    }
+
+    fn populated() -> Database {
+        let mut db = Database::mock();
+        db.get_or_create(0, b"string").set(b"value".to_vec()).unwrap();
+        db.get_or_create(0, b"int").set(b"-123".to_vec()).unwrap();
+        db.get_or_create(0, b"list").push(b"a".to_vec(), true).unwrap();
+        db.get_or_create(0, b"list").push(b"b".to_vec(), true).unwrap();
+        db.get_or_create(1, b"intset").sadd(b"1".to_vec(), 512).unwrap();
+        db.get_or_create(1, b"intset").sadd(b"70000".to_vec(), 512).unwrap();
+        db.get_or_create(1, b"set").sadd(b"a".to_vec(), 512).unwrap();
+        db.get_or_create(1, b"zset").zadd(-1.5, b"a".to_vec(), false, false, false, false).unwrap();
+        db.get_or_create(1, b"zset").zadd(2.0, b"b".to_vec(), false, false, false, false).unwrap();
+        db.get_or_create(2, b"hash").hset(b"field".to_vec(), b"value".to_vec()).unwrap();
+        db.set_msexpiration(2, b"hash".to_vec(), 0x7fff_ffff_ffff);
+        db
+    }
+
+    #[test]
+    fn bgsave() {
+        let mut path = temp_dir();
+        path.push("rsedis-rdb-test-bgsave.rdb");
+
+        let mut db = populated();
+        db.config.dbfilename = path.to_str().unwrap().to_owned();
+        db.rdb_bgsave().unwrap();
+        assert!(db.rdb_child_pid.is_some());
+        while db.rdb_child_pid.is_some() {
+            db.rdb_bgsave_check();
+        }
+        assert!(db.rdb_last_bgsave_ok);
+
+        let mut db2 = Database::mock();
+        db2.rdb_load_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(db2.dbsize(1), 3);
+    }
+
+    #[test]
+    fn bgsave_kill() {
+        let mut path = temp_dir();
+        path.push("rsedis-rdb-test-bgsave-kill.rdb");
+
+        let mut db = populated();
+        db.config.dbfilename = path.to_str().unwrap().to_owned();
+        let pid = db.rdb_bgsave().unwrap();
+        db.rdb_bgsave_kill();
+        assert!(db.rdb_child_pid.is_none());
+        // the child was already reaped
+        assert_eq!(waitpid_nohang(pid), Some(false));
+        assert!(!path.with_file_name(format!("temp-{}.rdb", pid)).exists());
+    }
+
+    #[test]
+    fn auto_save() {
+        let mut path = temp_dir();
+        path.push("rsedis-rdb-test-auto-save.rdb");
+
+        let mut db = populated();
+        db.config.dbfilename = path.to_str().unwrap().to_owned();
+        db.config.save = vec![(0, 2)];
+        db.dirty = 1;
+        db.rdb_auto_save();
+        assert!(db.rdb_child_pid.is_none());
+
+        db.dirty = 2;
+        db.rdb_auto_save();
+        assert!(db.rdb_child_pid.is_some());
+        db.dirty = 3;
+        while db.rdb_child_pid.is_some() {
+            db.rdb_bgsave_check();
+        }
+        assert_eq!(db.dirty, 1);
+    }
+
+    #[test]
+    fn load_roundtrip() {
+        let db = populated();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+
+        let mut db2 = Database::mock();
+        db2.rdb_load(&mut &*v).unwrap();
+        for index in 0..3 {
+            assert_eq!(db.dbsize(index), db2.dbsize(index));
+            for key in db.keys(index, b"*") {
+                assert_eq!(db.get(index, &key), db2.get(index, &key));
+            }
+        }
+        assert_eq!(db2.get_msexpiration(2, b"hash"), Some(&0x7fff_ffff_ffff));
+        match db2.get(1, b"intset").unwrap() {
+            Value::Set(s) => assert!(s.is_intset()),
+            _ => panic!("Expected a set"),
+        }
+    }
+
+    #[test]
+    fn load_functions() {
+        fn engine(body: &[u8], _: &Logger) -> Result<Vec<FunctionInfo>, String> {
+            Ok(vec![FunctionInfo {
+                name: String::from_utf8_lossy(body).trim().to_owned(),
+                description: None,
+                flags: vec![],
+            }])
+        }
+        let mut db = Database::mock();
+        db.functions.set_engine(engine);
+        db.functions.load(b"#!lua name=lib\nfunc", false, &Logger::new(Level::Warning)).unwrap();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        assert_eq!(rdb_check(&mut &*v, |_, _| ()).unwrap().keys, 0);
+
+        let mut db2 = Database::mock();
+        assert!(db2.rdb_load(&mut &*v).is_err());
+        db2.functions.set_engine(engine);
+        db2.rdb_load(&mut &*v).unwrap();
+        assert_eq!(db2.functions.get("func").unwrap().0.name, "lib");
+    }
+
+    #[test]
+    fn load_module_values() {
+        #[derive(Debug)]
+        struct Point(u8, u8);
+
+        impl ModuleType for Point {
+            const NAME: &'static str = "point-xy0";
+
+            fn rdb_save(&self) -> Vec<u8> {
+                vec![self.0, self.1]
+            }
+
+            fn rdb_load(data: &[u8], _: u16) -> Result<Self, String> {
+                match data {
+                    [x, y] => Ok(Point(*x, *y)),
+                    _ => Err("bad point".to_owned()),
+                }
+            }
+        }
+
+        fn init(module: &mut ModuleInit, _: &[Vec<u8>]) -> Result<(), String> {
+            module.set_name("point", 1);
+            module.create_type::<Point>()
+        }
+
+        extern "C" fn entry(
+            api: *const ModuleApi,
+            module: *mut c_void,
+            argv: *const RawSlice,
+            argc: usize,
+            err: RawWriter,
+        ) -> i32 {
+            unsafe { init_module(api, module, argv, argc, err, init) }
+        }
+
+        let mut db = populated();
+        db.modules.add(Module::load("point.so", vec![], entry).unwrap()).unwrap();
+        let id = type_id("point-xy0", 0).unwrap().to_be_bytes();
+        let data = [&b"\x81"[..], &id, b"\x05\x02\x01\x02\x00"].concat();
+        let value = Value::rdb_load_with(TYPE_MODULE_2, &mut &data[..], &db.modules).unwrap();
+        *db.get_or_create(0, b"point") = value;
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        assert_eq!(rdb_check(&mut &*v, |_, _| ()).unwrap().keys, 8);
+
+        let mut db2 = Database::mock();
+        assert!(db2.rdb_load(&mut &*v).is_err());
+        db2.modules.add(Module::load("point.so", vec![], entry).unwrap()).unwrap();
+        db2.rdb_load(&mut &*v).unwrap();
+        assert_eq!(db.get(0, b"point"), db2.get(0, b"point"));
+    }
+
+    #[test]
+    fn load_truncated() {
+        let db = populated();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        let len = v.len();
+        match Database::mock().rdb_load(&mut &v[..len - 20]).unwrap_err() {
+            DecodeError::UnexpectedEOF => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn load_truncated_huge_length() {
+        // RESIZEDB with 2^40 keys, then a set declaring 2^32 - 1 members
+        let set = b"REDIS0007\xfe\x00\xfb\x81\x00\x00\x01\x00\x00\x00\x00\x00\x00\x02\x01k\x80\xff\xff\xff\xff\x01a";
+        // a key of 2^40 bytes
+        let key = b"REDIS0007\xfe\x00\x00\x81\x00\x00\x01\x00\x00\x00\x00\x00key";
+        for v in [&set[..], &key[..]].iter() {
+            match Database::mock().rdb_load(&mut &v[..]).unwrap_err() {
+                DecodeError::UnexpectedEOF => (),
+                e => panic!("Unexpected error {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn load_checksum_mismatch() {
+        let db = populated();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        let len = v.len();
+        v[len - 1] ^= 1;
+        match Database::mock().rdb_load(&mut &*v).unwrap_err() {
+            DecodeError::ChecksumMismatch { .. } => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        let mut db2 = Database::mock();
+        db2.config.rdbchecksum = false;
+        db2.rdb_load(&mut &*v).unwrap();
+        assert_eq!(db2.dbsize(0), 3);
+    }
+
+    #[test]
+    fn load_wrong_signature() {
+        assert!(Database::mock().rdb_load(&mut &b"RADIS0007\xff"[..]).is_err());
+    }
+
+    #[test]
+    fn check() {
+        let db = populated();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        v.extend_from_slice(b"trailing data");
+        let mut reader = &*v;
+        let mut selected = 0;
+        let check = rdb_check(&mut reader, |_, msg| {
+            if msg.starts_with("Selecting DB") {
+                selected += 1;
+            }
+        })
+        .unwrap();
+        let len = v.len() as u64 - 13;
+        assert_eq!(
+            check,
+            RdbCheck {
+                version: VERSION,
+                keys: 7,
+                expires: 1,
+                already_expired: 0,
+                len,
+                checksum: Some(crc64(0, &v[..len as usize - 8])),
+            }
+        );
+        assert_eq!(selected, 3);
+        assert_eq!(reader, b"trailing data");
+    }
+
+    #[test]
+    fn check_errors() {
+        let db = populated();
+        let mut v = vec![];
+        db.rdb_dump(&mut v).unwrap();
+        let len = v.len();
+
+        let err = rdb_check(&mut &v[..len - 20], |_, _| ()).unwrap_err();
+        assert_eq!(err.offset, len as u64 - 20);
+        match err.error {
+            DecodeError::UnexpectedEOF => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        v[len - 1] ^= 1;
+        let err = rdb_check(&mut &*v, |_, _| ()).unwrap_err();
+        assert_eq!(err.doing, "read-checksum");
+        match err.error {
+            DecodeError::ChecksumMismatch { .. } => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,941 @@
+//! Master side of the replication: keeps track of the replicas, sends them
+//! a snapshot followed by the stream of write commands, and keeps a backlog
+//! of that stream so a replica that reconnects can resume where it left off.
+//! It also keeps the state of the link to the master when this server is a
+//! replica itself; the connection is handled by the networking layer.
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem::replace;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use compat::{exit_child, fork, pipe, Fork};
+
+use logger::Level;
+use response::Response;
+use util::{get_random_hex_chars, mstime};
+
+use super::Database;
+
+/// How often online replicas get a PING through the stream
+const REPL_PING_PERIOD_MS: i64 = 10_000;
+
+/// Circular buffer with the most recent bytes of the replication stream.
+pub struct Backlog {
+    buf: Vec<u8>,
+    /// Position in `buf` where the next byte is written
+    idx: usize,
+    /// Number of valid bytes in `buf`
+    histlen: usize,
+    /// Replication offset right after the last byte written
+    end_offset: u64,
+}
+
+impl Backlog {
+    /// Creates an empty backlog of `size` bytes whose next byte will be at
+    /// `offset` in the replication stream.
+    pub fn new(size: usize, offset: u64) -> Backlog {
+        Backlog {
+            buf: vec![0; size],
+            idx: 0,
+            histlen: 0,
+            end_offset: offset,
+        }
+    }
+
+    /// Appends `data`, dropping the oldest bytes if needed.
+    pub fn feed(&mut self, mut data: &[u8]) {
+        let size = self.buf.len();
+        self.end_offset += data.len() as u64;
+        if size == 0 {
+            return;
+        }
+        if data.len() > size {
+            data = &data[data.len() - size..];
+        }
+        self.histlen = (self.histlen + data.len()).min(size);
+        while !data.is_empty() {
+            let len = (size - self.idx).min(data.len());
+            self.buf[self.idx..self.idx + len].copy_from_slice(&data[..len]);
+            self.idx = (self.idx + len) % size;
+            data = &data[len..];
+        }
+    }
+
+    /// Returns the stream from `offset` up to the last byte written, or
+    /// `None` if that part of the stream is no longer (or not yet) available.
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.first_offset() || offset > self.end_offset {
+            return None;
+        }
+        let len = (self.end_offset - offset) as usize;
+        let size = self.buf.len();
+        let start = (self.idx + size - len) % size.max(1);
+        let mut data = Vec::with_capacity(len);
+        if start + len <= size {
+            data.extend_from_slice(&self.buf[start..start + len]);
+        } else {
+            data.extend_from_slice(&self.buf[start..]);
+            data.extend_from_slice(&self.buf[..len - (size - start)]);
+        }
+        Some(data)
+    }
+
+    /// Offset of the oldest byte still available.
+    pub fn first_offset(&self) -> u64 {
+        self.end_offset - self.histlen as u64
+    }
+
+    /// Number of bytes available.
+    pub fn histlen(&self) -> usize {
+        self.histlen
+    }
+
+    /// Capacity in bytes.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReplicaState {
+    /// Waiting for a snapshot to be started for it
+    WaitBgsaveStart,
+    /// Waiting for the snapshot to finish, the stream is kept meanwhile
+    WaitBgsaveEnd,
+    /// Receiving the replication stream
+    Online,
+}
+
+impl ReplicaState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ReplicaState::WaitBgsaveStart | ReplicaState::WaitBgsaveEnd => "wait_bgsave",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+/// What a replica announced about itself through REPLCONF before syncing.
+#[derive(Clone, Default, Debug)]
+pub struct ReplicaConf {
+    pub listening_port: u16,
+    pub ip_address: Option<String>,
+    pub capa: Vec<String>,
+}
+
+/// A replica connected to this server.
+pub struct Replica {
+    /// Identifier of the client connection
+    pub id: usize,
+    /// Channel to write into the replica connection
+    pub sender: Sender<Option<Response>>,
+    pub state: ReplicaState,
+    /// Address the replica is reachable at
+    pub ip: String,
+    pub conf: ReplicaConf,
+    /// Last offset the replica acknowledged
+    pub ack_offset: u64,
+    /// Milliseconds when the last acknowledge was received
+    pub ack_time: i64,
+    /// Offset of the snapshot the replica is waiting for
+    initial_offset: u64,
+    /// Stream produced while the snapshot is being written
+    pending: Vec<u8>,
+    /// Whether it synced with PSYNC, and expects a FULLRESYNC reply
+    psync: bool,
+    /// Milliseconds when the replica asked for a full synchronization
+    sync_start: i64,
+}
+
+impl Replica {
+    pub fn new(id: usize, sender: Sender<Option<Response>>, ip: String, conf: ReplicaConf) -> Replica {
+        Replica {
+            id,
+            sender,
+            state: ReplicaState::WaitBgsaveStart,
+            ip,
+            conf,
+            ack_offset: 0,
+            ack_time: mstime(),
+            initial_offset: 0,
+            pending: Vec::new(),
+            psync: false,
+            sync_start: mstime(),
+        }
+    }
+
+    fn send(&self, data: Vec<u8>) -> bool {
+        self.sender.send(Some(Response::Raw(data))).is_ok()
+    }
+
+    /// Disconnects the replica.
+    fn close(&self) {
+        let _ = self.sender.send(None);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MasterLinkState {
+    /// Must connect to the master
+    Connect,
+    /// Connected, doing the handshake
+    Connecting,
+    /// Receiving the snapshot
+    Sync,
+    /// Receiving the replication stream
+    Connected,
+}
+
+impl MasterLinkState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MasterLinkState::Connect => "connect",
+            MasterLinkState::Connecting => "connecting",
+            MasterLinkState::Sync => "sync",
+            MasterLinkState::Connected => "connected",
+        }
+    }
+}
+
+/// The master this server replicates.
+pub struct MasterLink {
+    /// Identifies the link, a connection for an old link must stop
+    pub id: u64,
+    pub host: String,
+    pub port: u16,
+    pub state: MasterLinkState,
+    /// Milliseconds when data was last received from the master
+    pub last_io: i64,
+    /// Milliseconds when the link went down
+    pub down_since: i64,
+    /// Milliseconds of the last connection attempt
+    pub last_connect: i64,
+    /// Size of the snapshot being received
+    pub sync_size: u64,
+    /// Bytes of the snapshot received so far
+    pub sync_read: u64,
+}
+
+fn select_command(dbindex: usize) -> Vec<u8> {
+    let n = format!("{}", dbindex);
+    format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", n.len(), n).into_bytes()
+}
+
+impl Database {
+    /// Adds a command to the replication stream. A SELECT is added first if
+    /// the stream was on a different database.
+    /// A replica does not feed its own writes, it passes on the stream
+    /// received from its master instead.
+    pub fn repl_feed(&mut self, dbindex: usize, command: &[u8]) {
+        if self.master.is_some() || (self.repl_backlog.is_none() && self.replicas.is_empty()) {
+            return;
+        }
+        if self.repl_seldb != Some(dbindex) {
+            self.repl_feed_raw(&select_command(dbindex));
+            self.repl_seldb = Some(dbindex);
+        }
+        self.repl_feed_raw(command);
+    }
+
+    fn repl_feed_raw(&mut self, data: &[u8]) {
+        if let Some(backlog) = &mut self.repl_backlog {
+            backlog.feed(data);
+        }
+        self.master_repl_offset += data.len() as u64;
+        self.replicas.retain(|replica| match replica.state {
+            ReplicaState::WaitBgsaveStart => true,
+            ReplicaState::WaitBgsaveEnd => true,
+            ReplicaState::Online => replica.send(data.to_vec()),
+        });
+        for replica in self.replicas.iter_mut() {
+            if replica.state == ReplicaState::WaitBgsaveEnd {
+                replica.pending.extend_from_slice(data);
+            }
+        }
+    }
+
+    /// Starts replicating to `replica`. `psync` has the replication id and
+    /// offset the replica asked for, or `None` if it used SYNC.
+    /// If the id matches and the backlog still has the stream from that
+    /// offset, only the missing part is sent. Otherwise a full
+    /// synchronization follows, sending a snapshot first.
+    pub fn repl_attach(&mut self, mut replica: Replica, psync: Option<(&str, i64)>) {
+        self.repl_detach(replica.id);
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(Backlog::new(self.config.repl_backlog_size, self.master_repl_offset));
+        }
+
+        if let Some((replid, offset)) = psync {
+            if let Some(data) = self.repl_partial_data(replid, offset) {
+                log!(
+                    self.config.logger,
+                    Notice,
+                    "Partial resynchronization request from {}:{} accepted. Sending {} bytes of backlog starting from offset {}.",
+                    replica.ip,
+                    replica.conf.listening_port,
+                    data.len(),
+                    offset
+                );
+                let reply = if replica.conf.capa.iter().any(|c| c == "psync2") {
+                    format!("+CONTINUE {}\r\n", self.replid)
+                } else {
+                    "+CONTINUE\r\n".to_owned()
+                };
+                replica.state = ReplicaState::Online;
+                replica.ack_offset = offset.max(1) as u64 - 1;
+                if replica.send(reply.into_bytes()) && replica.send(data) {
+                    self.replicas.push(replica);
+                }
+                return;
+            }
+            replica.psync = true;
+        }
+
+        log!(
+            self.config.logger,
+            Notice,
+            "Full resync requested by replica {}:{}",
+            replica.ip,
+            replica.conf.listening_port
+        );
+        replica.sync_start = mstime();
+        if self.rdb_child_pid.is_some() {
+            // a snapshot is being written for other replicas, the new one can
+            // use it too if it gets the same stream they got since it started,
+            // unless it is being sent over their connections
+            let attached = match self.repl_transfer {
+                Some(_) => None,
+                None => self
+                    .replicas
+                    .iter()
+                    .find(|r| r.state == ReplicaState::WaitBgsaveEnd)
+                    .map(|r| (r.initial_offset, r.pending.clone())),
+            };
+            if let Some((initial_offset, pending)) = attached {
+                log!(self.config.logger, Notice, "Waiting for end of BGSAVE for SYNC");
+                replica.state = ReplicaState::WaitBgsaveEnd;
+                replica.initial_offset = initial_offset;
+                replica.pending = pending;
+                if replica.psync {
+                    let reply = format!("+FULLRESYNC {} {}\r\n", self.replid, initial_offset);
+                    if !replica.send(reply.into_bytes()) {
+                        return;
+                    }
+                }
+            } else {
+                log!(self.config.logger, Notice, "Waiting for next BGSAVE for SYNC");
+            }
+            self.replicas.push(replica);
+        } else {
+            self.replicas.push(replica);
+            if self.aof_child_pid.is_none() && self.repl_bgsave_due() {
+                self.repl_bgsave();
+            }
+        }
+    }
+
+    /// Returns the part of the stream a replica is missing, if it can be
+    /// resumed from the backlog.
+    fn repl_partial_data(&self, replid: &str, offset: i64) -> Option<Vec<u8>> {
+        if replid != self.replid || offset < 1 {
+            return None;
+        }
+        match &self.repl_backlog {
+            Some(backlog) => backlog.since(offset as u64 - 1),
+            None => None,
+        }
+    }
+
+    /// Forgets the client `id`, stopping the replication to it if it is a
+    /// replica.
+    pub fn repl_detach(&mut self, id: usize) {
+        self.replicas.retain(|r| r.id != id);
+        self.repl_waiters.retain(|w| w.0 != id);
+    }
+
+    /// Records the offset acknowledged by the replica `id`, waking up the
+    /// clients in WAIT that have enough acknowledgements now.
+    pub fn repl_ack(&mut self, id: usize, offset: u64) {
+        match self.replicas.iter_mut().find(|r| r.id == id) {
+            Some(replica) => {
+                replica.ack_offset = offset;
+                replica.ack_time = mstime();
+            }
+            None => return,
+        }
+        let waiters = replace(&mut self.repl_waiters, vec![]);
+        for (client_id, offset, numreplicas, sender) in waiters {
+            if self.repl_acked(offset) < numreplicas {
+                self.repl_waiters.push((client_id, offset, numreplicas, sender));
+            } else {
+                let _ = sender.send(true);
+            }
+        }
+    }
+
+    /// Number of replicas that acknowledged the stream up to `offset`.
+    pub fn repl_acked(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|r| r.state == ReplicaState::Online && r.ack_offset >= offset)
+            .count()
+    }
+
+    /// Number of online replicas that acknowledged something within
+    /// `min-slaves-max-lag` seconds.
+    pub fn repl_good_replicas(&self) -> usize {
+        let now = mstime();
+        let max_lag = self.config.min_slaves_max_lag as i64 * 1000;
+        self.replicas
+            .iter()
+            .filter(|r| r.state == ReplicaState::Online && now - r.ack_time <= max_lag)
+            .count()
+    }
+
+    /// Notifies `sender` once `numreplicas` replicas acknowledged the stream
+    /// up to `offset`, replacing the previous wait of the client `id`. The
+    /// replicas are asked for an acknowledgement right away instead of
+    /// waiting for the next periodic one.
+    pub fn repl_wait(&mut self, id: usize, offset: u64, numreplicas: usize, sender: Sender<bool>) {
+        self.repl_waiters.retain(|w| w.0 != id);
+        self.repl_waiters.push((id, offset, numreplicas, sender));
+        self.repl_feed_raw(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n");
+    }
+
+    /// Discards the backlog content and changes its size.
+    pub fn repl_backlog_resize(&mut self, size: usize) {
+        self.config.repl_backlog_size = size;
+        if self.repl_backlog.is_some() {
+            self.repl_backlog = Some(Backlog::new(size, self.master_repl_offset));
+        }
+    }
+
+    /// Whether the replicas sync over their connections, which needs them to
+    /// understand a snapshot framed by EOF marks.
+    fn repl_diskless(&self) -> bool {
+        self.config.repl_diskless_sync
+            && self
+                .replicas
+                .iter()
+                .filter(|r| r.state == ReplicaState::WaitBgsaveStart)
+                .all(|r| r.conf.capa.iter().any(|c| c == "eof"))
+    }
+
+    /// Whether a snapshot for the waiting replicas should start. A diskless
+    /// one is delayed by `repl-diskless-sync-delay` seconds, so the replicas
+    /// arriving meanwhile share the transfer.
+    fn repl_bgsave_due(&self) -> bool {
+        let oldest = self
+            .replicas
+            .iter()
+            .filter(|r| r.state == ReplicaState::WaitBgsaveStart)
+            .map(|r| r.sync_start)
+            .min();
+        match oldest {
+            Some(start) => {
+                !self.repl_diskless()
+                    || mstime() - start >= self.config.repl_diskless_sync_delay as i64 * 1000
+            }
+            None => false,
+        }
+    }
+
+    /// Starts a snapshot for the replicas waiting for one.
+    fn repl_bgsave(&mut self) {
+        let r = if self.repl_diskless() {
+            self.repl_bgsave_diskless()
+        } else {
+            self.rdb_bgsave().map(|_| ())
+        };
+        match r {
+            Ok(()) => (),
+            Err(e) => {
+                log!(self.config.logger, Warning, "Can't BGSAVE for replication: {}", e);
+                for replica in self.replicas.iter() {
+                    if replica.state == ReplicaState::WaitBgsaveStart {
+                        replica.close();
+                    }
+                }
+                self.replicas.retain(|r| r.state != ReplicaState::WaitBgsaveStart);
+            }
+        }
+    }
+
+    /// Starts a forked child that writes the snapshot into a pipe, which a
+    /// thread copies into the connections of the waiting replicas. Its size
+    /// is not known in advance, so it is sent between two random marks,
+    /// `$EOF:<mark>\r\n` before and `<mark>` after it.
+    fn repl_bgsave_diskless(&mut self) -> io::Result<()> {
+        let (mut reader, writer) = pipe()?;
+        let mark = get_random_hex_chars(40);
+        match fork()? {
+            Fork::Child => {
+                drop(reader);
+                let mut writer = BufWriter::new(writer);
+                let r = writer
+                    .write_all(format!("$EOF:{}\r\n", mark).as_bytes())
+                    .and_then(|_| self.rdb_dump(&mut writer))
+                    .and_then(|_| writer.write_all(mark.as_bytes()))
+                    .and_then(|_| writer.flush());
+                exit_child(if r.is_ok() { 0 } else { 1 });
+            }
+            Fork::Parent(pid) => {
+                drop(writer);
+                log!(self.config.logger, Notice, "Background RDB transfer started by pid {}", pid);
+                self.rdb_child_pid = Some(pid);
+                self.busy.child_started(pid, None);
+                self.rdb_save_time_start = mstime();
+                self.repl_bgsave_started();
+                let senders = self
+                    .replicas
+                    .iter()
+                    .filter(|r| r.state == ReplicaState::WaitBgsaveEnd)
+                    .map(|r| r.sender.clone())
+                    .collect::<Vec<_>>();
+                self.repl_transfer = Some(thread::spawn(move || {
+                    let mut buf = [0; 16 * 1024];
+                    loop {
+                        let len = match reader.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(len) => len,
+                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(_) => break,
+                        };
+                        for sender in senders.iter() {
+                            let _ = sender.send(Some(Response::Raw(buf[..len].to_vec())));
+                        }
+                    }
+                }));
+                Ok(())
+            }
+        }
+    }
+
+    /// Called when a background save starts. The replicas waiting for a
+    /// snapshot will get this one.
+    pub fn repl_bgsave_started(&mut self) {
+        let offset = self.master_repl_offset;
+        let replid = self.replid.clone();
+        let mut started = false;
+        for replica in self.replicas.iter_mut() {
+            if replica.state != ReplicaState::WaitBgsaveStart {
+                continue;
+            }
+            replica.state = ReplicaState::WaitBgsaveEnd;
+            replica.initial_offset = offset;
+            replica.pending.clear();
+            if replica.psync {
+                replica.send(format!("+FULLRESYNC {} {}\r\n", replid, offset).into_bytes());
+            }
+            started = true;
+        }
+        if started {
+            // the stream sent after the snapshot has to start with a SELECT
+            self.repl_seldb = None;
+        }
+    }
+
+    /// Called when a background save ends. The replicas waiting for it get
+    /// the snapshot followed by the stream produced meanwhile, or are
+    /// disconnected if it failed.
+    pub fn repl_bgsave_done(&mut self, success: bool) {
+        // the snapshot was already sent if it went over the connections
+        let diskless = match self.repl_transfer.take() {
+            Some(transfer) => {
+                let _ = transfer.join();
+                true
+            }
+            None => false,
+        };
+        let mut snapshot = None;
+        if success && !diskless && self.replicas.iter().any(|r| r.state == ReplicaState::WaitBgsaveEnd) {
+            let mut data = vec![];
+            let r = File::open(&*self.config.dbfilename).and_then(|mut f| f.read_to_end(&mut data));
+            match r {
+                Ok(_) => snapshot = Some(data),
+                Err(e) => log!(
+                    self.config.logger,
+                    Warning,
+                    "SYNC failed. Can't open/stat DB after BGSAVE: {}",
+                    e
+                ),
+            }
+        }
+
+        let mut replicas = Vec::with_capacity(self.replicas.len());
+        for mut replica in self.replicas.drain(..) {
+            if replica.state != ReplicaState::WaitBgsaveEnd {
+                replicas.push(replica);
+                continue;
+            }
+            let bulk = match &snapshot {
+                Some(data) => {
+                    let mut bulk = format!("${}\r\n", data.len()).into_bytes();
+                    bulk.extend_from_slice(data);
+                    Some(bulk)
+                }
+                None if diskless && success => None,
+                None => {
+                    replica.close();
+                    continue;
+                }
+            };
+            let pending = replace(&mut replica.pending, Vec::new());
+            if bulk.map_or(true, |bulk| replica.send(bulk)) && replica.send(pending) {
+                log!(
+                    self.config.logger,
+                    Notice,
+                    "Synchronization with replica {}:{} succeeded",
+                    replica.ip,
+                    replica.conf.listening_port
+                );
+                replica.state = ReplicaState::Online;
+                replica.ack_time = mstime();
+                replicas.push(replica);
+            }
+        }
+        self.replicas = replicas;
+
+        if self.repl_bgsave_due() {
+            self.repl_bgsave();
+        }
+    }
+
+    /// Makes this server a replica of `host:port`. Its own replicas are
+    /// disconnected, since the dataset is going to be replaced.
+    pub fn repl_set_master(&mut self, host: String, port: u16) {
+        log!(self.config.logger, Notice, "REPLICAOF {}:{} enabled", host, port);
+        for replica in self.replicas.drain(..) {
+            replica.close();
+        }
+        self.repl_link_counter += 1;
+        let now = mstime();
+        self.master = Some(MasterLink {
+            id: self.repl_link_counter,
+            host,
+            port,
+            state: MasterLinkState::Connect,
+            last_io: now,
+            down_since: now,
+            last_connect: 0,
+            sync_size: 0,
+            sync_read: 0,
+        });
+    }
+
+    /// Turns a replica into a master. The replication id changes because
+    /// the stream produced from now on diverges from the old master's.
+    pub fn repl_unset_master(&mut self) {
+        if self.master.take().is_some() {
+            log!(self.config.logger, Notice, "MASTER MODE enabled");
+            self.replid = get_random_hex_chars(40);
+            self.repl_seldb = None;
+        }
+    }
+
+    /// Returns the link to the master `id` if it is still the current one.
+    pub fn repl_link(&mut self, id: u64) -> Option<&mut MasterLink> {
+        match &mut self.master {
+            Some(link) if link.id == id => Some(link),
+            _ => None,
+        }
+    }
+
+    /// Returns the link that needs a new connection, if any, marking it as
+    /// connecting. Attempts are at least a second apart.
+    pub fn repl_connect_due(&mut self) -> Option<(u64, String, u16)> {
+        let now = mstime();
+        match &mut self.master {
+            Some(link) if link.state == MasterLinkState::Connect && now - link.last_connect >= 1000 => {
+                link.state = MasterLinkState::Connecting;
+                link.last_connect = now;
+                Some((link.id, link.host.clone(), link.port))
+            }
+            _ => None,
+        }
+    }
+
+    /// Marks the connection of the link `id` as lost, to retry later.
+    pub fn repl_link_lost(&mut self, id: u64) {
+        if let Some(link) = self.repl_link(id) {
+            if link.state == MasterLinkState::Connected {
+                link.down_since = mstime();
+            }
+            link.state = MasterLinkState::Connect;
+        }
+    }
+
+    /// Called once the snapshot from the master was loaded. The stream that
+    /// follows starts at `offset` of the master's `replid` history.
+    pub fn repl_full_sync_done(&mut self, id: u64, replid: String, offset: u64) {
+        if let Some(link) = self.repl_link(id) {
+            link.state = MasterLinkState::Connected;
+        }
+        self.replid = replid;
+        self.master_repl_offset = offset;
+        self.repl_backlog = Some(Backlog::new(self.config.repl_backlog_size, offset));
+    }
+
+    /// Called when the master accepted to continue the stream where this
+    /// replica left it.
+    pub fn repl_partial_sync_done(&mut self, id: u64, replid: Option<String>) {
+        if let Some(link) = self.repl_link(id) {
+            link.state = MasterLinkState::Connected;
+        }
+        if let Some(replid) = replid {
+            self.replid = replid;
+        }
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(Backlog::new(self.config.repl_backlog_size, self.master_repl_offset));
+        }
+    }
+
+    /// Adds data received from the master to the replication stream, so the
+    /// offset keeps up with the master's and this server's replicas get it
+    /// too.
+    pub fn repl_feed_from_master(&mut self, data: &[u8]) {
+        self.repl_feed_raw(data);
+    }
+
+    /// Periodic replication tasks: starts the snapshots replicas are
+    /// waiting for and pings the replicas so they know the link is alive.
+    pub fn repl_cron(&mut self) {
+        if self.rdb_child_pid.is_none() && self.aof_child_pid.is_none() && self.repl_bgsave_due() {
+            self.repl_bgsave();
+        }
+
+        // a replica forwards the pings of its master instead
+        let now = mstime();
+        if self.master.is_none() && now - self.repl_last_ping >= REPL_PING_PERIOD_MS {
+            self.repl_last_ping = now;
+            if self.replicas.iter().any(|r| r.state == ReplicaState::Online) {
+                self.repl_feed_raw(b"*1\r\n$4\r\nPING\r\n");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_replication {
+    use std::env::temp_dir;
+    use std::sync::mpsc::{channel, Receiver};
+
+    use response::Response;
+
+    use super::super::Database;
+    use super::{Backlog, MasterLinkState, Replica, ReplicaConf, ReplicaState};
+
+    fn recv_raw(rx: &Receiver<Option<Response>>) -> Vec<u8> {
+        match rx.try_recv().unwrap() {
+            Some(Response::Raw(data)) => data,
+            r => panic!("Unexpected message {:?}", r),
+        }
+    }
+
+    #[test]
+    fn backlog_feed() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.since(100), Some(vec![]));
+        assert_eq!(backlog.since(99), None);
+        backlog.feed(b"abcde");
+        assert_eq!(backlog.first_offset(), 100);
+        assert_eq!(backlog.since(102), Some(b"cde".to_vec()));
+        backlog.feed(b"fghij");
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.first_offset(), 102);
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(102), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.since(109), Some(b"j".to_vec()));
+        assert_eq!(backlog.since(111), None);
+    }
+
+    #[test]
+    fn backlog_feed_larger_than_size() {
+        let mut backlog = Backlog::new(4, 0);
+        backlog.feed(b"ab");
+        backlog.feed(b"0123456789");
+        assert_eq!(backlog.first_offset(), 8);
+        assert_eq!(backlog.since(8), Some(b"6789".to_vec()));
+    }
+
+    #[test]
+    fn feed_without_replicas() {
+        let mut db = Database::mock();
+        db.repl_feed(0, b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(db.master_repl_offset, 0);
+        assert!(db.repl_backlog.is_none());
+    }
+
+    #[test]
+    fn full_and_partial_resync() {
+        let mut path = temp_dir();
+        path.push("rsedis-replication-test-resync.rdb");
+
+        let mut db = Database::mock();
+        db.config.dbfilename = path.to_str().unwrap().to_owned();
+        db.get_or_create(0, b"key").set(b"value".to_vec()).unwrap();
+
+        let (tx, rx) = channel();
+        let replica = Replica::new(1, tx.clone(), "127.0.0.1".to_owned(), ReplicaConf::default());
+        db.repl_attach(replica, Some(("?", -1)));
+        assert!(db.rdb_child_pid.is_some());
+        let fullresync = recv_raw(&rx);
+        assert_eq!(fullresync, format!("+FULLRESYNC {} 0\r\n", db.replid).into_bytes());
+
+        db.repl_feed(1, b"*1\r\n$4\r\nPING\r\n");
+        while db.rdb_child_pid.is_some() {
+            db.rdb_bgsave_check();
+        }
+        let bulk = recv_raw(&rx);
+        assert!(bulk.starts_with(b"$"));
+        assert!(bulk.windows(5).any(|w| w == b"REDIS"));
+        let stream = b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n*1\r\n$4\r\nPING\r\n".to_vec();
+        assert_eq!(recv_raw(&rx), stream);
+        assert_eq!(db.replicas[0].state, ReplicaState::Online);
+        assert_eq!(db.master_repl_offset, stream.len() as u64);
+
+        db.repl_feed(1, b"*1\r\n$3\r\nFOO\r\n");
+        assert_eq!(recv_raw(&rx), b"*1\r\n$3\r\nFOO\r\n".to_vec());
+
+        // reconnects after receiving only the first command
+        let replid = db.replid.clone();
+        let replica = Replica::new(1, tx, "127.0.0.1".to_owned(), ReplicaConf::default());
+        db.repl_attach(replica, Some((&*replid, 24)));
+        assert_eq!(recv_raw(&rx), b"+CONTINUE\r\n".to_vec());
+        assert_eq!(recv_raw(&rx), b"*1\r\n$4\r\nPING\r\n*1\r\n$3\r\nFOO\r\n".to_vec());
+        assert_eq!(db.replicas.len(), 1);
+        assert!(db.rdb_child_pid.is_none());
+    }
+
+    #[test]
+    fn attach_to_running_bgsave() {
+        let mut path = temp_dir();
+        path.push("rsedis-replication-test-attach.rdb");
+
+        let mut db = Database::mock();
+        db.config.dbfilename = path.to_str().unwrap().to_owned();
+
+        let (tx1, rx1) = channel();
+        db.repl_attach(Replica::new(1, tx1, "127.0.0.1".to_owned(), ReplicaConf::default()), Some(("?", -1)));
+        recv_raw(&rx1);
+        db.repl_feed(0, b"*1\r\n$4\r\nPING\r\n");
+
+        let (tx2, rx2) = channel();
+        db.repl_attach(Replica::new(2, tx2, "127.0.0.1".to_owned(), ReplicaConf::default()), Some(("?", -1)));
+        assert_eq!(recv_raw(&rx2), format!("+FULLRESYNC {} 0\r\n", db.replid).into_bytes());
+
+        while db.rdb_child_pid.is_some() {
+            db.rdb_bgsave_check();
+        }
+        for rx in &[rx1, rx2] {
+            recv_raw(rx);
+            assert_eq!(recv_raw(rx), b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*1\r\n$4\r\nPING\r\n".to_vec());
+        }
+    }
+
+    #[test]
+    fn master_link() {
+        let mut path = temp_dir();
+        path.push("rsedis-replication-test-master-link.rdb");
+
+        let mut db = Database::mock();
+        db.config.dbfilename = path.to_str().unwrap().to_owned();
+        let (tx, rx) = channel();
+        db.repl_attach(Replica::new(1, tx, "127.0.0.1".to_owned(), ReplicaConf::default()), None);
+
+        while db.rdb_child_pid.is_some() {
+            db.rdb_bgsave_check();
+        }
+        db.repl_set_master("127.0.0.1".to_owned(), 6379);
+        assert!(db.replicas.is_empty());
+        assert!(rx.try_iter().any(|m| m.is_none()));
+        let (id, host, port) = db.repl_connect_due().unwrap();
+        assert_eq!((host.as_str(), port), ("127.0.0.1", 6379));
+        assert!(db.repl_connect_due().is_none());
+
+        db.repl_full_sync_done(id, "a".repeat(40), 10);
+        assert_eq!(db.master.as_ref().unwrap().state, MasterLinkState::Connected);
+        assert_eq!(db.master_repl_offset, 10);
+        db.repl_feed_from_master(b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(db.master_repl_offset, 24);
+
+        // a stale link does not touch the current one
+        db.repl_link_lost(id + 1);
+        assert_eq!(db.master.as_ref().unwrap().state, MasterLinkState::Connected);
+        db.repl_link_lost(id);
+        assert_eq!(db.master.as_ref().unwrap().state, MasterLinkState::Connect);
+
+        db.repl_unset_master();
+        assert!(db.master.is_none());
+        assert_ne!(db.replid, "a".repeat(40));
+    }
+
+    #[test]
+    fn wait_for_acks() {
+        let mut db = Database::mock();
+        let replid = db.replid.clone();
+        let (tx, rx) = channel();
+        db.repl_attach(Replica::new(1, tx, "127.0.0.1".to_owned(), ReplicaConf::default()), Some((&*replid, 1)));
+        db.repl_feed(0, b"*1\r\n$4\r\nPING\r\n");
+        let offset = db.master_repl_offset;
+        assert_eq!(db.repl_acked(offset), 0);
+
+        let (txack, rxack) = channel();
+        db.repl_wait(2, offset, 1, txack);
+        let getack = rx.try_iter().last().unwrap();
+        assert_eq!(getack, Some(Response::Raw(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n".to_vec())));
+        db.repl_ack(1, offset - 1);
+        assert!(rxack.try_recv().is_err());
+        db.repl_ack(1, offset);
+        assert_eq!(rxack.try_recv(), Ok(true));
+        assert_eq!(db.repl_acked(offset), 1);
+    }
+
+    #[test]
+    fn good_replicas() {
+        let mut db = Database::mock();
+        let replid = db.replid.clone();
+        let (tx, _rx) = channel();
+        db.repl_attach(Replica::new(1, tx, "127.0.0.1".to_owned(), ReplicaConf::default()), Some((&*replid, 1)));
+        assert_eq!(db.repl_good_replicas(), 1);
+        db.replicas[0].ack_time -= 11000;
+        assert_eq!(db.repl_good_replicas(), 0);
+        db.config.min_slaves_max_lag = 20;
+        assert_eq!(db.repl_good_replicas(), 1);
+    }
+
+    #[test]
+    fn diskless_sync() {
+        let mut db = Database::mock();
+        db.config.repl_diskless_sync = true;
+        db.config.repl_diskless_sync_delay = 0;
+        db.get_or_create(0, b"key").set(b"value".to_vec()).unwrap();
+
+        let (tx, rx) = channel();
+        let conf = ReplicaConf {
+            capa: vec!["eof".to_owned(), "psync2".to_owned()],
+            ..ReplicaConf::default()
+        };
+        db.repl_attach(Replica::new(1, tx, "127.0.0.1".to_owned(), conf), Some(("?", -1)));
+        assert!(db.rdb_child_pid.is_some());
+        assert_eq!(recv_raw(&rx), format!("+FULLRESYNC {} 0\r\n", db.replid).into_bytes());
+        db.repl_feed(0, b"*1\r\n$4\r\nPING\r\n");
+        while db.rdb_child_pid.is_some() {
+            db.rdb_bgsave_check();
+        }
+        assert_eq!(db.replicas[0].state, ReplicaState::Online);
+
+        let mut data = vec![];
+        while let Ok(Some(Response::Raw(chunk))) = rx.try_recv() {
+            data.extend(chunk);
+        }
+        assert!(data.starts_with(b"$EOF:"));
+        let mark = data[5..45].to_vec();
+        assert_eq!(&data[45..47], b"\r\n");
+        assert_eq!(&data[47..52], b"REDIS");
+        let end = data.windows(40).rposition(|w| w == &*mark).unwrap();
+        assert!(end > 47);
+        assert_eq!(&data[end + 40..], b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*1\r\n$4\r\nPING\r\n");
+    }
+}
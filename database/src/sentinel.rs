@@ -0,0 +1,1666 @@
+//! Sentinel mode. Instead of serving data, the server monitors masters and
+//! their replicas. When enough sentinels agree that a master is down, one of
+//! them is elected to promote a replica, and the others follow the new
+//! configuration.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{remove_file, rename, File};
+use std::io::{self, Read, Write};
+use std::mem::replace;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use rand;
+
+use logger::Level;
+use response::Response;
+use util::{get_random_hex_chars, mstime};
+
+use aof::write_command;
+
+use super::Database;
+
+/// Channel where the sentinels announce themselves on the monitored servers
+pub const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+/// How often every instance is pinged, in milliseconds
+const PING_PERIOD_MS: i64 = 1000;
+
+/// How often masters and replicas are asked for INFO, in milliseconds
+const INFO_PERIOD_MS: i64 = 10_000;
+
+/// How often the hello message is published, in milliseconds
+const HELLO_PERIOD_MS: i64 = 2000;
+
+/// How often the other sentinels are asked about a master that seems down
+const ASK_PERIOD_MS: i64 = 1000;
+
+/// Random delay added to the failover start so sentinels do not compete
+const MAX_DESYNC_MS: i64 = 1000;
+
+/// Longest time waiting for the votes of the other sentinels
+const ELECTION_TIMEOUT_MS: i64 = 10_000;
+
+/// How long before a disconnected instance is connected again
+const RECONNECT_PERIOD_MS: i64 = 1000;
+
+const DEFAULT_DOWN_AFTER_MS: i64 = 30_000;
+const DEFAULT_FAILOVER_TIMEOUT_MS: i64 = 180_000;
+const DEFAULT_PARALLEL_SYNCS: usize = 1;
+
+/// What a command sent on a link was, to handle its reply.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Request {
+    Auth,
+    Ping,
+    Info,
+    Publish,
+    IsMasterDown,
+    Command,
+}
+
+/// Connection to an instance. The commands are queued into `sender`, and
+/// their replies come back in the same order as `pending`.
+struct Link {
+    serial: u64,
+    sender: Sender<Vec<u8>>,
+    pending: VecDeque<Request>,
+    ctime: i64,
+}
+
+impl Link {
+    fn send(&mut self, request: Request, args: &[&[u8]]) {
+        let mut data = vec![];
+        write_command(&mut data, args).unwrap();
+        if self.sender.send(data).is_ok() {
+            self.pending.push_back(request);
+        }
+    }
+}
+
+/// Progress of a replica being moved to the promoted one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Reconf {
+    None,
+    Sent,
+    Done,
+}
+
+/// A monitored master or replica, or another sentinel.
+pub struct Instance {
+    pub ip: String,
+    pub port: u16,
+    pub run_id: String,
+    link: Option<Link>,
+    /// Connection subscribed to the hello channel, for masters and replicas
+    pubsub: Option<Link>,
+    last_connect: i64,
+    /// When the ping waiting for a reply was sent, or 0
+    pub ping_sent: i64,
+    last_ping: i64,
+    /// Last time the instance replied to a ping properly
+    pub last_avail: i64,
+    last_info: i64,
+    /// Last time an INFO reply was received
+    pub info_refresh: i64,
+    /// Last time a hello was sent to a server, or received from a sentinel
+    pub last_hello: i64,
+    /// Since when the instance is subjectively down, or 0
+    pub sdown_since: i64,
+    pub role_master: bool,
+    /// Last time the role or the master reported by the instance changed
+    pub role_reported_time: i64,
+    pub master_host: String,
+    pub master_port: u16,
+    pub master_link_up: bool,
+    pub offset: u64,
+    pub priority: u32,
+    reconf: Reconf,
+    /// Whether the sentinel thinks the master is down, and when it said so
+    pub master_down: bool,
+    master_down_time: i64,
+    /// The vote of the sentinel for the failover leader
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+}
+
+impl Instance {
+    fn new(ip: String, port: u16, now: i64) -> Instance {
+        Instance {
+            ip,
+            port,
+            run_id: String::new(),
+            link: None,
+            pubsub: None,
+            last_connect: 0,
+            ping_sent: 0,
+            last_ping: 0,
+            last_avail: now,
+            last_info: 0,
+            info_refresh: 0,
+            last_hello: 0,
+            sdown_since: 0,
+            role_master: false,
+            role_reported_time: now,
+            master_host: String::new(),
+            master_port: 0,
+            master_link_up: false,
+            offset: 0,
+            priority: 100,
+            reconf: Reconf::None,
+            master_down: false,
+            master_down_time: 0,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    pub fn connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    /// Number of commands waiting for a reply.
+    pub fn pending_commands(&self) -> usize {
+        self.link.as_ref().map_or(0, |link| link.pending.len())
+    }
+
+    fn send(&mut self, request: Request, args: &[&[u8]]) {
+        if let Some(ref mut link) = self.link {
+            link.send(request, args);
+        }
+    }
+
+    fn waiting(&self, request: Request) -> bool {
+        self.link.as_ref().map_or(false, |link| link.pending.contains(&request))
+    }
+}
+
+/// Step of a failover in progress.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FailoverState {
+    None,
+    WaitStart,
+    SelectSlave,
+    SendSlaveofNoone,
+    WaitPromotion,
+    ReconfSlaves,
+}
+
+impl FailoverState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailoverState::None => "none",
+            FailoverState::WaitStart => "wait_start",
+            FailoverState::SelectSlave => "select_slave",
+            FailoverState::SendSlaveofNoone => "send_slaveof_noone",
+            FailoverState::WaitPromotion => "wait_promotion",
+            FailoverState::ReconfSlaves => "reconf_slaves",
+        }
+    }
+}
+
+/// A monitored master, with its replicas and the other sentinels watching
+/// it.
+pub struct Master {
+    pub name: String,
+    pub instance: Instance,
+    pub quorum: usize,
+    pub down_after: i64,
+    pub failover_timeout: i64,
+    pub parallel_syncs: usize,
+    pub auth_pass: Option<String>,
+    /// Epoch of the failover that produced the current configuration
+    pub config_epoch: u64,
+    /// The vote of this sentinel for the failover leader
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+    /// Replicas by address
+    pub replicas: BTreeMap<String, Instance>,
+    /// Other sentinels by run id
+    pub sentinels: BTreeMap<String, Instance>,
+    /// Since when the master is objectively down, or 0
+    pub odown_since: i64,
+    pub failover_state: FailoverState,
+    pub failover_epoch: u64,
+    pub failover_start_time: i64,
+    failover_state_change_time: i64,
+    /// Address of the replica being promoted
+    pub promoted: Option<String>,
+}
+
+impl Master {
+    fn new(name: String, ip: String, port: u16, quorum: usize, now: i64) -> Master {
+        Master {
+            name,
+            instance: Instance::new(ip, port, now),
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER_MS,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT_MS,
+            parallel_syncs: DEFAULT_PARALLEL_SYNCS,
+            auth_pass: None,
+            config_epoch: 0,
+            leader: None,
+            leader_epoch: 0,
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            odown_since: 0,
+            failover_state: FailoverState::None,
+            failover_epoch: 0,
+            failover_start_time: 0,
+            failover_state_change_time: 0,
+            promoted: None,
+        }
+    }
+
+    /// Describes the master for the events.
+    fn describe(&self) -> String {
+        format!("master {} {} {}", self.name, self.instance.ip, self.instance.port)
+    }
+
+    /// Describes a replica or a sentinel of the master for the events.
+    fn describe_instance(&self, kind: &str, name: &str, instance: &Instance) -> String {
+        format!(
+            "{} {} {} {} @ {} {} {}",
+            kind, name, instance.ip, instance.port, self.name, self.instance.ip, self.instance.port
+        )
+    }
+
+    /// The address clients should use: once the promoted replica accepted
+    /// its role it is the master, even before the failover ends.
+    pub fn current_address(&self) -> (String, u16) {
+        if self.failover_state == FailoverState::ReconfSlaves {
+            if let Some(replica) = self.promoted.as_ref().and_then(|addr| self.replicas.get(addr)) {
+                return (replica.ip.clone(), replica.port);
+            }
+        }
+        (self.instance.ip.clone(), self.instance.port)
+    }
+
+    /// The flags of the master, or of one of its replicas or sentinels.
+    pub fn flags(&self, kind: &str, instance: &Instance) -> String {
+        let mut flags = vec![kind];
+        if instance.sdown_since != 0 {
+            flags.push("s_down");
+        }
+        if kind == "master" && self.odown_since != 0 {
+            flags.push("o_down");
+        }
+        if instance.link.is_none() {
+            flags.push("disconnected");
+        }
+        if kind == "master" && self.failover_state != FailoverState::None {
+            flags.push("failover_in_progress");
+        }
+        if kind == "slave" && self.promoted.as_ref() == Some(&instance.addr()) {
+            flags.push("promoted");
+        }
+        flags.join(",")
+    }
+
+    fn abort_failover(&mut self, now: i64) {
+        self.failover_state = FailoverState::None;
+        self.failover_state_change_time = now;
+        self.promoted = None;
+        for replica in self.replicas.values_mut() {
+            replica.reconf = Reconf::None;
+        }
+    }
+}
+
+/// State of a sentinel.
+pub struct Sentinel {
+    pub myid: String,
+    pub current_epoch: u64,
+    pub masters: BTreeMap<String, Master>,
+    /// Address other sentinels reach this one at, learned from the links
+    pub ip: String,
+    link_counter: u64,
+    /// Events to log and publish, as level, type and description
+    events: Vec<(Level, &'static str, String)>,
+    /// The configuration changed and should be saved
+    todo_save: bool,
+}
+
+fn parse_number<T: ::std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| "Invalid argument".to_owned())
+}
+
+impl Sentinel {
+    pub fn new() -> Sentinel {
+        Sentinel {
+            myid: get_random_hex_chars(40),
+            current_epoch: 0,
+            masters: BTreeMap::new(),
+            ip: String::new(),
+            link_counter: 0,
+            events: vec![],
+            todo_save: false,
+        }
+    }
+
+    /// Builds the sentinel from the arguments of the `sentinel` directives
+    /// of the configuration file.
+    pub fn from_config(directives: &[Vec<String>]) -> Result<Sentinel, String> {
+        let mut sentinel = Sentinel::new();
+        let now = mstime();
+        for argv in directives {
+            let option = argv[0].to_ascii_lowercase();
+            match (&*option, argv.len()) {
+                ("myid", 2) => {
+                    if argv[1].len() != 40 {
+                        return Err("Malformed Sentinel id in myid option.".to_owned());
+                    }
+                    sentinel.myid = argv[1].clone();
+                }
+                ("current-epoch", 2) => sentinel.current_epoch = parse_number(&argv[1])?,
+                ("monitor", 5) => {
+                    let port = parse_number(&argv[3])?;
+                    let quorum = parse_number(&argv[4])?;
+                    sentinel.monitor(&argv[1], &argv[2], port, quorum)?;
+                }
+                ("config-epoch", 3) => sentinel.get_mut(&argv[1])?.config_epoch = parse_number(&argv[2])?,
+                ("leader-epoch", 3) => sentinel.get_mut(&argv[1])?.leader_epoch = parse_number(&argv[2])?,
+                ("known-replica", 4) | ("known-slave", 4) => {
+                    let port = parse_number(&argv[3])?;
+                    let master = sentinel.get_mut(&argv[1])?;
+                    let replica = Instance::new(argv[2].clone(), port, now);
+                    master.replicas.insert(replica.addr(), replica);
+                }
+                ("known-sentinel", 5) => {
+                    let port = parse_number(&argv[3])?;
+                    let master = sentinel.get_mut(&argv[1])?;
+                    let mut peer = Instance::new(argv[2].clone(), port, now);
+                    peer.run_id = argv[4].clone();
+                    master.sentinels.insert(argv[4].clone(), peer);
+                }
+                (_, 3) => {
+                    let (name, value) = (argv[1].clone(), argv[2].clone());
+                    sentinel.set(&name, &option, &value)?;
+                }
+                _ => return Err("Unrecognized sentinel configuration statement.".to_owned()),
+            }
+        }
+        sentinel.events.clear();
+        Ok(sentinel)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Master, String> {
+        self.masters
+            .get(name)
+            .ok_or_else(|| "No such master with that name".to_owned())
+    }
+
+    fn get_mut(&mut self, name: &str) -> Result<&mut Master, String> {
+        self.masters
+            .get_mut(name)
+            .ok_or_else(|| "No such master with that name".to_owned())
+    }
+
+    /// Starts monitoring the master `name` at `ip:port`.
+    pub fn monitor(&mut self, name: &str, ip: &str, port: u16, quorum: usize) -> Result<(), String> {
+        if quorum == 0 {
+            return Err("Quorum must be 1 or greater.".to_owned());
+        }
+        if port == 0 {
+            return Err("Invalid port number".to_owned());
+        }
+        if self.masters.contains_key(name) {
+            return Err("Duplicated master name.".to_owned());
+        }
+        let master = Master::new(name.to_owned(), ip.to_owned(), port, quorum, mstime());
+        self.events.push((Level::Warning, "+monitor", format!("{} quorum {}", master.describe(), quorum)));
+        self.masters.insert(name.to_owned(), master);
+        self.todo_save = true;
+        Ok(())
+    }
+
+    /// Stops monitoring the master `name`.
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        let master = self.masters.remove(name).ok_or_else(|| "No such master with that name".to_owned())?;
+        self.events.push((Level::Warning, "-monitor", master.describe()));
+        self.todo_save = true;
+        Ok(())
+    }
+
+    /// Changes an option of the master `name`.
+    pub fn set(&mut self, name: &str, option: &str, value: &str) -> Result<(), String> {
+        let master = self.get_mut(name)?;
+        match option {
+            "down-after-milliseconds" => {
+                let ms = parse_number::<i64>(value)?;
+                if ms <= 0 {
+                    return Err("Invalid argument".to_owned());
+                }
+                master.down_after = ms;
+            }
+            "failover-timeout" => {
+                let ms = parse_number::<i64>(value)?;
+                if ms <= 0 {
+                    return Err("Invalid argument".to_owned());
+                }
+                master.failover_timeout = ms;
+            }
+            "parallel-syncs" => master.parallel_syncs = parse_number(value)?,
+            "quorum" => {
+                let quorum = parse_number::<usize>(value)?;
+                if quorum == 0 {
+                    return Err("Quorum must be 1 or greater.".to_owned());
+                }
+                master.quorum = quorum;
+            }
+            "auth-pass" => {
+                master.auth_pass = if value.is_empty() { None } else { Some(value.to_owned()) };
+            }
+            _ => return Err(format!("Invalid argument '{}' for SENTINEL SET", option)),
+        }
+        self.todo_save = true;
+        Ok(())
+    }
+
+    /// The `sentinel` directives to save the state into the configuration
+    /// file.
+    pub fn config(&self) -> Vec<String> {
+        let mut lines = vec![format!("sentinel myid {}", self.myid)];
+        for master in self.masters.values() {
+            let name = &master.name;
+            let (ip, port) = master.current_address();
+            lines.push(format!("sentinel monitor {} {} {} {}", name, ip, port, master.quorum));
+            if master.down_after != DEFAULT_DOWN_AFTER_MS {
+                lines.push(format!("sentinel down-after-milliseconds {} {}", name, master.down_after));
+            }
+            if master.failover_timeout != DEFAULT_FAILOVER_TIMEOUT_MS {
+                lines.push(format!("sentinel failover-timeout {} {}", name, master.failover_timeout));
+            }
+            if master.parallel_syncs != DEFAULT_PARALLEL_SYNCS {
+                lines.push(format!("sentinel parallel-syncs {} {}", name, master.parallel_syncs));
+            }
+            if let Some(ref auth_pass) = master.auth_pass {
+                lines.push(format!("sentinel auth-pass {} {}", name, auth_pass));
+            }
+            lines.push(format!("sentinel config-epoch {} {}", name, master.config_epoch));
+            lines.push(format!("sentinel leader-epoch {} {}", name, master.leader_epoch));
+            let addr = format!("{}:{}", ip, port);
+            for replica in master.replicas.values() {
+                if replica.addr() != addr {
+                    lines.push(format!("sentinel known-replica {} {} {}", name, replica.ip, replica.port));
+                }
+            }
+            if addr != master.instance.addr() {
+                lines.push(format!(
+                    "sentinel known-replica {} {} {}",
+                    name, master.instance.ip, master.instance.port
+                ));
+            }
+            for peer in master.sentinels.values() {
+                lines.push(format!(
+                    "sentinel known-sentinel {} {} {} {}",
+                    name, peer.ip, peer.port, peer.run_id
+                ));
+            }
+        }
+        lines.push(format!("sentinel current-epoch {}", self.current_epoch));
+        lines
+    }
+
+    fn event(&mut self, level: Level, kind: &'static str, description: String) {
+        self.events.push((level, kind, description));
+    }
+
+    /// Opens the links of `instance` that are missing, at most once per
+    /// reconnect period.
+    fn connect(
+        &mut self,
+        instance: &mut Instance,
+        pubsub: bool,
+        auth_pass: &Option<String>,
+        now: i64,
+        connect: &mut Vec<(u64, String, u16, Receiver<Vec<u8>>)>,
+    ) {
+        if (instance.link.is_some() && (!pubsub || instance.pubsub.is_some()))
+            || now - instance.last_connect < RECONNECT_PERIOD_MS
+        {
+            return;
+        }
+        instance.last_connect = now;
+        let (ip, port) = (instance.ip.clone(), instance.port);
+        let mut new_link = || {
+            let (sender, receiver) = channel();
+            self.link_counter += 1;
+            let mut link = Link {
+                serial: self.link_counter,
+                sender,
+                pending: VecDeque::new(),
+                ctime: now,
+            };
+            if let Some(ref auth_pass) = *auth_pass {
+                link.send(Request::Auth, &[b"AUTH", auth_pass.as_bytes()]);
+            }
+            connect.push((self.link_counter, ip.clone(), port, receiver));
+            link
+        };
+        if instance.link.is_none() {
+            let mut link = new_link();
+            link.send(Request::Ping, &[b"PING"]);
+            instance.link = Some(link);
+            instance.ping_sent = now;
+            instance.last_ping = now;
+        }
+        if pubsub && instance.pubsub.is_none() {
+            let link = new_link();
+            // the replies on this link are never matched to requests
+            let mut data = vec![];
+            write_command(&mut data, &[b"SUBSCRIBE", HELLO_CHANNEL.as_bytes()]).unwrap();
+            let _ = link.sender.send(data);
+            instance.pubsub = Some(link);
+        }
+    }
+
+    /// Pings the instance, and drops its link when the replies stopped
+    /// arriving for half of `down_after`, in case it got stuck.
+    fn ping(&mut self, instance: &mut Instance, down_after: i64, now: i64) {
+        if instance.ping_sent != 0
+            && now - instance.ping_sent > down_after / 2
+            && instance.link.as_ref().map_or(false, |link| now - link.ctime > down_after / 2)
+        {
+            instance.link = None;
+            instance.pubsub = None;
+            return;
+        }
+        let period = if down_after < PING_PERIOD_MS { down_after } else { PING_PERIOD_MS };
+        if instance.link.is_some() && instance.ping_sent == 0 && now - instance.last_ping >= period {
+            instance.send(Request::Ping, &[b"PING"]);
+            instance.ping_sent = now;
+            instance.last_ping = now;
+        }
+    }
+
+    /// Flags the instance as subjectively down when it did not reply for
+    /// `down_after`, or clears the flag.
+    fn check_sdown(&mut self, instance: &mut Instance, description: String, down_after: i64, now: i64) {
+        let down = now - instance.last_avail > down_after;
+        if down && instance.sdown_since == 0 {
+            instance.sdown_since = now;
+            self.event(Level::Warning, "+sdown", description);
+        } else if !down && instance.sdown_since != 0 {
+            instance.sdown_since = 0;
+            self.event(Level::Warning, "-sdown", description);
+        }
+    }
+
+    /// The hello message announcing this sentinel and its view of `master`.
+    fn hello(&self, master: &Master, port: u16) -> String {
+        let (ip, mport) = master.current_address();
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.ip, port, self.myid, self.current_epoch, master.name, ip, mport, master.config_epoch
+        )
+    }
+
+    /// Periodic tasks for the master `name`.
+    fn master_cron(
+        &mut self,
+        master: &mut Master,
+        port: u16,
+        now: i64,
+        connect: &mut Vec<(u64, String, u16, Receiver<Vec<u8>>)>,
+    ) {
+        let auth_pass = master.auth_pass.clone();
+        let down_after = master.down_after;
+        let master_down = master.instance.sdown_since != 0;
+        let failover = master.failover_state != FailoverState::None;
+
+        // masters and replicas: ping, INFO and hello
+        let hello = if self.ip.is_empty() { None } else { Some(self.hello(master, port)) };
+        let mut instances = vec![(None, master.describe())];
+        for (addr, replica) in master.replicas.iter() {
+            instances.push((Some(addr.clone()), master.describe_instance("slave", addr, replica)));
+        }
+        for (addr, description) in instances {
+            let instance = match addr {
+                Some(ref addr) => master.replicas.get_mut(addr).unwrap(),
+                None => &mut master.instance,
+            };
+            self.connect(instance, true, &auth_pass, now, connect);
+            self.ping(instance, down_after, now);
+            // replicas are followed closely while their master is in trouble
+            let info_period = if addr.is_some() && (master_down || failover) {
+                1000
+            } else {
+                INFO_PERIOD_MS
+            };
+            if now - instance.last_info > info_period && !instance.waiting(Request::Info) {
+                instance.send(Request::Info, &[b"INFO"]);
+                instance.last_info = now;
+            }
+            if let Some(ref hello) = hello {
+                if now - instance.last_hello > HELLO_PERIOD_MS {
+                    instance.send(Request::Publish, &[b"PUBLISH", HELLO_CHANNEL.as_bytes(), hello.as_bytes()]);
+                    instance.last_hello = now;
+                }
+            }
+            self.check_sdown(instance, description, down_after, now);
+        }
+
+        // the other sentinels: ping, and ask them about a master down
+        let epoch = format!("{}", self.current_epoch);
+        let (ip, mport) = (master.instance.ip.clone(), format!("{}", master.instance.port));
+        let runid = if failover { self.myid.clone() } else { "*".to_owned() };
+        let peers = master
+            .sentinels
+            .iter()
+            .map(|(id, peer)| (id.clone(), master.describe_instance("sentinel", id, peer)))
+            .collect::<Vec<_>>();
+        for (id, description) in peers {
+            let peer = master.sentinels.get_mut(&id).unwrap();
+            self.connect(peer, false, &None, now, connect);
+            self.ping(peer, down_after, now);
+            self.check_sdown(peer, description, down_after, now);
+            // an old answer is not valid anymore
+            if now - peer.master_down_time > 5 * ASK_PERIOD_MS {
+                peer.master_down = false;
+                peer.leader = None;
+            }
+            if master.instance.sdown_since != 0
+                && now - peer.master_down_time > ASK_PERIOD_MS && !peer.waiting(Request::IsMasterDown) {
+                peer.send(
+                    Request::IsMasterDown,
+                    &[
+                        b"SENTINEL",
+                        b"is-master-down-by-addr",
+                        ip.as_bytes(),
+                        mport.as_bytes(),
+                        epoch.as_bytes(),
+                        runid.as_bytes(),
+                    ],
+                );
+            }
+        }
+
+        self.check_odown(master, now);
+        self.failover_cron(master, now);
+    }
+
+    /// Flags the master as objectively down when enough sentinels agree
+    /// that it is subjectively down.
+    fn check_odown(&mut self, master: &mut Master, now: i64) {
+        let votes = if master.instance.sdown_since != 0 {
+            1 + master.sentinels.values().filter(|peer| peer.master_down).count()
+        } else {
+            0
+        };
+        if votes >= master.quorum {
+            if master.odown_since == 0 {
+                master.odown_since = now;
+                let description = format!("{} #quorum {}/{}", master.describe(), votes, master.quorum);
+                self.event(Level::Warning, "+odown", description);
+            }
+        } else if master.odown_since != 0 {
+            master.odown_since = 0;
+            self.event(Level::Warning, "-odown", master.describe());
+        }
+    }
+
+    /// Votes for `runid` as the leader of the failover of `master` in
+    /// `epoch`, unless this sentinel already voted in that epoch.
+    /// Returns the leader this sentinel voted for, and the epoch of its vote.
+    fn vote(&mut self, master: &mut Master, epoch: u64, runid: &str, now: i64) -> (Option<String>, u64) {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            self.todo_save = true;
+            self.event(Level::Warning, "+new-epoch", format!("{}", epoch));
+        }
+        if master.leader_epoch < epoch && self.current_epoch <= epoch {
+            master.leader = Some(runid.to_owned());
+            master.leader_epoch = self.current_epoch;
+            self.todo_save = true;
+            let description = format!("{} {}", runid, master.leader_epoch);
+            self.event(Level::Warning, "+vote-for-leader", description);
+            // give the leader time to do its job
+            if runid != self.myid {
+                master.failover_start_time = now + (rand::random::<u64>() % MAX_DESYNC_MS as u64) as i64;
+            }
+        }
+        (master.leader.clone(), master.leader_epoch)
+    }
+
+    /// Counts the votes for the leader of the failover of `master` in
+    /// `epoch`. This sentinel votes for the most voted sentinel, or for
+    /// itself.
+    /// Returns the leader if it has the majority of the sentinels and at
+    /// least the quorum.
+    fn get_leader(&mut self, master: &mut Master, epoch: u64, now: i64) -> Option<String> {
+        let voters = master.sentinels.len() + 1;
+        let mut counters: HashMap<String, usize> = HashMap::new();
+        for peer in master.sentinels.values() {
+            if let Some(ref leader) = peer.leader {
+                if peer.leader_epoch == epoch {
+                    *counters.entry(leader.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+        let most_voted = |counters: &HashMap<String, usize>| {
+            counters
+                .iter()
+                .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+                .map(|(leader, votes)| (leader.clone(), *votes))
+        };
+        let candidate = most_voted(&counters).map_or_else(|| self.myid.clone(), |(leader, _)| leader);
+        if let (Some(leader), leader_epoch) = self.vote(master, epoch, &candidate, now) {
+            if leader_epoch == epoch {
+                *counters.entry(leader).or_insert(0) += 1;
+            }
+        }
+        match most_voted(&counters) {
+            Some((leader, votes)) if votes >= voters / 2 + 1 && votes >= master.quorum => Some(leader),
+            _ => None,
+        }
+    }
+
+    fn start_failover(&mut self, master: &mut Master, now: i64) {
+        self.current_epoch += 1;
+        master.failover_epoch = self.current_epoch;
+        master.failover_state = FailoverState::WaitStart;
+        master.failover_state_change_time = now;
+        master.failover_start_time = now + (rand::random::<u64>() % MAX_DESYNC_MS as u64) as i64;
+        self.todo_save = true;
+        self.event(Level::Warning, "+new-epoch", format!("{}", self.current_epoch));
+        self.event(Level::Warning, "+try-failover", master.describe());
+    }
+
+    /// Forces a failover of `name` without asking the other sentinels.
+    pub fn failover(&mut self, name: &str, now: i64) -> Result<(), String> {
+        let mut master = self.masters.remove(name).ok_or_else(|| "No such master with that name".to_owned())?;
+        let r = if master.failover_state != FailoverState::None {
+            Err("INPROG Failover already in progress".to_owned())
+        } else if self.select_replica(&master, now).is_none() {
+            Err("NOGOODSLAVE No suitable replica to promote".to_owned())
+        } else {
+            self.start_failover(&mut master, now);
+            // this sentinel is the leader already
+            master.failover_state = FailoverState::SelectSlave;
+            self.event(Level::Warning, "+failover-state-select-slave", master.describe());
+            Ok(())
+        };
+        self.masters.insert(name.to_owned(), master);
+        r
+    }
+
+    /// Checks whether enough sentinels are reachable to agree that `name`
+    /// is down and to authorize its failover. Returns how many are.
+    pub fn check_quorum(&self, name: &str) -> Result<usize, String> {
+        let master = self.get(name)?;
+        let voters = master.sentinels.len() + 1;
+        let usable = 1 + master
+            .sentinels
+            .values()
+            .filter(|sentinel| sentinel.sdown_since == 0 && sentinel.link.is_some())
+            .count();
+        if usable < master.quorum {
+            return Err(format!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the specified quorum for this master",
+                usable
+            ));
+        }
+        if usable < voters / 2 + 1 {
+            return Err(format!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the majority and authorize a failover",
+                usable
+            ));
+        }
+        Ok(usable)
+    }
+
+    /// Picks the best replica to promote: reachable, with recent
+    /// information, by priority and then by replication offset.
+    fn select_replica(&self, master: &Master, now: i64) -> Option<String> {
+        let info_validity = if master.instance.sdown_since != 0 {
+            5 * PING_PERIOD_MS
+        } else {
+            3 * INFO_PERIOD_MS
+        };
+        let mut candidates = master
+            .replicas
+            .values()
+            .filter(|replica| {
+                replica.sdown_since == 0
+                    && replica.link.is_some()
+                    && now - replica.last_avail <= 5 * PING_PERIOD_MS
+                    && now - replica.info_refresh <= info_validity
+                    && replica.priority != 0
+                    && !replica.role_master
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            a.priority
+                .cmp(&b.priority)
+                .then(b.offset.cmp(&a.offset))
+                .then(a.run_id.cmp(&b.run_id))
+        });
+        candidates.first().map(|replica| replica.addr())
+    }
+
+    fn set_failover_state(&mut self, master: &mut Master, state: FailoverState, kind: &'static str, now: i64) {
+        master.failover_state = state;
+        master.failover_state_change_time = now;
+        self.event(Level::Warning, kind, master.describe());
+    }
+
+    /// Moves the failover of `master` forward.
+    fn failover_cron(&mut self, master: &mut Master, now: i64) {
+        match master.failover_state {
+            FailoverState::None => {
+                if master.odown_since != 0 && now - master.failover_start_time > 2 * master.failover_timeout {
+                    self.start_failover(master, now);
+                }
+            }
+            FailoverState::WaitStart => {
+                let epoch = master.failover_epoch;
+                if self.get_leader(master, epoch, now).as_ref() == Some(&self.myid) {
+                    self.event(Level::Warning, "+elected-leader", master.describe());
+                    self.set_failover_state(master, FailoverState::SelectSlave, "+failover-state-select-slave", now);
+                } else if now - master.failover_start_time > ELECTION_TIMEOUT_MS.min(master.failover_timeout) {
+                    self.event(Level::Warning, "-failover-abort-not-elected", master.describe());
+                    master.abort_failover(now);
+                }
+            }
+            FailoverState::SelectSlave => match self.select_replica(master, now) {
+                Some(addr) => {
+                    let description = master.describe_instance("slave", &addr, &master.replicas[&addr]);
+                    self.event(Level::Warning, "+selected-slave", description);
+                    master.promoted = Some(addr);
+                    self.set_failover_state(
+                        master,
+                        FailoverState::SendSlaveofNoone,
+                        "+failover-state-send-slaveof-noone",
+                        now,
+                    );
+                }
+                None => {
+                    self.event(Level::Warning, "-failover-abort-no-good-slave", master.describe());
+                    master.abort_failover(now);
+                }
+            },
+            FailoverState::SendSlaveofNoone => {
+                let addr = master.promoted.clone().unwrap();
+                let sent = match master.replicas.get_mut(&addr) {
+                    Some(ref mut replica) if replica.link.is_some() => {
+                        replica.send(Request::Command, &[b"SLAVEOF", b"NO", b"ONE"]);
+                        true
+                    }
+                    _ => false,
+                };
+                if sent {
+                    self.set_failover_state(master, FailoverState::WaitPromotion, "+failover-state-wait-promotion", now);
+                } else if now - master.failover_state_change_time > master.failover_timeout {
+                    self.event(Level::Warning, "-failover-abort-slave-timeout", master.describe());
+                    master.abort_failover(now);
+                }
+            }
+            FailoverState::WaitPromotion => {
+                if now - master.failover_state_change_time > master.failover_timeout {
+                    self.event(Level::Warning, "-failover-abort-slave-timeout", master.describe());
+                    master.abort_failover(now);
+                }
+            }
+            FailoverState::ReconfSlaves => self.reconf_cron(master, now),
+        }
+    }
+
+    /// Points the replicas to the promoted one, `parallel-syncs` at a time,
+    /// and switches to the new master when they are done.
+    fn reconf_cron(&mut self, master: &mut Master, now: i64) {
+        let promoted = master.promoted.clone().unwrap();
+        let (ip, port) = master.current_address();
+        let port_str = format!("{}", port);
+        let timeout = now - master.failover_state_change_time > master.failover_timeout;
+        let mut in_progress = master.replicas.values().filter(|replica| replica.reconf == Reconf::Sent).count();
+        let addrs = master.replicas.keys().cloned().collect::<Vec<_>>();
+        for addr in addrs.iter() {
+            let replica = &master.replicas[addr];
+            if *addr == promoted
+                || replica.reconf != Reconf::None
+                || replica.link.is_none()
+                || replica.sdown_since != 0
+                || (in_progress >= master.parallel_syncs && !timeout)
+            {
+                continue;
+            }
+            let description = master.describe_instance("slave", addr, replica);
+            self.event(Level::Notice, "+slave-reconf-sent", description);
+            let replica = master.replicas.get_mut(addr).unwrap();
+            replica.send(Request::Command, &[b"SLAVEOF", ip.as_bytes(), port_str.as_bytes()]);
+            replica.reconf = Reconf::Sent;
+            in_progress += 1;
+        }
+        let done = master
+            .replicas
+            .iter()
+            .all(|(addr, replica)| *addr == promoted || replica.reconf == Reconf::Done || replica.sdown_since != 0);
+        if done || timeout {
+            if !done {
+                self.event(Level::Warning, "-failover-end-for-timeout", master.describe());
+            }
+            self.event(Level::Warning, "+failover-end", master.describe());
+            self.switch_master(master, ip, port, now);
+        }
+    }
+
+    /// Replaces the address of `master`: the old master and its replicas
+    /// become replicas of the new one.
+    fn switch_master(&mut self, master: &mut Master, ip: String, port: u16, now: i64) {
+        let description = format!(
+            "{} {} {} {} {}",
+            master.name, master.instance.ip, master.instance.port, ip, port
+        );
+        self.event(Level::Warning, "+switch-master", description);
+        let new_addr = format!("{}:{}", ip, port);
+        let mut addrs = master
+            .replicas
+            .values()
+            .filter(|replica| replica.addr() != new_addr)
+            .map(|replica| (replica.ip.clone(), replica.port))
+            .collect::<Vec<_>>();
+        if master.instance.addr() != new_addr {
+            addrs.push((master.instance.ip.clone(), master.instance.port));
+        }
+        master.instance = Instance::new(ip, port, now);
+        master.replicas.clear();
+        for (ip, port) in addrs {
+            let replica = Instance::new(ip, port, now);
+            let description = master.describe_instance("slave", &replica.addr(), &replica);
+            self.event(Level::Notice, "+slave", description);
+            master.replicas.insert(replica.addr(), replica);
+        }
+        master.odown_since = 0;
+        master.abort_failover(now);
+        for peer in master.sentinels.values_mut() {
+            peer.master_down = false;
+            peer.leader = None;
+        }
+        self.todo_save = true;
+    }
+
+    /// Periodic tasks for all the masters.
+    /// Returns the links to connect, with their serial, the address of the
+    /// instance and the receiver of the commands to send on them.
+    pub fn cron(&mut self, port: u16, now: i64) -> Vec<(u64, String, u16, Receiver<Vec<u8>>)> {
+        let mut connect = vec![];
+        let names = self.masters.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            let mut master = self.masters.remove(&name).unwrap();
+            self.master_cron(&mut master, port, now, &mut connect);
+            self.masters.insert(name, master);
+        }
+        connect
+    }
+
+    /// Finds the owner of the link `serial`: the name of the master, the
+    /// kind and the key of the instance, and whether it is a pubsub link.
+    fn link_owner(&self, serial: u64) -> Option<(String, &'static str, String, bool)> {
+        let is = |link: &Option<Link>| link.as_ref().map_or(false, |link| link.serial == serial);
+        for master in self.masters.values() {
+            let instances = Some(("master", master.name.clone(), &master.instance))
+                .into_iter()
+                .chain(master.replicas.iter().map(|(addr, replica)| ("slave", addr.clone(), replica)))
+                .chain(master.sentinels.iter().map(|(id, peer)| ("sentinel", id.clone(), peer)));
+            for (kind, key, instance) in instances {
+                if is(&instance.link) || is(&instance.pubsub) {
+                    return Some((master.name.clone(), kind, key, is(&instance.pubsub)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the link `serial` is still wanted.
+    pub fn link_alive(&self, serial: u64) -> bool {
+        self.link_owner(serial).is_some()
+    }
+
+    /// Drops the link `serial` after its connection was lost.
+    pub fn link_lost(&mut self, serial: u64) {
+        if let Some((name, kind, key, pubsub)) = self.link_owner(serial) {
+            let master = self.masters.get_mut(&name).unwrap();
+            let instance = match kind {
+                "master" => &mut master.instance,
+                "slave" => master.replicas.get_mut(&key).unwrap(),
+                _ => master.sentinels.get_mut(&key).unwrap(),
+            };
+            if pubsub {
+                instance.pubsub = None;
+            } else {
+                instance.link = None;
+            }
+        }
+    }
+
+    /// Handles the `reply` received on the link `serial`.
+    pub fn reply(&mut self, serial: u64, reply: &Response, now: i64) {
+        let (name, kind, key, pubsub) = match self.link_owner(serial) {
+            Some(owner) => owner,
+            None => return,
+        };
+        if pubsub {
+            if let Response::Array(ref items) = *reply {
+                if let (Some(&Response::Data(ref kind)), Some(&Response::Data(ref payload))) = (items.get(0), items.get(2)) {
+                    if kind == b"message" {
+                        self.process_hello(&String::from_utf8_lossy(payload), now);
+                    }
+                }
+            }
+            return;
+        }
+        let mut master = self.masters.remove(&name).unwrap();
+        {
+            let instance = match kind {
+                "master" => &mut master.instance,
+                "slave" => master.replicas.get_mut(&key).unwrap(),
+                _ => master.sentinels.get_mut(&key).unwrap(),
+            };
+            let request = instance.link.as_mut().and_then(|link| link.pending.pop_front());
+            match (request, reply) {
+                (Some(Request::Ping), _) => {
+                    instance.ping_sent = 0;
+                    let valid = match *reply {
+                        Response::Status(ref status) => status == "PONG",
+                        Response::Error(ref error) => error.starts_with("LOADING") || error.starts_with("MASTERDOWN"),
+                        _ => false,
+                    };
+                    if valid {
+                        instance.last_avail = now;
+                    }
+                }
+                (Some(Request::IsMasterDown), &Response::Array(ref items)) => {
+                    if let (Some(&Response::Integer(down)), Some(&Response::Data(ref leader)), Some(&Response::Integer(epoch))) =
+                        (items.get(0), items.get(1), items.get(2))
+                    {
+                        instance.master_down = down == 1;
+                        instance.master_down_time = now;
+                        if leader != b"*" {
+                            instance.leader = Some(String::from_utf8_lossy(leader).into_owned());
+                            instance.leader_epoch = epoch as u64;
+                        }
+                    }
+                }
+                _ => (),
+            }
+            if let (Some(Request::Info), &Response::Data(ref info)) = (request, reply) {
+                let info = String::from_utf8_lossy(info).into_owned();
+                self.refresh_from_info(&mut master, kind, &key, &info, now);
+            }
+        }
+        self.masters.insert(name, master);
+    }
+
+    /// Updates what is known about a master or a replica from its INFO, and
+    /// fixes its role when it does not match the configuration.
+    fn refresh_from_info(&mut self, master: &mut Master, kind: &str, key: &str, info: &str, now: i64) {
+        let mut discovered = vec![];
+        {
+            let instance = if kind == "master" {
+                &mut master.instance
+            } else {
+                master.replicas.get_mut(key).unwrap()
+            };
+            let (role_master, master_host, master_port) =
+                (instance.role_master, instance.master_host.clone(), instance.master_port);
+            instance.info_refresh = now;
+            instance.priority = 100;
+            for line in info.lines() {
+                let mut parts = line.splitn(2, ':');
+                let (field, value) = match (parts.next(), parts.next()) {
+                    (Some(field), Some(value)) => (field, value.trim()),
+                    _ => continue,
+                };
+                match field {
+                    "run_id" => instance.run_id = value.to_owned(),
+                    "role" => instance.role_master = value == "master",
+                    "master_host" => instance.master_host = value.to_owned(),
+                    "master_port" => instance.master_port = value.parse().unwrap_or(0),
+                    "master_link_status" => instance.master_link_up = value == "up",
+                    "slave_repl_offset" => instance.offset = value.parse().unwrap_or(0),
+                    "slave_priority" | "replica_priority" => instance.priority = value.parse().unwrap_or(100),
+                    _ if field.starts_with("slave") && value.starts_with("ip=") => {
+                        let mut ip = None;
+                        let mut port = None;
+                        for pair in value.split(',') {
+                            if pair.starts_with("ip=") {
+                                ip = Some(pair[3..].to_owned());
+                            } else if pair.starts_with("port=") {
+                                port = pair[5..].parse::<u16>().ok();
+                            }
+                        }
+                        if let (Some(ip), Some(port)) = (ip, port) {
+                            discovered.push((ip, port));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if instance.role_master {
+                instance.master_host.clear();
+                instance.master_port = 0;
+            }
+            if role_master != instance.role_master
+                || master_host != instance.master_host
+                || master_port != instance.master_port
+            {
+                instance.role_reported_time = now;
+            }
+        }
+
+        if kind == "master" {
+            if master.instance.role_master {
+                for (ip, port) in discovered {
+                    let replica = Instance::new(ip, port, now);
+                    let addr = replica.addr();
+                    if !master.replicas.contains_key(&addr) && addr != master.instance.addr() {
+                        let description = master.describe_instance("slave", &addr, &replica);
+                        self.event(Level::Notice, "+slave", description);
+                        master.replicas.insert(addr, replica);
+                        self.todo_save = true;
+                    }
+                }
+            }
+            return;
+        }
+
+        let (ip, port) = (master.instance.ip.clone(), master.instance.port);
+        let master_ok = master.instance.sdown_since == 0;
+        let description = master.describe_instance("slave", key, &master.replicas[key]);
+        let promoted = master.promoted.as_ref().map_or(false, |addr| addr == key);
+        let replica = master.replicas.get_mut(key).unwrap();
+        // wait a bit for a role change to settle before fixing it
+        let settled = now - replica.role_reported_time > 4 * HELLO_PERIOD_MS;
+        match master.failover_state {
+            FailoverState::WaitPromotion if promoted && replica.role_master => {
+                master.config_epoch = master.failover_epoch;
+                self.todo_save = true;
+                self.event(Level::Warning, "+promoted-slave", description);
+                self.set_failover_state(master, FailoverState::ReconfSlaves, "+failover-state-reconf-slaves", now);
+            }
+            FailoverState::ReconfSlaves => {
+                let (pip, pport) = master.current_address();
+                let replica = master.replicas.get_mut(key).unwrap();
+                if !promoted
+                    && replica.reconf != Reconf::Done
+                    && !replica.role_master
+                    && replica.master_host == pip
+                    && replica.master_port == pport
+                    && replica.master_link_up
+                {
+                    replica.reconf = Reconf::Done;
+                    self.event(Level::Notice, "+slave-reconf-done", description);
+                }
+            }
+            FailoverState::None if master_ok && settled => {
+                let port_str = format!("{}", port);
+                if replica.role_master {
+                    replica.send(Request::Command, &[b"SLAVEOF", ip.as_bytes(), port_str.as_bytes()]);
+                    replica.role_reported_time = now;
+                    self.event(Level::Notice, "+convert-to-slave", description);
+                } else if replica.master_host != ip || replica.master_port != port {
+                    replica.send(Request::Command, &[b"SLAVEOF", ip.as_bytes(), port_str.as_bytes()]);
+                    replica.role_reported_time = now;
+                    self.event(Level::Notice, "+fix-slave-config", description);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Handles a hello message: learns about other sentinels, their epoch
+    /// and newer configurations of the masters.
+    pub fn process_hello(&mut self, hello: &str, now: i64) {
+        let fields = hello.split(',').collect::<Vec<_>>();
+        if fields.len() != 8 || fields[2] == self.myid {
+            return;
+        }
+        let (ip, runid, master_ip) = (fields[0], fields[2], fields[5]);
+        let (port, current_epoch, master_port, config_epoch) = match (
+            fields[1].parse::<u16>(),
+            fields[3].parse::<u64>(),
+            fields[6].parse::<u16>(),
+            fields[7].parse::<u64>(),
+        ) {
+            (Ok(port), Ok(current_epoch), Ok(master_port), Ok(config_epoch)) => {
+                (port, current_epoch, master_port, config_epoch)
+            }
+            _ => return,
+        };
+        let mut master = match self.masters.remove(fields[4]) {
+            Some(master) => master,
+            None => return,
+        };
+        if !master.sentinels.contains_key(runid) {
+            // a sentinel at the same address restarted with another id
+            let addr = format!("{}:{}", ip, port);
+            let stale = master
+                .sentinels
+                .iter()
+                .filter(|&(_, peer)| peer.addr() == addr)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in stale {
+                let description = master.describe_instance("sentinel", &id, &master.sentinels[&id]);
+                self.event(Level::Notice, "-dup-sentinel", description);
+                master.sentinels.remove(&id);
+            }
+            let mut peer = Instance::new(ip.to_owned(), port, now);
+            peer.run_id = runid.to_owned();
+            let description = master.describe_instance("sentinel", runid, &peer);
+            self.event(Level::Notice, "+sentinel", description);
+            master.sentinels.insert(runid.to_owned(), peer);
+            self.todo_save = true;
+        }
+        master.sentinels.get_mut(runid).unwrap().last_hello = now;
+
+        if current_epoch > self.current_epoch {
+            self.current_epoch = current_epoch;
+            self.todo_save = true;
+            self.event(Level::Warning, "+new-epoch", format!("{}", current_epoch));
+        }
+        if master.config_epoch < config_epoch {
+            master.config_epoch = config_epoch;
+            self.todo_save = true;
+            if master.instance.ip != master_ip || master.instance.port != master_port {
+                let description = master.describe_instance("sentinel", runid, &master.sentinels[runid]);
+                self.event(Level::Warning, "+config-update-from", description);
+                self.switch_master(&mut master, master_ip.to_owned(), master_port, now);
+            }
+        }
+        self.masters.insert(master.name.clone(), master);
+    }
+
+    /// Answers another sentinel asking whether the master at `ip:port` is
+    /// down, voting for `runid` as the failover leader unless it is `*`.
+    /// Returns whether the master is down, and the vote of this sentinel.
+    pub fn is_master_down(
+        &mut self,
+        ip: &str,
+        port: u16,
+        epoch: u64,
+        runid: &str,
+        now: i64,
+    ) -> (bool, Option<String>, u64) {
+        let name = match self
+            .masters
+            .values()
+            .find(|master| master.instance.ip == ip && master.instance.port == port)
+        {
+            Some(master) => master.name.clone(),
+            None => return (false, None, 0),
+        };
+        let mut master = self.masters.remove(&name).unwrap();
+        let down = master.instance.sdown_since != 0;
+        let (leader, leader_epoch) = if runid != "*" {
+            self.vote(&mut master, epoch, runid, now)
+        } else {
+            (None, 0)
+        };
+        self.masters.insert(name, master);
+        (down, leader, leader_epoch)
+    }
+
+    /// Learns the address this sentinel is reached at, for the hello
+    /// messages.
+    pub fn link_connected(&mut self, local_ip: &str) {
+        if self.ip.is_empty() {
+            self.ip = local_ip.to_owned();
+        }
+    }
+}
+
+impl Database {
+    /// Builds the sentinel from the configuration, and saves it so the
+    /// sentinel keeps its id after a restart.
+    pub fn sentinel_load_config(&mut self) -> Result<(), String> {
+        if self.sentinel.is_none() {
+            return Ok(());
+        }
+        self.sentinel = Some(Sentinel::from_config(&self.config.sentinel)?);
+        self.sentinel_save_config().map_err(|err| err.to_string())
+    }
+
+    /// Replaces the `sentinel` directives of the configuration file with the
+    /// current state, through a temporary file so a crash never leaves it
+    /// half written.
+    pub fn sentinel_save_config(&self) -> io::Result<()> {
+        let sentinel = match self.sentinel {
+            Some(ref sentinel) => sentinel,
+            None => return Ok(()),
+        };
+        let filename = match self.config.configfile {
+            Some(ref filename) => filename,
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        File::open(filename)?.read_to_string(&mut contents)?;
+        let mut lines = contents
+            .lines()
+            .filter(|line| !line.trim_start().to_ascii_lowercase().starts_with("sentinel "))
+            .map(|line| line.to_owned())
+            .collect::<Vec<_>>();
+        lines.extend(sentinel.config());
+
+        let path = Path::new(filename);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("sentinel.conf");
+        let tmppath = path.with_file_name(format!("temp-{}-{}", process::id(), name));
+        let r = File::create(&tmppath)
+            .and_then(|mut file| {
+                for line in lines {
+                    writeln!(file, "{}", line)?;
+                }
+                file.sync_all()
+            })
+            .and_then(|_| rename(&tmppath, filename));
+        if r.is_err() {
+            let _ = remove_file(&tmppath);
+        }
+        r
+    }
+
+    /// Logs and publishes the events of the sentinel, and saves the
+    /// configuration if it changed.
+    pub fn sentinel_flush(&mut self) {
+        let (events, todo_save) = match self.sentinel {
+            Some(ref mut sentinel) => (
+                replace(&mut sentinel.events, vec![]),
+                replace(&mut sentinel.todo_save, false),
+            ),
+            None => return,
+        };
+        for (level, kind, description) in events {
+            self.config.logger.log(level, format!("{} {}", kind, description), None);
+            self.publish(kind.as_bytes(), description.as_bytes());
+        }
+        if todo_save {
+            if let Err(err) = self.sentinel_save_config() {
+                log!(self.config.logger, Warning, "Could not save the sentinel configuration: {}", err);
+            }
+        }
+    }
+
+    /// Periodic sentinel tasks.
+    /// Returns the links to connect, with their serial, the address of the
+    /// instance and the receiver of the commands to send on them.
+    pub fn sentinel_cron(&mut self) -> Vec<(u64, String, u16, Receiver<Vec<u8>>)> {
+        let port = self.config.port;
+        let connect = match self.sentinel {
+            Some(ref mut sentinel) => sentinel.cron(port, mstime()),
+            None => return vec![],
+        };
+        self.sentinel_flush();
+        connect
+    }
+
+    /// Whether the link `serial` is still wanted.
+    pub fn sentinel_link_alive(&self, serial: u64) -> bool {
+        match self.sentinel {
+            Some(ref sentinel) => sentinel.link_alive(serial),
+            None => false,
+        }
+    }
+
+    /// Drops the link `serial` after its connection was lost. The cron opens
+    /// a new one.
+    pub fn sentinel_link_lost(&mut self, serial: u64) {
+        if let Some(ref mut sentinel) = self.sentinel {
+            sentinel.link_lost(serial);
+        }
+    }
+
+    /// Notes that a link was connected from `local_ip`.
+    pub fn sentinel_link_connected(&mut self, local_ip: &str) {
+        if let Some(ref mut sentinel) = self.sentinel {
+            sentinel.link_connected(local_ip);
+        }
+    }
+
+    /// Handles the `reply` received on the link `serial`.
+    pub fn sentinel_reply(&mut self, serial: u64, reply: &Response) {
+        if let Some(ref mut sentinel) = self.sentinel {
+            sentinel.reply(serial, reply, mstime());
+        }
+        self.sentinel_flush();
+    }
+}
+
+#[cfg(test)]
+mod test_sentinel {
+    use std::sync::mpsc::Receiver;
+
+    use response::Response;
+
+    use super::{FailoverState, Instance, Request, Sentinel};
+
+    fn sentinel(config: &[&str]) -> Sentinel {
+        let directives = config
+            .iter()
+            .map(|line| line.split(' ').map(|arg| arg.to_owned()).collect())
+            .collect::<Vec<_>>();
+        Sentinel::from_config(&directives).unwrap()
+    }
+
+    /// Connects every instance, answering the first ping on the links.
+    fn connect(sentinel: &mut Sentinel, now: i64) -> Vec<(u64, Receiver<Vec<u8>>)> {
+        let links = sentinel.cron(26379, now);
+        let mut receivers = vec![];
+        for (serial, _, _, receiver) in links {
+            sentinel.reply(serial, &Response::Status("PONG".to_owned()), now);
+            receivers.push((serial, receiver));
+        }
+        receivers
+    }
+
+    fn serial(instance: &Instance) -> u64 {
+        instance.link.as_ref().unwrap().serial
+    }
+
+    fn info_replica(port: u16, master_port: u16, offset: u64) -> Response {
+        Response::Data(
+            format!(
+                "# Replication\r\nrole:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:{}\r\nmaster_link_status:up\r\nslave_repl_offset:{}\r\nslave_priority:100\r\nrun_id:{}\r\n",
+                master_port,
+                offset,
+                format!("{}", port).repeat(10)
+            )
+            .into_bytes(),
+        )
+    }
+
+    /// Answers the pending INFO of the replica at `addr`.
+    fn reply_info(sentinel: &mut Sentinel, addr: &str, info: Response, now: i64) {
+        let replica = &sentinel.masters["mymaster"].replicas[addr];
+        assert!(replica.waiting(Request::Info));
+        let serial = serial(replica);
+        // the ping sent on connection comes first
+        while sentinel.masters["mymaster"].replicas[addr].link.as_ref().unwrap().pending[0] != Request::Info {
+            sentinel.reply(serial, &Response::Status("PONG".to_owned()), now);
+        }
+        sentinel.reply(serial, &info, now);
+    }
+
+    #[test]
+    fn config_roundtrip() {
+        let s = sentinel(&[
+            "myid aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            "monitor mymaster 127.0.0.1 6379 2",
+            "down-after-milliseconds mymaster 5000",
+            "parallel-syncs mymaster 3",
+            "config-epoch mymaster 4",
+            "known-replica mymaster 127.0.0.1 6380",
+            "known-sentinel mymaster 127.0.0.1 26380 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+            "current-epoch 5",
+        ]);
+        let config = s.config();
+        assert_eq!(
+            config,
+            vec![
+                "sentinel myid aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                "sentinel monitor mymaster 127.0.0.1 6379 2",
+                "sentinel down-after-milliseconds mymaster 5000",
+                "sentinel parallel-syncs mymaster 3",
+                "sentinel config-epoch mymaster 4",
+                "sentinel leader-epoch mymaster 0",
+                "sentinel known-replica mymaster 127.0.0.1 6380",
+                "sentinel known-sentinel mymaster 127.0.0.1 26380 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+                "sentinel current-epoch 5",
+            ]
+        );
+        let directives = config
+            .iter()
+            .map(|line| line.split(' ').skip(1).map(|arg| arg.to_owned()).collect())
+            .collect::<Vec<_>>();
+        assert_eq!(Sentinel::from_config(&directives).unwrap().config(), config);
+    }
+
+    #[test]
+    fn config_errors() {
+        let parse = |line: &str| Sentinel::from_config(&[line.split(' ').map(|arg| arg.to_owned()).collect()]);
+        assert!(parse("monitor mymaster 127.0.0.1 6379 0").is_err());
+        assert!(parse("down-after-milliseconds mymaster 5000").is_err());
+        assert!(parse("unknown mymaster").is_err());
+    }
+
+    #[test]
+    fn sdown_odown() {
+        let mut s = sentinel(&[
+            "monitor mymaster 127.0.0.1 6379 2",
+            "down-after-milliseconds mymaster 1000",
+            "known-sentinel mymaster 127.0.0.1 26380 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+        ]);
+        let now = 1_000_000;
+        assert!(s.check_quorum("mymaster").unwrap_err().starts_with("NOQUORUM 1 usable Sentinels."));
+        let _links = connect(&mut s, now);
+        assert_eq!(s.check_quorum("mymaster"), Ok(2));
+        s.cron(26379, now + 500);
+        assert_eq!(s.masters["mymaster"].instance.sdown_since, 0);
+
+        // the master stops answering
+        let peer = serial(&s.masters["mymaster"].sentinels["b".repeat(40).as_str()]);
+        let now = now + 2000;
+        s.cron(26379, now);
+        let master = &s.masters["mymaster"];
+        assert_eq!(master.instance.sdown_since, now);
+        assert_eq!(master.odown_since, 0);
+        let peer_instance = &master.sentinels["b".repeat(40).as_str()];
+        assert!(peer_instance.waiting(Request::IsMasterDown));
+
+        // the other sentinel agrees
+        let down = Response::Array(vec![Response::Integer(1), Response::Data(b"*".to_vec()), Response::Integer(0)]);
+        while s.masters["mymaster"].sentinels["b".repeat(40).as_str()].link.as_ref().unwrap().pending[0]
+            != Request::IsMasterDown
+        {
+            s.reply(peer, &Response::Status("PONG".to_owned()), now);
+        }
+        s.reply(peer, &down, now);
+        s.cron(26379, now + 10);
+        let master = &s.masters["mymaster"];
+        assert_eq!(master.odown_since, now + 10);
+        assert_eq!(master.failover_state, FailoverState::WaitStart);
+        assert_eq!(s.current_epoch, 1);
+        assert!(s.events.iter().any(|event| event.1 == "+odown"));
+    }
+
+    #[test]
+    fn vote() {
+        let mut s = sentinel(&["monitor mymaster 127.0.0.1 6379 2"]);
+        let a = "a".repeat(40);
+        let b = "b".repeat(40);
+        assert_eq!(s.is_master_down("127.0.0.1", 6379, 3, &a, 0), (false, Some(a.clone()), 3));
+        assert_eq!(s.current_epoch, 3);
+        // one vote per epoch
+        assert_eq!(s.is_master_down("127.0.0.1", 6379, 3, &b, 0), (false, Some(a.clone()), 3));
+        assert_eq!(s.is_master_down("127.0.0.1", 6379, 4, &b, 0), (false, Some(b.clone()), 4));
+        assert_eq!(s.is_master_down("127.0.0.1", 6379, 4, "*", 0), (false, None, 0));
+        assert_eq!(s.is_master_down("127.0.0.1", 6380, 5, &a, 0), (false, None, 0));
+    }
+
+    #[test]
+    fn discover_from_info_and_hello() {
+        let mut s = sentinel(&["monitor mymaster 127.0.0.1 6379 2"]);
+        let now = 1_000_000;
+        let _links = connect(&mut s, now);
+        let master = serial(&s.masters["mymaster"].instance);
+        s.reply(
+            master,
+            &Response::Data(b"role:master\r\nslave0:ip=127.0.0.1,port=6380,state=online,offset=10,lag=0\r\n".to_vec()),
+            now,
+        );
+        assert!(s.masters["mymaster"].replicas.contains_key("127.0.0.1:6380"));
+
+        let id = "c".repeat(40);
+        s.process_hello(&format!("127.0.0.1,26381,{},7,mymaster,127.0.0.1,6379,0", id), now);
+        assert!(s.masters["mymaster"].sentinels.contains_key(&id));
+        assert_eq!(s.current_epoch, 7);
+
+        // a newer configuration moves the master
+        s.process_hello(&format!("127.0.0.1,26381,{},7,mymaster,127.0.0.1,6380,7", id), now);
+        let master = &s.masters["mymaster"];
+        assert_eq!(master.instance.addr(), "127.0.0.1:6380");
+        assert_eq!(master.config_epoch, 7);
+        assert!(master.replicas.contains_key("127.0.0.1:6379"));
+        assert!(!master.replicas.contains_key("127.0.0.1:6380"));
+        assert!(s.events.iter().any(|event| event.1 == "+switch-master"));
+    }
+
+    #[test]
+    fn failover() {
+        let mut s = sentinel(&[
+            "monitor mymaster 127.0.0.1 6379 1",
+            "known-replica mymaster 127.0.0.1 6380",
+            "known-replica mymaster 127.0.0.1 6381",
+        ]);
+        let now = 1_000_000;
+        let links = connect(&mut s, now);
+        reply_info(&mut s, "127.0.0.1:6380", info_replica(6380, 6379, 100), now);
+        reply_info(&mut s, "127.0.0.1:6381", info_replica(6381, 6379, 200), now);
+
+        s.failover("mymaster", now).unwrap();
+        assert_eq!(s.failover("mymaster", now), Err("INPROG Failover already in progress".to_owned()));
+        s.cron(26379, now);
+        // the replica with the highest offset is promoted
+        assert_eq!(s.masters["mymaster"].promoted, Some("127.0.0.1:6381".to_owned()));
+        s.cron(26379, now);
+        assert_eq!(s.masters["mymaster"].failover_state, FailoverState::WaitPromotion);
+        let promoted = &s.masters["mymaster"].replicas["127.0.0.1:6381"];
+        assert!(promoted.waiting(Request::Command));
+
+        let serial = serial(promoted);
+        let sent = links
+            .iter()
+            .find(|link| link.0 == serial)
+            .unwrap()
+            .1
+            .try_iter()
+            .collect::<Vec<_>>()
+            .concat();
+        assert!(String::from_utf8_lossy(&sent).contains("SLAVEOF\r\n$2\r\nNO\r\n$3\r\nONE"));
+
+        // the replica accepts its new role
+        s.reply(serial, &Response::Status("OK".to_owned()), now);
+        s.cron(26379, now + 1001);
+        reply_info(&mut s, "127.0.0.1:6381", Response::Data(b"role:master\r\n".to_vec()), now + 1001);
+        assert_eq!(s.masters["mymaster"].failover_state, FailoverState::ReconfSlaves);
+        assert_eq!(s.masters["mymaster"].current_address(), ("127.0.0.1".to_owned(), 6381));
+
+        // the other replica follows it
+        s.cron(26379, now + 1002);
+        assert!(s.masters["mymaster"].replicas["127.0.0.1:6380"].waiting(Request::Command));
+        s.cron(26379, now + 2003);
+        reply_info(&mut s, "127.0.0.1:6380", info_replica(6380, 6381, 200), now + 2003);
+        s.cron(26379, now + 2004);
+        let master = &s.masters["mymaster"];
+        assert_eq!(master.failover_state, FailoverState::None);
+        assert_eq!(master.instance.addr(), "127.0.0.1:6381");
+        assert_eq!(master.config_epoch, 1);
+        let mut replicas = master.replicas.keys().cloned().collect::<Vec<_>>();
+        replicas.sort();
+        assert_eq!(replicas, vec!["127.0.0.1:6379", "127.0.0.1:6380"]);
+    }
+}
@@ -1,11 +1,11 @@
 use std::collections::HashSet;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 
 use dbutil::usize_to_vec;
 use dbutil::vec_to_usize;
 use rdbutil::constants::*;
-use rdbutil::{encode_len, encode_slice_u8, EncodeError};
+use rdbutil::{decode_len, decode_slice_u8, encode_len, encode_slice_u8, prealloc_len, DecodeError, EncodeError};
 use rdbutil::{encode_u16_to_slice_u8, encode_u32_to_slice_u8, encode_u64_to_slice_u8};
 use util::glob_match;
 
@@ -373,12 +373,17 @@ impl ValueSet {
         result
     }
 
-    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+    pub fn rdb_type(&self) -> u8 {
+        match self {
+            ValueSet::Integer(_) => TYPE_SET_INTSET,
+            ValueSet::Data(_) => TYPE_SET,
+        }
+    }
+
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
-        let settype;
         match self {
             ValueSet::Integer(set) => {
-                settype = TYPE_SET_INTSET;
                 let max = *set.iter().max().unwrap();
                 let encoding = if max <= 0xff {
                     2
@@ -412,15 +417,64 @@ impl ValueSet {
                 v.extend(tmp);
             }
             ValueSet::Data(set) => {
-                settype = TYPE_SET;
                 encode_len(set.len(), &mut v).unwrap();
                 for item in set {
                     encode_slice_u8(&*item, &mut v, true)?;
                 }
             }
         };
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    pub fn rdb_load<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let len = decode_len(reader)?;
+        let mut set = HashSet::with_capacity(prealloc_len(len));
+        for _ in 0..len {
+            set.insert(decode_slice_u8(reader)?);
+        }
+        Ok(ValueSet::create_with_hashset(set))
+    }
+
+    /// Loads a set stored as an intset blob: the integer width and the
+    /// number of items, followed by the items, all little endian.
+    pub fn rdb_load_intset<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let blob = decode_slice_u8(reader)?;
+        let invalid = || DecodeError::InvalidData("Invalid intset encoding".to_owned());
+        if blob.len() < 8 {
+            return Err(invalid());
+        }
+        let mut header = [0; 4];
+        header.copy_from_slice(&blob[0..4]);
+        let encoding = u32::from_le_bytes(header) as usize;
+        header.copy_from_slice(&blob[4..8]);
+        let len = u32::from_le_bytes(header) as usize;
+        if (encoding != 2 && encoding != 4 && encoding != 8) || blob.len() != 8 + len * encoding {
+            return Err(invalid());
+        }
+
+        let mut items = Vec::with_capacity(len);
+        for chunk in blob[8..].chunks(encoding) {
+            let mut buf = [0; 8];
+            buf[..encoding].copy_from_slice(chunk);
+            // sign extend narrower integers
+            let shift = 64 - encoding * 8;
+            items.push((i64::from_le_bytes(buf) << shift) >> shift);
+        }
+        if items.iter().all(|i| *i >= 0) {
+            Ok(ValueSet::Integer(items.into_iter().map(|i| i as usize).collect()))
+        } else {
+            Ok(ValueSet::Data(
+                items.into_iter().map(|i| format!("{}", i).into_bytes()).collect(),
+            ))
+        }
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
         let data = [
-            vec![settype],
+            vec![self.rdb_type()],
             v,
             vec![(VERSION & 0xff) as u8],
             vec![((VERSION >> 8) & 0xff) as u8],
@@ -642,8 +696,8 @@ mod test_set {
         }
         set.dump(&mut v).unwrap();
         assert!(
-            v == b"\x02\x02\x01a\x01b\x07\x00".to_vec()
-                || v == b"\x02\x02\x01b\x01a\x07\x00".to_vec()
+            v == b"\x02\x02\x01a\x01b\x0a\x00".to_vec()
+                || v == b"\x02\x02\x01b\x01a\x0a\x00".to_vec()
         );
     }
 
@@ -656,8 +710,8 @@ mod test_set {
         }
         set.dump(&mut v).unwrap();
         assert!(
-            v == b"\x0b\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x02\x00\x07\x00".to_vec()
-                || v == b"\x0b\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x01\x00\x07\x00"
+            v == b"\x0b\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x02\x00\x0a\x00".to_vec()
+                || v == b"\x0b\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x01\x00\x0a\x00"
                     .to_vec()
         );
     }
@@ -0,0 +1,639 @@
+//! Streams: append only logs of entries, each one a list of field-value
+//! pairs identified by an ID that grows with every entry.
+//!
+//! Like in Redis, the entries are kept in nodes indexed by the ID each node
+//! started with, so a range finds its first node without walking the ones
+//! before it. The RDB file stores every node as a listpack, in the same
+//! format Redis uses.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::str::from_utf8;
+
+use error::OperationError;
+use rdbutil::constants::*;
+use rdbutil::listpack;
+use rdbutil::{decode_len, decode_slice_u8, encode_len, encode_slice_u8, DecodeError};
+use util::mstime;
+
+/// Entries in a node before a new one is started, the default
+/// `stream-node-max-entries` of Redis.
+pub const NODE_MAX_ENTRIES: usize = 100;
+
+/// The entry was deleted, it is only kept in the listpack
+const FLAG_DELETED: i64 = 1;
+/// The entry has the same fields as the first entry of the listpack
+const FLAG_SAMEFIELDS: i64 = 2;
+
+fn invalid_id() -> OperationError {
+    OperationError::ValueError("ERR Invalid stream ID specified as stream command argument".to_owned())
+}
+
+fn invalid_rdb(msg: &str) -> DecodeError {
+    DecodeError::InvalidData(format!("Invalid stream: {}", msg))
+}
+
+/// Identifies an entry: the milliseconds when it was added and a sequence
+/// number for the entries added in the same millisecond.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::max_value(),
+        seq: u64::max_value(),
+    };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms: ms, seq: seq }
+    }
+
+    /// Parses `ms-seq`, or `ms` alone with `missing_seq` as its sequence.
+    /// `-` and `+` are the smallest and the largest IDs.
+    ///
+    /// # Examples
+    /// ```
+    /// use database::stream::StreamId;
+    ///
+    /// assert_eq!(StreamId::parse(b"5-3", 0).unwrap(), StreamId::new(5, 3));
+    /// assert_eq!(StreamId::parse(b"5", u64::max_value()).unwrap(), StreamId::new(5, u64::max_value()));
+    /// assert_eq!(StreamId::parse(b"-", 0).unwrap(), StreamId::MIN);
+    /// assert!(StreamId::parse(b"5-x", 0).is_err());
+    /// ```
+    pub fn parse(s: &[u8], missing_seq: u64) -> Result<StreamId, OperationError> {
+        match s {
+            b"-" => return Ok(StreamId::MIN),
+            b"+" => return Ok(StreamId::MAX),
+            _ => {}
+        }
+        let s = from_utf8(s).map_err(|_| invalid_id())?;
+        let number = |n: &str| match n.bytes().all(|c| c.is_ascii_digit()) {
+            true => n.parse::<u64>().map_err(|_| invalid_id()),
+            false => Err(invalid_id()),
+        };
+        match s.find('-') {
+            Some(pos) => Ok(StreamId::new(number(&s[..pos])?, number(&s[pos + 1..])?)),
+            None => Ok(StreamId::new(number(s)?, missing_seq)),
+        }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<StreamId> {
+        if self.seq < u64::max_value() {
+            Some(StreamId::new(self.ms, self.seq + 1))
+        } else if self.ms < u64::max_value() {
+            Some(StreamId::new(self.ms + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// The largest ID smaller than this one.
+    pub fn prev(self) -> Option<StreamId> {
+        if self.seq > 0 {
+            Some(StreamId::new(self.ms, self.seq - 1))
+        } else if self.ms > 0 {
+            Some(StreamId::new(self.ms - 1, u64::max_value()))
+        } else {
+            None
+        }
+    }
+
+    /// The 128 bits big endian key of a node in the RDB file.
+    fn to_key(self) -> Vec<u8> {
+        [self.ms.to_be_bytes(), self.seq.to_be_bytes()].concat()
+    }
+
+    fn from_key(key: &[u8]) -> Result<StreamId, DecodeError> {
+        if key.len() != 16 {
+            return Err(invalid_rdb("wrong node key length"));
+        }
+        let mut ms = [0; 8];
+        let mut seq = [0; 8];
+        ms.copy_from_slice(&key[..8]);
+        seq.copy_from_slice(&key[8..]);
+        Ok(StreamId::new(u64::from_be_bytes(ms), u64::from_be_bytes(seq)))
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID XADD is asked to give to a new entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewId {
+    /// `*`, from the current time
+    Auto,
+    /// `ms-*`, the next sequence number in that millisecond
+    Seq(u64),
+    /// `ms-seq`, or `ms` for its first sequence number
+    Id(StreamId),
+}
+
+impl NewId {
+    /// # Examples
+    /// ```
+    /// use database::stream::{NewId, StreamId};
+    ///
+    /// assert_eq!(NewId::parse(b"*").unwrap(), NewId::Auto);
+    /// assert_eq!(NewId::parse(b"7-*").unwrap(), NewId::Seq(7));
+    /// assert_eq!(NewId::parse(b"7").unwrap(), NewId::Id(StreamId::new(7, 0)));
+    /// assert!(NewId::parse(b"+").is_err());
+    /// ```
+    pub fn parse(s: &[u8]) -> Result<NewId, OperationError> {
+        if s == b"*" {
+            return Ok(NewId::Auto);
+        }
+        if s.ends_with(b"-*") {
+            return Ok(NewId::Seq(StreamId::parse(&s[..s.len() - 2], 0)?.ms));
+        }
+        if s == b"-" || s == b"+" {
+            return Err(invalid_id());
+        }
+        Ok(NewId::Id(StreamId::parse(s, 0)?))
+    }
+}
+
+/// How XADD and XTRIM shorten a stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trim {
+    /// Keeps at most this many entries
+    MaxLen(usize),
+    /// Drops the entries with a smaller ID
+    MinId(StreamId),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    /// Field names and values, alternating
+    pub fields: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValueStream {
+    /// Entries in nodes of up to `NODE_MAX_ENTRIES`, by the ID each node
+    /// started with
+    nodes: BTreeMap<StreamId, Vec<StreamEntry>>,
+    length: usize,
+    /// ID of the last entry ever added, new entries must be greater
+    last_id: StreamId,
+}
+
+impl Default for ValueStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ValueStream {
+    pub fn new() -> ValueStream {
+        ValueStream {
+            nodes: BTreeMap::new(),
+            length: 0,
+            last_id: StreamId::MIN,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// The ID a new entry gets, if it is greater than the last one.
+    fn new_id(&self, id: NewId) -> Result<StreamId, OperationError> {
+        let smaller = || {
+            OperationError::ValueError(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_owned(),
+            )
+        };
+        let last = self.last_id;
+        match id {
+            NewId::Auto => {
+                let now = mstime() as u64;
+                if now > last.ms {
+                    Ok(StreamId::new(now, 0))
+                } else {
+                    last.next().ok_or_else(|| {
+                        OperationError::ValueError(
+                            "ERR The stream has exhausted the last possible ID, unable to add more items"
+                                .to_owned(),
+                        )
+                    })
+                }
+            }
+            NewId::Seq(ms) if ms > last.ms => Ok(StreamId::new(ms, 0)),
+            NewId::Seq(ms) if ms == last.ms && last.seq < u64::max_value() => {
+                Ok(StreamId::new(ms, last.seq + 1))
+            }
+            NewId::Seq(_) => Err(smaller()),
+            NewId::Id(id) if id == StreamId::MIN => Err(OperationError::ValueError(
+                "ERR The ID specified in XADD must be greater than 0-0".to_owned(),
+            )),
+            NewId::Id(id) if id > last => Ok(id),
+            NewId::Id(_) => Err(smaller()),
+        }
+    }
+
+    /// Appends an entry and returns its ID.
+    pub fn add(&mut self, id: NewId, fields: Vec<Vec<u8>>) -> Result<StreamId, OperationError> {
+        let id = self.new_id(id)?;
+        let entry = StreamEntry {
+            id: id,
+            fields: fields,
+        };
+        match self.nodes.values_mut().next_back() {
+            Some(node) if node.len() < NODE_MAX_ENTRIES => node.push(entry),
+            _ => {
+                self.nodes.insert(id, vec![entry]);
+            }
+        }
+        self.length += 1;
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// The entries with an ID between `start` and `end`, both included, up
+    /// to `count` of them. With `rev` they are walked from the end.
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>, rev: bool) -> Vec<&StreamEntry> {
+        if start > end {
+            return vec![];
+        }
+        // the node holding `start` began before it
+        let first = self.nodes.range(..=start).next_back().map_or(start, |(id, _)| *id);
+        let nodes = self.nodes.range(first..=end).map(|(_, node)| node);
+        let in_range = |entry: &&StreamEntry| entry.id >= start && entry.id <= end;
+        let count = count.unwrap_or(usize::max_value());
+        if rev {
+            nodes
+                .rev()
+                .flat_map(|node| node.iter().rev())
+                .filter(in_range)
+                .take(count)
+                .collect()
+        } else {
+            nodes
+                .flat_map(|node| node.iter())
+                .filter(in_range)
+                .take(count)
+                .collect()
+        }
+    }
+
+    /// Removes the entries with these IDs, and returns how many existed.
+    pub fn delete(&mut self, ids: &[StreamId]) -> usize {
+        let mut deleted = 0;
+        for id in ids {
+            let (start, node) = match self.nodes.range_mut(..=*id).next_back() {
+                Some((start, node)) => (*start, node),
+                None => continue,
+            };
+            if let Ok(pos) = node.binary_search_by_key(id, |entry| entry.id) {
+                node.remove(pos);
+                if node.is_empty() {
+                    self.nodes.remove(&start);
+                }
+                self.length -= 1;
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    /// Removes entries from the start of the stream, and returns how many.
+    /// An `approx` trim only removes whole nodes, so it may keep some of the
+    /// entries an exact one would remove. `limit` caps the entries removed,
+    /// 0 for no limit.
+    pub fn trim(&mut self, trim: Trim, approx: bool, limit: usize) -> usize {
+        let limit = if limit == 0 { usize::max_value() } else { limit };
+        let mut removed = 0;
+        while let Some((&start, node)) = self.nodes.iter_mut().next() {
+            let whole = match trim {
+                Trim::MaxLen(maxlen) => self.length - node.len() >= maxlen,
+                Trim::MinId(minid) => node.last().map_or(true, |entry| entry.id < minid),
+            };
+            if whole && removed + node.len() <= limit {
+                removed += node.len();
+                self.length -= node.len();
+                self.nodes.remove(&start);
+                continue;
+            }
+            if !approx {
+                let mut n = 0;
+                while n < node.len() && removed + n < limit {
+                    let remove = match trim {
+                        Trim::MaxLen(maxlen) => self.length - n > maxlen,
+                        Trim::MinId(minid) => node[n].id < minid,
+                    };
+                    if !remove {
+                        break;
+                    }
+                    n += 1;
+                }
+                node.drain(..n);
+                removed += n;
+                self.length -= n;
+            }
+            break;
+        }
+        removed
+    }
+
+    pub fn rdb_type(&self) -> u8 {
+        TYPE_STREAM_LISTPACKS
+    }
+
+    /// Encodes a node as a listpack: a master entry with the fields of the
+    /// first entry, and then every entry with its ID relative to `master`.
+    fn encode_node(master: StreamId, node: &[StreamEntry]) -> Vec<u8> {
+        let int = |i: i64| format!("{}", i).into_bytes();
+        let master_fields = node[0].fields.iter().step_by(2).cloned().collect::<Vec<_>>();
+        let mut lp = vec![int(node.len() as i64), int(0), int(master_fields.len() as i64)];
+        lp.extend(master_fields.iter().cloned());
+        lp.push(int(0));
+        for entry in node {
+            let samefields = entry.fields.len() == master_fields.len() * 2
+                && entry.fields.iter().step_by(2).eq(master_fields.iter());
+            lp.push(int(if samefields { FLAG_SAMEFIELDS } else { 0 }));
+            lp.push(int(entry.id.ms.wrapping_sub(master.ms) as i64));
+            lp.push(int(entry.id.seq.wrapping_sub(master.seq) as i64));
+            let pairs = entry.fields.len() / 2;
+            if samefields {
+                lp.extend(entry.fields.iter().skip(1).step_by(2).cloned());
+                lp.push(int(pairs as i64 + 3));
+            } else {
+                lp.push(int(pairs as i64));
+                lp.extend(entry.fields.iter().cloned());
+                lp.push(int(pairs as i64 * 2 + 4));
+            }
+        }
+        listpack::encode(&lp)
+    }
+
+    /// Decodes a node written by `encode_node`, without its deleted entries.
+    fn decode_node(master: StreamId, data: &[u8]) -> Result<Vec<StreamEntry>, DecodeError> {
+        let lp = listpack::decode(data)?;
+        // the counts cannot be larger than the listpack itself
+        let max = lp.len();
+        let mut lp = lp.into_iter();
+        let mut next = || lp.next().ok_or_else(|| invalid_rdb("truncated listpack"));
+        let int = |s: Vec<u8>| {
+            from_utf8(&s)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or_else(|| invalid_rdb("expected an integer"))
+        };
+        let count = int(next()?)?;
+        let deleted = int(next()?)?;
+        let master_len = int(next()?)?;
+        if count < 0 || deleted < 0 || master_len < 0 {
+            return Err(invalid_rdb("negative count"));
+        }
+        let mut master_fields = Vec::with_capacity((master_len as usize).min(max));
+        for _ in 0..master_len {
+            master_fields.push(next()?);
+        }
+        // the master entry ends in a zero
+        next()?;
+
+        let mut node = Vec::with_capacity((count as usize).min(max));
+        for _ in 0..count + deleted {
+            let flags = int(next()?)?;
+            let id = StreamId::new(
+                master.ms.wrapping_add(int(next()?)? as u64),
+                master.seq.wrapping_add(int(next()?)? as u64),
+            );
+            let mut fields = vec![];
+            if flags & FLAG_SAMEFIELDS != 0 {
+                for field in master_fields.iter() {
+                    fields.push(field.clone());
+                    fields.push(next()?);
+                }
+            } else {
+                for _ in 0..int(next()?)? * 2 {
+                    fields.push(next()?);
+                }
+            }
+            // the number of elements of the entry, to walk it backwards
+            next()?;
+            if flags & FLAG_DELETED == 0 {
+                node.push(StreamEntry {
+                    id: id,
+                    fields: fields,
+                });
+            }
+        }
+        Ok(node)
+    }
+
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        encode_len(self.nodes.len(), &mut v)?;
+        for (master, node) in self.nodes.iter() {
+            encode_slice_u8(&master.to_key(), &mut v, false)?;
+            encode_slice_u8(&ValueStream::encode_node(*master, node), &mut v, false)?;
+        }
+        encode_len(self.length, &mut v)?;
+        encode_len(self.last_id.ms as usize, &mut v)?;
+        encode_len(self.last_id.seq as usize, &mut v)?;
+        // consumer groups
+        encode_len(0, &mut v)?;
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    pub fn rdb_load<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let mut stream = ValueStream::new();
+        let nodes = decode_len(reader)?;
+        for _ in 0..nodes {
+            let master = StreamId::from_key(&decode_slice_u8(reader)?)?;
+            let node = ValueStream::decode_node(master, &decode_slice_u8(reader)?)?;
+            if !node.is_empty() {
+                stream.length += node.len();
+                stream.nodes.insert(master, node);
+            }
+        }
+        if decode_len(reader)? != stream.length {
+            return Err(invalid_rdb("wrong number of entries"));
+        }
+        let ms = decode_len(reader)? as u64;
+        let seq = decode_len(reader)? as u64;
+        stream.last_id = StreamId::new(ms, seq);
+        if decode_len(reader)? != 0 {
+            return Err(invalid_rdb("consumer groups are not supported"));
+        }
+        Ok(stream)
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
+        let data = [
+            vec![self.rdb_type()],
+            v,
+            vec![(VERSION & 0xff) as u8],
+            vec![((VERSION >> 8) & 0xff) as u8],
+        ]
+        .concat();
+        writer.write(&*data)
+    }
+
+    pub fn debug_object(&self) -> String {
+        let mut serialized_data = vec![];
+        let serialized = self.dump(&mut serialized_data).unwrap();
+        format!(
+            "Value at:0x0000000000 refcount:1 encoding:stream serializedlength:{} lru:0 \
+             lru_seconds_idle:0",
+            serialized
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_stream {
+    use super::{NewId, StreamId, Trim, ValueStream, NODE_MAX_ENTRIES};
+
+    fn fields(n: usize) -> Vec<Vec<u8>> {
+        vec![b"n".to_vec(), format!("{}", n).into_bytes()]
+    }
+
+    fn stream(len: usize) -> ValueStream {
+        let mut stream = ValueStream::new();
+        for n in 1..=len {
+            stream.add(NewId::Id(StreamId::new(n as u64, 0)), fields(n)).unwrap();
+        }
+        stream
+    }
+
+    fn ids(stream: &ValueStream) -> Vec<u64> {
+        stream
+            .range(StreamId::MIN, StreamId::MAX, None, false)
+            .iter()
+            .map(|entry| entry.id.ms)
+            .collect()
+    }
+
+    #[test]
+    fn add_ids() {
+        let mut stream = ValueStream::new();
+        assert!(stream.add(NewId::Id(StreamId::MIN), fields(0)).is_err());
+        assert_eq!(stream.add(NewId::Seq(0), fields(0)).unwrap(), StreamId::new(0, 1));
+        assert_eq!(stream.add(NewId::Id(StreamId::new(5, 0)), fields(0)).unwrap(), StreamId::new(5, 0));
+        assert!(stream.add(NewId::Id(StreamId::new(5, 0)), fields(0)).is_err());
+        assert!(stream.add(NewId::Seq(4), fields(0)).is_err());
+        assert_eq!(stream.add(NewId::Seq(5), fields(0)).unwrap(), StreamId::new(5, 1));
+        let id = stream.add(NewId::Auto, fields(0)).unwrap();
+        assert!(id > StreamId::new(5, 1));
+        assert_eq!(stream.add(NewId::Auto, fields(0)).unwrap() > id, true);
+        assert_eq!(stream.len(), 5);
+    }
+
+    #[test]
+    fn range() {
+        let stream = stream(NODE_MAX_ENTRIES * 2 + 10);
+        let range = |start, end, count, rev| {
+            stream
+                .range(StreamId::new(start, 0), StreamId::new(end, 0), count, rev)
+                .iter()
+                .map(|entry| entry.id.ms)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(range(99, 102, None, false), vec![99, 100, 101, 102]);
+        assert_eq!(range(99, 102, Some(2), true), vec![102, 101]);
+        assert_eq!(range(205, 1000, None, false), vec![205, 206, 207, 208, 209, 210]);
+        assert_eq!(range(5, 4, None, false), Vec::<u64>::new());
+        assert_eq!(ids(&stream).len(), NODE_MAX_ENTRIES * 2 + 10);
+    }
+
+    #[test]
+    fn delete() {
+        let mut stream = stream(NODE_MAX_ENTRIES + 1);
+        let last = StreamId::new(NODE_MAX_ENTRIES as u64 + 1, 0);
+        assert_eq!(stream.delete(&[StreamId::new(2, 0), last, StreamId::new(2, 0)]), 2);
+        assert_eq!(stream.len(), NODE_MAX_ENTRIES - 1);
+        assert_eq!(ids(&stream)[..3], [1, 3, 4]);
+        // the last ID is kept after deleting its entry
+        assert_eq!(stream.last_id(), last);
+        assert!(stream.add(NewId::Id(last), fields(0)).is_err());
+    }
+
+    #[test]
+    fn trim_exact() {
+        let mut stream = stream(250);
+        assert_eq!(stream.trim(Trim::MaxLen(140), false, 0), 110);
+        assert_eq!(stream.len(), 140);
+        assert_eq!(ids(&stream)[0], 111);
+        assert_eq!(stream.trim(Trim::MinId(StreamId::new(200, 0)), false, 0), 89);
+        assert_eq!(ids(&stream)[0], 200);
+        assert_eq!(stream.trim(Trim::MaxLen(0), false, 0), 51);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn trim_approx() {
+        let mut stream = stream(250);
+        // only the first node can go without keeping less than 140
+        assert_eq!(stream.trim(Trim::MaxLen(140), true, 0), 100);
+        assert_eq!(stream.len(), 150);
+        assert_eq!(stream.trim(Trim::MinId(StreamId::new(250, 0)), true, 0), 100);
+        assert_eq!(stream.trim(Trim::MaxLen(0), true, 10), 0);
+        assert_eq!(stream.len(), 50);
+    }
+
+    #[test]
+    fn dump_restore() {
+        let mut stream = stream(NODE_MAX_ENTRIES + 20);
+        stream.add(NewId::Seq(1000), vec![b"other".to_vec(), b"x".to_vec()]).unwrap();
+        stream.delete(&[StreamId::new(5, 0), StreamId::new(1000, 0)]);
+        let mut v = vec![];
+        stream.rdb_save(&mut v).unwrap();
+        assert_eq!(ValueStream::rdb_load(&mut &*v).unwrap(), stream);
+        assert_eq!(ValueStream::rdb_load(&mut &*v).unwrap().last_id(), StreamId::new(1000, 0));
+    }
+
+    #[test]
+    fn redis_payload() {
+        // 1-1 a=1 and 2-0 a=2 b=x, laid out as redis writes them: one node
+        // whose master entry has the field "a", so the first entry only has
+        // its value
+        let data = [
+            &b"\x01\x10\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01"[..],
+            b"\x32\x32\x00\x00\x00\x13\x00",
+            b"\x02\x01\x00\x01\x01\x01\x81a\x02\x00\x01",
+            b"\x02\x01\x00\x01\x00\x01\x01\x01\x04\x01",
+            b"\x00\x01\x01\x01\xdf\xff\x02\x02\x01\x81a\x02\x02\x01\x81b\x02\x81x\x02\x08\x01",
+            b"\xff\x02\x02\x00\x00",
+        ]
+        .concat();
+        let stream = ValueStream::rdb_load(&mut &data[..]).unwrap();
+        let entries = stream.range(StreamId::MIN, StreamId::MAX, None, false);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, StreamId::new(1, 1));
+        assert_eq!(entries[0].fields, vec![b"a".to_vec(), b"1".to_vec()]);
+        assert_eq!(entries[1].id, StreamId::new(2, 0));
+        assert_eq!(
+            entries[1].fields,
+            vec![b"a".to_vec(), b"2".to_vec(), b"b".to_vec(), b"x".to_vec()]
+        );
+        assert_eq!(stream.last_id(), StreamId::new(2, 0));
+        let mut v = vec![];
+        stream.rdb_save(&mut v).unwrap();
+        assert_eq!(v, data);
+    }
+}
@@ -1,12 +1,12 @@
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::str;
 
 use basichll::HLL;
 use dbutil::normalize_position;
 use error::OperationError;
 use rdbutil::constants::*;
-use rdbutil::{encode_i64, encode_slice_u8, EncodeError};
+use rdbutil::{decode_slice_u8, encode_i64, encode_slice_u8, DecodeError, EncodeError};
 
 const HLL_ERROR: f64 = 0.0019;
 
@@ -240,7 +240,11 @@ impl ValueString {
         Ok(())
     }
 
-    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+    pub fn rdb_type(&self) -> u8 {
+        TYPE_STRING
+    }
+
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
         match self {
             ValueString::Integer(i) => match encode_i64(*i, &mut v) {
@@ -252,8 +256,19 @@ impl ValueString {
             },
             ValueString::Data(d) => encode_slice_u8(&*d, &mut v, true)?,
         };
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    pub fn rdb_load<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        Ok(ValueString::new(decode_slice_u8(reader)?))
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
         let data = [
-            vec![TYPE_STRING],
+            vec![self.rdb_type()],
             v,
             vec![(VERSION & 0xff) as u8],
             vec![((VERSION >> 8) & 0xff) as u8],
@@ -288,14 +303,14 @@ mod test_rdb {
     fn dump_integer() {
         let mut v = vec![];
         ValueString::Integer(1).dump(&mut v).unwrap();
-        assert_eq!(&*v, b"\x00\xc0\x01\x07\x00");
+        assert_eq!(&*v, b"\x00\xc0\x01\x0a\x00");
     }
 
     #[test]
     fn dump_integer_overflow() {
         let mut v = vec![];
         ValueString::Integer(i64::MAX).dump(&mut v).unwrap();
-        assert_eq!(&*v, b"\x00\x139223372036854775807\x07\x00");
+        assert_eq!(&*v, b"\x00\x139223372036854775807\x0a\x00");
     }
 
     #[test]
@@ -304,6 +319,6 @@ mod test_rdb {
         ValueString::Data(b"hello world".to_vec())
             .dump(&mut v)
             .unwrap();
-        assert_eq!(&*v, b"\x00\x0bhello world\x07\x00");
+        assert_eq!(&*v, b"\x00\x0bhello world\x0a\x00");
     }
 }
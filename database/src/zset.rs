@@ -2,16 +2,18 @@ use std::cmp::Ordering;
 use std::collections::Bound;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::f64::{INFINITY, NEG_INFINITY};
+use std::f64::{INFINITY, NAN, NEG_INFINITY};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::str::from_utf8;
 
 use skiplist::OrderedSkipList;
 
 use dbutil::normalize_position;
 use error::OperationError;
 use rdbutil::constants::*;
-use rdbutil::{encode_len, encode_slice_u8};
+use rdbutil::ziplist;
+use rdbutil::{decode_len, decode_slice_u8, decode_u8, encode_len, encode_slice_u8, DecodeError};
 use util::glob_match;
 
 pub enum Aggregate {
@@ -756,12 +758,14 @@ impl ValueSortedSet {
         }
     }
 
-    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+    pub fn rdb_type(&self) -> u8 {
+        TYPE_ZSET
+    }
+
+    pub fn rdb_save<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
         let mut v = vec![];
-        let settype;
         match self {
             ValueSortedSet::Data(_, hash) => {
-                settype = TYPE_ZSET;
                 encode_len(hash.len(), &mut v).unwrap();
                 for (value, score) in hash {
                     encode_slice_u8(&*value, &mut v, true)?;
@@ -774,14 +778,75 @@ impl ValueSortedSet {
                             v.write_all(&[255])?;
                         }
                     } else {
-                        let scorestr = format!("{}", score.abs()).to_owned();
-                        encode_slice_u8(scorestr.as_bytes(), &mut v, false)?;
+                        // scores use a single byte length prefix, fall back
+                        // to exponent notation for very large numbers
+                        let mut scorestr = format!("{}", score);
+                        if scorestr.len() > 250 {
+                            scorestr = format!("{:e}", score);
+                        }
+                        v.write_all(&[scorestr.len() as u8])?;
+                        v.write_all(scorestr.as_bytes())?;
                     }
                 }
             }
         };
+        writer.write_all(&*v)?;
+        Ok(v.len())
+    }
+
+    pub fn rdb_load<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let len = decode_len(reader)?;
+        let mut zset = ValueSortedSet::new();
+        for _ in 0..len {
+            let member = decode_slice_u8(reader)?;
+            let score = match decode_u8(reader)? {
+                253 => NAN,
+                254 => INFINITY,
+                255 => NEG_INFINITY,
+                len => {
+                    let mut buf = vec![0; len as usize];
+                    reader.read_exact(&mut buf)?;
+                    match from_utf8(&buf).ok().and_then(|s| s.parse().ok()) {
+                        Some(score) => score,
+                        None => {
+                            return Err(DecodeError::InvalidData(
+                                "Invalid sorted set score".to_owned(),
+                            ))
+                        }
+                    }
+                }
+            };
+            // NaN scores are not accepted by ZADD, load them as zero
+            let _ = zset.zadd(score, member, false, false, false, false, true);
+        }
+        Ok(zset)
+    }
+
+    /// Loads a sorted set stored as a ziplist of alternating members and
+    /// scores.
+    pub fn rdb_load_ziplist<T: Read>(reader: &mut T) -> Result<Self, DecodeError> {
+        let entries = ziplist::decode(&decode_slice_u8(reader)?)?;
+        let invalid = || DecodeError::InvalidData("Invalid sorted set ziplist".to_owned());
+        if entries.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let mut zset = ValueSortedSet::new();
+        let mut it = entries.into_iter();
+        while let (Some(member), Some(score)) = (it.next(), it.next()) {
+            let score = from_utf8(&score)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(invalid)?;
+            let _ = zset.zadd(score, member, false, false, false, false, true);
+        }
+        Ok(zset)
+    }
+
+    pub fn dump<T: Write>(&self, writer: &mut T) -> io::Result<usize> {
+        let mut v = vec![];
+        self.rdb_save(&mut v)?;
         let data = [
-            &[settype],
+            &[self.rdb_type()],
             &v[..],
             &[(VERSION & 0xff) as u8],
             &[((VERSION >> 8) & 0xff) as u8],
@@ -814,8 +879,8 @@ fn dump_zset() {
         .unwrap();
     zset.dump(&mut v).unwrap();
     assert!(
-        v == b"\x03\x02\x01b\x012\x01a\x011\x07\x00".to_vec()
-            || v == b"\x03\x02\x01a\x011\x01b\x012\x07\x00".to_vec()
+        v == b"\x03\x02\x01b\x012\x01a\x011\x0a\x00".to_vec()
+            || v == b"\x03\x02\x01a\x011\x01b\x012\x0a\x00".to_vec()
     );
 }
 
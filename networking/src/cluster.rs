@@ -0,0 +1,151 @@
+//! Cluster bus: every node listens on a second port where the other nodes
+//! connect to exchange the messages that keep their views of the cluster
+//! in sync. The messages themselves are handled by the database.
+use logger::log;
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::Receiver,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use database::cluster::Message;
+use database::Database;
+use logger::Level;
+use parser::{ParseError, Parser};
+
+/// How long a read waits before checking whether the link is still wanted
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Handles the messages read from `stream` and sends the replies through
+/// `writer`. `serial` is the link of a connection opened by this node, and
+/// reading stops once the link is not wanted anymore.
+fn handle(
+    db: &Arc<Mutex<Database>>,
+    mut stream: TcpStream,
+    writer: &Mutex<TcpStream>,
+    serial: Option<u64>,
+) -> io::Result<()> {
+    let ip = stream.peer_addr()?.ip().to_string();
+    let local_ip = stream.local_addr()?.ip().to_string();
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut parser = Parser::new();
+    loop {
+        if parser.is_incomplete() {
+            parser.allocate();
+            let len = {
+                let pos = parser.written;
+                let buffer = parser.get_mut();
+                match stream.read(&mut buffer[pos..]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "the node closed the connection",
+                        ))
+                    }
+                    Ok(len) => len,
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        if let Some(serial) = serial {
+                            if !db.lock().unwrap().cluster_link_alive(serial) {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            parser.written += len;
+        }
+
+        let command = match parser.next() {
+            Ok(command) => command,
+            Err(ParseError::Incomplete) => continue,
+            Err(e) => return Err(protocol_error(format!("Protocol error: {:?}", e))),
+        };
+        let message = match Message::decode(&command) {
+            Some(message) => message,
+            None => return Err(protocol_error("Invalid cluster bus message".to_owned())),
+        };
+        let reply = db
+            .lock()
+            .unwrap()
+            .cluster_process(&message, serial, &ip, &local_ip);
+        if let Some(reply) = reply {
+            writer.lock().unwrap().write_all(&reply.encode())?;
+        }
+    }
+}
+
+/// Accepts the connections of other nodes until a message arrives on `rx`.
+pub fn accept(db: Arc<Mutex<Database>>, listener: TcpListener, rx: Receiver<u8>) {
+    for stream in listener.incoming() {
+        if rx.try_recv().is_ok() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                let db = db.lock().unwrap();
+                log!(db.config.logger, Warning, "Accepting cluster node connection: {}", e);
+                continue;
+            }
+        };
+        let db = db.clone();
+        thread::spawn(move || {
+            let r = stream
+                .try_clone()
+                .and_then(|writer| handle(&db, stream, &Mutex::new(writer), None));
+            if let Err(e) = r {
+                let db = db.lock().unwrap();
+                log!(db.config.logger, Verbose, "Connection with cluster node closed: {}", e);
+            }
+        });
+    }
+}
+
+fn run(db: &Arc<Mutex<Database>>, serial: u64, ip: &str, cport: u16, rx: Receiver<Vec<u8>>) -> io::Result<()> {
+    let timeout = Duration::from_millis(db.lock().unwrap().config.cluster_node_timeout.max(1000));
+    let addr = match (ip, cport).to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(protocol_error(format!("Can't resolve {}", ip))),
+    };
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    {
+        let writer = writer.clone();
+        thread::spawn(move || {
+            for data in rx {
+                if writer.lock().unwrap().write_all(&data).is_err() {
+                    break;
+                }
+            }
+            // the link was dropped, the reader stops too
+            let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+        });
+    }
+    handle(db, stream, &writer, Some(serial))
+}
+
+/// Opens the link `serial` to the cluster bus at `ip:cport` in a new
+/// thread, and sends the messages queued in `rx`. When the connection is
+/// lost the link is dropped, to be opened again by the cron.
+pub fn connect(db: Arc<Mutex<Database>>, serial: u64, ip: String, cport: u16, rx: Receiver<Vec<u8>>) {
+    thread::spawn(move || {
+        let r = run(&db, serial, &ip, cport, rx);
+        let mut db = db.lock().unwrap();
+        if let Err(e) = r {
+            log!(db.config.logger, Verbose, "Connection with cluster node {}:{} lost: {}", ip, cport, e);
+        }
+        db.cluster_link_lost(serial);
+    });
+}
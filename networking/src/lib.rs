@@ -1,28 +1,34 @@
-use logger::{log, sendlog};
+use logger::{log, log_and_exit, sendlog};
 
 use std::{
     io::{self, Read, Write},
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
     process,
     sync::mpsc::{channel, Receiver, Sender},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex, MutexGuard,
+        Arc, Mutex, MutexGuard, TryLockError,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use net2::{TcpBuilder, TcpStreamExt};
+
+mod cluster;
+mod replication;
+mod sentinel;
 #[cfg(unix)]
-use std::{fs::File, path::Path};
+use std::fs::File;
 #[cfg(unix)]
 use unix_socket::{UnixListener, UnixStream};
 
 use config::Config;
+use database::busy::Busy;
 use database::Database;
 use logger::Level;
-use parser::{OwnedParsedCommand, ParseError, Parser};
+use parser::{OwnedParsedCommand, ParseError, ParsedCommand, Parser};
 use response::{Response, ResponseError};
 
 /// A stream connection.
@@ -55,6 +61,22 @@ impl Stream {
         }
     }
 
+    /// Shuts down both halves of the connection.
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+    /// Returns the address of the remote peer, empty for UNIX sockets.
+    fn peer_addr(&self) -> String {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().map(|a| a.to_string()).unwrap_or_default(),
+            Stream::Unix(_) => String::new(),
+        }
+    }
+
     /// Sets the keepalive timeout to the timeout specified.
     /// It fails silently for UNIX sockets.
     fn set_keepalive(&self, duration: Option<Duration>) -> io::Result<()> {
@@ -113,6 +135,20 @@ impl Stream {
         }
     }
 
+    /// Shuts down both halves of the connection.
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+    /// Returns the address of the remote peer.
+    fn peer_addr(&self) -> String {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().map(|a| a.to_string()).unwrap_or_default(),
+        }
+    }
+
     /// Sets the keepalive timeout to the timeout specified.
     /// It fails silently for UNIX sockets.
     fn set_keepalive(&self, duration: Option<Duration>) -> io::Result<()> {
@@ -155,6 +191,8 @@ struct Client {
     stream: Stream,
     /// A reference to the database
     db: Arc<Mutex<Database>>,
+    /// What the server is busy with, readable without the database
+    busy: Arc<Busy>,
     /// The client unique identifier
     id: usize,
 }
@@ -163,6 +201,8 @@ struct Client {
 pub struct Server {
     /// A reference to the database
     db: Arc<Mutex<Database>>,
+    /// What the server is busy with, readable without the database
+    busy: Arc<Busy>,
     /// A list of channels listening for incoming connections
     listener_channels: Vec<Sender<u8>>,
     /// A list of threads listening for incoming connections
@@ -175,25 +215,28 @@ pub struct Server {
 
 impl Client {
     /// Creates a new TCP socket client
-    pub fn tcp(stream: TcpStream, db: Arc<Mutex<Database>>, id: usize) -> Client {
+    pub fn tcp(stream: TcpStream, db: Arc<Mutex<Database>>, busy: Arc<Busy>, id: usize) -> Client {
         Client {
             stream: Stream::Tcp(stream),
             db,
+            busy,
             id,
         }
     }
 
     /// Creates a new UNIX socket client
     #[cfg(unix)]
-    pub fn unix(stream: UnixStream, db: Arc<Mutex<Database>>, id: usize) -> Client {
+    pub fn unix(stream: UnixStream, db: Arc<Mutex<Database>>, busy: Arc<Busy>, id: usize) -> Client {
         Client {
             stream: Stream::Unix(stream),
             db,
+            busy,
             id,
         }
     }
 
-    /// Creates a thread that writes into the client stream each response received
+    /// Creates a thread that writes into the client stream each response
+    /// received. A `None` closes the connection.
     fn create_writer_thread(
         &self,
         sender: Sender<(Level, String)>,
@@ -209,9 +252,29 @@ impl Client {
                     }
                 }
             }
+            let _ = stream.shutdown();
         });
     }
 
+    /// Locks the database to run `parsed_command`. While another connection
+    /// keeps the server busy, the command is answered without the lock
+    /// instead. `Err(None)` means the lock is poisoned.
+    fn lock_db(
+        &self,
+        parsed_command: &mut ParsedCommand,
+        client: &mut command::Client,
+    ) -> Result<MutexGuard<'_, Database>, Option<Response>> {
+        match self.db.try_lock() {
+            Ok(db) => return Ok(db),
+            Err(TryLockError::Poisoned(_)) => return Err(None),
+            Err(TryLockError::WouldBlock) => (),
+        }
+        if self.busy.wait_busy() {
+            return Err(Some(command::busy_command(parsed_command, &self.busy, client)));
+        }
+        self.db.lock().map_err(|_| None)
+    }
+
     /// Runs all clients commands. The function loops until the client
     /// disconnects.
     pub fn run(&mut self, sender: Sender<(Level, String)>) {
@@ -219,6 +282,16 @@ impl Client {
         self.create_writer_thread(sender.clone(), rx);
 
         let mut client = command::Client::new(stream_tx.clone(), self.id);
+        client.addr = self.stream.peer_addr();
+        if let Ok(stream) = self.stream.try_clone() {
+            self.busy.add_connection(
+                self.id,
+                client.addr.clone(),
+                Box::new(move || {
+                    let _ = stream.shutdown();
+                }),
+            );
+        }
         let mut parser = Parser::new();
 
         let mut this_command: Option<OwnedParsedCommand>;
@@ -256,7 +329,7 @@ impl Client {
             next_command = None;
 
             // try to parse received command
-            let parsed_command = match &this_command {
+            let mut parsed_command = match &this_command {
                 Some(c) => c.get_command(),
                 None => {
                     match parser.next() {
@@ -286,14 +359,17 @@ impl Client {
                 }
             };
 
-            let r = {
-                let mut db = match self.db.lock() {
-                    Ok(db) => db,
-                    Err(_) => break,
-                };
-
-                // execute the command
-                command::command(parsed_command, &mut *db, &mut client)
+            let r = match self.lock_db(&mut parsed_command, &mut client) {
+                Ok(mut db) => {
+                    let name = parsed_command.get_str(0).unwrap_or_default().to_ascii_lowercase();
+                    self.busy.begin(self.id, name);
+                    // execute the command
+                    let r = command::command(parsed_command, &mut *db, &mut client);
+                    self.busy.end();
+                    r
+                }
+                Err(Some(response)) => Ok(response),
+                Err(None) => break,
             };
 
             // check out the response
@@ -338,6 +414,7 @@ impl Client {
             }
         }
 
+        self.busy.remove_connection(self.id);
         {
             let mut db = match self.db.lock() {
                 Ok(db) => db,
@@ -347,6 +424,7 @@ impl Client {
             for (channel_name, subscriber_id) in client.subscriptions.into_iter() {
                 db.unsubscribe(channel_name.clone(), subscriber_id);
             }
+            db.repl_detach(client.id);
         }
     }
 }
@@ -354,6 +432,7 @@ impl Client {
 macro_rules! handle_listener {
     ($logger: expr, $listener: expr, $server: expr, $rx: expr, $tcp_keepalive: expr, $timeout: expr, $t: ident) => {{
         let db = $server.db.clone();
+        let busy = $server.busy.clone();
         let sender = $logger.sender();
         let next_id = $server.next_id.clone();
         thread::spawn(move || {
@@ -366,11 +445,12 @@ macro_rules! handle_listener {
                     Ok(stream) => {
                         sendlog!(sender, Verbose, "Accepted connection to {:?}", stream).unwrap();
                         let db1 = db.clone();
+                        let busy1 = busy.clone();
                         let mysender = sender.clone();
                         let id = next_id.fetch_add(1, Ordering::Relaxed);
 
                         thread::spawn(move || {
-                            let mut client = Client::$t(stream, db1, id);
+                            let mut client = Client::$t(stream, db1, busy1, id);
                             client
                                 .stream
                                 .set_keepalive(if $tcp_keepalive > 0 {
@@ -410,8 +490,10 @@ macro_rules! handle_listener {
 impl Server {
     /// Creates a new server
     pub fn new(config: Config) -> Server {
-        let db = Database::new(config);
+        let mut db = Database::new(config);
+        db.functions.set_engine(command::scripting::register);
         Server {
+            busy: db.busy.clone(),
             db: Arc::new(Mutex::new(db)),
             listener_channels: Vec::new(),
             listener_threads: Vec::new(),
@@ -435,7 +517,7 @@ impl Server {
             if let fork::Fork::Child = fork::daemon(true, true).expect("Fork failed") {
                 if let Ok(mut fp) = File::create(Path::new(&*pidfile)) {
                     match write!(fp, "{}", process::id()) {
-                        Ok(_) => (),
+                        Ok(_) => self.busy.set_pidfile(Path::new(&*pidfile).to_path_buf()),
                         Err(e) => {
                             let db = self.db.lock().unwrap();
                             log!(db.config.logger, Warning, "Error writing pid: {}", e);
@@ -518,15 +600,62 @@ impl Server {
         Ok(())
     }
 
+    /// Listens to the other nodes of the cluster on the cluster bus port.
+    fn listen_cluster_bus<T: ToSocketAddrs>(&mut self, t: T) -> io::Result<()> {
+        for addr in t.to_socket_addrs()? {
+            let (tx, rx) = channel();
+            let listener = TcpListener::bind(addr)?;
+            self.listener_channels.push(tx);
+            let db = self.db.clone();
+            self.listener_threads
+                .push(thread::spawn(move || cluster::accept(db, listener, rx)));
+        }
+        Ok(())
+    }
+
     /// Starts threads listening to new connections.
     pub fn start(&mut self) {
-        let (tcp_keepalive, timeout, addresses, tcp_backlog) = {
-            let db = self.db.lock().unwrap();
+        let (tcp_keepalive, timeout, addresses, tcp_backlog, cport) = {
+            let mut db = self.db.lock().unwrap();
+            if let Err(err) = db.sentinel_load_config() {
+                log_and_exit!(
+                    db.config.logger,
+                    Warning,
+                    1,
+                    "Unrecoverable error: invalid sentinel configuration: {}",
+                    err
+                );
+            }
+            if let Err(err) = db.cluster_load_config() {
+                log_and_exit!(
+                    db.config.logger,
+                    Warning,
+                    1,
+                    "Unrecoverable error: corrupted cluster config file {}: {}",
+                    db.config.cluster_config_file,
+                    err
+                );
+            }
+            // the modules come before the data, which may hold their types
+            for (path, args) in db.config.loadmodule.clone() {
+                match command::module_load(&mut db, &path, args) {
+                    Ok(name) => log!(db.config.logger, Notice, "Module '{}' loaded from {}", name, path),
+                    Err(err) => log_and_exit!(
+                        db.config.logger,
+                        Warning,
+                        1,
+                        "Module {} failed to load: {}",
+                        path,
+                        err
+                    ),
+                }
+            }
             (
                 db.config.tcp_keepalive,
                 db.config.timeout,
                 db.config.addresses(),
                 db.config.tcp_backlog,
+                db.cluster.as_ref().map(|cluster| cluster.myself().cport),
             )
         };
         for (host, port) in addresses {
@@ -555,17 +684,52 @@ impl Server {
             }
         }
 
+        if let Some(cport) = cport {
+            let addresses = self.db.lock().unwrap().config.addresses();
+            for (host, _) in addresses {
+                if let Err(err) = self.listen_cluster_bus((&host[..], cport)) {
+                    let db = self.db.lock().unwrap();
+                    log_and_exit!(
+                        db.config.logger,
+                        Warning,
+                        1,
+                        "Creating the cluster bus listening socket {}:{}: {}",
+                        host,
+                        cport,
+                        err
+                    );
+                }
+            }
+        }
+
         self.handle_unixsocket();
 
         {
             let (hz_stop_tx, hz_stop_rx) = channel();
             self.hz_stop = Some(hz_stop_tx);
             let dblock = self.db.clone();
+            let next_id = self.next_id.clone();
             thread::spawn(move || {
                 while hz_stop_rx.try_recv().is_err() {
                     let mut db = dblock.lock().unwrap();
                     let hz = db.config.hz;
                     db.active_expire_cycle(10);
+                    db.rdb_bgsave_check();
+                    db.aof_rewrite_check();
+                    db.aof_retry_write();
+                    db.repl_cron();
+                    if let Some((id, host, port)) = db.repl_connect_due() {
+                        let client_id = next_id.fetch_add(1, Ordering::Relaxed);
+                        replication::connect(dblock.clone(), id, host, port, client_id);
+                    }
+                    for (serial, ip, cport, rx) in db.cluster_cron() {
+                        cluster::connect(dblock.clone(), serial, ip, cport, rx);
+                    }
+                    for (serial, ip, port, rx) in db.sentinel_cron() {
+                        sentinel::connect(dblock.clone(), serial, ip, port, rx);
+                    }
+                    db.aof_auto_rewrite();
+                    db.rdb_auto_save();
                     drop(db);
                     thread::sleep(Duration::from_millis(10000 / hz as u64));
                 }
@@ -573,8 +737,31 @@ impl Server {
         }
 
         let mut db = self.db.lock().unwrap();
+        // a sentinel holds no data
+        if db.sentinel.is_some() {
+            return;
+        }
         if db.aof.is_some() {
             command::aof::load(&mut *db);
+        } else if Path::new(&*db.config.dbfilename).exists() {
+            let start = Instant::now();
+            let filename = db.config.dbfilename.clone();
+            match db.rdb_load_file(&*filename) {
+                Ok(()) => log!(
+                    db.config.logger,
+                    Notice,
+                    "DB loaded from disk: {:.3} seconds",
+                    start.elapsed().as_secs_f64()
+                ),
+                Err(err) => log_and_exit!(
+                    db.config.logger,
+                    Warning,
+                    1,
+                    "Error loading {}: {}",
+                    filename,
+                    err
+                ),
+            }
         }
     }
 
@@ -630,15 +817,23 @@ impl Server {
         for sender in self.listener_channels.iter() {
             let _ = sender.send(0);
             let db = self.db.lock().unwrap();
+            let cport = db.cluster.as_ref().map(|cluster| cluster.myself().cport);
             for (host, port) in db.config.addresses() {
-                for addrs in (&host[..], port).to_socket_addrs().unwrap() {
-                    let _ = TcpStream::connect(addrs);
+                for port in Some(port).into_iter().chain(cport) {
+                    for addrs in (&host[..], port).to_socket_addrs().unwrap() {
+                        let _ = TcpStream::connect(addrs);
+                    }
                 }
             }
         }
         if let Some(t) = &self.hz_stop {
             let _ = t.send(());
         }
+        {
+            let mut db = self.db.lock().unwrap();
+            db.rdb_bgsave_kill();
+            db.aof_rewrite_kill();
+        }
         self.join();
     }
 }
@@ -655,7 +850,14 @@ mod test_networking {
     use logger::{Level, Logger};
 
     use super::Server;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
+    use std::sync::atomic::Ordering;
+
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut buf = [0u8; 256];
+        let len = stream.read(&mut buf).unwrap();
+        from_utf8(&buf[..len]).unwrap().to_owned()
+    }
 
     #[test]
     fn parse_ping() {
@@ -737,10 +939,78 @@ mod test_networking {
         let addr = format!("127.0.0.1:{}", port);
         let _ = TcpStream::connect(&*addr);
         thread::sleep(Duration::from_millis(100));
-        assert_eq!(*server.next_id.lock().unwrap(), 1);
+        assert_eq!(server.next_id.load(Ordering::Relaxed), 1);
         let _ = TcpStream::connect(&*addr);
         thread::sleep(Duration::from_millis(100));
-        assert_eq!(*server.next_id.lock().unwrap(), 2);
+        assert_eq!(server.next_id.load(Ordering::Relaxed), 2);
+        server.stop();
+    }
+    #[test]
+    fn busy_reply() {
+        let port = 16383;
+        let mut config = Config::default(port, Logger::new(Level::Warning));
+        config.busy_reply_threshold = 100;
+        let mut server = Server::new(config);
+        server.start();
+
+        let addr = format!("127.0.0.1:{}", port);
+        let mut sleeper = TcpStream::connect(&*addr).unwrap();
+        let mut stream = TcpStream::connect(&*addr).unwrap();
+        let get = b"*2\r\n$3\r\nget\r\n$3\r\nkey\r\n";
+
+        // a command shorter than the threshold is waited for
+        sleeper.write_all(b"*3\r\n$5\r\ndebug\r\n$5\r\nsleep\r\n$4\r\n0.05\r\n").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        stream.write_all(get).unwrap();
+        assert_eq!(read_reply(&mut stream), "$-1\r\n");
+        assert_eq!(read_reply(&mut sleeper), "+OK\r\n");
+
+        // a longer one makes the server busy once the threshold passes
+        sleeper.write_all(b"*3\r\n$5\r\ndebug\r\n$5\r\nsleep\r\n$3\r\n0.5\r\n").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let start = Instant::now();
+        stream.write_all(get).unwrap();
+        let reply = read_reply(&mut stream);
+        assert!(reply.starts_with("-BUSY"), "{}", reply);
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        stream.write_all(b"*1\r\n$4\r\nping\r\n").unwrap();
+        assert_eq!(read_reply(&mut stream), "+PONG\r\n");
+
+        assert_eq!(read_reply(&mut sleeper), "+OK\r\n");
+        stream.write_all(get).unwrap();
+        assert_eq!(read_reply(&mut stream), "$-1\r\n");
+        server.stop();
+    }
+    #[test]
+    fn loadmodule() {
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+        let target = format!("{}/target/modules", root);
+        let status = std::process::Command::new(option_env!("CARGO").unwrap_or("cargo"))
+            .args(&["build", "--quiet", "--manifest-path"])
+            .arg(format!("{}/tests/modules/counter/Cargo.toml", root))
+            .arg("--target-dir")
+            .arg(&target)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let path = format!(
+            "{}/debug/{}counter{}",
+            target,
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        );
+
+        let port = 16384;
+        let mut config = Config::default(port, Logger::new(Level::Warning));
+        config.loadmodule.push((path, vec![b"x".to_vec()]));
+        let mut server = Server::new(config);
+        server.start();
+
+        let mut stream = TcpStream::connect(&*format!("127.0.0.1:{}", port)).unwrap();
+        stream.write_all(b"*1\r\n$12\r\ncounter.args\r\n").unwrap();
+        assert_eq!(read_reply(&mut stream), "*1\r\n$1\r\nx\r\n");
+        stream.write_all(b"*2\r\n$12\r\ncounter.incr\r\n$3\r\nkey\r\n").unwrap();
+        assert_eq!(read_reply(&mut stream), ":1\r\n");
         server.stop();
     }
 }
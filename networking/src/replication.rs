@@ -0,0 +1,499 @@
+//! Replica side of the replication: connects to the master, does the
+//! handshake, loads the snapshot it sends and then applies its stream of
+//! commands.
+use logger::log;
+
+use std::{
+    fs::{remove_file, rename, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    mem::replace,
+    net::{TcpStream, ToSocketAddrs},
+    process,
+    sync::mpsc::channel,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use database::replication::MasterLinkState;
+use database::Database;
+use logger::Level;
+use parser::{ParseError, Parser};
+use util::mstime;
+
+/// How long a read waits before checking whether the link is still wanted
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the replica acknowledges the offset it processed
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Length of the mark around a snapshot sent by a diskless master
+const EOF_MARK_LEN: usize = 40;
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut data = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        data.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        data.extend_from_slice(arg);
+        data.extend_from_slice(b"\r\n");
+    }
+    data
+}
+
+fn ack(offset: u64) -> Vec<u8> {
+    encode(&[b"REPLCONF", b"ACK", format!("{}", offset).as_bytes()])
+}
+
+/// Reads from the master. Reads time out regularly to check the link was
+/// not replaced meanwhile, and fail if nothing arrives for `repl-timeout`.
+struct MasterStream {
+    stream: TcpStream,
+    db: Arc<Mutex<Database>>,
+    id: u64,
+}
+
+impl MasterStream {
+    /// Fails if the link is not the current one anymore or if the master
+    /// has been silent for too long.
+    fn check(&self) -> io::Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let timeout = db.config.repl_timeout as i64 * 1000;
+        match db.repl_link(self.id) {
+            None => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the master changed",
+            )),
+            Some(link) if mstime() - link.last_io > timeout => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timeout receiving data from the MASTER",
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for MasterStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Ok(len) => {
+                    if let Some(link) = self.db.lock().unwrap().repl_link(self.id) {
+                        link.last_io = mstime();
+                    }
+                    return Ok(len);
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    self.check()?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A connection to the master.
+struct Link {
+    db: Arc<Mutex<Database>>,
+    id: u64,
+    reader: BufReader<MasterStream>,
+    writer: Arc<Mutex<TcpStream>>,
+}
+
+impl Link {
+    fn send(&self, args: &[&[u8]]) -> io::Result<()> {
+        self.writer.lock().unwrap().write_all(&encode(args))
+    }
+
+    /// Reads a single line reply, without the line ending.
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the master closed the connection",
+            ));
+        }
+        Ok(line.trim_end_matches(|c| c == '\r' || c == '\n').to_owned())
+    }
+
+    /// Sends a command and returns its reply.
+    fn command(&mut self, args: &[&[u8]]) -> io::Result<String> {
+        self.send(args)?;
+        self.read_line()
+    }
+
+    fn set_state(&self, state: MasterLinkState) -> io::Result<()> {
+        match self.db.lock().unwrap().repl_link(self.id) {
+            Some(link) => {
+                link.state = state;
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the master changed",
+            )),
+        }
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        let (masterauth, port) = {
+            let db = self.db.lock().unwrap();
+            (db.config.masterauth.clone(), db.config.port)
+        };
+
+        // a master with a password answers -NOAUTH, AUTH comes next
+        let reply = self.command(&[b"PING"])?;
+        if reply.starts_with('-') && !reply.starts_with("-NOAUTH") {
+            return Err(protocol_error(format!("Error reply to PING from master: '{}'", reply)));
+        }
+        if let Some(masterauth) = masterauth {
+            let reply = self.command(&[b"AUTH", masterauth.as_bytes()])?;
+            if reply.starts_with('-') {
+                return Err(protocol_error(format!("Unable to AUTH to MASTER: {}", reply)));
+            }
+        }
+
+        let reply = self.command(&[b"REPLCONF", b"listening-port", format!("{}", port).as_bytes()])?;
+        if reply.starts_with('-') {
+            let db = self.db.lock().unwrap();
+            log!(
+                db.config.logger,
+                Notice,
+                "(Non critical) Master does not understand REPLCONF listening-port: {}",
+                reply
+            );
+        }
+        let reply = self.command(&[b"REPLCONF", b"capa", b"eof", b"capa", b"psync2"])?;
+        if reply.starts_with('-') {
+            let db = self.db.lock().unwrap();
+            log!(
+                db.config.logger,
+                Notice,
+                "(Non critical) Master does not understand REPLCONF capa: {}",
+                reply
+            );
+        }
+        Ok(())
+    }
+
+    /// Asks the master to continue the stream where this server left it.
+    fn psync(&mut self) -> io::Result<()> {
+        let (replid, offset) = {
+            let db = self.db.lock().unwrap();
+            log!(
+                db.config.logger,
+                Notice,
+                "Trying a partial resynchronization (request {}:{}).",
+                db.replid,
+                db.master_repl_offset + 1
+            );
+            (db.replid.clone(), db.master_repl_offset + 1)
+        };
+        let reply = self.command(&[b"PSYNC", replid.as_bytes(), format!("{}", offset).as_bytes()])?;
+
+        if reply.starts_with("+FULLRESYNC") {
+            let mut parts = reply.split(' ').skip(1);
+            let replid = parts.next().unwrap_or("").to_owned();
+            let offset = parts.next().and_then(|o| o.parse().ok());
+            match offset {
+                Some(offset) if replid.len() == 40 => {
+                    {
+                        let db = self.db.lock().unwrap();
+                        log!(db.config.logger, Notice, "Full resync from master: {}:{}", replid, offset);
+                    }
+                    self.full_sync(replid, offset)
+                }
+                _ => Err(protocol_error(format!("Master replied with wrong +FULLRESYNC syntax: {}", reply))),
+            }
+        } else if reply.starts_with("+CONTINUE") {
+            let replid = reply.split(' ').nth(1).map(|r| r.to_owned());
+            let mut db = self.db.lock().unwrap();
+            log!(
+                db.config.logger,
+                Notice,
+                "Successful partial resynchronization with master."
+            );
+            db.repl_partial_sync_done(self.id, replid);
+            Ok(())
+        } else {
+            Err(protocol_error(format!("Unexpected reply to PSYNC from master: {}", reply)))
+        }
+    }
+
+    /// Receives the snapshot into a temporary file and loads it, replacing
+    /// the current data.
+    fn full_sync(&mut self, replid: String, offset: u64) -> io::Result<()> {
+        self.set_state(MasterLinkState::Sync)?;
+        // the master may send newlines while the snapshot is being created
+        let mut header = String::new();
+        while header.is_empty() {
+            header = self.read_line()?;
+        }
+        if !header.starts_with('$') {
+            return Err(protocol_error(format!(
+                "Bad protocol from MASTER, the first byte is not '$' (we received '{}'), are you sure the host and port are right?",
+                header
+            )));
+        }
+        // a diskless master does not know the size, and sends a mark instead
+        // that is repeated at the end of the snapshot
+        let mark = match header.get(1..5) {
+            Some("EOF:") if header.len() == 5 + EOF_MARK_LEN => Some(header[5..].as_bytes().to_vec()),
+            _ => None,
+        };
+        let size: u64 = match mark {
+            Some(_) => 0,
+            None => header[1..]
+                .parse()
+                .map_err(|_| protocol_error(format!("Bad snapshot size from MASTER: {}", header)))?,
+        };
+        {
+            let mut db = self.db.lock().unwrap();
+            if mark.is_some() {
+                log!(db.config.logger, Notice, "MASTER <-> REPLICA sync: receiving streamed RDB from master");
+            } else {
+                log!(db.config.logger, Notice, "MASTER <-> REPLICA sync: receiving {} bytes from master", size);
+            }
+            if let Some(link) = db.repl_link(self.id) {
+                link.sync_size = size;
+                link.sync_read = 0;
+            }
+        }
+
+        let tmpfile = format!("temp-{}.{}.rdb", mstime() / 1000, process::id());
+        let r = match mark {
+            Some(mark) => self.receive_snapshot_eof(&tmpfile, &mark),
+            None => self.receive_snapshot(&tmpfile, size),
+        };
+        let r = r.and_then(|_| self.load_snapshot(&tmpfile, replid, offset));
+        let _ = remove_file(&tmpfile);
+        r
+    }
+
+    fn receive_snapshot(&mut self, tmpfile: &str, size: u64) -> io::Result<()> {
+        let mut file = File::create(tmpfile)?;
+        let mut buf = [0; 16 * 1024];
+        let mut read = 0;
+        while read < size {
+            let len = ((size - read) as usize).min(buf.len());
+            let len = self.reader.read(&mut buf[..len])?;
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the master closed the connection during the transfer",
+                ));
+            }
+            file.write_all(&buf[..len])?;
+            read += len as u64;
+            if let Some(link) = self.db.lock().unwrap().repl_link(self.id) {
+                link.sync_read = read;
+            }
+        }
+        file.sync_all()
+    }
+
+    /// Receives a snapshot that ends with `mark`. The stream follows right
+    /// after it, so nothing past the mark is consumed.
+    fn receive_snapshot_eof(&mut self, tmpfile: &str, mark: &[u8]) -> io::Result<()> {
+        let mut file = File::create(tmpfile)?;
+        // the last bytes read, kept until it is known they are not the mark
+        let mut tail = vec![];
+        let mut read = 0;
+        loop {
+            let (data, len) = {
+                let buf = self.reader.fill_buf()?;
+                if buf.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the master closed the connection during the transfer",
+                    ));
+                }
+                let mut data = replace(&mut tail, vec![]);
+                data.extend_from_slice(buf);
+                (data, buf.len())
+            };
+            if let Some(pos) = data.windows(mark.len()).position(|w| w == mark) {
+                file.write_all(&data[..pos])?;
+                self.reader.consume(pos + mark.len() - (data.len() - len));
+                break;
+            }
+            let keep = data.len().min(mark.len() - 1);
+            file.write_all(&data[..data.len() - keep])?;
+            tail = data[data.len() - keep..].to_vec();
+            self.reader.consume(len);
+            read += len as u64;
+            if let Some(link) = self.db.lock().unwrap().repl_link(self.id) {
+                link.sync_read = read;
+            }
+        }
+        file.sync_all()
+    }
+
+    fn load_snapshot(&mut self, tmpfile: &str, replid: String, offset: u64) -> io::Result<()> {
+        let mut db = self.db.lock().unwrap();
+        if db.repl_link(self.id).is_none() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the master changed"));
+        }
+        let dbfilename = db.config.dbfilename.clone();
+        rename(tmpfile, &*dbfilename)?;
+        log!(db.config.logger, Notice, "MASTER <-> REPLICA sync: Loading DB in memory");
+        if let Err(e) = db.rdb_load_file(&*dbfilename) {
+            return Err(protocol_error(format!(
+                "Failed trying to load the MASTER synchronization DB from disk: {:?}",
+                e
+            )));
+        }
+        db.repl_full_sync_done(self.id, replid, offset);
+        // the append only file has the old data, it gets rewritten
+        if db.aof.is_some() {
+            if db.aof_child_pid.is_some() || db.rdb_child_pid.is_some() {
+                db.aof_rewrite_scheduled = true;
+            } else if let Err(e) = db.aof_bgrewrite() {
+                log!(db.config.logger, Warning, "Can't rewrite the append only file after the sync: {}", e);
+            }
+        }
+        log!(db.config.logger, Notice, "MASTER <-> REPLICA sync: Finished with success");
+        Ok(())
+    }
+
+    /// Acknowledges the processed offset every second while the link is up.
+    fn spawn_ack_thread(&self) {
+        let db = self.db.clone();
+        let writer = self.writer.clone();
+        let id = self.id;
+        thread::spawn(move || loop {
+            thread::sleep(ACK_PERIOD);
+            let offset = {
+                let mut db = db.lock().unwrap();
+                match db.repl_link(id) {
+                    Some(link) if link.state == MasterLinkState::Connected => (),
+                    _ => break,
+                }
+                db.master_repl_offset
+            };
+            if writer.lock().unwrap().write_all(&ack(offset)).is_err() {
+                break;
+            }
+        });
+    }
+
+    /// Applies the commands sent by the master until the connection is lost.
+    fn apply_stream(&mut self, client_id: usize) -> io::Result<()> {
+        let mut client = command::Client::new(channel().0, client_id);
+        client.auth = true;
+        client.is_master = true;
+        let mut parser = Parser::new();
+        loop {
+            if parser.is_incomplete() {
+                parser.allocate();
+                let len = {
+                    let pos = parser.written;
+                    let buffer = parser.get_mut();
+                    self.reader.read(&mut buffer[pos..])?
+                };
+                if len == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the master closed the connection",
+                    ));
+                }
+                parser.written += len;
+            }
+
+            let command = match parser.next() {
+                Ok(command) => command,
+                Err(ParseError::Incomplete) => continue,
+                Err(e) => return Err(protocol_error(format!("Protocol error from master: {:?}", e))),
+            };
+            let getack = match (command.get_str(0), command.get_str(1)) {
+                (Ok(name), Ok(arg)) => {
+                    name.eq_ignore_ascii_case("replconf") && arg.eq_ignore_ascii_case("getack")
+                }
+                _ => false,
+            };
+
+            let mut db = self.db.lock().unwrap();
+            if db.repl_link(self.id).is_none() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the master changed"));
+            }
+            let data = command.get_data().to_vec();
+            if getack {
+                db.repl_feed_from_master(&data);
+                let offset = db.master_repl_offset;
+                drop(db);
+                self.writer.lock().unwrap().write_all(&ack(offset))?;
+            } else {
+                // the master does not read replies
+                let _ = command::command(command, &mut db, &mut client);
+                db.repl_feed_from_master(&data);
+            }
+        }
+    }
+}
+
+fn run(db: Arc<Mutex<Database>>, id: u64, host: &str, port: u16, client_id: usize) -> io::Result<()> {
+    {
+        let db = db.lock().unwrap();
+        log!(db.config.logger, Notice, "Connecting to MASTER {}:{}", host, port);
+    }
+    let timeout = Duration::from_secs(db.lock().unwrap().config.repl_timeout);
+    let addr = match (host, port).to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(protocol_error(format!("Can't resolve {}", host))),
+    };
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut link = Link {
+        db: db.clone(),
+        id,
+        reader: BufReader::new(MasterStream {
+            stream,
+            db: db.clone(),
+            id,
+        }),
+        writer,
+    };
+    {
+        let mut db = db.lock().unwrap();
+        log!(db.config.logger, Notice, "MASTER <-> REPLICA sync started");
+        if let Some(link) = db.repl_link(id) {
+            link.last_io = mstime();
+        }
+    }
+
+    link.handshake()?;
+    link.psync()?;
+    link.spawn_ack_thread();
+    link.apply_stream(client_id)
+}
+
+/// Connects to the master of the link `id` in a new thread. When the
+/// connection is lost the link is marked to be retried.
+pub fn connect(db: Arc<Mutex<Database>>, id: u64, host: String, port: u16, client_id: usize) {
+    thread::spawn(move || {
+        let r = run(db.clone(), id, &*host, port, client_id);
+        let mut db = db.lock().unwrap();
+        if db.repl_link(id).is_none() {
+            // the link was replaced or removed, nothing to report
+            return;
+        }
+        if let Err(e) = r {
+            log!(
+                db.config.logger,
+                Warning,
+                "Error condition on socket for SYNC with {}:{}: {}",
+                host,
+                port,
+                e
+            );
+        }
+        db.repl_link_lost(id);
+    });
+}
@@ -0,0 +1,145 @@
+//! Links of a sentinel to the servers it monitors and to the other
+//! sentinels. The commands to send are queued by the database, and the
+//! replies are handed back to it.
+use logger::log;
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::mpsc::Receiver,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use database::Database;
+use logger::Level;
+use response::Response;
+
+/// How long a read waits before checking whether the link is still wanted
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long connecting to an instance may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads from an instance. Reads time out regularly to stop once the link
+/// is not wanted anymore.
+struct LinkStream {
+    stream: TcpStream,
+    db: Arc<Mutex<Database>>,
+    serial: u64,
+}
+
+impl Read for LinkStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Ok(len) => return Ok(len),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if !self.db.lock().unwrap().sentinel_link_alive(self.serial) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "the link was dropped",
+                        ));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reads a reply in the protocol format.
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Response> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the instance closed the connection",
+        ));
+    }
+    let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+    if line.is_empty() {
+        return Err(protocol_error("Empty reply".to_owned()));
+    }
+    let (kind, rest) = line.split_at(1);
+    let number = || {
+        rest.parse::<i64>()
+            .map_err(|_| protocol_error(format!("Invalid length '{}'", rest)))
+    };
+    Ok(match kind {
+        "+" => Response::Status(rest.to_owned()),
+        "-" => Response::Error(rest.to_owned()),
+        ":" => Response::Integer(number()?),
+        "$" => match number()? {
+            len if len < 0 => Response::Nil,
+            len => {
+                let mut data = vec![0; len as usize + 2];
+                reader.read_exact(&mut data)?;
+                data.truncate(len as usize);
+                Response::Data(data)
+            }
+        },
+        "*" => match number()? {
+            len if len < 0 => Response::Nil,
+            len => {
+                let mut items = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    items.push(read_reply(reader)?);
+                }
+                Response::Array(items)
+            }
+        },
+        _ => return Err(protocol_error(format!("Invalid reply '{}'", line))),
+    })
+}
+
+fn run(db: &Arc<Mutex<Database>>, serial: u64, ip: &str, port: u16, rx: Receiver<Vec<u8>>) -> io::Result<()> {
+    let addr = match (ip, port).to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(protocol_error(format!("Can't resolve {}", ip))),
+    };
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let local_ip = stream.local_addr()?.ip().to_string();
+    db.lock().unwrap().sentinel_link_connected(&local_ip);
+    let mut writer = stream.try_clone()?;
+    thread::spawn(move || {
+        for data in rx {
+            if writer.write_all(&data).is_err() {
+                break;
+            }
+        }
+        // the link was dropped, the reader stops too
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+    let mut reader = BufReader::new(LinkStream {
+        stream,
+        db: db.clone(),
+        serial,
+    });
+    loop {
+        let reply = read_reply(&mut reader)?;
+        db.lock().unwrap().sentinel_reply(serial, &reply);
+    }
+}
+
+/// Opens the link `serial` to the instance at `ip:port` in a new thread,
+/// and sends the commands queued in `rx`. When the connection is lost the
+/// link is dropped, to be opened again by the cron.
+pub fn connect(db: Arc<Mutex<Database>>, serial: u64, ip: String, port: u16, rx: Receiver<Vec<u8>>) {
+    thread::spawn(move || {
+        let r = run(&db, serial, &ip, port, rx);
+        let mut db = db.lock().unwrap();
+        if let Err(e) = r {
+            log!(db.config.logger, Verbose, "Connection with {}:{} lost: {}", ip, port, e);
+        }
+        db.sentinel_link_lost(serial);
+    });
+}
@@ -396,7 +396,7 @@ pub fn parse(input: &[u8]) -> Result<(ParsedCommand, usize), ParseError> {
             return Err(ParseError::Incomplete);
         }
     }
-    Ok((ParsedCommand::new(input, argv), pos))
+    Ok((ParsedCommand::new(&input[..pos], argv), pos))
 }
 
 /// A stream parser
@@ -558,16 +558,16 @@ mod test_parser {
     #[test]
     fn parser_multiple() {
         let mut parser = Parser::new();
+        let message = b"*2\r\n$3\r\nfoo\r\n$4\r\nbarz\r\n";
         {
-            let message = b"*2\r\n$3\r\nfoo\r\n$4\r\nbarz\r\n";
             parser.written += message.len();
             parser.written += message.len();
             let mut v = parser.get_mut();
             v.extend(&*message.to_vec());
             v.extend(&*message.to_vec());
         }
-        parser.next().unwrap();
-        parser.next().unwrap();
+        assert_eq!(parser.next().unwrap().get_data(), &message[..]);
+        assert_eq!(parser.next().unwrap().get_data(), &message[..]);
         assert_eq!(parser.next().unwrap_err(), ParseError::Incomplete);
     }
 
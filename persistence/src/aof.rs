@@ -1,69 +1,466 @@
+use std::fs::rename;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
+use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::usize;
 
-use parser::ParsedCommand;
+use parser::{ParseError, ParsedCommand, Parser};
+
+/// Milliseconds a background fsync may take before writes stop waiting
+/// for it
+const FSYNC_MAX_DELAY_MS: i64 = 2000;
+
+/// When the written data is flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    /// After every write, before replying to the client
+    Always,
+    /// Once per second, in a background thread
+    EverySec,
+    /// Whenever the operating system decides to
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Fsync, ()> {
+        match &*s.to_ascii_lowercase() {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(()),
+        }
+    }
+}
+
+fn mstime() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// State shared with the thread running the `everysec` fsyncs.
+struct BackgroundFsync {
+    fp: Mutex<File>,
+    /// Was anything written since the last fsync
+    dirty: AtomicBool,
+    /// Skip fsyncs while a rewrite is in progress
+    suspended: AtomicBool,
+    /// Milliseconds when the running fsync started, 0 if there is none
+    started: AtomicI64,
+    /// Error of the last fsync, if it failed. It is reported until an
+    /// fsync succeeds
+    error: Mutex<Option<String>>,
+    stop: AtomicBool,
+}
+
+impl BackgroundFsync {
+    fn spawn(fp: File) -> io::Result<Arc<BackgroundFsync>> {
+        let state = Arc::new(BackgroundFsync {
+            fp: Mutex::new(fp),
+            dirty: AtomicBool::new(false),
+            suspended: AtomicBool::new(false),
+            started: AtomicI64::new(0),
+            error: Mutex::new(None),
+            stop: AtomicBool::new(false),
+        });
+        let s = state.clone();
+        thread::Builder::new()
+            .name("aof-fsync".to_owned())
+            .spawn(move || {
+                while !s.stop.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_secs(1));
+                    if s.suspended.load(Ordering::Relaxed) || !s.dirty.swap(false, Ordering::Relaxed) {
+                        continue;
+                    }
+                    s.started.store(mstime(), Ordering::Relaxed);
+                    let r = s.fp.lock().unwrap().sync_data();
+                    s.started.store(0, Ordering::Relaxed);
+                    match r {
+                        Ok(()) => *s.error.lock().unwrap() = None,
+                        Err(e) => {
+                            // try again on the next tick
+                            *s.error.lock().unwrap() = Some(e.to_string());
+                            s.dirty.store(true, Ordering::Relaxed);
+                        }
+                    }
+                }
+            })?;
+        Ok(state)
+    }
+}
 
 pub struct Aof {
     fp: File,
+    path: PathBuf,
     dbindex: usize,
+    /// Data not written into the file yet, after a write failed
+    pending: Vec<u8>,
+    /// Commands written while a rewrite is in progress. They are appended to
+    /// the rewritten file before it replaces the current one.
+    rewrite_buffer: Option<Vec<u8>>,
+    fsync: Fsync,
+    no_fsync_on_rewrite: bool,
+    background_fsync: Option<Arc<BackgroundFsync>>,
+    /// Number of writes that did not wait for a slow background fsync
+    delayed_fsync: u64,
+    /// Size in bytes of the file
+    current_size: u64,
+    /// Size in bytes of the file when it was opened or last rewritten
+    base_size: u64,
 }
 
 impl Aof {
+    /// Opens the file at `path`, leaving the fsyncs to the operating system
+    /// until `set_fsync` is called.
     pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Aof> {
+        let fp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.as_ref())?;
+        let size = fp.metadata()?.len();
         Ok(Aof {
-            fp: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(path)?,
+            fp,
+            path: path.as_ref().to_path_buf(),
             dbindex: usize::MAX,
+            pending: vec![],
+            rewrite_buffer: None,
+            fsync: Fsync::No,
+            no_fsync_on_rewrite: false,
+            background_fsync: None,
+            delayed_fsync: 0,
+            current_size: size,
+            base_size: size,
         })
     }
 
+    /// Changes the fsync policy, starting or stopping the background thread
+    /// used by `Fsync::EverySec`.
+    pub fn set_fsync(&mut self, fsync: Fsync) -> io::Result<()> {
+        if fsync == Fsync::EverySec && self.background_fsync.is_none() {
+            let state = BackgroundFsync::spawn(self.fp.try_clone()?)?;
+            state.suspended.store(self.fsync_suspended(), Ordering::Relaxed);
+            self.background_fsync = Some(state);
+        } else if fsync != Fsync::EverySec {
+            self.stop_background_fsync();
+        }
+        self.fsync = fsync;
+        Ok(())
+    }
+
+    pub fn get_fsync(&self) -> Fsync {
+        self.fsync
+    }
+
+    /// Do not fsync while a rewrite is in progress, trading durability for
+    /// latency.
+    pub fn set_no_fsync_on_rewrite(&mut self, no_fsync_on_rewrite: bool) {
+        self.no_fsync_on_rewrite = no_fsync_on_rewrite;
+        self.update_fsync_suspended();
+    }
+
+    /// Number of writes that did not wait for a background fsync that was
+    /// taking too long.
+    pub fn delayed_fsync(&self) -> u64 {
+        self.delayed_fsync
+    }
+
+    pub fn current_size(&self) -> u64 {
+        self.current_size
+    }
+
+    pub fn base_size(&self) -> u64 {
+        self.base_size
+    }
+
+    /// Is a background fsync running right now.
+    pub fn fsync_in_progress(&self) -> bool {
+        self.background_fsync
+            .as_ref()
+            .map_or(false, |state| state.started.load(Ordering::Relaxed) != 0)
+    }
+
+    fn fsync_suspended(&self) -> bool {
+        self.no_fsync_on_rewrite && self.rewrite_buffer.is_some()
+    }
+
+    fn update_fsync_suspended(&self) {
+        if let Some(state) = &self.background_fsync {
+            state.suspended.store(self.fsync_suspended(), Ordering::Relaxed);
+        }
+    }
+
+    fn stop_background_fsync(&mut self) {
+        if let Some(state) = self.background_fsync.take() {
+            state.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
     pub fn select(&mut self, dbindex: usize) -> io::Result<()> {
         if self.dbindex != dbindex {
             // TODO: use logarithms to know the length?
             let n = format!("{}", dbindex);
-            write!(self.fp, "*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", n.len(), n)?;
+            let select = format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", n.len(), n);
+            self.append(select.as_bytes())?;
             self.dbindex = dbindex;
         }
         Ok(())
     }
 
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        if let Some(buf) = &mut self.rewrite_buffer {
+            buf.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    /// Writes the pending data into the file. If it fails, the file is
+    /// truncated back so the data can be written again whole later; when
+    /// that is not possible, only what was not written is kept.
+    fn write_pending(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.pending.len() {
+            match self.fp.write(&self.pending[written..]) {
+                Ok(0) => {
+                    return self.write_failed(written, io::Error::new(io::ErrorKind::WriteZero, "short write"));
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return self.write_failed(written, e),
+            }
+        }
+        self.current_size += written as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn write_failed(&mut self, written: usize, err: io::Error) -> io::Result<()> {
+        if written > 0 && !self.truncate(self.current_size as usize) {
+            self.current_size += written as u64;
+            self.pending.drain(..written);
+        }
+        Err(err)
+    }
+
+    /// Writes the pending data and flushes the file to disk, whatever the
+    /// fsync policy. The background fsyncs stop, as the server is exiting.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.stop_background_fsync();
+        self.write_pending()?;
+        self.fp.sync_data()
+    }
+
+    /// Writes the data left behind by a failed write, and checks that the
+    /// file reached the disk according to the fsync policy.
+    pub fn retry(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.flush()
+    }
+
+    /// Applies the fsync policy after a write.
+    fn flush(&mut self) -> io::Result<()> {
+        match self.fsync {
+            Fsync::Always if !self.fsync_suspended() => self.fp.sync_data(),
+            Fsync::EverySec => {
+                let state = match &self.background_fsync {
+                    Some(state) => state,
+                    None => return Ok(()),
+                };
+                if let Some(e) = &*state.error.lock().unwrap() {
+                    return Err(io::Error::new(io::ErrorKind::Other, e.clone()));
+                }
+                // the data was written already, it is just not waiting for
+                // the fsync to finish
+                let started = state.started.load(Ordering::Relaxed);
+                if started != 0 && mstime() - started > FSYNC_MAX_DELAY_MS {
+                    self.delayed_fsync += 1;
+                }
+                state.dirty.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts keeping a copy of every write until the rewrite is finished or
+    /// aborted.
+    pub fn rewrite_start(&mut self) {
+        self.rewrite_buffer = Some(Vec::new());
+        // the buffered commands must not depend on the rewritten file's last
+        // selected database
+        self.dbindex = usize::MAX;
+        self.update_fsync_suspended();
+    }
+
+    /// Number of bytes written since the rewrite started.
+    pub fn rewrite_buffer_len(&self) -> usize {
+        self.rewrite_buffer.as_ref().map_or(0, |buf| buf.len())
+    }
+
+    /// Appends the writes buffered during the rewrite to `tmppath` and
+    /// atomically replaces the current file with it.
+    /// On error the current file is kept and the buffer discarded.
+    pub fn rewrite_finish<P: AsRef<Path>>(&mut self, tmppath: P) -> io::Result<()> {
+        let buf = self.rewrite_buffer.take().unwrap_or_default();
+        let mut fp = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(tmppath.as_ref())?;
+        fp.write_all(&buf)?;
+        fp.sync_all()?;
+        rename(tmppath.as_ref(), &self.path)?;
+        if let Some(state) = &self.background_fsync {
+            *state.fp.lock().unwrap() = fp.try_clone()?;
+        }
+        self.current_size = fp.metadata()?.len();
+        self.base_size = self.current_size;
+        self.fp = fp;
+        // the buffer had the data that could not be written too
+        self.pending.clear();
+        self.dbindex = usize::MAX;
+        self.update_fsync_suspended();
+        Ok(())
+    }
+
+    /// Stops buffering writes for a rewrite that did not complete.
+    pub fn rewrite_abort(&mut self) {
+        self.rewrite_buffer = None;
+        self.update_fsync_suspended();
+    }
+
     pub fn truncate(&mut self, pos: usize) -> bool {
         if self.fp.set_len(pos as u64).is_err() {
             return false;
         }
+        self.current_size = pos as u64;
         self.fp.seek(SeekFrom::Start(pos as u64)).is_ok()
     }
 
     pub fn write(&mut self, dbindex: usize, command: &ParsedCommand) -> io::Result<()> {
         self.select(dbindex)?;
-        self.fp.write_all(command.get_data())?;
-        Ok(())
+        self.append(command.get_data())?;
+        self.write_pending()?;
+        self.flush()
     }
 }
 
+impl Drop for Aof {
+    fn drop(&mut self) {
+        self.stop_background_fsync();
+    }
+}
+
+/// Outcome of validating an append only file with `check`.
+#[derive(Debug, PartialEq)]
+pub struct AofCheck {
+    /// Number of valid commands
+    pub commands: usize,
+    /// Length in bytes of the valid prefix of the file
+    pub valid_len: usize,
+    /// Why the data after `valid_len` is not valid, if there is any
+    pub error: Option<String>,
+}
+
+/// Parses every command in `reader`, stopping at the first one that is
+/// truncated or malformed.
+/// A `MULTI` without its `EXEC` is also invalid, since loading it would
+/// apply half a transaction, so the valid prefix ends before it.
+pub fn check<R: Read>(reader: &mut R) -> io::Result<AofCheck> {
+    let mut parser = Parser::new();
+    let mut commands = 0;
+    let mut offset = 0;
+    let mut multi = None;
+    let mut error = None;
+    loop {
+        if parser.is_incomplete() {
+            parser.allocate();
+            let len = {
+                let pos = parser.written;
+                let buffer = parser.get_mut();
+                reader.read(&mut buffer[pos..])?
+            };
+            parser.written += len;
+            if len == 0 {
+                if parser.written > parser.position {
+                    error = Some("Unexpected end of file".to_owned());
+                }
+                break;
+            }
+        }
+
+        let start = parser.position;
+        let name = match parser.next() {
+            Ok(command) => command.get_str(0).map(|s| s.to_ascii_lowercase()).unwrap_or_default(),
+            Err(ParseError::Incomplete) => continue,
+            Err(err) => {
+                error = Some(format!("Bad file format: {}", err));
+                break;
+            }
+        };
+        match &*name {
+            "multi" if multi.is_none() => multi = Some(offset),
+            "exec" => multi = None,
+            _ => (),
+        }
+        offset += parser.position - start;
+        commands += 1;
+    }
+
+    // a transaction without its EXEC is not applied, even when the file is
+    // not valid after it
+    if let Some(pos) = multi {
+        return Ok(AofCheck {
+            commands,
+            valid_len: pos,
+            error: Some(error.unwrap_or_else(|| "Reached end of file before EXEC for MULTI".to_owned())),
+        });
+    }
+    Ok(AofCheck {
+        commands,
+        valid_len: offset,
+        error,
+    })
+}
+
 impl io::Read for Aof {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.fp.read(buf)
     }
 }
 
+impl io::Seek for Aof {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.fp.seek(pos)
+    }
+}
+
 #[cfg(test)]
 mod test_aof {
     use std::env::temp_dir;
+    use std::fs::remove_file;
     use std::fs::File;
     use std::io::Read;
     use std::io::Write;
 
-    use super::Aof;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{check, Aof, AofCheck, Fsync};
     use parser::parse;
 
     #[test]
@@ -90,6 +487,43 @@ mod test_aof {
         }
     }
 
+    #[test]
+    fn test_write_retry() {
+        let mut path = temp_dir();
+        path.push("aoftest-retry");
+        let _ = remove_file(path.as_path());
+        let get = b"*2\r\n$3\r\nget\r\n$1\r\na\r\n";
+        let command = parse(get).unwrap().0;
+
+        let mut aof = Aof::new(path.as_path()).unwrap();
+        aof.write(0, &command).unwrap();
+        let size = aof.current_size();
+
+        // writing into a read only handle fails, and the data is kept
+        let writable = std::mem::replace(&mut aof.fp, File::open(path.as_path()).unwrap());
+        assert!(aof.write(0, &command).is_err());
+        assert!(aof.write(1, &command).is_err());
+        assert_eq!(aof.current_size(), size);
+        aof.fp = writable;
+        aof.retry().unwrap();
+        aof.retry().unwrap();
+        let size = aof.current_size();
+
+        // on shutdown the pending data is written and synced
+        let writable = std::mem::replace(&mut aof.fp, File::open(path.as_path()).unwrap());
+        assert!(aof.write(1, &command).is_err());
+        aof.fp = writable;
+        aof.sync().unwrap();
+        assert_eq!(aof.current_size(), size + get.len() as u64);
+
+        let mut data = vec![];
+        File::open(path.as_path()).unwrap().read_to_end(&mut data).unwrap();
+        let select = |n: &[u8]| [&b"*2\r\n$6\r\nSELECT\r\n$1\r\n"[..], n, b"\r\n"].concat();
+        assert_eq!(data, [&select(b"0")[..], get, get, &select(b"1")[..], get, get].concat());
+        assert_eq!(aof.current_size(), data.len() as u64);
+        let _ = remove_file(path.as_path());
+    }
+
     #[test]
     fn test_read() {
         let mut path = temp_dir();
@@ -104,4 +538,157 @@ mod test_aof {
         assert_eq!(11, aof.read(&mut r).unwrap());
         assert_eq!(&r, b"hello world!");
     }
+
+    #[test]
+    fn test_rewrite() {
+        let mut path = temp_dir();
+        path.push("aoftest3");
+        let mut tmppath = temp_dir();
+        tmppath.push("aoftest3-rewrite");
+        let _ = remove_file(path.as_path());
+        let command = parse(b"*2\r\n$3\r\nget\r\n$1\r\na\r\n").unwrap().0;
+
+        let mut aof = Aof::new(path.as_path()).unwrap();
+        aof.write(0, &command).unwrap();
+        assert_eq!(aof.current_size(), 43);
+        assert_eq!(aof.base_size(), 0);
+        aof.rewrite_start();
+        File::create(tmppath.as_path())
+            .unwrap()
+            .write_all(b"rewritten\r\n")
+            .unwrap();
+        aof.write(0, &command).unwrap();
+        assert_eq!(aof.rewrite_buffer_len(), 43);
+        aof.rewrite_finish(tmppath.as_path()).unwrap();
+        assert!(!tmppath.exists());
+        assert_eq!(aof.base_size(), 54);
+        aof.write(1, &command).unwrap();
+        assert_eq!(aof.current_size(), 97);
+        assert_eq!(aof.base_size(), 54);
+
+        let mut data = String::new();
+        File::open(path.as_path())
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(
+            data,
+            "rewritten\r\n\
+             *2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*2\r\n$3\r\nget\r\n$1\r\na\r\n\
+             *2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n*2\r\n$3\r\nget\r\n$1\r\na\r\n"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let data = b"*2\r\n$3\r\nget\r\n$1\r\na\r\n*2\r\n$3\r\nget\r\n$1\r\nb\r\n";
+        assert_eq!(
+            check(&mut &data[..]).unwrap(),
+            AofCheck {
+                commands: 2,
+                valid_len: data.len(),
+                error: None,
+            }
+        );
+
+        let r = check(&mut &data[..data.len() - 3]).unwrap();
+        assert_eq!(r.commands, 1);
+        assert_eq!(r.valid_len, 20);
+        assert_eq!(r.error, Some("Unexpected end of file".to_owned()));
+
+        let r = check(&mut &b"*2\r\n$3\r\nget\r\n$1\r\na\r\n!!\r\n"[..]).unwrap();
+        assert_eq!(r.commands, 1);
+        assert_eq!(r.valid_len, 20);
+        assert!(r.error.unwrap().starts_with("Bad file format"));
+    }
+
+    #[test]
+    fn test_check_multi() {
+        let multi = b"*1\r\n$5\r\nMULTI\r\n";
+        let exec = b"*1\r\n$4\r\nEXEC\r\n";
+        let get = b"*2\r\n$3\r\nget\r\n$1\r\na\r\n";
+
+        let data = [&get[..], multi, get, exec].concat();
+        let r = check(&mut &*data).unwrap();
+        assert_eq!(r.valid_len, data.len());
+        assert_eq!(r.error, None);
+
+        let data = [&get[..], multi, get].concat();
+        let r = check(&mut &*data).unwrap();
+        assert_eq!(r.commands, 3);
+        assert_eq!(r.valid_len, get.len());
+        assert!(r.error.is_some());
+
+        // the file is truncated at the MULTI when a corrupt command follows
+        let data = [&get[..], multi, get, b"!!\r\n", exec].concat();
+        let r = check(&mut &*data).unwrap();
+        assert_eq!(r.commands, 3);
+        assert_eq!(r.valid_len, get.len());
+        assert!(r.error.unwrap().starts_with("Bad file format"));
+
+        let data = [&get[..], multi, &get[..get.len() - 3]].concat();
+        let r = check(&mut &*data).unwrap();
+        assert_eq!(r.commands, 2);
+        assert_eq!(r.valid_len, get.len());
+        assert_eq!(r.error, Some("Unexpected end of file".to_owned()));
+    }
+
+    #[test]
+    fn test_fsync_policy() {
+        assert_eq!("always".parse(), Ok(Fsync::Always));
+        assert_eq!("EverySec".parse(), Ok(Fsync::EverySec));
+        assert_eq!("no".parse(), Ok(Fsync::No));
+        assert_eq!("sometimes".parse::<Fsync>(), Err(()));
+    }
+
+    #[test]
+    fn test_fsync_always() {
+        let mut path = temp_dir();
+        path.push("aoftest4");
+        let command = parse(b"*2\r\n$3\r\nget\r\n$1\r\na\r\n").unwrap().0;
+
+        let mut aof = Aof::new(path.as_path()).unwrap();
+        aof.set_fsync(Fsync::Always).unwrap();
+        aof.write(0, &command).unwrap();
+        assert!(!aof.fsync_in_progress());
+        assert_eq!(aof.delayed_fsync(), 0);
+    }
+
+    #[test]
+    fn test_fsync_everysec() {
+        let mut path = temp_dir();
+        path.push("aoftest5");
+        let command = parse(b"*2\r\n$3\r\nget\r\n$1\r\na\r\n").unwrap().0;
+
+        let mut aof = Aof::new(path.as_path()).unwrap();
+        aof.set_fsync(Fsync::EverySec).unwrap();
+        aof.write(0, &command).unwrap();
+        {
+            let state = aof.background_fsync.as_ref().unwrap();
+            assert!(state.dirty.load(super::Ordering::Relaxed));
+        }
+        thread::sleep(Duration::from_millis(1500));
+        {
+            let state = aof.background_fsync.as_ref().unwrap();
+            assert!(!state.dirty.load(super::Ordering::Relaxed));
+        }
+
+        aof.set_fsync(Fsync::No).unwrap();
+        assert!(aof.background_fsync.is_none());
+        aof.write(0, &command).unwrap();
+    }
+
+    #[test]
+    fn test_no_fsync_on_rewrite() {
+        let mut path = temp_dir();
+        path.push("aoftest6");
+
+        let mut aof = Aof::new(path.as_path()).unwrap();
+        aof.set_fsync(Fsync::EverySec).unwrap();
+        aof.set_no_fsync_on_rewrite(true);
+        aof.rewrite_start();
+        assert!(aof.background_fsync.as_ref().unwrap().suspended.load(super::Ordering::Relaxed));
+        aof.rewrite_abort();
+        assert!(!aof.background_fsync.as_ref().unwrap().suspended.load(super::Ordering::Relaxed));
+    }
 }
@@ -20,6 +20,8 @@ pub enum Response {
     Status(String),
     /// An array of responses that may mix different types
     Array(Vec<Response>),
+    /// Bytes already encoded in Redis protocol, sent as they are
+    Raw(Vec<u8>),
 }
 
 /// No response was issued
@@ -69,6 +71,7 @@ impl Response {
                 &(a.iter().map(|el| el.as_bytes()).collect::<Vec<_>>()[..].concat())[..],
             ]
             .concat(),
+            Response::Raw(d) => d.clone(),
         }
     }
 
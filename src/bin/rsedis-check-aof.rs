@@ -0,0 +1,109 @@
+use std::env::args;
+use std::fs::OpenOptions;
+use std::io::{stdin, stdout, BufRead, BufReader, Write};
+use std::process::exit;
+
+use database::rdb::rdb_check;
+use persistence::aof::check;
+
+fn usage() -> ! {
+    eprintln!("Usage: {} [--fix] <file.aof>", args().next().unwrap());
+    exit(1);
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N]: ", question);
+    let _ = stdout().flush();
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+fn main() {
+    let (fix, filename) = match (args().nth(1), args().nth(2)) {
+        (Some(ref flag), Some(filename)) if flag == "--fix" => (true, filename),
+        (Some(filename), None) => (false, filename),
+        _ => usage(),
+    };
+    let file = match OpenOptions::new().read(true).write(fix).open(&filename) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Cannot open file {}: {}", filename, e);
+            exit(1);
+        }
+    };
+    let size = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(e) => {
+            eprintln!("Cannot stat file {}: {}", filename, e);
+            exit(1);
+        }
+    };
+
+    let mut reader = BufReader::new(&file);
+    let has_preamble = match reader.fill_buf() {
+        Ok(buf) => buf.starts_with(b"REDIS"),
+        Err(e) => {
+            eprintln!("Cannot read file {}: {}", filename, e);
+            exit(1);
+        }
+    };
+    let preamble = if has_preamble {
+        println!("The AOF appears to start with an RDB preamble.");
+        println!("Checking the RDB preamble to start:");
+        match rdb_check(&mut reader, |offset, msg| println!("[offset {}] {}", offset, msg)) {
+            Ok(check) => {
+                println!("RDB preamble is OK, proceeding with AOF tail...");
+                check.len
+            }
+            Err(err) => {
+                println!("RDB preamble of AOF file is not sane, aborting.");
+                println!("{}", err);
+                exit(1);
+            }
+        }
+    } else {
+        0
+    };
+
+    let result = match check(&mut reader) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Cannot read file {}: {}", filename, e);
+            exit(1);
+        }
+    };
+    let valid = preamble + result.valid_len as u64;
+    if let Some(error) = &result.error {
+        println!("0x{:>8x}: {}", valid, error);
+    }
+    println!(
+        "AOF analyzed: size={}, ok_up_to={}, diff={}",
+        size,
+        valid,
+        size - valid
+    );
+
+    if result.error.is_none() {
+        println!("AOF is valid");
+        return;
+    }
+    if !fix {
+        println!("AOF is not valid. Use the --fix option to try fixing it.");
+        exit(1);
+    }
+    println!(
+        "This will shrink the AOF from {} bytes, with {} bytes, to {} bytes",
+        size,
+        size - valid,
+        valid
+    );
+    if !confirm("Continue?") {
+        println!("Aborting...");
+        exit(1);
+    }
+    if let Err(e) = file.set_len(valid) {
+        println!("Failed to truncate AOF: {}", e);
+        exit(1);
+    }
+    println!("Successfully truncated AOF");
+}
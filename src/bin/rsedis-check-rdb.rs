@@ -0,0 +1,50 @@
+use std::env::args;
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+
+use database::rdb::rdb_check;
+
+fn main() {
+    let filename = match args().nth(1) {
+        Some(f) => f,
+        None => {
+            eprintln!("Usage: {} <rdb-file-name>", args().next().unwrap());
+            exit(1);
+        }
+    };
+    let file = match File::open(&filename) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", filename, e);
+            exit(1);
+        }
+    };
+
+    println!("[offset 0] Checking RDB file {}", filename);
+    let mut reader = BufReader::new(file);
+    match rdb_check(&mut reader, |offset, msg| println!("[offset {}] {}", offset, msg)) {
+        Ok(check) => {
+            match check.checksum {
+                Some(_) => println!("[offset {}] Checksum OK", check.len),
+                None => println!(
+                    "[offset {}] RDB file was saved with checksum disabled: no check performed.",
+                    check.len
+                ),
+            }
+            println!("[offset {}] \\o/ RDB looks OK! \\o/", check.len);
+            println!("[info] {} keys read", check.keys);
+            println!("[info] {} expires", check.expires);
+            println!("[info] {} already expired", check.already_expired);
+        }
+        Err(err) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[offset {}] {}", err.offset, err.error);
+            println!("[additional info] While doing: {}", err.doing);
+            if let Some(key) = err.key {
+                println!("[additional info] Reading key '{}'", String::from_utf8_lossy(&key));
+            }
+            exit(1);
+        }
+    }
+}
@@ -11,10 +11,20 @@ use networking::Server;
 
 fn main() {
     let mut config = Config::new(Logger::new(Level::Notice));
-    if let Some(f) = args().nth(1) {
+    let sentinel = args().skip(1).any(|arg| arg == "--sentinel");
+    let configfile = args().skip(1).find(|arg| !arg.starts_with("--"));
+    if sentinel {
+        config.port = 26379;
+        config.sentinel_mode = true;
+    }
+    if let Some(f) = configfile {
         if config.parsefile(f).is_err() {
             exit(1);
         }
+    } else if sentinel {
+        // the sentinel saves its state into the configuration file
+        eprintln!("Sentinel started without a config file. Exiting...");
+        exit(1);
     }
 
     let (port, daemonize) = (config.port, config.daemonize);
@@ -0,0 +1,85 @@
+//! A module for the tests: a `counter-t` type with the commands
+//! COUNTER.INCR and COUNTER.GET, and COUNTER.ARGS replying the arguments it
+//! was loaded with.
+use database::declare_module;
+use database::module::{CommandFlags, CommandProperties, Context, ModuleInit, ModuleType};
+use parser::ParsedCommand;
+use response::Response;
+
+#[derive(Debug)]
+struct Counter(i64);
+
+impl ModuleType for Counter {
+    const NAME: &'static str = "counter-t";
+
+    fn rdb_save(&self) -> Vec<u8> {
+        self.0.to_string().into_bytes()
+    }
+
+    fn rdb_load(data: &[u8], _: u16) -> Result<Self, String> {
+        match String::from_utf8_lossy(data).parse() {
+            Ok(n) => Ok(Counter(n)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+fn incr(ctx: &mut Context, parser: &ParsedCommand) -> Response {
+    let key = parser.get_vec(1).unwrap();
+    let n = match ctx.get_typed_mut::<Counter>(&key) {
+        Ok(Some(counter)) => {
+            counter.0 += 1;
+            counter.0
+        }
+        Ok(None) => match ctx.set_typed(&key, Counter(1)) {
+            Ok(()) => 1,
+            Err(err) => return Response::Error(err),
+        },
+        Err(err) => return Response::Error(err.to_string()),
+    };
+    ctx.key_updated(&key);
+    Response::Integer(n)
+}
+
+fn get(ctx: &mut Context, parser: &ParsedCommand) -> Response {
+    match ctx.get_typed::<Counter>(&parser.get_vec(1).unwrap()) {
+        Ok(Some(counter)) => Response::Integer(counter.0),
+        Ok(None) => Response::Nil,
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+fn properties(arity: i64, flags: CommandFlags, first_key_index: i64) -> CommandProperties {
+    CommandProperties {
+        arity,
+        flags,
+        first_key_index,
+        last_key_index: first_key_index,
+        key_step: first_key_index,
+    }
+}
+
+fn init(module: &mut ModuleInit, args: &[Vec<u8>]) -> Result<(), String> {
+    module.set_name("counter", 1);
+    module.create_type::<Counter>()?;
+    module.create_command(
+        "counter.incr",
+        properties(2, CommandFlags::WRITE | CommandFlags::FAST, 1),
+        incr,
+    )?;
+    module.create_command(
+        "counter.get",
+        properties(2, CommandFlags::READONLY | CommandFlags::FAST, 1),
+        get,
+    )?;
+    let args = args.to_vec();
+    module.create_command(
+        "counter.args",
+        properties(1, CommandFlags::READONLY, 0),
+        move |_: &mut Context, _: &ParsedCommand| {
+            Response::Array(args.iter().map(|arg| Response::Data(arg.clone())).collect())
+        },
+    )
+}
+
+declare_module!(init);
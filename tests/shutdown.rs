@@ -0,0 +1,56 @@
+use std::env::temp_dir;
+use std::fs::{remove_file, write};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// SHUTDOWN NOSAVE exits while another connection holds the database,
+/// without waiting for it.
+#[test]
+fn shutdown_nosave_while_busy() {
+    let port = 16391;
+    let mut config = temp_dir();
+    config.push("rsedis-test-shutdown.conf");
+    write(&config, format!("port {}\nbusy-reply-threshold 100\n", port)).unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rsedis"))
+        .arg(&config)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let addr = format!("127.0.0.1:{}", port);
+    let start = Instant::now();
+    let mut sleeper = loop {
+        match TcpStream::connect(&*addr) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(10) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                let _ = server.kill();
+                panic!("The server did not start: {}", e);
+            }
+        }
+    };
+    let mut stream = TcpStream::connect(&*addr).unwrap();
+    sleeper.write_all(b"*3\r\n$5\r\ndebug\r\n$5\r\nsleep\r\n$2\r\n30\r\n").unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    stream.write_all(b"*2\r\n$8\r\nshutdown\r\n$6\r\nnosave\r\n").unwrap();
+    let status = loop {
+        if let Some(status) = server.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            let _ = server.kill();
+            panic!("SHUTDOWN NOSAVE waited for the busy command");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+    // the connection is closed without a reply
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf).unwrap_or(0), 0);
+    let _ = remove_file(&config);
+}